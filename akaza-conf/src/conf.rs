@@ -1,4 +1,3 @@
-use std::process::Command;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -8,7 +7,7 @@ use gtk::{Application, ApplicationWindow, Button, Label, Notebook};
 use gtk4 as gtk;
 use gtk4::gio::ApplicationFlags;
 use gtk4::Grid;
-use log::{error, info};
+use log::info;
 
 use libakaza::config::{Config, EngineConfig};
 
@@ -63,6 +62,9 @@ fn connect_activate(app: &Application, config: Arc<Mutex<Config>>) -> Result<()>
             keymap: config.keymap.to_string(),
             romkan: config.romkan.to_string(),
             live_conversion: config.live_conversion,
+            user_data: config.user_data.clone(),
+            thumb_shift: config.thumb_shift.clone(),
+            kana_layout: config.kana_layout.clone(),
             engine: EngineConfig {
                 model: config.engine.model.to_string(),
                 dicts: config.engine.dicts.clone(),
@@ -73,22 +75,7 @@ fn connect_activate(app: &Application, config: Arc<Mutex<Config>>) -> Result<()>
 
         config.save().unwrap();
 
-        // 最後に ibus restart をしちゃおう。設定の再読み込みとか実装するのは大変。
-        let output = Command::new("ibus").arg("restart").output().unwrap();
-
-        if !output.status.success() {
-            error!(
-                "Cannot run `ibus restart`: out={}, err={}",
-                String::from_utf8(output.stdout).unwrap(),
-                String::from_utf8(output.stderr).unwrap()
-            );
-        } else {
-            info!(
-                "Ran `ibus restart`: out={}, err={}",
-                String::from_utf8(output.stdout).unwrap(),
-                String::from_utf8(output.stderr).unwrap()
-            );
-        }
+        // ibus-akaza は設定ファイルの変更を監視していて、次のキー入力のときに読みなおす。
     });
     let cancel_button = Button::with_label("Cancel");
     {
@@ -1,10 +1,38 @@
-use gtk4::prelude::{CheckButtonExt, ComboBoxExt, GridExt};
-use gtk4::{CheckButton, ComboBoxText, Grid, Label};
+use gtk4::builders::MessageDialogBuilder;
+use gtk4::prelude::{CheckButtonExt, ComboBoxExt, DialogExt, GridExt, GtkWindowExt, WidgetExt};
+use gtk4::{ButtonsType, CheckButton, ComboBoxText, Grid, Label, MessageType};
 use libakaza::config::Config;
+use libakaza::keymap_check::{check_keymap, KeymapProblemLevel};
+use libakaza::resource::table_dirs;
 use log::info;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+/// 選ばれたキーマップを検査して、問題があればダイアログで知らせる。
+fn show_keymap_problems(keymap_path: &str) {
+    let messages = match check_keymap(keymap_path) {
+        Ok(problems) => problems
+            .iter()
+            .filter(|it| it.level != KeymapProblemLevel::Info)
+            .map(|it| it.to_string())
+            .collect::<Vec<_>>(),
+        Err(err) => vec![format!("{err:?}")],
+    };
+    if messages.is_empty() {
+        return;
+    }
+
+    let dialog = MessageDialogBuilder::new()
+        .message_type(MessageType::Warning)
+        .buttons(ButtonsType::Close)
+        .text("キーマップに問題があります")
+        .secondary_text(&messages.join("\n"))
+        .build();
+    dialog.connect_response(|dialog, _| dialog.close());
+    dialog.show();
+}
+
 pub fn build_core_pane(config: Arc<Mutex<Config>>) -> anyhow::Result<Grid> {
     // キーマップとローマ字テーブル、モデルの設定ができるようにする。
     let grid = Grid::new();
@@ -19,15 +47,16 @@ pub fn build_core_pane(config: Arc<Mutex<Config>>) -> anyhow::Result<Grid> {
     grid.attach(
         &{
             let cbt = ComboBoxText::new();
-            let keymap = get_list("keymap", |f| f.to_string_lossy().ends_with(".yml"));
+            let keymap = get_table_list("keymap");
             for item in keymap {
                 cbt.append(Some(&item.path), &item.name);
             }
-            cbt.set_active_id(Some(&config.lock().unwrap().keymap));
+            cbt.set_active_id(Some(&table_name(&config.lock().unwrap().keymap)));
             {
                 let config = config.clone();
                 cbt.connect_changed(move |f| {
                     if let Some(id) = f.active_id() {
+                        show_keymap_problems(&id);
                         config.lock().unwrap().keymap = id.to_string();
                     }
                 });
@@ -52,12 +81,12 @@ pub fn build_core_pane(config: Arc<Mutex<Config>>) -> anyhow::Result<Grid> {
     grid.attach(
         &{
             let cbt = ComboBoxText::new();
-            let romkan = get_list("romkan", |f| f.to_string_lossy().ends_with(".yml"));
+            let romkan = get_table_list("romkan");
             info!("romkan: {:?}", romkan);
             for item in romkan {
                 cbt.append(Some(&item.path), &item.name);
             }
-            cbt.set_active_id(Some(&config.lock().unwrap().romkan));
+            cbt.set_active_id(Some(&table_name(&config.lock().unwrap().romkan)));
 
             let config = config.clone();
             cbt.connect_changed(move |f| {
@@ -143,6 +172,43 @@ where
         .collect::<Vec<_>>()
 }
 
+/// keymap や romkan のテーブルを名前で並べる。設定ファイルにも名前で保存して、
+/// ibus-akaza がユーザーの設定ディレクトリから順に探せるようにする。
+fn get_table_list(base: &str) -> Vec<PathConfItem> {
+    let mut names = Vec::new();
+    for dir in table_dirs(base).unwrap_or_default() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_suffix(".yml") {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| PathConfItem {
+            name: name.clone(),
+            path: name,
+        })
+        .collect()
+}
+
+/// 以前の設定ファイルにはテーブルのパスが保存されているので、名前にする。
+fn table_name(name_or_path: &str) -> String {
+    match name_or_path.strip_suffix(".yml") {
+        Some(path) => Path::new(path)
+            .file_name()
+            .map(|it| it.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        None => name_or_path.to_string(),
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct PathConfItem {
     name: String,
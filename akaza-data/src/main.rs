@@ -5,14 +5,18 @@ use std::io::Write;
 use clap::{Parser, Subcommand};
 
 use crate::subcmd::check::check;
+use crate::subcmd::check_keymap::check_keymap_file;
 use crate::subcmd::dump_bigram_dict::dump_bigram_dict;
 use crate::subcmd::dump_unigram_dict::dump_unigram_dict;
 use crate::subcmd::evaluate::evaluate;
+use crate::subcmd::import_mozc_romkan::import_mozc_romkan;
 use crate::subcmd::learn_corpus::learn_corpus;
 use crate::subcmd::make_dict::make_system_dict;
 use crate::subcmd::make_stats_system_bigram_lm::make_stats_system_bigram_lm;
+use crate::subcmd::make_stats_system_trigram_lm::make_stats_system_trigram_lm;
 use crate::subcmd::make_stats_system_unigram_lm::make_stats_system_unigram_lm;
 use crate::subcmd::tokenize::tokenize;
+use crate::subcmd::user_data::{export_user_data, import_user_data, merge_user_data};
 use crate::subcmd::vocab::vocab;
 use crate::subcmd::wfreq::wfreq;
 
@@ -51,6 +55,8 @@ enum Commands {
     WordcntUnigram(WordcntUnigramArgs),
     #[clap(arg_required_else_help = true)]
     WordcntBigram(WordcntBigramArgs),
+    #[clap(arg_required_else_help = true)]
+    WordcntTrigram(WordcntTrigramArgs),
 
     LearnCorpus(LearnCorpusArgs),
 
@@ -58,9 +64,15 @@ enum Commands {
     Check(CheckArgs),
     #[clap(arg_required_else_help = true)]
     Evaluate(EvaluateArgs),
+    CheckKeymap(CheckKeymapArgs),
+    #[clap(arg_required_else_help = true)]
+    ImportMozcRomkan(ImportMozcRomkanArgs),
 
     DumpUnigramDict(DumpUnigramDictArgs),
     DumpBigramDict(DumpBigramDictArgs),
+
+    #[clap(subcommand)]
+    UserData(UserDataCommands),
 }
 
 /// コーパスを形態素解析機でトーカナイズする
@@ -125,6 +137,27 @@ struct WordcntBigramArgs {
     corpus_dirs: Vec<String>,
     unigram_trie_file: String,
     bigram_trie_file: String,
+    /// バックオフコストをつけた unigram trie の出力先。unigram_trie_file とは別のファイルにする。
+    /// 指定しない場合は、バックオフコストを計算しない。
+    #[arg(long)]
+    dst_unigram_trie_file: Option<String>,
+}
+
+/// trigram のシステム言語モデルを生成する。
+#[derive(Debug, clap::Args)]
+struct WordcntTrigramArgs {
+    #[arg(short, long)]
+    threshold: u32,
+    #[arg(long)]
+    corpus_dirs: Vec<String>,
+    /// wordcnt-bigram で書き出した unigram trie。
+    /// バックオフコストを使う場合は、--dst-unigram-trie-file で書き出したもの。
+    unigram_trie_file: String,
+    trigram_trie_file: String,
+    /// wordcnt-bigram で書き出した bigram trie。
+    /// 指定した場合は、文脈ごとのバックオフコストを trigram trie に書き込む。
+    #[arg(long)]
+    bigram_trie_file: Option<String>,
 }
 
 /// 動作確認する
@@ -145,6 +178,11 @@ struct LearnCorpusArgs {
     src_bigram: String,
     dst_unigram: String,
     dst_bigram: String,
+    /// wordcnt-trigram で生成した trigram ファイル。指定した場合は trigram も学習する。
+    #[arg(long, requires = "dst_trigram")]
+    src_trigram: Option<String>,
+    #[arg(long, requires = "src_trigram")]
+    dst_trigram: Option<String>,
 }
 
 /// 動作確認する
@@ -174,6 +212,25 @@ struct EvaluateArgs {
     eucjp_dict: Vec<String>,
     #[arg(long)]
     model_dir: String,
+    /// 上位 N 件の変換候補の中に正解が含まれる割合もあわせて計測する
+    #[arg(long, default_value_t = 1)]
+    n_best: usize,
+}
+
+/// キーマップの設定を検査する
+#[derive(Debug, clap::Args)]
+struct CheckKeymapArgs {
+    /// キーマップファイルのパス。省略すると、設定ファイルで選ばれているキーマップを検査する
+    keymap: Option<String>,
+}
+
+/// Mozc のローマ字テーブル(TSV)を、akaza のローマ字テーブルに変換する
+#[derive(Debug, clap::Args)]
+struct ImportMozcRomkanArgs {
+    /// Mozc からエクスポートしたローマ字テーブル
+    src: String,
+    /// 書き出す akaza のローマ字テーブル(yml)
+    dst: String,
 }
 
 /// ユニグラム辞書ファイルをダンプする
@@ -189,6 +246,23 @@ struct DumpBigramDictArgs {
     bigram_file: String,
 }
 
+/// ユーザーの学習データ(unigram, bigram の統計とユーザー辞書)を、別の環境に移す
+#[derive(Debug, Subcommand)]
+enum UserDataCommands {
+    /// 学習データを一つのアーカイブファイルに書き出す
+    Export(UserDataArchiveArgs),
+    /// アーカイブファイルの内容で、学習データを置き換える
+    Import(UserDataArchiveArgs),
+    /// アーカイブファイルの内容を、学習データに足し合わせる
+    Merge(UserDataArchiveArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct UserDataArchiveArgs {
+    /// アーカイブファイルのパス(.tar.gz)
+    archive: String,
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
@@ -231,6 +305,14 @@ fn main() -> anyhow::Result<()> {
             &opt.corpus_dirs,
             &opt.unigram_trie_file,
             &opt.bigram_trie_file,
+            opt.dst_unigram_trie_file.as_deref(),
+        ),
+        Commands::WordcntTrigram(opt) => make_stats_system_trigram_lm(
+            opt.threshold,
+            &opt.corpus_dirs,
+            &opt.unigram_trie_file,
+            opt.bigram_trie_file.as_deref(),
+            &opt.trigram_trie_file,
         ),
         Commands::WordcntUnigram(opt) => {
             make_stats_system_unigram_lm(opt.src_file.as_str(), opt.dst_file.as_str())
@@ -247,6 +329,8 @@ fn main() -> anyhow::Result<()> {
             opts.src_bigram.as_str(),
             opts.dst_unigram.as_str(),
             opts.dst_bigram.as_str(),
+            opts.src_trigram.as_deref(),
+            opts.dst_trigram.as_deref(),
         ),
         Commands::Check(opt) => check(
             &opt.yomi,
@@ -256,12 +340,21 @@ fn main() -> anyhow::Result<()> {
             &opt.utf8_dict,
             &opt.model_dir,
         ),
-        Commands::Evaluate(opt) => {
-            evaluate(&opt.corpus, &opt.eucjp_dict, &opt.utf8_dict, opt.model_dir)
-        }
+        Commands::Evaluate(opt) => evaluate(
+            &opt.corpus,
+            &opt.eucjp_dict,
+            &opt.utf8_dict,
+            opt.model_dir,
+            opt.n_best,
+        ),
+        Commands::CheckKeymap(opt) => check_keymap_file(opt.keymap.as_deref()),
+        Commands::ImportMozcRomkan(opt) => import_mozc_romkan(&opt.src, &opt.dst),
         Commands::DumpUnigramDict(opt) => dump_unigram_dict(opt.dict.as_str()),
         Commands::DumpBigramDict(opt) => {
             dump_bigram_dict(opt.unigram_file.as_str(), opt.bigram_file.as_str())
         }
+        Commands::UserData(UserDataCommands::Export(opt)) => export_user_data(&opt.archive),
+        Commands::UserData(UserDataCommands::Import(opt)) => import_user_data(&opt.archive),
+        Commands::UserData(UserDataCommands::Merge(opt)) => merge_user_data(&opt.archive),
     }
 }
@@ -4,7 +4,7 @@ use std::sync::{Arc, Mutex};
 
 use log::info;
 
-use libakaza::config::{DictConfig, DictEncoding, DictType, DictUsage, EngineConfig};
+use libakaza::config::{Config, DictConfig, DictEncoding, DictType, DictUsage, EngineConfig};
 use libakaza::engine::bigram_word_viterbi_engine::BigramWordViterbiEngineBuilder;
 use libakaza::user_side_data::user_data::UserData;
 
@@ -42,7 +42,7 @@ pub fn check(
     });
     if user_data {
         info!("Enabled user data");
-        let user_data = UserData::load_from_default_path()?;
+        let user_data = UserData::load_from_default_path(&Config::load()?.user_data)?;
         builder.user_data(Arc::new(Mutex::new(user_data)));
     }
     let engine = builder.build()?;
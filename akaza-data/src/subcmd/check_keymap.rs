@@ -0,0 +1,28 @@
+use anyhow::bail;
+
+use libakaza::config::Config;
+use libakaza::keymap_check::{check_keymap, KeymapProblemLevel};
+
+/// キーマップを、継承元も含めて検査して問題を表示する。エラーがあれば失敗にする。
+/// keymap を指定しなければ、設定ファイルで選ばれているキーマップを検査する。
+pub fn check_keymap_file(keymap: Option<&str>) -> anyhow::Result<()> {
+    let keymap = match keymap {
+        Some(keymap) => keymap.to_string(),
+        None => Config::load()?.keymap,
+    };
+
+    let problems = check_keymap(&keymap)?;
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|it| it.level == KeymapProblemLevel::Error)
+        .count();
+    if errors > 0 {
+        bail!("Found {} error(s) in {}", errors, keymap);
+    }
+    println!("OK: {keymap}");
+    Ok(())
+}
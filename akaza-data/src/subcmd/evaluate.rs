@@ -45,11 +45,15 @@ impl SaigenRitsu {
 /// にのっている評価方法を採用。
 ///
 /// なぜこうしているかというと、mozc の論文にのっている BLEU を使用する方式より実装が楽だからです!
+///
+/// n_best に 2 以上を指定した場合は、上位 n_best 件の文全体の変換候補の中に
+/// 正解が含まれている割合(top-N 正解率)もあわせて出力する。
 pub fn evaluate(
     corpus: &Vec<String>,
     eucjp_dict: &Vec<String>,
     utf8_dict: &Vec<String>,
     model_dir: String,
+    n_best: usize,
 ) -> anyhow::Result<()> {
     let mut dicts: Vec<DictConfig> = Vec::new();
     for path in eucjp_dict {
@@ -79,6 +83,7 @@ pub fn evaluate(
 
     let mut good_cnt = 0;
     let mut bad_cnt = 0;
+    let mut n_best_good_cnt = 0;
 
     let force_ranges = Vec::new();
     let total_t1 = SystemTime::now();
@@ -112,6 +117,13 @@ pub fn evaluate(
             // 最長共通部分列を算出。
             saigen_ritsu.add(&surface, &got);
 
+            if n_best > 1 {
+                let paths = akaza.convert_k_best(yomi.as_str(), Some(&force_ranges), n_best)?;
+                if paths.iter().any(|path| path.surface() == surface) {
+                    n_best_good_cnt += 1;
+                }
+            }
+
             if surface == got {
                 info!("{} => (teacher={}, akaza={})", yomi, surface, got);
                 good_cnt += 1;
@@ -148,6 +160,15 @@ pub fn evaluate(
         total_elapsed.as_millis(),
         saigen_ritsu.rate(),
     );
+    if n_best > 1 {
+        info!(
+            "Top-{} accuracy={}/{} ({}%)",
+            n_best,
+            n_best_good_cnt,
+            good_cnt + bad_cnt,
+            100.0 * (n_best_good_cnt as f32) / ((good_cnt + bad_cnt) as f32)
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,21 @@
+use std::fs;
+
+use anyhow::Context;
+
+use libakaza::romkan::{RomKanConfig, RomKanConverter};
+
+/// Mozc からエクスポートしたローマ字テーブルを、akaza のローマ字テーブルに変換する。
+pub fn import_mozc_romkan(src: &str, dst: &str) -> anyhow::Result<()> {
+    let tsv = fs::read_to_string(src).with_context(|| format!("Cannot read {src}"))?;
+    let config = RomKanConfig::from_mozc_tsv(&tsv)?;
+    fs::write(
+        dst,
+        format!("# Imported from {}\n{}", src, config.to_yaml()?),
+    )
+    .with_context(|| format!("Cannot write {dst}"))?;
+
+    // 読み込めないテーブルを書き出していないか確認する。
+    RomKanConverter::new(dst)?;
+    println!("Imported {src} to {dst}");
+    Ok(())
+}
@@ -1,13 +1,12 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::Path;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use encoding_rs::UTF_8;
 use log::{debug, info};
 
 use crate::wordcnt::wordcnt_bigram::WordcntBigram;
+use crate::wordcnt::wordcnt_trigram::WordcntTrigram;
 use crate::wordcnt::wordcnt_unigram::WordcntUnigram;
 use libakaza::corpus::{read_corpus_file, FullAnnotationCorpus};
 use libakaza::dict::skk::read::read_skkdict;
@@ -16,10 +15,12 @@ use libakaza::graph::graph_resolver::GraphResolver;
 use libakaza::graph::segmenter::Segmenter;
 use libakaza::kana_kanji::hashmap_vec::HashmapVecKanaKanjiDict;
 use libakaza::kana_trie::cedarwood_kana_trie::CedarwoodKanaTrie;
-use libakaza::lm::base::{SystemBigramLM, SystemUnigramLM};
+use libakaza::lm::base::{SystemBigramLM, SystemTrigramLM, SystemUnigramLM};
 use libakaza::lm::on_memory::on_memory_system_bigram_lm::OnMemorySystemBigramLM;
+use libakaza::lm::on_memory::on_memory_system_trigram_lm::OnMemorySystemTrigramLM;
 use libakaza::lm::on_memory::on_memory_system_unigram_lm::OnMemorySystemUnigramLM;
 use libakaza::lm::system_bigram::MarisaSystemBigramLMBuilder;
+use libakaza::lm::system_trigram::MarisaSystemTrigramLMBuilder;
 use libakaza::lm::system_unigram_lm::{MarisaSystemUnigramLM, MarisaSystemUnigramLMBuilder};
 use libakaza::user_side_data::user_data::UserData;
 
@@ -27,12 +28,18 @@ struct LearningService {
     graph_builder:
         GraphBuilder<OnMemorySystemUnigramLM, OnMemorySystemBigramLM, HashmapVecKanaKanjiDict>,
     segmenter: Segmenter,
-    system_unigram_lm: Rc<OnMemorySystemUnigramLM>,
-    system_bigram_lm: Rc<OnMemorySystemBigramLM>,
+    system_unigram_lm: Arc<OnMemorySystemUnigramLM>,
+    system_bigram_lm: Arc<OnMemorySystemBigramLM>,
+    system_trigram_lm: Option<Arc<OnMemorySystemTrigramLM>>,
 }
 
 impl LearningService {
-    pub fn new(src_unigram: &str, src_bigram: &str, corpuses: &[&str]) -> anyhow::Result<Self> {
+    pub fn new(
+        src_unigram: &str,
+        src_bigram: &str,
+        src_trigram: Option<&str>,
+        corpuses: &[&str],
+    ) -> anyhow::Result<Self> {
         let system_kana_kanji_dict = read_skkdict(Path::new("data/SKK-JISYO.akaza"), UTF_8)?;
         let all_yomis = system_kana_kanji_dict.keys().cloned().collect::<Vec<_>>();
         let system_kana_trie = CedarwoodKanaTrie::build(all_yomis);
@@ -65,34 +72,54 @@ impl LearningService {
                 }
             }
         }
-        let system_unigram_lm = Rc::new(OnMemorySystemUnigramLM::new(
-            Rc::new(RefCell::new(unigram_map)),
+        let mut system_unigram_lm = OnMemorySystemUnigramLM::new(
+            Arc::new(RwLock::new(unigram_map)),
             src_system_unigram_lm.total_words,
             src_system_unigram_lm.unique_words,
-        ));
+        );
+        system_unigram_lm.set_backoff_map(src_system_unigram_lm.to_backoff_hashmap());
+        let system_unigram_lm = Arc::new(system_unigram_lm);
 
         info!("bigram source file: {}", src_bigram);
         let src_system_bigram_lm = WordcntBigram::load(src_bigram)?;
-        let system_bigram_lm = Rc::new(OnMemorySystemBigramLM::new(
-            Rc::new(RefCell::new(src_system_bigram_lm.to_cnt_map())),
+        let system_bigram_lm = Arc::new(OnMemorySystemBigramLM::new(
+            Arc::new(RwLock::new(src_system_bigram_lm.to_cnt_map())),
             src_system_bigram_lm.get_default_edge_cost(),
             src_system_bigram_lm.total_words,
             src_system_bigram_lm.unique_words,
         ));
 
-        let graph_builder = GraphBuilder::new(
+        let system_trigram_lm = if let Some(src_trigram) = src_trigram {
+            info!("trigram source file: {}", src_trigram);
+            let src_system_trigram_lm = WordcntTrigram::load(src_trigram)?;
+            let mut system_trigram_lm = OnMemorySystemTrigramLM::new(
+                Arc::new(RwLock::new(src_system_trigram_lm.to_cnt_map())),
+                src_system_trigram_lm.total_words,
+                src_system_trigram_lm.unique_words,
+            );
+            system_trigram_lm.set_backoff_map(src_system_trigram_lm.to_backoff_map());
+            Some(Arc::new(system_trigram_lm))
+        } else {
+            None
+        };
+
+        let mut graph_builder = GraphBuilder::new(
             HashmapVecKanaKanjiDict::new(system_kana_kanji_dict),
             HashmapVecKanaKanjiDict::new(HashMap::default()),
             Arc::new(Mutex::new(UserData::default())),
             system_unigram_lm.clone(),
             system_bigram_lm.clone(),
         );
+        if let Some(system_trigram_lm) = &system_trigram_lm {
+            graph_builder.set_system_trigram_lm(system_trigram_lm.clone());
+        }
 
         Ok(LearningService {
             graph_builder,
             segmenter,
             system_unigram_lm,
             system_bigram_lm,
+            system_trigram_lm,
         })
     }
 
@@ -172,6 +199,25 @@ impl LearningService {
                 }
             }
 
+            // learn trigram
+            if let Some(system_trigram_lm) = &self.system_trigram_lm {
+                let word_ids = teacher
+                    .nodes
+                    .iter()
+                    .map(|node| self.system_unigram_lm.find(node.key().as_str()))
+                    .collect::<Vec<_>>();
+                for window in word_ids.windows(3) {
+                    let [Some((word_id1, _)), Some((word_id2, _)), Some((word_id3, _))] = window
+                    else {
+                        continue;
+                    };
+                    let v = system_trigram_lm
+                        .get_edge_cnt(*word_id1, *word_id2, *word_id3)
+                        .unwrap_or(0_u32);
+                    system_trigram_lm.update(*word_id1, *word_id2, *word_id3, v + delta);
+                }
+            }
+
             debug!("BAD! result={}, surface={}", result, surface);
             Ok(false)
         } else {
@@ -184,7 +230,11 @@ impl LearningService {
         // unigram
         let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
         for (key, (_, cost)) in self.system_unigram_lm.as_hash_map() {
-            unigram_builder.add(key.as_str(), cost);
+            if let Some(backoff) = self.system_unigram_lm.find_backoff(key.as_str()) {
+                unigram_builder.add_with_backoff(key.as_str(), cost, backoff);
+            } else {
+                unigram_builder.add(key.as_str(), cost);
+            }
         }
         // ↓本来なら現在のデータで再調整すべきだが、一旦元のものを使う。
         // TODO あとで整理する
@@ -244,6 +294,51 @@ impl LearningService {
 
         Ok(())
     }
+
+    pub fn save_trigram(&self, dst_unigram: &str, dst_trigram: &str) -> anyhow::Result<()> {
+        let Some(system_trigram_lm) = &self.system_trigram_lm else {
+            return Ok(());
+        };
+
+        // trigram の保存。単語IDは新しい unigram のものにふりなおす。
+        let new_unigram = MarisaSystemUnigramLM::load(dst_unigram)?;
+        let src_wordid2key = self
+            .system_unigram_lm
+            .as_hash_map()
+            .iter()
+            .map(|(key, (word_id, _))| (*word_id, key.to_string()))
+            .collect::<HashMap<i32, String>>();
+        let to_new_word_id = |word_id: i32| -> Option<i32> {
+            let word = src_wordid2key.get(&word_id)?;
+            let (new_word_id, _) = new_unigram.find(word)?;
+            Some(new_word_id)
+        };
+
+        let mut trigram_builder = MarisaSystemTrigramLMBuilder::default();
+        for ((word_id1, word_id2, word_id3), cost) in system_trigram_lm.as_hash_map() {
+            let (Some(new_word_id1), Some(new_word_id2), Some(new_word_id3)) = (
+                to_new_word_id(word_id1),
+                to_new_word_id(word_id2),
+                to_new_word_id(word_id3),
+            ) else {
+                info!("Unknown word_id: {},{},{}", word_id1, word_id2, word_id3);
+                continue;
+            };
+            trigram_builder.add(new_word_id1, new_word_id2, new_word_id3, cost);
+        }
+        for ((word_id1, word_id2), backoff) in system_trigram_lm.backoff_map() {
+            let (Some(new_word_id1), Some(new_word_id2)) =
+                (to_new_word_id(*word_id1), to_new_word_id(*word_id2))
+            else {
+                continue;
+            };
+            trigram_builder.add_backoff(new_word_id1, new_word_id2, *backoff);
+        }
+        info!("Save trigram to {}", dst_trigram);
+        trigram_builder.save(dst_trigram)?;
+
+        Ok(())
+    }
 }
 
 /// コーパスを元にした学習を行います。
@@ -260,10 +355,13 @@ pub fn learn_corpus(
     src_bigram: &str,
     dst_unigram: &str,
     dst_bigram: &str,
+    src_trigram: Option<&str>,
+    dst_trigram: Option<&str>,
 ) -> anyhow::Result<()> {
     let service = LearningService::new(
         src_unigram,
         src_bigram,
+        src_trigram,
         &[may_corpus, should_corpus, must_corpus],
     )?;
 
@@ -279,6 +377,9 @@ pub fn learn_corpus(
     // 保存していく
     service.save_unigram(dst_unigram)?;
     service.save_bigram(dst_unigram, dst_bigram)?;
+    if let Some(dst_trigram) = dst_trigram {
+        service.save_trigram(dst_unigram, dst_trigram)?;
+    }
 
     Ok(())
 }
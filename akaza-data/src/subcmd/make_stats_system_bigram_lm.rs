@@ -3,25 +3,44 @@ use std::fs::File;
 use std::io::{prelude::*, BufReader};
 use std::path::{Path, PathBuf};
 
-use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
+use anyhow::{anyhow, bail};
 use chrono::Local;
 use log::info;
 use rayon::prelude::*;
 
-use libakaza::lm::base::{SystemBigramLM, SystemUnigramLM};
+use libakaza::cost::{
+    calc_backoff_cost, calc_cost, kneser_ney_discount, modified_kneser_ney_discounts,
+};
+use libakaza::lm::base::{SystemBigramLM, SystemUnigramLM, UnigramBackoff};
 
 use crate::utils::get_file_list;
 use crate::wordcnt::wordcnt_bigram::{WordcntBigram, WordcntBigramBuilder};
-use crate::wordcnt::wordcnt_unigram::WordcntUnigram;
+use crate::wordcnt::wordcnt_unigram::{WordcntUnigram, WordcntUnigramBuilder};
 
+/// bigram のシステム言語モデルを作成する。
+///
+/// dst_unigram_trie_file を指定した場合は、あわせて、modified Kneser-Ney で求めた
+/// 単語ごとのバックオフコストと継続コストをつけた unigram trie を書き出す。trie を作り直すと単語IDが変わるので、
+/// bigram trie の単語IDは dst_unigram_trie_file のものになる。
+/// wordcnt-trigram や learn-corpus でも dst_unigram_trie_file を使うこと。
 pub fn make_stats_system_bigram_lm(
     threshold: u32,
     corpus_dirs: &Vec<String>,
     unigram_trie_file: &str,
     bigram_trie_file: &str,
+    dst_unigram_trie_file: Option<&str>,
 ) -> Result<()> {
+    if let Some(dst_unigram_trie_file) = dst_unigram_trie_file {
+        if Path::new(unigram_trie_file) == Path::new(dst_unigram_trie_file) {
+            bail!(
+                "The unigram trie with backoff costs must be written to another file: {}",
+                dst_unigram_trie_file
+            );
+        }
+    }
+
     // まずは unigram の language model を読み込む
     let unigram_lm = WordcntUnigram::load(unigram_trie_file)?;
     info!(
@@ -89,21 +108,161 @@ pub fn make_stats_system_bigram_lm(
         }
     }
 
+    let new_word_ids = if let Some(dst_unigram_trie_file) = dst_unigram_trie_file {
+        write_backoff_unigram(
+            &unigram_lm,
+            &merged,
+            &wordcnt,
+            &reverse_unigram_map,
+            dst_unigram_trie_file,
+        )?
+    } else {
+        unigram_map
+            .values()
+            .map(|word_id| (*word_id, *word_id))
+            .collect()
+    };
+
     // 結果を書き込む
     info!("Generating trie file");
     let mut builder = WordcntBigramBuilder::default();
     for ((word_id1, word_id2), cnt) in wordcnt {
-        builder.add(word_id1, word_id2, cnt);
+        let (Some(new_word_id1), Some(new_word_id2)) =
+            (new_word_ids.get(&word_id1), new_word_ids.get(&word_id2))
+        else {
+            continue;
+        };
+        builder.add(*new_word_id1, *new_word_id2, cnt);
     }
     info!("Writing {}", bigram_trie_file);
     builder.save(bigram_trie_file)?;
 
-    validation(unigram_trie_file, bigram_trie_file)?;
+    validation(
+        dst_unigram_trie_file.unwrap_or(unigram_trie_file),
+        bigram_trie_file,
+    )?;
 
     println!("DONE");
     Ok(())
 }
 
+/// バックオフコストをつけた unigram trie を書き出す。入力の unigram trie はそのまま残す。
+///
+/// @return 入力の unigram trie の単語ID -> 書き出した unigram trie の単語ID
+fn write_backoff_unigram(
+    unigram_lm: &WordcntUnigram,
+    merged: &HashMap<(i32, i32), u32>,
+    wordcnt: &HashMap<(i32, i32), u32>,
+    reverse_unigram_map: &HashMap<i32, String>,
+    dst_unigram_trie_file: &str,
+) -> Result<HashMap<i32, i32>> {
+    // 単語ごとのバックオフコストを計算する
+    let n = [1, 2, 3, 4].map(|k| merged.values().filter(|cnt| **cnt == k).count() as u32);
+    let discounts = modified_kneser_ney_discounts(n);
+    info!("Modified Kneser-Ney discounts: n={:?} D={:?}", n, discounts);
+    let backoff_map = calc_backoff_map(unigram_lm, merged, wordcnt, &discounts);
+
+    info!("Writing backoff costs to {}", dst_unigram_trie_file);
+    let mut unigram_builder = WordcntUnigramBuilder::default();
+    for (word, (word_id, cnt)) in unigram_lm.to_count_hashmap() {
+        if let Some(backoff) = backoff_map.get(&word_id) {
+            unigram_builder.add_with_backoff(word.as_str(), cnt, *backoff);
+        } else {
+            unigram_builder.add(word.as_str(), cnt);
+        }
+    }
+    unigram_builder.save(dst_unigram_trie_file)?;
+
+    // trie を作り直したので、単語IDを振り直す。
+    let new_unigram_lm = WordcntUnigram::load(dst_unigram_trie_file)?;
+    Ok(reverse_unigram_map
+        .iter()
+        .filter_map(|(word_id, word)| {
+            new_unigram_lm
+                .find(word)
+                .map(|(new_word_id, _)| (*word_id, new_word_id))
+        })
+        .collect())
+}
+
+/// 単語IDごとのバックオフコストと継続コストを計算する。
+///
+/// 継続コストは、その単語がいくつの種類の単語の後ろに出現したか (継続回数) から求める、Kneser-Ney の下位モデル。
+/// バックオフコストは、割引係数 D1, D2, D3+ による割引と、あしきりで落とされた bigram の出現回数を、
+/// 未知の bigram に継続コストの確率に比例して配分するように求める。
+fn calc_backoff_map(
+    unigram_lm: &WordcntUnigram,
+    merged: &HashMap<(i32, i32), u32>,
+    wordcnt: &HashMap<(i32, i32), u32>,
+    discounts: &[f32; 3],
+) -> HashMap<i32, UnigramBackoff> {
+    let unigram_cnt = unigram_lm
+        .to_count_hashmap()
+        .into_values()
+        .collect::<HashMap<i32, u32>>();
+    let unigram_cost = |word_id: &i32| {
+        calc_cost(
+            *unigram_cnt.get(word_id).unwrap_or(&0),
+            unigram_lm.total_words,
+            unigram_lm.unique_words,
+        )
+    };
+
+    // w2 の前に出現した単語の種類数
+    let mut continuation_cnt: HashMap<i32, u32> = HashMap::new();
+    for (_, word_id2) in merged.keys() {
+        *continuation_cnt.entry(*word_id2).or_default() += 1;
+    }
+    let continuation_cost = |word_id: &i32| {
+        calc_cost(
+            *continuation_cnt.get(word_id).unwrap_or(&0),
+            merged.len() as u32,
+            unigram_lm.unique_words,
+        )
+    };
+
+    // w1 から始まる bigram の出現回数の合計
+    let mut context_cnt: HashMap<i32, u32> = HashMap::new();
+    // 割引によって余った出現回数
+    let mut left_cnt: HashMap<i32, f32> = HashMap::new();
+    // bigram に登録された後続単語の、継続回数から求めた確率の和
+    let mut followers_prob: HashMap<i32, f32> = HashMap::new();
+    for ((word_id1, word_id2), cnt) in merged {
+        *context_cnt.entry(*word_id1).or_default() += cnt;
+        if wordcnt.contains_key(&(*word_id1, *word_id2)) {
+            *left_cnt.entry(*word_id1).or_default() += kneser_ney_discount(discounts, *cnt);
+            *followers_prob.entry(*word_id1).or_default() +=
+                10_f32.powf(-continuation_cost(word_id2));
+        } else {
+            *left_cnt.entry(*word_id1).or_default() += *cnt as f32;
+        }
+    }
+
+    // 後続の bigram がない単語も、継続コストを使うので登録する。
+    // その場合は、余った確率質量が 1 なので、バックオフコストは unigram コストになる。
+    unigram_cnt
+        .keys()
+        .map(|word_id| {
+            let left_mass = match context_cnt.get(word_id) {
+                Some(cnt) => left_cnt.get(word_id).unwrap_or(&0_f32) / (*cnt as f32),
+                None => 1_f32,
+            };
+            let backoff = calc_backoff_cost(
+                unigram_cost(word_id),
+                left_mass,
+                *followers_prob.get(word_id).unwrap_or(&0_f32),
+            );
+            (
+                *word_id,
+                UnigramBackoff {
+                    backoff,
+                    continuation: continuation_cost(word_id),
+                },
+            )
+        })
+        .collect()
+}
+
 fn count_bigram(
     src: &PathBuf,
     unigram_lm: &HashMap<String, i32>,
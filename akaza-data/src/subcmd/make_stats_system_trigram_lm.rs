@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::info;
+use rayon::prelude::*;
+
+use libakaza::cost::{calc_backoff_cost, kneser_ney_discount, modified_kneser_ney_discounts};
+use libakaza::lm::base::{SystemBigramLM, SystemUnigramLM, UnigramBackoff};
+
+use crate::utils::get_file_list;
+use crate::wordcnt::wordcnt_bigram::WordcntBigram;
+use crate::wordcnt::wordcnt_trigram::{WordcntTrigram, WordcntTrigramBuilder};
+use crate::wordcnt::wordcnt_unigram::WordcntUnigram;
+
+/// trigram のシステム言語モデルを作成する。
+///
+/// bigram_trie_file を指定した場合は、あわせて、modified Kneser-Ney の割引係数で求めた
+/// 文脈ごとのバックオフコストを trigram trie に書き込む。
+/// unigram_trie_file と bigram_trie_file は、wordcnt-bigram で作ったものを使うこと。
+pub fn make_stats_system_trigram_lm(
+    threshold: u32,
+    corpus_dirs: &Vec<String>,
+    unigram_trie_file: &str,
+    bigram_trie_file: Option<&str>,
+    trigram_trie_file: &str,
+) -> Result<()> {
+    // まずは unigram の language model を読み込む
+    let unigram_lm = WordcntUnigram::load(unigram_trie_file)?;
+    info!(
+        "Unigram system lm: {} threshold={}",
+        unigram_lm.num_keys(),
+        threshold
+    );
+
+    let unigram_map = unigram_lm
+        .as_hash_map()
+        .iter()
+        .map(|(key, (word_id, _))| (key.clone(), *word_id))
+        .collect::<HashMap<_, _>>();
+
+    // 次に、コーパスをスキャンして trigram を読み取る。
+    let mut file_list: Vec<PathBuf> = Vec::new();
+    for corpus_dir in corpus_dirs {
+        let list = get_file_list(Path::new(corpus_dir))?;
+        for x in list {
+            file_list.push(x)
+        }
+    }
+    let results = file_list
+        .par_iter()
+        .map(|src| count_trigram(src, &unigram_map))
+        .collect::<Vec<_>>();
+
+    // 集計した結果をマージする
+    info!("Merging");
+    let mut merged: HashMap<(i32, i32, i32), u32> = HashMap::new();
+    for result in results {
+        let result = result?;
+        for (word_ids, cnt) in result {
+            *merged.entry(word_ids).or_insert(0) += cnt;
+        }
+    }
+
+    // 文脈ごとのバックオフコストを計算する
+    let backoff_map = if let Some(bigram_trie_file) = bigram_trie_file {
+        let n = [1, 2, 3, 4].map(|k| merged.values().filter(|cnt| **cnt == k).count() as u32);
+        let discounts = modified_kneser_ney_discounts(n);
+        info!("Modified Kneser-Ney discounts: n={:?} D={:?}", n, discounts);
+        let bigram_lm = WordcntBigram::load(bigram_trie_file)?;
+        calc_backoff_map(&unigram_lm, &bigram_lm, &merged, threshold, &discounts)
+    } else {
+        HashMap::new()
+    };
+
+    // 結果を書き込む
+    // trigram は組み合わせが多いので、bigram 以上にあしきりが重要になる。
+    info!("Generating trie file");
+    let mut builder = WordcntTrigramBuilder::default();
+    for ((word_id1, word_id2, word_id3), cnt) in merged {
+        if cnt > threshold {
+            builder.add(word_id1, word_id2, word_id3, cnt);
+        }
+    }
+    for ((word_id1, word_id2), backoff) in backoff_map {
+        builder.add_backoff(word_id1, word_id2, backoff);
+    }
+    info!("Writing {}", trigram_trie_file);
+    builder.save(trigram_trie_file)?;
+
+    let trigram = WordcntTrigram::load(trigram_trie_file)?;
+    info!(
+        "Trigram system lm: total_words={} unique_words={}",
+        trigram.total_words, trigram.unique_words
+    );
+
+    println!("DONE");
+    Ok(())
+}
+
+/// 文脈 (w1, w2) ごとのバックオフコストを計算する。
+///
+/// 割引係数 D1, D2, D3+ による割引と、あしきりで落とされた trigram の出現回数を、未知の trigram に配分する。
+/// 配分の重みには、bigram から求めた P(w3 | w2) を使う。bigram 自体の下位モデルは継続回数から求めている。
+/// trigram が一つも残らなかった文脈は、バックオフコストが 0 なので登録しない。
+fn calc_backoff_map(
+    unigram_lm: &WordcntUnigram,
+    bigram_lm: &WordcntBigram,
+    merged: &HashMap<(i32, i32, i32), u32>,
+    threshold: u32,
+    discounts: &[f32; 3],
+) -> HashMap<(i32, i32), f32> {
+    let words = unigram_lm.as_hash_map();
+    let unigram_cost = words
+        .values()
+        .map(|(word_id, cost)| (*word_id, *cost))
+        .collect::<HashMap<i32, f32>>();
+    let unigram_backoff = unigram_lm
+        .to_backoff_hashmap()
+        .iter()
+        .filter_map(|(word, backoff)| words.get(word).map(|(word_id, _)| (*word_id, *backoff)))
+        .collect::<HashMap<i32, UnigramBackoff>>();
+    // エンジンと同じく、未知の bigram は unigram のバックオフコストと継続コストで見積もる。
+    let bigram_cost = |word_id1: i32, word_id2: i32| -> Option<f32> {
+        bigram_lm.get_edge_cost(word_id1, word_id2).or_else(|| {
+            let continuation = unigram_backoff
+                .get(&word_id2)
+                .map(|it| it.continuation)
+                .or_else(|| unigram_cost.get(&word_id2).copied())?;
+            Some(unigram_backoff.get(&word_id1)?.backoff + continuation)
+        })
+    };
+
+    // (w1, w2) から始まる trigram の出現回数の合計
+    let mut context_cnt: HashMap<(i32, i32), u32> = HashMap::new();
+    // 割引によって余った出現回数
+    let mut left_cnt: HashMap<(i32, i32), f32> = HashMap::new();
+    // trigram に登録された後続単語の P(w3 | w2) の和
+    let mut followers_prob: HashMap<(i32, i32), f32> = HashMap::new();
+    for ((word_id1, word_id2, word_id3), cnt) in merged {
+        let context = (*word_id1, *word_id2);
+        *context_cnt.entry(context).or_default() += cnt;
+        if *cnt > threshold {
+            *left_cnt.entry(context).or_default() += kneser_ney_discount(discounts, *cnt);
+            if let (Some(cost), Some(word2_cost)) = (
+                bigram_cost(*word_id2, *word_id3),
+                unigram_cost.get(word_id2),
+            ) {
+                *followers_prob.entry(context).or_default() += 10_f32.powf(word2_cost - cost);
+            }
+        } else {
+            *left_cnt.entry(context).or_default() += *cnt as f32;
+        }
+    }
+
+    followers_prob
+        .keys()
+        .map(|context| {
+            let left_mass = left_cnt.get(context).unwrap_or(&0_f32) / (context_cnt[context] as f32);
+            let backoff = calc_backoff_cost(0_f32, left_mass, followers_prob[context].min(1_f32));
+            (*context, backoff)
+        })
+        .collect()
+}
+
+fn count_trigram(
+    src: &PathBuf,
+    unigram_lm: &HashMap<String, i32>,
+) -> Result<HashMap<(i32, i32, i32), u32>> {
+    info!("Counting {}", src.to_string_lossy());
+    let file = File::open(src)?;
+    let mut map: HashMap<(i32, i32, i32), u32> = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        let words = line.split(' ').collect::<Vec<_>>();
+        if words.len() < 3 {
+            continue;
+        }
+        let word_ids = words
+            .iter()
+            .map(|word| unigram_lm.get(&word.to_string()))
+            .collect::<Vec<_>>();
+
+        for window in word_ids.windows(3) {
+            let [Some(word_id1), Some(word_id2), Some(word_id3)] = window else {
+                continue;
+            };
+            *map.entry((**word_id1, **word_id2, **word_id3)).or_insert(0) += 1;
+        }
+    }
+    Ok(map)
+}
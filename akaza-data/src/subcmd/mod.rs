@@ -1,11 +1,15 @@
 pub mod check;
+pub mod check_keymap;
 pub mod dump_bigram_dict;
 pub mod dump_unigram_dict;
 pub mod evaluate;
+pub mod import_mozc_romkan;
 pub mod learn_corpus;
 pub mod make_dict;
 pub mod make_stats_system_bigram_lm;
+pub mod make_stats_system_trigram_lm;
 pub mod make_stats_system_unigram_lm;
 pub mod tokenize;
+pub mod user_data;
 pub mod vocab;
 pub mod wfreq;
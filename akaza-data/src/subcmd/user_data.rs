@@ -0,0 +1,198 @@
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use tempfile::TempDir;
+
+use libakaza::config::Config;
+use libakaza::user_side_data::user_data::UserData;
+
+// アーカイブに含めるファイルの名前
+const FORMAT_FILE: &str = "format.txt";
+const UNIGRAM_FILE: &str = "unigram.v2.txt";
+const BIGRAM_FILE: &str = "bigram.v2.txt";
+const DICT_FILE: &str = "SKK-JISYO.user";
+
+/// アーカイブの形式のバージョン。FORMAT_FILE に書く。
+const FORMAT_VERSION: &str = "akaza-user-data 2";
+
+/// ユーザーの学習データを一つのアーカイブファイルに書き出す。
+pub fn export_user_data(archive: &str) -> anyhow::Result<()> {
+    let user_data = UserData::load_from_default_path(&Config::load()?.user_data)?;
+    write_archive(&user_data, Path::new(archive))?;
+    println!("Exported user data to {archive}");
+    Ok(())
+}
+
+/// アーカイブファイルの内容で、ユーザーの学習データを置き換える。
+/// 動いている ibus-akaza や akaza-server は、保存のスレッドが新しい世代に気づいて読み込みなおす。
+pub fn import_user_data(archive: &str) -> anyhow::Result<()> {
+    let config = Config::load()?.user_data;
+    // 壊れたアーカイブで学習データを上書きしないように、先に全部読んでおく。
+    let src = read_archive(Path::new(archive))?;
+    let evicted = UserData::update_default_files(&config, |user_data| user_data.replace(src))?;
+    print_evicted(evicted, config.max_entries);
+    println!("Imported user data from {archive}.");
+    Ok(())
+}
+
+/// アーカイブファイルの内容を、ユーザーの学習データに足し合わせる。
+pub fn merge_user_data(archive: &str) -> anyhow::Result<()> {
+    let config = Config::load()?.user_data;
+    let src = read_archive(Path::new(archive))?;
+    let evicted = UserData::update_default_files(&config, |user_data| user_data.merge(&src))?;
+    print_evicted(evicted, config.max_entries);
+    println!("Merged user data from {archive}.");
+    Ok(())
+}
+
+fn print_evicted(evicted: usize, max_entries: usize) {
+    if evicted > 0 {
+        println!(
+            "Dropped {evicted} least recently used entries to keep max_entries ({max_entries})"
+        );
+    }
+}
+
+fn write_archive(user_data: &UserData, archive: &Path) -> anyhow::Result<()> {
+    let tmpdir = TempDir::new()?;
+    let path = |name: &str| tmpdir.path().join(name).to_string_lossy().to_string();
+    user_data.write_files_to(&path(UNIGRAM_FILE), &path(BIGRAM_FILE), &path(DICT_FILE))?;
+    fs::write(path(FORMAT_FILE), format!("{FORMAT_VERSION}\n"))?;
+
+    // 書き出しに失敗したときに、既存のアーカイブを壊さないように一時ファイルに書く。
+    let tmp_archive = archive.with_extension("tmp");
+    {
+        let file = File::create(&tmp_archive)
+            .with_context(|| format!("Cannot create {}", tmp_archive.display()))?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        for name in [FORMAT_FILE, UNIGRAM_FILE, BIGRAM_FILE, DICT_FILE] {
+            builder.append_path_with_name(tmpdir.path().join(name), name)?;
+        }
+        builder.into_inner()?.finish()?;
+    }
+    fs::rename(&tmp_archive, archive)?;
+    info!("Wrote {}", archive.display());
+    Ok(())
+}
+
+/// アーカイブを読む。形式が違うときや、壊れた行が一つでもあるときはエラーにする。
+fn read_archive(archive: &Path) -> anyhow::Result<UserData> {
+    let tmpdir = TempDir::new()?;
+    let file = File::open(archive).with_context(|| format!("Cannot open {}", archive.display()))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        // 想定していないパスには展開しない。
+        if ![FORMAT_FILE, UNIGRAM_FILE, BIGRAM_FILE, DICT_FILE].contains(&name.as_str()) {
+            bail!("Unexpected file in the archive: {}", name);
+        }
+        entry.unpack(tmpdir.path().join(&name))?;
+    }
+
+    let path = |name: &str| tmpdir.path().join(name).to_string_lossy().to_string();
+    for name in [FORMAT_FILE, UNIGRAM_FILE, BIGRAM_FILE, DICT_FILE] {
+        if !Path::new(&path(name)).exists() {
+            bail!("{} is not in the archive: {}", name, archive.display());
+        }
+    }
+    let format = fs::read_to_string(path(FORMAT_FILE))?;
+    if format.trim() != FORMAT_VERSION {
+        bail!(
+            "Unsupported archive format: {:?} (expected {:?})",
+            format.trim(),
+            FORMAT_VERSION
+        );
+    }
+    UserData::load_strict(&path(UNIGRAM_FILE), &path(BIGRAM_FILE), &path(DICT_FILE))
+        .with_context(|| format!("Broken archive: {}", archive.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use libakaza::graph::candidate::Candidate;
+    use libakaza::graph::word_node::WordNode;
+
+    use super::*;
+
+    #[test]
+    fn test_export_and_merge() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let archive = tmpdir.path().join("akaza.tar.gz");
+
+        let mut compound = Candidate::new("きたかな", "北香那", 0_f32);
+        compound.compound_word = true;
+        let mut src = UserData::default();
+        src.record_entries(&[Candidate::new("わたし", "私", 0_f32), compound]);
+        write_archive(&src, &archive)?;
+
+        let mut dst = UserData::default();
+        dst.record_entries(&[Candidate::new("わたし", "私", 0_f32)]);
+        dst.record_entries(&[Candidate::new("わたし", "渡し", 0_f32)]);
+        let watashi = WordNode::new(0, "私", "わたし", None, false);
+        let cost_before = dst.get_unigram_cost(&watashi);
+
+        assert_eq!(dst.merge(&read_archive(&archive)?), 0);
+
+        // 出現回数は足し合わされる。
+        assert!(dst.get_unigram_cost(&watashi) < cost_before);
+        let kitakana = WordNode::new(0, "北香那", "きたかな", None, false);
+        assert!(dst.get_bigram_cost(&watashi, &kitakana).is_some());
+        assert_eq!(dst.dict.get("きたかな"), Some(&vec!["北香那".to_string()]));
+        Ok(())
+    }
+
+    /// アーカイブの中のファイルを書き換えたアーカイブを作る。
+    fn rewrite_archive(archive: &Path, name: &str, content: &str) -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        tar::Archive::new(GzDecoder::new(File::open(archive)?)).unpack(tmpdir.path())?;
+        fs::write(tmpdir.path().join(name), content)?;
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(archive)?,
+            Compression::default(),
+        ));
+        for name in [FORMAT_FILE, UNIGRAM_FILE, BIGRAM_FILE, DICT_FILE] {
+            builder.append_path_with_name(tmpdir.path().join(name), name)?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_broken_archive() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let archive = tmpdir.path().join("akaza.tar.gz");
+        let mut src = UserData::default();
+        src.record_entries(&[
+            Candidate::new("わたし", "私", 0_f32),
+            Candidate::new("が", "が", 0_f32),
+        ]);
+
+        for (name, content) in [
+            (FORMAT_FILE, "akaza-user-data 1\n"),
+            // v1 形式の行
+            (UNIGRAM_FILE, "私/わたし 3\n"),
+            // 途中で切れた行
+            (BIGRAM_FILE, "私/わたし\tが/が 1 16725"),
+            (DICT_FILE, "きたかな 北香那\n"),
+        ] {
+            write_archive(&src, &archive)?;
+            read_archive(&archive)?;
+            rewrite_archive(&archive, name, content)?;
+            assert!(read_archive(&archive).is_err(), "{name}: {content}");
+        }
+
+        // 途中で切れたアーカイブ
+        write_archive(&src, &archive)?;
+        let bytes = fs::read(&archive)?;
+        fs::write(&archive, &bytes[..bytes.len() / 2])?;
+        assert!(read_archive(&archive).is_err());
+        Ok(())
+    }
+}
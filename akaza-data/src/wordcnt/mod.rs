@@ -1,2 +1,3 @@
 pub mod wordcnt_bigram;
+pub mod wordcnt_trigram;
 pub mod wordcnt_unigram;
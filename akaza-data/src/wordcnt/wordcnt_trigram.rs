@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use log::info;
+
+use libakaza::cost::calc_cost;
+use libakaza::lm::base::{SystemTrigramLM, TRIGRAM_BACKOFF_WORD_ID};
+use libakaza::search_result::SearchResult;
+use marisa_sys::{Keyset, Marisa};
+
+/**
+ * trigram 言語モデル。
+ * unigram の生成のときに得られた単語IDを利用することで、圧縮している。
+ */
+#[derive(Default)]
+pub struct WordcntTrigramBuilder {
+    keyset: Keyset,
+}
+
+impl WordcntTrigramBuilder {
+    pub fn add(&mut self, word_id1: i32, word_id2: i32, word_id3: i32, cnt: u32) {
+        assert_ne!(word_id3, TRIGRAM_BACKOFF_WORD_ID);
+        self.push(word_id1, word_id2, word_id3, cnt.to_le_bytes());
+    }
+
+    /// 文脈 (word_id1, word_id2) のバックオフコストを登録する。出現回数の代わりに f32 を格納する。
+    pub fn add_backoff(&mut self, word_id1: i32, word_id2: i32, backoff: f32) {
+        self.push(
+            word_id1,
+            word_id2,
+            TRIGRAM_BACKOFF_WORD_ID,
+            backoff.to_le_bytes(),
+        );
+    }
+
+    fn push(&mut self, word_id1: i32, word_id2: i32, word_id3: i32, value: [u8; 4]) {
+        let mut key: Vec<u8> = Vec::new();
+        for word_id in [word_id1, word_id2, word_id3] {
+            let bytes = word_id.to_le_bytes();
+            assert_eq!(bytes[3], 0);
+            key.extend(bytes[0..3].iter());
+        }
+        key.extend(value);
+        self.keyset.push_back(key.as_slice());
+    }
+
+    pub fn save(&self, ofname: &str) -> anyhow::Result<()> {
+        let mut marisa = Marisa::default();
+        marisa.build(&self.keyset);
+        marisa.save(ofname)?;
+        Ok(())
+    }
+}
+
+pub struct WordcntTrigram {
+    marisa: Marisa,
+    pub total_words: u32,
+    pub unique_words: u32,
+}
+
+impl WordcntTrigram {
+    pub fn to_cnt_map(&self) -> HashMap<(i32, i32, i32), u32> {
+        Self::_to_map(&self.marisa)
+    }
+
+    /// (word_id, word_id) -> 文脈のバックオフコスト
+    pub fn to_backoff_map(&self) -> HashMap<(i32, i32), f32> {
+        let mut map: HashMap<(i32, i32), f32> = HashMap::new();
+        Self::_scan(&self.marisa, |(word_id1, word_id2, word_id3), value| {
+            if word_id3 == TRIGRAM_BACKOFF_WORD_ID {
+                map.insert((word_id1, word_id2), f32::from_le_bytes(value));
+            }
+        });
+        map
+    }
+
+    fn _to_map(marisa: &Marisa) -> HashMap<(i32, i32, i32), u32> {
+        let mut map: HashMap<(i32, i32, i32), u32> = HashMap::new();
+        Self::_scan(marisa, |word_ids, value| {
+            if word_ids.2 != TRIGRAM_BACKOFF_WORD_ID {
+                map.insert(word_ids, u32::from_le_bytes(value));
+            }
+        });
+        map
+    }
+
+    fn _scan(marisa: &Marisa, mut callback: impl FnMut((i32, i32, i32), [u8; 4])) {
+        marisa.predictive_search("".as_bytes(), |word, _id| {
+            if word.len() == 13 {
+                let word_id1 = i32::from_le_bytes([word[0], word[1], word[2], 0]);
+                let word_id2 = i32::from_le_bytes([word[3], word[4], word[5], 0]);
+                let word_id3 = i32::from_le_bytes([word[6], word[7], word[8], 0]);
+                callback(
+                    (word_id1, word_id2, word_id3),
+                    [word[9], word[10], word[11], word[12]],
+                );
+            }
+            true
+        });
+    }
+
+    fn find_value(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<[u8; 4]> {
+        let mut key: Vec<u8> = Vec::new();
+        key.extend(word_id1.to_le_bytes()[0..3].iter());
+        key.extend(word_id2.to_le_bytes()[0..3].iter());
+        key.extend(word_id3.to_le_bytes()[0..3].iter());
+        let mut got: Vec<SearchResult> = Vec::new();
+        self.marisa.predictive_search(key.as_slice(), |key, id| {
+            got.push(SearchResult {
+                keyword: key.to_vec(),
+                id,
+            });
+            true
+        });
+        let result = got.first()?;
+        Some(
+            result.keyword[result.keyword.len() - 4..result.keyword.len()]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    pub fn load(filename: &str) -> Result<WordcntTrigram> {
+        info!("Loading system-trigram: {}", filename);
+        let mut marisa = Marisa::default();
+        marisa.load(filename)?;
+
+        let map: HashMap<(i32, i32, i32), u32> = Self::_to_map(&marisa);
+
+        // 総出現単語数
+        let total_words = map.values().sum();
+        // 単語の種類数
+        let unique_words = map.keys().count() as u32;
+
+        Ok(WordcntTrigram {
+            marisa,
+            total_words,
+            unique_words,
+        })
+    }
+}
+
+impl SystemTrigramLM for WordcntTrigram {
+    /**
+     * edge cost を得る。
+     * この ID は、unigram の trie でふられたもの。
+     */
+    fn get_edge_cost(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<f32> {
+        let cnt: u32 = u32::from_le_bytes(self.find_value(word_id1, word_id2, word_id3)?);
+        Some(calc_cost(cnt, self.total_words, self.unique_words))
+    }
+
+    fn get_backoff_cost(&self, word_id1: i32, word_id2: i32) -> Option<f32> {
+        let value = self.find_value(word_id1, word_id2, TRIGRAM_BACKOFF_WORD_ID)?;
+        Some(f32::from_le_bytes(value))
+    }
+
+    fn as_hash_map(&self) -> HashMap<(i32, i32, i32), f32> {
+        self.to_cnt_map()
+            .into_iter()
+            .map(|(ids, cnt)| (ids, calc_cost(cnt, self.total_words, self.unique_words)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_build() -> Result<()> {
+        let named_tmpfile = NamedTempFile::new().unwrap();
+        let tmpfile = named_tmpfile.path().to_str().unwrap().to_string();
+
+        let mut builder = WordcntTrigramBuilder::default();
+        builder.add(4, 5, 6, 29);
+        builder.add(8, 9, 10, 32);
+        builder.add_backoff(4, 5, 0.25);
+        builder.save(tmpfile.as_str())?;
+
+        let trigram = WordcntTrigram::load(tmpfile.as_str())?;
+        assert_eq!(
+            trigram.to_cnt_map(),
+            HashMap::from([((4, 5, 6), 29), ((8, 9, 10), 32),])
+        );
+        assert_eq!(trigram.total_words, 61);
+        assert!(trigram.get_edge_cost(4, 5, 6).is_some());
+        assert!(trigram.get_edge_cost(4, 5, 7).is_none());
+        assert_eq!(trigram.to_backoff_map(), HashMap::from([((4, 5), 0.25)]));
+        assert_eq!(trigram.get_backoff_cost(4, 5), Some(0.25));
+        assert_eq!(trigram.get_backoff_cost(8, 9), None);
+
+        Ok(())
+    }
+}
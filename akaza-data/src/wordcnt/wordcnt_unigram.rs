@@ -4,7 +4,8 @@ use anyhow::Result;
 use log::info;
 
 use libakaza::cost::calc_cost;
-use libakaza::lm::base::SystemUnigramLM;
+use libakaza::lm::base::{SystemUnigramLM, UnigramBackoff};
+use libakaza::lm::system_unigram_lm::{decode_unigram_key, encode_unigram_key};
 use marisa_sys::{Keyset, Marisa};
 
 /**
@@ -13,26 +14,23 @@ use marisa_sys::{Keyset, Marisa};
  */
 #[derive(Default)]
 pub struct WordcntUnigramBuilder {
-    data: Vec<(String, u32)>,
+    data: Vec<(String, u32, Option<UnigramBackoff>)>,
 }
 
 impl WordcntUnigramBuilder {
     pub fn add(&mut self, word: &str, cnt: u32) {
-        self.data.push((word.to_string(), cnt));
+        self.data.push((word.to_string(), cnt, None));
+    }
+
+    /// バックオフコストつきで単語を登録する。
+    pub fn add_with_backoff(&mut self, word: &str, cnt: u32, backoff: UnigramBackoff) {
+        self.data.push((word.to_string(), cnt, Some(backoff)));
     }
 
     pub fn keyset(&self) -> Keyset {
         let mut keyset = Keyset::default();
-        for (kanji, score) in &self.data {
-            // 区切り文字をいれなくても、末尾の4バイトを取り出せば十分な気がしないでもない。。
-            // 先頭一致にして、+4バイトになるものを探せばいいはず。
-            // 最適化の余地だけど、現実的には空間効率よりも速度のほうが重要かもしれない。
-            let key = [
-                kanji.as_bytes(),
-                b"\xff",
-                score.to_le_bytes().as_slice(), // バイナリにしてデータ容量を節約する
-            ]
-            .concat();
+        for (kanji, score, backoff) in &self.data {
+            let key = encode_unigram_key(kanji, score.to_le_bytes(), *backoff);
             keyset.push_back(key.as_slice());
         }
         keyset
@@ -64,9 +62,8 @@ impl WordcntUnigram {
     fn _to_count_hashmap(marisa: &Marisa) -> HashMap<String, (i32, u32)> {
         let mut map: HashMap<String, (i32, u32)> = HashMap::new();
         marisa.predictive_search("".as_bytes(), |word, id| {
-            let idx = word.iter().position(|f| *f == b'\xff').unwrap();
-            let bytes: [u8; 4] = word[idx + 1..idx + 1 + 4].try_into().unwrap();
-            let word = String::from_utf8_lossy(&word[0..idx]);
+            let (word, bytes, _) = decode_unigram_key(word);
+            let word = String::from_utf8_lossy(word);
             let cost = u32::from_le_bytes(bytes);
             map.insert(word.to_string(), (id as i32, cost));
             true
@@ -74,6 +71,18 @@ impl WordcntUnigram {
         map
     }
 
+    /// word -> backoff cost
+    pub fn to_backoff_hashmap(&self) -> HashMap<String, UnigramBackoff> {
+        let mut map: HashMap<String, UnigramBackoff> = HashMap::new();
+        self.marisa.predictive_search("".as_bytes(), |word, _| {
+            if let (key, _, Some(backoff)) = decode_unigram_key(word) {
+                map.insert(String::from_utf8_lossy(key).to_string(), backoff);
+            }
+            true
+        });
+        map
+    }
+
     pub fn load(fname: &str) -> Result<WordcntUnigram> {
         info!("Reading {}", fname);
         let mut marisa = Marisa::default();
@@ -110,8 +119,7 @@ impl SystemUnigramLM for WordcntUnigram {
         marisa.predictive_search(key.as_slice(), |word, id| {
             word_id = id;
 
-            let idx = word.iter().position(|f| *f == b'\xff').unwrap();
-            let bytes: [u8; 4] = word[idx + 1..idx + 1 + 4].try_into().unwrap();
+            let (_, bytes, _) = decode_unigram_key(word);
             score = u32::from_le_bytes(bytes);
             false
         });
@@ -125,12 +133,21 @@ impl SystemUnigramLM for WordcntUnigram {
         }
     }
 
+    fn find_backoff(&self, word: &str) -> Option<UnigramBackoff> {
+        let key = [word.as_bytes(), b"\xff"].concat();
+        let mut backoff = None;
+        self.marisa.predictive_search(key.as_slice(), |word, _| {
+            (_, _, backoff) = decode_unigram_key(word);
+            false
+        });
+        backoff
+    }
+
     fn as_hash_map(&self) -> HashMap<String, (i32, f32)> {
         let mut map = HashMap::new();
         self.marisa.predictive_search("".as_bytes(), |word, id| {
-            let idx = word.iter().position(|f| *f == b'\xff').unwrap();
-            let bytes: [u8; 4] = word[idx + 1..idx + 1 + 4].try_into().unwrap();
-            let word = String::from_utf8_lossy(&word[0..idx]);
+            let (word, bytes, _) = decode_unigram_key(word);
+            let word = String::from_utf8_lossy(word);
             let cnt = u32::from_le_bytes(bytes);
             map.insert(
                 word.to_string(),
@@ -183,6 +200,33 @@ mod tests {
                 ("彼/かれ".to_string(), (0_i32, 0.048848562)),
             ])
         );
+        assert_eq!(wordcnt.find_backoff("私/わたし"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() -> Result<()> {
+        let named_tmpfile = NamedTempFile::new().unwrap();
+        let tmpfile = named_tmpfile.path().to_str().unwrap().to_string();
+
+        let backoff = UnigramBackoff {
+            backoff: 1.5,
+            continuation: 2.5,
+        };
+        let mut builder = WordcntUnigramBuilder::default();
+        builder.add_with_backoff("私/わたし", 3, backoff);
+        builder.add("彼/かれ", 42);
+        builder.save(tmpfile.as_str())?;
+
+        let wordcnt = WordcntUnigram::load(tmpfile.as_str())?;
+        assert_eq!(wordcnt.total_words, 45);
+        assert_eq!(wordcnt.find_backoff("私/わたし"), Some(backoff));
+        assert_eq!(wordcnt.find_backoff("彼/かれ"), None);
+        assert_eq!(
+            wordcnt.to_backoff_hashmap(),
+            HashMap::from([("私/わたし".to_string(), backoff)])
+        );
 
         Ok(())
     }
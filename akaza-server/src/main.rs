@@ -0,0 +1,142 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use anyhow::bail;
+use clap::Parser;
+use log::{error, info, warn};
+
+use libakaza::config::{Config, UserDataConfig};
+use libakaza::engine::base::HenkanEngine;
+use libakaza::engine::bigram_word_viterbi_engine::BigramWordViterbiEngineBuilder;
+use libakaza::user_side_data::user_data::UserData;
+
+use crate::server::AkazaServer;
+
+mod protocol;
+mod server;
+
+/// かな漢字変換エンジンを、Unix domain socket 越しの JSON-RPC で提供する。
+#[derive(Debug, clap::Parser)]
+#[command(author, version, about, long_about = None)]
+struct AkazaServerArgs {
+    /// 待ち受ける socket のパス。省略時は $XDG_RUNTIME_DIR/akaza/akaza-server.sock
+    #[arg(long)]
+    socket: Option<String>,
+
+    #[clap(flatten)]
+    verbose: clap_verbosity_flag::Verbosity,
+}
+
+fn load_user_data(config: &UserDataConfig) -> Arc<Mutex<UserData>> {
+    match UserData::load_from_default_path(config) {
+        Ok(user_data) => Arc::new(Mutex::new(user_data)),
+        Err(err) => {
+            error!("Cannot load user data: {}", err);
+            Arc::new(Mutex::new(UserData::default()))
+        }
+    }
+}
+
+fn socket_path(args: &AkazaServerArgs) -> anyhow::Result<PathBuf> {
+    if let Some(socket) = &args.socket {
+        return Ok(PathBuf::from(socket));
+    }
+    Ok(xdg::BaseDirectories::with_prefix("akaza")?.place_runtime_file("akaza-server.sock")?)
+}
+
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("akaza-server is already running: {}", path.display());
+        }
+        // 前回のプロセスが残していった socket ファイルなので消す。
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // 学習データを読み書きできるので、他のユーザーからは接続させない。
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn handle_client<E: HenkanEngine>(
+    stream: UnixStream,
+    server: Arc<AkazaServer<E>>,
+) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = server.handle_line(&line);
+        if let Some(response) = response {
+            writeln!(writer, "{response}")?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = AkazaServerArgs::parse();
+
+    env_logger::Builder::new()
+        .filter_level(args.verbose.log_level_filter())
+        .init();
+
+    let socket_path = socket_path(&args)?;
+    let listener = bind(&socket_path)?;
+    info!("Listening on {}", socket_path.display());
+
+    let user_data = load_user_data(&Config::load()?.user_data);
+    let server = AkazaServer::new(
+        user_data.clone(),
+        Box::new(|user_data| {
+            BigramWordViterbiEngineBuilder::new(Config::load()?.engine)
+                .user_data(user_data)
+                .build()
+        }),
+    )?;
+
+    // ユーザー辞書をバックグラウンドで保存するスレッド。
+    thread::Builder::new()
+        .name("user-data-save-thread".to_string())
+        .spawn(move || {
+            let interval = time::Duration::from_secs(3);
+            loop {
+                if let Ok(mut data) = user_data.lock() {
+                    if let Err(e) = data.write_user_files() {
+                        warn!("Cannot save user stats file: {}", e);
+                    }
+                } else {
+                    warn!("Cannot get mutex for saving user data")
+                };
+                thread::sleep(interval);
+            }
+        })?;
+
+    // 全てのクライアントのスレッドで、一つの変換エンジンを共有する。
+    let server = Arc::new(server);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = server.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle_client(stream, server) {
+                        warn!("Client error: {:?}", err);
+                    }
+                });
+            }
+            Err(err) => warn!("Cannot accept the connection: {:?}", err),
+        }
+    }
+    Ok(())
+}
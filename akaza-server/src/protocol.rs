@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use libakaza::graph::candidate::Candidate;
+
+// JSON-RPC 2.0 で定義されているエラーコード
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    /// id がないリクエストは通知なので、レスポンスを返さない。
+    pub id: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorObject>,
+    pub id: Value,
+}
+
+impl Response {
+    pub fn new(id: Value, result: Result<Value, ErrorObject>) -> Response {
+        match result {
+            Ok(result) => Response {
+                jsonrpc: "2.0",
+                result: Some(result),
+                error: None,
+                id,
+            },
+            Err(error) => Response {
+                jsonrpc: "2.0",
+                result: None,
+                error: Some(error),
+                id,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ErrorObject {
+    pub code: i32,
+    pub message: String,
+}
+
+impl ErrorObject {
+    pub fn new(code: i32, message: String) -> ErrorObject {
+        ErrorObject { code, message }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CandidateDto {
+    pub yomi: String,
+    pub surface: String,
+    #[serde(default)]
+    pub cost: f32,
+    /// 複合語として学習させたい場合は true にする。
+    #[serde(default)]
+    pub compound_word: bool,
+}
+
+impl From<&Candidate> for CandidateDto {
+    fn from(candidate: &Candidate) -> Self {
+        CandidateDto {
+            yomi: candidate.yomi.to_string(),
+            surface: candidate.surface_with_dynamic(),
+            cost: candidate.cost,
+            compound_word: candidate.compound_word,
+        }
+    }
+}
+
+impl From<&CandidateDto> for Candidate {
+    fn from(candidate: &CandidateDto) -> Self {
+        let mut got = Candidate::new(&candidate.yomi, &candidate.surface, candidate.cost);
+        got.compound_word = candidate.compound_word;
+        got
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConvertParams {
+    pub yomi: String,
+    /// 文節の区切りを強制する範囲。読みのバイト単位の [start, end) のリスト。
+    #[serde(default)]
+    pub force_ranges: Vec<[usize; 2]>,
+    /// 変換対象の左側にある単語列。
+    #[serde(default)]
+    pub left_context: Vec<CandidateDto>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LearnParams {
+    pub candidates: Vec<CandidateDto>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PredictParams {
+    pub yomi_prefix: String,
+    /// 返す候補の最大数。省略時は全件返す。
+    pub limit: Option<usize>,
+}
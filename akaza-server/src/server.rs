@@ -0,0 +1,324 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use libakaza::engine::base::HenkanEngine;
+use libakaza::graph::candidate::Candidate;
+use libakaza::user_side_data::user_data::UserData;
+
+use crate::protocol::{
+    CandidateDto, ConvertParams, ErrorObject, LearnParams, PredictParams, Request, Response,
+    INTERNAL_ERROR, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR,
+};
+
+pub type EngineLoader<E> = Box<dyn Fn(Arc<Mutex<UserData>>) -> anyhow::Result<E> + Send + Sync>;
+
+/// JSON-RPC のリクエストを変換エンジンに渡す。
+/// 全てのクライアントで、一つの変換エンジンと UserData を共有する。
+/// 変換は並行して処理し、学習のときだけ UserData のロックを取る。
+pub struct AkazaServer<E: HenkanEngine> {
+    /// reload で差し替えるときだけ書き込みロックを取る。
+    /// リクエストの処理中は Arc を複製して使うので、ロックを持ったまま変換しない。
+    engine: RwLock<Arc<E>>,
+    user_data: Arc<Mutex<UserData>>,
+    loader: EngineLoader<E>,
+}
+
+impl<E: HenkanEngine> AkazaServer<E> {
+    pub fn new(user_data: Arc<Mutex<UserData>>, loader: EngineLoader<E>) -> anyhow::Result<Self> {
+        let engine = loader(user_data.clone())?;
+        Ok(AkazaServer {
+            engine: RwLock::new(Arc::new(engine)),
+            user_data,
+            loader,
+        })
+    }
+
+    fn engine(&self) -> Arc<E> {
+        self.engine.read().unwrap().clone()
+    }
+
+    /// 一行分のリクエストを処理して、レスポンスを返す。
+    /// 通知の場合は None を返す。
+    pub fn handle_line(&self, line: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Request>(line) {
+            Ok(request) => {
+                if request.jsonrpc != "2.0" {
+                    Some(Response::new(
+                        request.id.unwrap_or(Value::Null),
+                        Err(ErrorObject::new(
+                            INVALID_REQUEST,
+                            format!("Unsupported jsonrpc version: {}", request.jsonrpc),
+                        )),
+                    ))
+                } else {
+                    let result = self.dispatch(&request.method, request.params);
+                    request.id.map(|id| Response::new(id, result))
+                }
+            }
+            Err(err) => Some(Response::new(
+                Value::Null,
+                Err(ErrorObject::new(PARSE_ERROR, err.to_string())),
+            )),
+        };
+
+        response.map(|response| serde_json::to_string(&response).unwrap())
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> Result<Value, ErrorObject> {
+        info!("dispatch: {}", method);
+        match method {
+            "convert" => self.convert(parse_params(params)?),
+            "learn" => self.learn(parse_params(params)?),
+            "predict" => self.predict(parse_params(params)?),
+            "reload" => self.reload(),
+            _ => Err(ErrorObject::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method: {method}"),
+            )),
+        }
+    }
+
+    fn convert(&self, params: ConvertParams) -> Result<Value, ErrorObject> {
+        let left_context = params
+            .left_context
+            .iter()
+            .map(Candidate::from)
+            .collect::<Vec<_>>();
+        let force_ranges = params
+            .force_ranges
+            .iter()
+            .map(|[start, end]| *start..*end)
+            .collect::<Vec<Range<usize>>>();
+        for range in &force_ranges {
+            if range.start >= range.end
+                || range.end > params.yomi.len()
+                || !params.yomi.is_char_boundary(range.start)
+                || !params.yomi.is_char_boundary(range.end)
+            {
+                return Err(ErrorObject::new(
+                    INVALID_PARAMS,
+                    format!("Invalid force range: {range:?}"),
+                ));
+            }
+        }
+
+        let clauses = self
+            .engine()
+            .convert_with_context(&left_context, &params.yomi, Some(&force_ranges))
+            .map_err(internal_error)?;
+        let clauses = clauses
+            .iter()
+            .map(|clause| clause.iter().map(CandidateDto::from).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_value(clauses).unwrap())
+    }
+
+    fn learn(&self, params: LearnParams) -> Result<Value, ErrorObject> {
+        let candidates = params
+            .candidates
+            .iter()
+            .map(Candidate::from)
+            .collect::<Vec<_>>();
+        self.engine().learn(&candidates);
+        Ok(Value::Bool(true))
+    }
+
+    fn predict(&self, params: PredictParams) -> Result<Value, ErrorObject> {
+        let candidates = self
+            .engine()
+            .predict(&params.yomi_prefix, params.limit.unwrap_or(usize::MAX))
+            .map_err(internal_error)?;
+        let candidates = candidates
+            .iter()
+            .map(CandidateDto::from)
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_value(candidates).unwrap())
+    }
+
+    /// 設定ファイルを読み直して、エンジンを作りなおす。学習データはそのまま引き継ぐ。
+    /// 作りなおしている間も、他のクライアントは古いエンジンで変換できる。
+    fn reload(&self) -> Result<Value, ErrorObject> {
+        let engine = (self.loader)(self.user_data.clone()).map_err(internal_error)?;
+        *self.engine.write().unwrap() = Arc::new(engine);
+        info!("Reloaded the engine");
+        Ok(Value::Bool(true))
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, ErrorObject> {
+    serde_json::from_value(params).map_err(|err| ErrorObject::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn internal_error(err: anyhow::Error) -> ErrorObject {
+    error!("Internal error: {:?}", err);
+    ErrorObject::new(INTERNAL_ERROR, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use libakaza::graph::graph_resolver::KBestPath;
+
+    use super::*;
+
+    /// 読みをそのまま返すだけのエンジン。
+    #[derive(Default)]
+    struct EchoEngine {
+        learned: Mutex<Vec<Candidate>>,
+    }
+
+    impl HenkanEngine for EchoEngine {
+        fn learn(&self, candidates: &[Candidate]) {
+            self.learned.lock().unwrap().extend_from_slice(candidates);
+        }
+
+        fn convert(
+            &self,
+            yomi: &str,
+            force_ranges: Option<&[Range<usize>]>,
+        ) -> anyhow::Result<Vec<Vec<Candidate>>> {
+            self.convert_with_context(&[], yomi, force_ranges)
+        }
+
+        fn convert_with_context(
+            &self,
+            left_context: &[Candidate],
+            yomi: &str,
+            _force_ranges: Option<&[Range<usize>]>,
+        ) -> anyhow::Result<Vec<Vec<Candidate>>> {
+            let surface = left_context
+                .iter()
+                .map(|it| it.surface.to_string())
+                .collect::<String>()
+                + yomi;
+            Ok(vec![vec![Candidate::new(yomi, &surface, 1_f32)]])
+        }
+
+        fn convert_k_best(
+            &self,
+            _yomi: &str,
+            _force_ranges: Option<&[Range<usize>]>,
+            _k: usize,
+        ) -> anyhow::Result<Vec<KBestPath>> {
+            Ok(Vec::new())
+        }
+
+        fn left_context_from_text(&self, text: &str) -> anyhow::Result<Vec<Candidate>> {
+            Ok(vec![Candidate::new(text, text, 0_f32)])
+        }
+
+        fn reverse_lookup(&self, surface: &str) -> anyhow::Result<String> {
+            Ok(surface.to_string())
+        }
+
+        fn predict(&self, yomi_prefix: &str, limit: usize) -> anyhow::Result<Vec<Candidate>> {
+            let mut candidates = vec![
+                Candidate::new(&(yomi_prefix.to_string() + "あ"), "亜", 1_f32),
+                Candidate::new(&(yomi_prefix.to_string() + "い"), "井", 2_f32),
+            ];
+            candidates.truncate(limit);
+            Ok(candidates)
+        }
+    }
+
+    fn server() -> anyhow::Result<AkazaServer<EchoEngine>> {
+        AkazaServer::new(
+            Arc::new(Mutex::new(UserData::default())),
+            Box::new(|_| Ok(EchoEngine::default())),
+        )
+    }
+
+    #[test]
+    fn test_convert() -> anyhow::Result<()> {
+        let server = server()?;
+        let got = server.handle_line(
+            r#"{"jsonrpc":"2.0","method":"convert","params":{"yomi":"にいく","left_context":[{"yomi":"とうきょう","surface":"東京"}]},"id":1}"#,
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&got.unwrap())?,
+            json!({
+                "jsonrpc": "2.0",
+                "result": [[{"yomi": "にいく", "surface": "東京にいく", "cost": 1.0, "compound_word": false}]],
+                "id": 1
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_learn_and_predict() -> anyhow::Result<()> {
+        let server = server()?;
+        // 通知にはレスポンスを返さない。
+        let got = server.handle_line(
+            r#"{"jsonrpc":"2.0","method":"learn","params":{"candidates":[{"yomi":"とうきょう","surface":"東京"}]}}"#,
+        );
+        assert_eq!(got, None);
+        assert_eq!(server.engine().learned.lock().unwrap()[0].surface, "東京");
+
+        let got = server.handle_line(
+            r#"{"jsonrpc":"2.0","method":"predict","params":{"yomi_prefix":"あ","limit":1},"id":"x"}"#,
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&got.unwrap())?,
+            json!({
+                "jsonrpc": "2.0",
+                "result": [{"yomi": "ああ", "surface": "亜", "cost": 1.0, "compound_word": false}],
+                "id": "x"
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload() -> anyhow::Result<()> {
+        let server = server()?;
+        server.handle_line(
+            r#"{"jsonrpc":"2.0","method":"learn","params":{"candidates":[{"yomi":"とうきょう","surface":"東京"}]}}"#,
+        );
+        // 処理中のリクエストが持っているエンジンは、差し替えられても使い続けられる。
+        let old_engine = server.engine();
+        let got = server.handle_line(r#"{"jsonrpc":"2.0","method":"reload","id":1}"#);
+        assert_eq!(
+            serde_json::from_str::<Value>(&got.unwrap())?,
+            json!({"jsonrpc": "2.0", "result": true, "id": 1})
+        );
+        assert!(!Arc::ptr_eq(&old_engine, &server.engine()));
+        assert_eq!(old_engine.learned.lock().unwrap().len(), 1);
+        assert!(server.engine().learned.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() -> anyhow::Result<()> {
+        let server = server()?;
+        let got = server.handle_line(r#"{"jsonrpc":"2.0","method":"unknown","id":1}"#);
+        assert_eq!(
+            serde_json::from_str::<Value>(&got.unwrap())?,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32601, "message": "Unknown method: unknown"},
+                "id": 1
+            })
+        );
+        assert!(server
+            .handle_line(r#"{"jsonrpc":"2.0","method":"convert","params":{},"id":2}"#)
+            .unwrap()
+            .contains(r#""code":-32602"#));
+        assert!(server
+            .handle_line(
+                r#"{"jsonrpc":"2.0","method":"convert","params":{"yomi":"あ","force_ranges":[[0,1]]},"id":3}"#
+            )
+            .unwrap()
+            .contains(r#""code":-32602"#));
+        assert!(server
+            .handle_line("{")
+            .unwrap()
+            .contains(r#""code":-32700"#));
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+use log::info;
 
 use ibus_sys::engine::IBusEngine;
+use libakaza::keymap::{CommandArgValue, KeymapCommand};
 
-use crate::input_mode::{
-    INPUT_MODE_ALNUM, INPUT_MODE_FULLWIDTH_ALNUM, INPUT_MODE_HALFWIDTH_KATAKANA,
-    INPUT_MODE_HIRAGANA, INPUT_MODE_KATAKANA,
-};
+use crate::input_mode::get_input_mode_from_name;
 use crate::AkazaContext;
 
 /**
@@ -13,7 +15,70 @@ use crate::AkazaContext;
  */
 pub type IbusAkazaCommand = fn(&mut AkazaContext, *mut IBusEngine) -> bool;
 
-pub(crate) fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaCommand> {
+/**
+ * 引数をとる機能。キーマップを読み込むときに検証した引数を受け取って、引数を埋め込んだ関数を返す。
+ */
+pub type IbusAkazaParameterizedCommand = fn(CommandArgValue) -> Result<BoundCommandFn>;
+
+type BoundCommandFn = Rc<dyn Fn(&mut AkazaContext, *mut IBusEngine) -> bool>;
+
+/// キーマップに書かれたコマンドを、引数まで解釈したもの。
+#[derive(Clone)]
+pub(crate) struct BoundCommand {
+    /// キーマップに書かれたままの文字列。ログに出す。
+    name: String,
+    function: BoundCommandFn,
+}
+
+impl BoundCommand {
+    #[cfg(test)]
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn run(&self, context: &mut AkazaContext, engine: *mut IBusEngine) -> bool {
+        info!("Calling function '{}'", self.name);
+        (self.function)(context, engine)
+    }
+}
+
+/// キーマップに書けるコマンドの一覧。
+pub(crate) struct IbusAkazaCommands {
+    commands: HashMap<&'static str, IbusAkazaCommand>,
+    parameterized_commands: HashMap<&'static str, IbusAkazaParameterizedCommand>,
+}
+
+impl IbusAkazaCommands {
+    pub(crate) fn new() -> Self {
+        IbusAkazaCommands {
+            commands: ibus_akaza_commands_map(),
+            parameterized_commands: ibus_akaza_parameterized_commands_map(),
+        }
+    }
+
+    /// "select_candidate_in_page(3)" のようなコマンドを解釈する。
+    /// 存在しないコマンドや、引数が正しくないときはエラーにする。
+    pub(crate) fn bind(&self, command: &str) -> Result<BoundCommand> {
+        let parsed = KeymapCommand::parse(command)?;
+        let function: BoundCommandFn = match parsed.validate()? {
+            CommandArgValue::None => match self.commands.get(parsed.name.as_str()) {
+                Some(function) => Rc::new(*function),
+                None => bail!("'{}' is not implemented", parsed.name),
+            },
+            arg => match self.parameterized_commands.get(parsed.name.as_str()) {
+                Some(parameterized) => parameterized(arg)
+                    .with_context(|| format!("Invalid argument for '{}'", parsed.name))?,
+                None => bail!("'{}' is not implemented", parsed.name),
+            },
+        };
+        Ok(BoundCommand {
+            name: command.to_string(),
+            function,
+        })
+    }
+}
+
+fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaCommand> {
     let mut function_map: HashMap<&'static str, IbusAkazaCommand> = HashMap::new();
 
     // shorthand
@@ -23,11 +88,23 @@ pub(crate) fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaComman
         context.commit_candidate(engine);
         true
     });
+    // 予測候補をコミットします
+    register("commit_suggestion", |context, engine| {
+        context.commit_suggestion(engine)
+    });
+    // 予測候補の選択を移動します。予測候補がないときは、キー入力をアプリケーションに渡します。
+    register("select_prev_suggestion", |context, engine| {
+        context.select_suggestion(engine, false)
+    });
+    register("select_next_suggestion", |context, engine| {
+        context.select_suggestion(engine, true)
+    });
     // 無変換状態では、ひらがなに変換してコミットします
     register("commit_preedit", |context, engine| {
         context.commit_preedit(engine);
         true
     });
+    register("reconvert", |context, engine| context.reconvert(engine));
     register("escape", |context, engine| {
         context.escape(engine);
         true
@@ -41,34 +118,24 @@ pub(crate) fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaComman
         true
     });
 
-    register("set_input_mode_hiragana", |context, engine| {
-        context.set_input_mode(engine, &INPUT_MODE_HIRAGANA);
-        true
-    });
-    register("set_input_mode_alnum", |context, engine| {
-        context.set_input_mode(engine, &INPUT_MODE_ALNUM);
-        true
-    });
-    register("set_input_mode_fullwidth_alnum", |context, engine| {
-        context.set_input_mode(engine, &INPUT_MODE_FULLWIDTH_ALNUM);
-        true
+    register("update_candidates", |context, engine| {
+        context.update_candidates(engine)
     });
-    register("set_input_mode_katakana", |context, engine| {
-        context.set_input_mode(engine, &INPUT_MODE_KATAKANA);
-        true
+    register("delete_candidate_from_history", |context, engine| {
+        context.delete_candidate_from_history(engine)
     });
-    register("set_input_mode_halfwidth_katakana", |context, engine| {
-        context.set_input_mode(engine, &INPUT_MODE_HALFWIDTH_KATAKANA);
+    register("toggle_incognito", |context, engine| {
+        context.toggle_incognito(engine);
         true
     });
-
-    register("update_candidates", |context, engine| {
-        context.update_candidates(engine)
-    });
     register("erase_character_before_cursor", |context, engine| {
         context.erase_character_before_cursor(engine);
         true
     });
+    register("erase_character_after_cursor", |context, engine| {
+        context.erase_character_after_cursor(engine);
+        true
+    });
     register("cursor_up", |context, engine| {
         context.cursor_up(engine);
         true
@@ -85,6 +152,14 @@ pub(crate) fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaComman
         context.cursor_left(engine);
         true
     });
+    register("cursor_home", |context, engine| {
+        context.cursor_home(engine);
+        true
+    });
+    register("cursor_end", |context, engine| {
+        context.cursor_end(engine);
+        true
+    });
     register("extend_clause_right", |context, engine| {
         context.extend_clause_right(engine).unwrap();
         true
@@ -114,39 +189,64 @@ pub(crate) fn ibus_akaza_commands_map() -> HashMap<&'static str, IbusAkazaComman
         true
     });
 
-    {
-        // TODO コピペがすごい。マクロかうまいなにかでまとめて登録できるようにしたい。
-        register("press_number_1", |context, engine| {
-            context.process_num_key(1, engine)
-        });
-        register("press_number_2", |context, engine| {
-            context.process_num_key(2, engine)
-        });
-        register("press_number_3", |context, engine| {
-            context.process_num_key(3, engine)
-        });
-        register("press_number_4", |context, engine| {
-            context.process_num_key(4, engine)
-        });
-        register("press_number_5", |context, engine| {
-            context.process_num_key(5, engine)
-        });
-        register("press_number_6", |context, engine| {
-            context.process_num_key(6, engine)
-        });
-        register("press_number_7", |context, engine| {
-            context.process_num_key(7, engine)
-        });
-        register("press_number_8", |context, engine| {
-            context.process_num_key(8, engine)
-        });
-        register("press_number_9", |context, engine| {
-            context.process_num_key(9, engine)
-        });
-        register("press_number_0", |context, engine| {
-            context.process_num_key(0, engine)
-        });
-    }
+    function_map
+}
+
+fn ibus_akaza_parameterized_commands_map() -> HashMap<&'static str, IbusAkazaParameterizedCommand> {
+    let mut function_map: HashMap<&'static str, IbusAkazaParameterizedCommand> = HashMap::new();
+
+    // shorthand
+    let mut register =
+        |name: &'static str, cmd: IbusAkazaParameterizedCommand| function_map.insert(name, cmd);
+
+    // 入力モードを変更します。引数は hiragana, katakana, halfwidth_katakana, alnum, fullwidth_alnum
+    register("set_input_mode", |arg| {
+        let CommandArgValue::InputMode(name) = arg else {
+            bail!("Not an input mode: {:?}", arg);
+        };
+        let input_mode = get_input_mode_from_name(name)?;
+        Ok(Rc::new(move |context, engine| {
+            context.set_input_mode(engine, &input_mode);
+            true
+        }))
+    });
+    // 変換候補の現在のページの、n 番目(1 から数える)の候補を選びます
+    register("select_candidate_in_page", |arg| {
+        let CommandArgValue::IndexInPage(index) = arg else {
+            bail!("Not an index in the page: {:?}", arg);
+        };
+        Ok(Rc::new(move |context, engine| {
+            context.select_candidate_in_page(engine, index)
+        }))
+    });
 
     function_map
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// libakaza のキーマップの検査で使うコマンドの一覧と、ここで登録しているコマンドが一致している。
+    #[test]
+    fn test_commands_match_keymap_commands() {
+        assert_eq!(
+            ibus_akaza_commands_map()
+                .into_keys()
+                .collect::<BTreeSet<_>>(),
+            KeymapCommand::known_names(false)
+                .into_iter()
+                .collect::<BTreeSet<_>>()
+        );
+        assert_eq!(
+            ibus_akaza_parameterized_commands_map()
+                .into_keys()
+                .collect::<BTreeSet<_>>(),
+            KeymapCommand::known_names(true)
+                .into_iter()
+                .collect::<BTreeSet<_>>()
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::{info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// 設定ファイルや辞書ファイルの変更を inotify で監視する。
+///
+/// 変更の反映は ibus のメインスレッドで行うので、ここでは変更されたファイルを覚えておくだけにする。
+pub(crate) struct ConfigWatcher {
+    // drop すると監視が止まるので持っておく。
+    _watcher: RecommendedWatcher,
+    changed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl ConfigWatcher {
+    pub(crate) fn new(paths: &[PathBuf]) -> Result<ConfigWatcher> {
+        let targets = paths.iter().cloned().collect::<HashSet<_>>();
+        let changed = Arc::new(Mutex::new(HashSet::new()));
+
+        let changed_by_event = changed.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    let mut changed = changed_by_event.lock().unwrap();
+                    for path in event.paths {
+                        if targets.contains(&path) {
+                            changed.insert(path);
+                        }
+                    }
+                }
+                Err(err) => warn!("Cannot watch the configuration files: {:?}", err),
+            })?;
+
+        // エディタは別のファイルに書いてから rename することが多いので、
+        // ファイルそのものではなく、ファイルのあるディレクトリを監視する。
+        let dirs = paths
+            .iter()
+            .filter_map(|it| it.parent())
+            .collect::<HashSet<&Path>>();
+        for dir in dirs {
+            match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                Ok(_) => info!("Watching {}", dir.display()),
+                Err(err) => warn!("Cannot watch {}: {:?}", dir.display(), err),
+            }
+        }
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            changed,
+        })
+    }
+
+    /// 前回呼ばれてから変更されたファイルを返す。
+    pub(crate) fn take_changed(&self) -> HashSet<PathBuf> {
+        std::mem::take(&mut *self.changed.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// イベントは別のスレッドで届くので、変更されたファイルが見つかるまで少し待つ。
+    fn wait_changed(watcher: &ConfigWatcher) -> HashSet<PathBuf> {
+        let started = Instant::now();
+        let mut changed = HashSet::new();
+        while changed.is_empty() && started.elapsed() < Duration::from_secs(5) {
+            sleep(Duration::from_millis(50));
+            changed = watcher.take_changed();
+        }
+        changed
+    }
+
+    #[test]
+    fn test_take_changed() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let target = tmpdir.path().join("config.yml");
+        let other = tmpdir.path().join("other.yml");
+        fs::write(&target, "a")?;
+        let watcher = ConfigWatcher::new(std::slice::from_ref(&target))?;
+        assert!(watcher.take_changed().is_empty());
+
+        // 同じディレクトリにある、監視していないファイルは無視する。
+        fs::write(&other, "b")?;
+        fs::write(&target, "b")?;
+        assert_eq!(wait_changed(&watcher), HashSet::from([target.clone()]));
+        // 一度返したら忘れる。
+        sleep(Duration::from_millis(100));
+        assert!(watcher.take_changed().is_empty());
+
+        // 別のファイルに書いてから rename しても気づく。
+        let tmp = tmpdir.path().join("config.yml.tmp");
+        fs::write(&tmp, "c")?;
+        fs::rename(&tmp, &target)?;
+        assert!(wait_changed(&watcher).contains(&target));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_file() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        // まだないファイルも、作られたら気づく。
+        let target = tmpdir.path().join("new.yml");
+        let watcher = ConfigWatcher::new(std::slice::from_ref(&target))?;
+        fs::write(&target, "a")?;
+        assert!(wait_changed(&watcher).contains(&target));
+        Ok(())
+    }
+}
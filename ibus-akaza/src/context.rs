@@ -1,75 +1,301 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use kelp::{h2z, hira2kata, z2h, ConvOption};
 use log::{error, info, trace, warn};
 
 use akaza_conf::conf::open_configuration_window;
 use akaza_dict::conf::open_userdict_window;
 use ibus_sys::core::{
-    IBusModifierType_IBUS_CONTROL_MASK, IBusModifierType_IBUS_HYPER_MASK,
-    IBusModifierType_IBUS_META_MASK, IBusModifierType_IBUS_MOD1_MASK,
-    IBusModifierType_IBUS_MOD2_MASK, IBusModifierType_IBUS_MOD3_MASK,
-    IBusModifierType_IBUS_MOD4_MASK, IBusModifierType_IBUS_MOD5_MASK,
+    IBusModifierType_IBUS_CONTROL_MASK, IBusModifierType_IBUS_MOD1_MASK,
     IBusModifierType_IBUS_RELEASE_MASK, IBusModifierType_IBUS_SHIFT_MASK,
 };
-use ibus_sys::engine::ibus_engine_commit_text;
-use ibus_sys::engine::IBusEngine;
-use ibus_sys::glib::guint;
+use ibus_sys::engine::{
+    ibus_engine_commit_text, ibus_engine_delete_surrounding_text, ibus_engine_forward_key_event,
+};
+use ibus_sys::engine::{
+    IBusEngine, IBusInputPurpose_IBUS_INPUT_PURPOSE_PASSWORD,
+    IBusInputPurpose_IBUS_INPUT_PURPOSE_PIN,
+};
+use ibus_sys::glib::{g_source_remove, g_timeout_add, gboolean, gpointer, guint};
 use ibus_sys::property::IBusPropState_PROP_STATE_CHECKED;
 use ibus_sys::text::StringExt;
-use libakaza::config::Config;
+use libakaza::config::{Config, EngineConfig};
 use libakaza::engine::base::HenkanEngine;
-use libakaza::engine::bigram_word_viterbi_engine::BigramWordViterbiEngine;
+use libakaza::engine::bigram_word_viterbi_engine::{
+    BigramWordViterbiEngine, BigramWordViterbiEngineBuilder,
+};
 use libakaza::graph::candidate::Candidate;
 use libakaza::kana_kanji::marisa_kana_kanji_dict::MarisaKanaKanjiDict;
+use libakaza::kana_layout::KanaLayout;
 use libakaza::keymap::Keymap;
+use libakaza::keymap_check::{check_keymap, KeymapProblemLevel};
 use libakaza::lm::system_bigram::MarisaSystemBigramLM;
 use libakaza::lm::system_unigram_lm::MarisaSystemUnigramLM;
+use libakaza::resource::find_table_path;
 use libakaza::romkan::RomKanConverter;
+use libakaza::thumb_shift::ThumbShiftOutput;
 
-use crate::commands::{ibus_akaza_commands_map, IbusAkazaCommand};
-use crate::current_state::CurrentState;
+use crate::config_watcher::ConfigWatcher;
+use crate::current_state::{CurrentState, ReconvertTarget};
 use crate::input_mode::get_input_mode_from_prop_name;
 use crate::input_mode::InputMode;
 use crate::input_mode::INPUT_MODE_HIRAGANA;
-use crate::keymap::IBusKeyMap;
+use crate::keymap::{IBusKeyMap, KeyMapResult, KEY_SEQUENCE_TIMEOUT};
+use crate::thumb_shift::IBusThumbShift;
 use crate::ui::prop_controller::PropController;
 
 #[repr(C)]
 pub struct AkazaContext {
     // ==== 設定 ====
     keymap: IBusKeyMap,
-    command_map: HashMap<&'static str, IbusAkazaCommand>,
+    /// キーシーケンスの途中のときだけ設定される、タイムアウトのタイマーの ID と、そのときのエンジン。
+    key_sequence_timer: Option<(guint, *mut IBusEngine)>,
+    /// 親指シフトを使うときだけ設定される。
+    thumb_shift: Option<IBusThumbShift>,
+    /// キーコードでかなを入力するときだけ設定される。
+    kana_layout: Option<KanaLayout>,
 
     // ==== 現在の入力状態を保持 ====
     current_state: CurrentState,
+    /// シークレットモード。学習とユーザー辞書への書き込みを止める。
+    incognito: bool,
+    /// パスワードや PIN の入力欄にフォーカスがある。この間もシークレットモードとして扱う。
+    sensitive_input: bool,
 
     // ==== UI 関連 ====
     prop_controller: PropController,
+
+    // ==== 設定の再読み込み ====
+    config_watcher: Option<ConfigWatcher>,
+    /// 辞書を読みなおす必要があるかを判断するために、今のエンジンの設定を覚えておく。
+    engine_config: EngineConfig,
+    /// 別スレッドでエンジンを作りなおしているときだけ設定される。
+    engine_builder: Option<Receiver<Result<Engine>>>,
 }
 
+type Engine =
+    BigramWordViterbiEngine<MarisaSystemUnigramLM, MarisaSystemBigramLM, MarisaKanaKanjiDict>;
+
 impl AkazaContext {
-    pub(crate) fn new(
-        engine: BigramWordViterbiEngine<
-            MarisaSystemUnigramLM,
-            MarisaSystemBigramLM,
-            MarisaKanaKanjiDict,
-        >,
-        config: Config,
-    ) -> Result<Self> {
+    pub(crate) fn new(engine: Engine, config: Config) -> Result<Self> {
         let input_mode = INPUT_MODE_HIRAGANA;
         let romkan = RomKanConverter::new(config.romkan.as_str())?;
+        Self::log_keymap_problems(&config.keymap);
         let keymap = Keymap::load(config.keymap.as_str())?;
+        let thumb_shift = config
+            .thumb_shift
+            .as_ref()
+            .map(IBusThumbShift::new)
+            .transpose()?;
+        let kana_layout = config
+            .kana_layout
+            .as_deref()
+            .map(KanaLayout::load)
+            .transpose()?;
+        let config_watcher = Self::watch(&config);
+        let engine_config = config.engine.clone();
 
         Ok(AkazaContext {
             current_state: CurrentState::new(input_mode, config.live_conversion, romkan, engine),
-            command_map: ibus_akaza_commands_map(),
+            incognito: false,
+            sensitive_input: false,
             keymap: IBusKeyMap::new(keymap)?,
+            key_sequence_timer: None,
+            thumb_shift,
+            kana_layout,
             prop_controller: PropController::new(input_mode, config)?,
+            config_watcher,
+            engine_config,
+            engine_builder: None,
         })
     }
 
+    /// キーマップの問題を、継承元も含めて検査してログに出す。
+    fn log_keymap_problems(keymap_path: &str) {
+        match check_keymap(keymap_path) {
+            Ok(problems) => {
+                for problem in problems {
+                    match problem.level {
+                        KeymapProblemLevel::Error => error!("{}", problem),
+                        KeymapProblemLevel::Warning => warn!("{}", problem),
+                        KeymapProblemLevel::Info => info!("{}", problem),
+                    }
+                }
+            }
+            Err(err) => error!("Cannot check the keymap: {:?}", err),
+        }
+    }
+
+    /// 設定ファイルと、設定で選ばれているキーマップ・ローマ字テーブル・辞書の変更を監視する。
+    /// キーマップとローマ字テーブルは、extends でたどれる継承元のファイルも監視する。
+    fn watch(config: &Config) -> Option<ConfigWatcher> {
+        let mut paths = Vec::new();
+        let mut tables = Vec::new();
+        for (base, name, source_paths) in [
+            (
+                "keymap",
+                &config.keymap,
+                Keymap::source_paths(&config.keymap),
+            ),
+            (
+                "romkan",
+                &config.romkan,
+                RomKanConverter::source_paths(&config.romkan),
+            ),
+        ] {
+            match source_paths {
+                Ok(source_paths) => paths.extend(source_paths.into_iter().map(PathBuf::from)),
+                Err(err) => {
+                    // 壊れたファイルを直したときに読みなおせるように、少なくとも指定されたファイルは監視する。
+                    warn!("Cannot find the files extended by the {}: {:?}", base, err);
+                    tables.push((base, name));
+                }
+            }
+        }
+        if let Some(thumb_shift) = &config.thumb_shift {
+            tables.push(("thumb_shift", &thumb_shift.table));
+        }
+        if let Some(kana_layout) = &config.kana_layout {
+            tables.push(("kana_layout", kana_layout));
+        }
+        for (base, name) in tables {
+            match find_table_path(base, name) {
+                Ok(path) => paths.push(PathBuf::from(path)),
+                Err(err) => warn!("Cannot find the {}: {:?}", base, err),
+            }
+        }
+        match Config::file_name() {
+            Ok(config_file) => paths.push(config_file),
+            Err(err) => warn!("Cannot get the path of the configuration file: {:?}", err),
+        }
+        paths.extend(config.engine.dicts.iter().map(|it| PathBuf::from(&it.path)));
+
+        match ConfigWatcher::new(&paths) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                error!("Cannot watch the configuration files: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// 設定ファイルや辞書が変更されていたら読みなおす。
+    /// 入力の途中で変換結果が変わらないように、未確定の文字列がないときだけ反映する。
+    fn reload_if_changed(&mut self, engine: *mut IBusEngine) {
+        if !self.current_state.get_raw_input().is_empty() {
+            return;
+        }
+        self.swap_engine_if_built();
+        let Some(config_watcher) = &self.config_watcher else {
+            return;
+        };
+        let changed = config_watcher.take_changed();
+        if changed.is_empty() {
+            return;
+        }
+
+        info!("Reloading the configuration: {:?}", changed);
+        if let Err(err) = self.reload(engine, &changed) {
+            error!("Cannot reload the configuration: {:?}", err);
+        }
+    }
+
+    /// 言語モデルや辞書を読みこむと入力が止まってしまうので、エンジンは別スレッドで作る。
+    /// 作っている途中に設定がまた変わったら、古いほうの結果は捨てる。
+    fn spawn_engine_builder(&mut self, engine_config: EngineConfig) {
+        info!("Rebuilding the engine: {}", engine_config.model);
+        let user_data = self.current_state.engine.user_data.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let engine = BigramWordViterbiEngineBuilder::new(engine_config)
+                .user_data(user_data)
+                .build();
+            // 受け取る側がもういないときは、新しい設定で作りなおしているので何もしない。
+            let _ = sender.send(engine);
+        });
+        self.engine_builder = Some(receiver);
+    }
+
+    /// 別スレッドでエンジンができあがっていたら差し替える。
+    fn swap_engine_if_built(&mut self) {
+        let Some(receiver) = &self.engine_builder else {
+            return;
+        };
+        let engine = match receiver.try_recv() {
+            Ok(engine) => engine,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("The engine builder has stopped")),
+        };
+        self.engine_builder = None;
+        match engine {
+            Ok(engine) => {
+                info!("Swapping in the rebuilt engine");
+                self.current_state.engine = engine;
+                // 前回の変換の途中結果は、古い辞書や言語モデルで作ったものなので捨てる。
+                self.current_state.clear_session();
+            }
+            Err(err) => error!("Cannot rebuild the engine: {:?}", err),
+        }
+    }
+
+    /// 設定を読みなおす。ユーザーデータは読みなおさずにそのまま使う。
+    /// 全部読めてから差し替えるので、途中で失敗したときは古い設定のまま動く。
+    /// エンジンは別スレッドで作りなおして、できあがったら差し替える。
+    fn reload(&mut self, engine: *mut IBusEngine, changed: &HashSet<PathBuf>) -> Result<()> {
+        let config_file = Config::file_name()?;
+        let config = if config_file.exists() {
+            // 書きかけのファイルを読んだときに、デフォルトの設定に戻ってしまわないようにする。
+            Config::load_from_file(&config_file.to_string_lossy())?
+        } else {
+            Config::load()?
+        };
+
+        let romkan = RomKanConverter::new(config.romkan.as_str())?;
+        Self::log_keymap_problems(&config.keymap);
+        let keymap = IBusKeyMap::new(Keymap::load(config.keymap.as_str())?)?;
+        let thumb_shift = config
+            .thumb_shift
+            .as_ref()
+            .map(IBusThumbShift::new)
+            .transpose()?;
+        let kana_layout = config
+            .kana_layout
+            .as_deref()
+            .map(KanaLayout::load)
+            .transpose()?;
+
+        let rebuild_engine = config.engine != self.engine_config
+            || config
+                .engine
+                .dicts
+                .iter()
+                .any(|it| changed.contains(Path::new(&it.path)));
+
+        let user_dicts = PropController::find_user_dicts(&config)?;
+        let config_watcher = Self::watch(&config);
+        let engine_config = config.engine.clone();
+        let live_conversion = config.live_conversion;
+
+        if rebuild_engine {
+            self.spawn_engine_builder(config.engine.clone());
+        }
+        self.current_state.romkan = romkan;
+        self.current_state.live_conversion = live_conversion;
+        self.keymap = keymap;
+        self.thumb_shift = thumb_shift;
+        self.kana_layout = kana_layout;
+        self.config_watcher = config_watcher;
+        self.engine_config = engine_config;
+        // ユーザー辞書のメニューは、設定に合わせて作りなおす。ほかのメニューはそのまま使う。
+        self.prop_controller.set_user_dicts(&user_dicts);
+        self.prop_controller.do_focus_in(engine);
+        Ok(())
+    }
+
     /// Set props
     pub(crate) fn do_property_activate(
         &mut self,
@@ -78,7 +304,10 @@ impl AkazaContext {
         prop_state: guint,
     ) {
         info!("do_property_activate: {}, {}", prop_name, prop_state);
-        if prop_name == "PrefPane" {
+        if prop_name == "Incognito" {
+            self.incognito = prop_state == IBusPropState_PROP_STATE_CHECKED;
+            self.on_incognito_change(engine);
+        } else if prop_name == "PrefPane" {
             match open_configuration_window() {
                 Ok(_) => {}
                 Err(e) => info!("Err: {}", e),
@@ -113,13 +342,12 @@ impl AkazaContext {
 }
 
 impl AkazaContext {
-    pub(crate) fn process_num_key(&mut self, nn: i32, engine: *mut IBusEngine) -> bool {
-        let idx = if nn == 0 { 9 } else { nn - 1 };
-
+    /// 変換候補の現在のページの、idx 番目(0 から数える)の候補を選ぶ。
+    pub(crate) fn select_candidate_in_page(&mut self, engine: *mut IBusEngine, idx: usize) -> bool {
         if self.current_state.lookup_table_visible {
-            self.set_lookup_table_cursor_pos_in_current_page(engine, idx)
+            self.set_lookup_table_cursor_pos_in_current_page(engine, idx as i32)
         } else {
-            info!("ignore process_num_key. lookup table is not enabled.");
+            info!("ignore select_candidate_in_page. lookup table is not enabled.");
             false
         }
     }
@@ -175,34 +403,161 @@ impl AkazaContext {
             modifiers
         );
 
+        if let Some(handled) = self.process_thumb_shift(engine, keyval, keycode, modifiers) {
+            return handled;
+        }
+
         // ignore key release event
         if modifiers & IBusModifierType_IBUS_RELEASE_MASK != 0 {
             return false;
         }
-        let key_state = self.current_state.get_key_state();
+        self.process_key(engine, keyval, keycode, modifiers)
+    }
 
-        trace!("KeyState={:?}", key_state);
-        if let Some(callback) = self
-            .keymap
-            .get(
-                &key_state,
-                keyval,
-                modifiers
-                    & (IBusModifierType_IBUS_CONTROL_MASK
-                        | IBusModifierType_IBUS_SHIFT_MASK
-                        | IBusModifierType_IBUS_META_MASK
-                        | IBusModifierType_IBUS_HYPER_MASK
-                        | IBusModifierType_IBUS_MOD1_MASK
-                        | IBusModifierType_IBUS_MOD2_MASK
-                        | IBusModifierType_IBUS_MOD3_MASK
-                        | IBusModifierType_IBUS_MOD4_MASK
-                        | IBusModifierType_IBUS_MOD5_MASK),
-            )
-            .cloned()
+    /// 親指シフトを使っているときは、かなの入力中のキーを同時打鍵の判定に渡す。
+    /// 判定に渡したキーなら、処理したかどうかを返す。
+    fn process_thumb_shift(
+        &mut self,
+        engine: *mut IBusEngine,
+        keyval: guint,
+        keycode: guint,
+        modifiers: guint,
+    ) -> Option<bool> {
+        if !matches!(
+            self.current_state.input_mode.prop_name,
+            "InputMode.Hiragana" | "InputMode.Katakana" | "InputMode.HalfWidthKatakana"
+        ) || self.keymap.is_pending()
         {
-            if self.run_callback_by_name(engine, callback.as_str()) {
+            return None;
+        }
+        let thumb_shift = self.thumb_shift.as_mut()?;
+        match thumb_shift.process(keyval, keycode, modifiers) {
+            Some(outputs) => {
+                self.apply_thumb_shift_outputs(engine, outputs);
+                Some(true)
+            }
+            None => {
+                // 親指シフトで扱わないキーを処理する前に、待っていたキーを確定する。
+                let outputs = thumb_shift.flush();
+                self.apply_thumb_shift_outputs(engine, outputs);
+                None
+            }
+        }
+    }
+
+    fn apply_thumb_shift_outputs(
+        &mut self,
+        engine: *mut IBusEngine,
+        outputs: Vec<ThumbShiftOutput>,
+    ) {
+        for output in outputs {
+            match output {
+                ThumbShiftOutput::Text(text) => self.insert_text(engine, &text),
+                ThumbShiftOutput::Thumb(thumb) => {
+                    // 親指キーが単独で押されたので、元のキーとして処理する。
+                    // キーマップに割り当てがなければ、アプリケーションに渡す。
+                    let Some(thumb_shift) = &self.thumb_shift else {
+                        continue;
+                    };
+                    let (keyval, keycode) = thumb_shift.thumb_key(thumb);
+                    if !self.process_key(engine, keyval, keycode, 0) {
+                        unsafe {
+                            ibus_engine_forward_key_event(engine, keyval, keycode, 0);
+                            ibus_engine_forward_key_event(
+                                engine,
+                                keyval,
+                                keycode,
+                                IBusModifierType_IBUS_RELEASE_MASK,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 入力した文字を未確定の文字列に追加する。
+    fn insert_text(&mut self, engine: *mut IBusEngine, text: &str) {
+        trace!(
+            "Insert new character to preedit: '{}'",
+            self.current_state.get_raw_input()
+        );
+
+        if self.current_state.lookup_table_visible && !self.current_state.clauses.is_empty() {
+            // 変換の途中に別の文字が入力された。
+            // よって、現在の preedit 文字列は確定させる。
+            self.commit_candidate(engine);
+        }
+
+        // 文字列を追加する。
+        for ch in text.chars() {
+            self.current_state.insert_raw_input(engine, ch);
+        }
+    }
+
+    fn is_kana_input_mode(&self) -> bool {
+        matches!(
+            self.current_state.input_mode.prop_name,
+            "InputMode.Hiragana" | "InputMode.Katakana" | "InputMode.HalfWidthKatakana"
+        )
+    }
+
+    fn process_key(
+        &mut self,
+        engine: *mut IBusEngine,
+        keyval: guint,
+        keycode: guint,
+        modifiers: guint,
+    ) -> bool {
+        let was_pending = self.keymap.is_pending();
+        self.reload_if_changed(engine);
+
+        // かな配列の文字キーは、変換中に数字キーで候補を選ぶようなキーマップより優先する。
+        if self.is_kana_input_mode() {
+            if let Some(kana_layout) = &self.kana_layout {
+                let shift = modifiers & IBusModifierType_IBUS_SHIFT_MASK != 0;
+                let control_or_alt = modifiers
+                    & (IBusModifierType_IBUS_CONTROL_MASK | IBusModifierType_IBUS_MOD1_MASK)
+                    != 0;
+                if let Some(text) =
+                    kana_layout.get_before_keymap(keycode, shift, control_or_alt, was_pending)
+                {
+                    let text = text.to_string();
+                    // 濁点や半濁点は、ローマ字かな変換で直前の文字と合わせる。
+                    self.insert_text(engine, &text);
+                    return true;
+                }
+            }
+        }
+
+        let key_state = self.current_state.get_key_state();
+        trace!("KeyState={:?}", key_state);
+        let result = self.keymap.process(&key_state, keyval, modifiers);
+        if was_pending && !self.keymap.is_pending() {
+            // キーシーケンスが終わったので、プレフィックスキーの表示を消す。
+            self.cancel_key_sequence_timer();
+            self.current_state.set_pending_keys(engine, "");
+        }
+        match result {
+            KeyMapResult::Command {
+                command,
+                after_prefix,
+            } => {
+                // キーシーケンスのキーは、コマンドが何もしなくてもアプリケーションには渡さない。
+                if command.run(self, engine) || after_prefix {
+                    return true;
+                }
+            }
+            KeyMapResult::Pending(prefix) => {
+                self.current_state.set_pending_keys(engine, &prefix);
+                self.start_key_sequence_timer(engine);
                 return true;
             }
+            KeyMapResult::Canceled => {
+                info!("Undefined key sequence");
+                return true;
+            }
+            KeyMapResult::NotFound => {}
         }
 
         match self.current_state.input_mode.prop_name {
@@ -215,21 +570,8 @@ impl AkazaContext {
                 }
 
                 if ('!' as u32) <= keyval && keyval <= ('~' as u32) {
-                    trace!(
-                        "Insert new character to preedit: '{}'",
-                        self.current_state.get_raw_input()
-                    );
-
-                    if self.current_state.lookup_table_visible {
-                        // 変換の途中に別の文字が入力された。
-                        // よって、現在の preedit 文字列は確定させる。
-                        self.commit_candidate(engine);
-                    }
-
-                    // 文字列を追加する。
                     let ch = char::from_u32(keyval).unwrap();
-                    self.current_state.append_raw_input(engine, ch);
-
+                    self.insert_text(engine, &ch.to_string());
                     return true;
                 }
             }
@@ -269,13 +611,57 @@ impl AkazaContext {
             return;
         }
 
-        // サイゴの一文字をけずるが、子音が先行しているばあいは、子音もついでにとる。
-        self.current_state.set_raw_input(
-            engine,
-            self.current_state
-                .romkan
-                .remove_last_char(self.current_state.get_raw_input()),
-        )
+        self.current_state.erase_raw_input_before_caret(engine);
+    }
+
+    /// キャレットの後ろの一文字を削除する。
+    pub(crate) fn erase_character_after_cursor(&mut self, engine: *mut IBusEngine) {
+        if !self.current_state.can_move_caret() {
+            return;
+        }
+        self.current_state.erase_raw_input_after_caret(engine);
+    }
+}
+
+/// キーシーケンスのタイムアウトで、glib のメインループから呼ばれる。
+unsafe extern "C" fn key_sequence_timeout(data: gpointer) -> gboolean {
+    let context = &mut *(data as *mut AkazaContext);
+    context.on_key_sequence_timeout();
+    // 一回だけでよいので、タイマーを止める。
+    0
+}
+
+impl AkazaContext {
+    /// キーシーケンスのタイムアウトのタイマーを、今から数えなおす。
+    fn start_key_sequence_timer(&mut self, engine: *mut IBusEngine) {
+        self.cancel_key_sequence_timer();
+        let id = unsafe {
+            g_timeout_add(
+                KEY_SEQUENCE_TIMEOUT.as_millis() as guint,
+                Some(key_sequence_timeout),
+                self as *mut AkazaContext as gpointer,
+            )
+        };
+        self.key_sequence_timer = Some((id, engine));
+    }
+
+    fn cancel_key_sequence_timer(&mut self) {
+        if let Some((id, _)) = self.key_sequence_timer.take() {
+            unsafe {
+                g_source_remove(id);
+            }
+        }
+    }
+
+    /// 次のキーが押されないまま時間がたったので、キーシーケンスの途中の状態と表示を消す。
+    fn on_key_sequence_timeout(&mut self) {
+        // タイマーは key_sequence_timeout が FALSE を返すことで止まるので、ここでは消さない。
+        let Some((_, engine)) = self.key_sequence_timer.take() else {
+            return;
+        };
+        info!("Key sequence timed out");
+        self.keymap.clear_pending();
+        self.current_state.set_pending_keys(engine, "");
     }
 }
 
@@ -301,28 +687,24 @@ impl AkazaContext {
         self.current_state.set_input_mode(engine, input_mode);
     }
 
-    pub(crate) fn run_callback_by_name(
-        &mut self,
-        engine: *mut IBusEngine,
-        function_name: &str,
-    ) -> bool {
-        if let Some(function) = self.command_map.get(function_name) {
-            info!("Calling function '{}'", function_name);
-            function(self, engine)
-        } else {
-            error!("Unknown function '{}'", function_name);
-            false
-        }
-    }
-
     pub fn commit_string(&mut self, engine: *mut IBusEngine, text: &str) {
         if !self.current_state.clauses.is_empty() {
             // 変換モードのときのみ学習を実施する
-            self.current_state
-                .engine
-                .learn(self.current_state.get_first_candidates().as_slice());
+            if self.is_incognito() {
+                // 確定した文字列は、学習にも文脈にも使わない。
+                self.current_state.clear_last_committed();
+            } else {
+                let candidates = self.current_state.get_first_candidates();
+                self.current_state.engine.learn(candidates.as_slice());
+                self.current_state.push_last_committed(candidates);
+            }
+        } else {
+            // 変換せずに確定した文字列は、単語として扱えないので文脈をリセットする。
+            self.current_state.clear_last_committed();
         }
 
+        // 再変換の元の文字列は、再変換を始めたときに消してある。
+        self.current_state.reconvert_target = None;
         unsafe {
             ibus_engine_commit_text(engine, text.to_ibus_text());
         }
@@ -337,6 +719,129 @@ impl AkazaContext {
         self.commit_string(engine, self.current_state.build_string().as_str());
     }
 
+    /// lookup table で選択されている予測候補を確定させる。
+    /// 予測候補がない場合は false を返す。
+    pub(crate) fn commit_suggestion(&mut self, engine: *mut IBusEngine) -> bool {
+        let cursor_pos = self.current_state.lookup_table.get_cursor_pos() as usize;
+        let Some(candidate) = self.current_state.suggestions.get(cursor_pos).cloned() else {
+            return false;
+        };
+
+        let incognito = self.is_incognito();
+        if !incognito {
+            self.current_state
+                .engine
+                .learn(std::slice::from_ref(&candidate));
+        }
+        self.commit_string(engine, candidate.surface_with_dynamic().as_str());
+        if !incognito {
+            self.current_state.push_last_committed(vec![candidate]);
+        }
+        true
+    }
+
+    /// 予測候補の選択を一つ移動する。next が false なら前の候補を選ぶ。
+    /// 予測候補を表示していない場合は false を返す。
+    pub(crate) fn select_suggestion(&mut self, engine: *mut IBusEngine, next: bool) -> bool {
+        if !self.current_state.clauses.is_empty()
+            || self.current_state.suggestions.is_empty()
+            || !self.current_state.lookup_table_visible
+        {
+            return false;
+        }
+
+        let moved = if next {
+            self.current_state.lookup_table.cursor_down()
+        } else {
+            self.current_state.lookup_table.cursor_up()
+        };
+        if moved {
+            self.current_state.update_lookup_table(engine, true);
+        }
+        true
+    }
+
+    /// 確定済みの文字列を再変換する。
+    /// 元の文字列を入力欄から消して、推定した読みを一文節で変換している状態にする。
+    /// キャンセルしたときは、元の文字列を入力欄に戻す。
+    pub(crate) fn reconvert(&mut self, engine: *mut IBusEngine) -> bool {
+        if !self.current_state.get_raw_input().is_empty() {
+            return false;
+        }
+
+        let Some((surface, offset)) = self.current_state.get_reconvert_target(engine) else {
+            info!("reconvert: there's no target text");
+            return false;
+        };
+        let yomi = match self.current_state.engine.reverse_lookup(surface.as_str()) {
+            Ok(yomi) => yomi,
+            Err(err) => {
+                error!("Cannot get yomi for reconversion: {:?}, {:?}", surface, err);
+                return false;
+            }
+        };
+        info!("reconvert: {:?} -> {:?}", surface, yomi);
+
+        self.current_state.clear_last_committed();
+        self.current_state.set_raw_input(engine, yomi);
+        let yomi_len = self
+            .current_state
+            .romkan
+            .to_hiragana(self.current_state.get_raw_input())
+            .len();
+        unsafe {
+            ibus_engine_delete_surrounding_text(engine, offset, surface.chars().count() as guint);
+        }
+        self.current_state.reconvert_target = Some(ReconvertTarget { surface });
+        // 元の文字列を候補にするため、最初は全体を一文節で変換する。
+        #[allow(clippy::single_range_in_vec_init)]
+        let whole = vec![0..yomi_len];
+        self.current_state.force_selected_clause = whole;
+        self.update_candidates(engine)
+    }
+
+    /// 再変換をやめて、消しておいた元の文字列を入力欄に戻す。
+    /// 再変換していなければ false を返す。
+    fn cancel_reconvert(&mut self, engine: *mut IBusEngine) -> bool {
+        let Some(target) = self.current_state.reconvert_target.take() else {
+            return false;
+        };
+        self.current_state.clear_raw_input(engine);
+        unsafe {
+            ibus_engine_commit_text(engine, target.surface.to_ibus_text());
+        }
+        true
+    }
+
+    /// 選択中の候補の学習結果を削除して、変換しなおす。
+    /// 誤って学習した候補が上位に出続けるのを止めるためのもの。
+    pub(crate) fn delete_candidate_from_history(&mut self, engine: *mut IBusEngine) -> bool {
+        if self.is_incognito() {
+            info!("delete_candidate_from_history: user data is read-only in incognito mode");
+            return false;
+        }
+        let Some(candidate) = self.current_state.get_selected_candidate().cloned() else {
+            return false;
+        };
+
+        {
+            let mut user_data = self.current_state.engine.user_data.lock().unwrap();
+            if !user_data.delete_candidate(&candidate) {
+                info!(
+                    "delete_candidate_from_history: not learned: {:?}",
+                    candidate
+                );
+                return false;
+            }
+            if let Err(err) = user_data.write_user_files() {
+                error!("Cannot save user data: {:?}", err);
+            }
+        }
+
+        self.current_state.clear_session();
+        self.update_candidates(engine)
+    }
+
     // space key を押して、最初に変換に入る時の処理。
     pub(crate) fn update_candidates(&mut self, engine: *mut IBusEngine) -> bool {
         if self.current_state.get_raw_input().is_empty() {
@@ -408,8 +913,9 @@ impl AkazaContext {
 
     /// 選択する分節を右にずらす。
     pub(crate) fn cursor_right(&mut self, engine: *mut IBusEngine) {
-        // 分節がない場合は、何もしない。
-        if self.current_state.clauses.is_empty() {
+        // 分節がない場合や、ライブコンバージョンで変換を始める前は、読みの中のキャレットを動かす。
+        if self.current_state.can_move_caret() {
+            self.current_state.move_caret(engine, |caret, _| caret + 1);
             return;
         }
 
@@ -418,14 +924,30 @@ impl AkazaContext {
 
     /// 選択する分節を左にずらす。
     pub(crate) fn cursor_left(&mut self, engine: *mut IBusEngine) {
-        // 分節がなければ何もしない
-        if self.current_state.clauses.is_empty() {
+        // 分節がない場合や、ライブコンバージョンで変換を始める前は、読みの中のキャレットを動かす。
+        if self.current_state.can_move_caret() {
+            self.current_state
+                .move_caret(engine, |caret, _| caret.saturating_sub(1));
             return;
         }
 
         self.current_state.select_left_clause(engine);
     }
 
+    /// 読みの中のキャレットを先頭に動かす。
+    pub(crate) fn cursor_home(&mut self, engine: *mut IBusEngine) {
+        if self.current_state.can_move_caret() {
+            self.current_state.move_caret(engine, |_, _| 0);
+        }
+    }
+
+    /// 読みの中のキャレットを末尾に動かす。
+    pub(crate) fn cursor_end(&mut self, engine: *mut IBusEngine) {
+        if self.current_state.can_move_caret() {
+            self.current_state.move_caret(engine, |_, len| len);
+        }
+    }
+
     /// 文節の選択範囲を右方向に広げる
     pub fn extend_clause_right(&mut self, engine: *mut IBusEngine) -> Result<()> {
         self.current_state.extend_right(engine);
@@ -461,15 +983,69 @@ impl AkazaContext {
     ) {
         info!("do_candidate_clicked");
         if self.set_lookup_table_cursor_pos_in_current_page(engine, index as i32) {
-            self.commit_candidate(engine)
+            if self.current_state.clauses.is_empty() {
+                // 変換前であれば、予測候補がクリックされている。
+                self.commit_suggestion(engine);
+            } else {
+                self.commit_candidate(engine)
+            }
         }
     }
 
     pub fn do_focus_in(&mut self, engine: *mut IBusEngine) {
         trace!("do_focus_in");
+        self.reload_if_changed(engine);
+        // 別の入力欄でキーシーケンスの続きを押すことはないので、途中の状態は捨てる。
+        self.cancel_key_sequence_timer();
+        self.keymap.clear_pending();
+        self.current_state.set_pending_keys(engine, "");
+        if let Some(thumb_shift) = &mut self.thumb_shift {
+            thumb_shift.flush();
+        }
+        // 別の入力欄に移ったので、直前に確定した単語は文脈として使わない。
+        self.current_state.clear_last_committed();
         self.prop_controller.do_focus_in(engine);
     }
 
+    pub fn do_focus_out(&mut self, engine: *mut IBusEngine) {
+        trace!("do_focus_out");
+        // 再変換はやめて、元の文字列を戻す。
+        self.cancel_reconvert(engine);
+    }
+
+    /// 入力欄の種類が変わったときに呼ばれる。
+    /// パスワードや PIN の入力欄では、自動的にシークレットモードにする。
+    pub fn do_set_content_type(&mut self, engine: *mut IBusEngine, purpose: guint) {
+        let sensitive_input = purpose == IBusInputPurpose_IBUS_INPUT_PURPOSE_PASSWORD
+            || purpose == IBusInputPurpose_IBUS_INPUT_PURPOSE_PIN;
+        if self.sensitive_input != sensitive_input {
+            info!("do_set_content_type: purpose={}", purpose);
+            self.sensitive_input = sensitive_input;
+            self.on_incognito_change(engine);
+        }
+    }
+
+    pub(crate) fn toggle_incognito(&mut self, engine: *mut IBusEngine) {
+        self.incognito = !self.incognito;
+        self.on_incognito_change(engine);
+    }
+
+    fn is_incognito(&self) -> bool {
+        self.incognito || self.sensitive_input
+    }
+
+    fn on_incognito_change(&mut self, engine: *mut IBusEngine) {
+        info!(
+            "incognito: {}(sensitive_input: {})",
+            self.incognito, self.sensitive_input
+        );
+        if self.is_incognito() {
+            self.current_state.clear_last_committed();
+        }
+        self.prop_controller
+            .set_incognito(self.is_incognito(), engine);
+    }
+
     /// convert selected word/characters to full-width hiragana (standard hiragana): ホワイト → ほわいと
     pub fn convert_to_full_hiragana(&mut self, engine: *mut IBusEngine) -> Result<()> {
         info!("Convert to full hiragana");
@@ -573,6 +1149,10 @@ impl AkazaContext {
     pub fn escape(&mut self, engine: *mut IBusEngine) {
         trace!("escape");
 
+        // 再変換はやめて、元の文字列を戻す。
+        if self.cancel_reconvert(engine) {
+            return;
+        }
         if self.current_state.live_conversion {
             self.current_state.clear_raw_input(engine);
         } else {
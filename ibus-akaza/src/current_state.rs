@@ -1,8 +1,9 @@
 use std::collections::HashMap;
+use std::ffi::CStr;
 use std::ops::Range;
 
 use kelp::{hira2kata, z2h, ConvOption};
-use log::info;
+use log::{info, warn};
 
 use ibus_sys::attr_list::{ibus_attr_list_append, ibus_attr_list_new};
 use ibus_sys::attribute::{
@@ -11,33 +12,166 @@ use ibus_sys::attribute::{
 };
 use ibus_sys::core::to_gboolean;
 use ibus_sys::engine::{
-    ibus_engine_hide_auxiliary_text, ibus_engine_hide_preedit_text,
-    ibus_engine_update_auxiliary_text, ibus_engine_update_lookup_table,
-    ibus_engine_update_preedit_text, IBusEngine,
+    ibus_engine_get_surrounding_text, ibus_engine_hide_auxiliary_text,
+    ibus_engine_hide_preedit_text, ibus_engine_update_auxiliary_text,
+    ibus_engine_update_lookup_table, ibus_engine_update_preedit_text, IBusEngine,
 };
 use ibus_sys::glib::guint;
 use ibus_sys::lookup_table::IBusLookupTable;
-use ibus_sys::text::{ibus_text_set_attributes, StringExt};
-use libakaza::consonant::ConsonantSuffixExtractor;
+use ibus_sys::text::{ibus_text_get_text, ibus_text_set_attributes, IBusText, StringExt};
 use libakaza::engine::base::HenkanEngine;
 use libakaza::engine::bigram_word_viterbi_engine::BigramWordViterbiEngine;
+use libakaza::engine::incremental_session::IncrementalSession;
 use libakaza::extend_clause::{extend_left, extend_right};
 use libakaza::graph::candidate::Candidate;
 use libakaza::kana_kanji::marisa_kana_kanji_dict::MarisaKanaKanjiDict;
-use libakaza::keymap::KeyState;
+use libakaza::keymap::{KeyState, CANDIDATE_PAGE_SIZE};
 use libakaza::lm::system_bigram::MarisaSystemBigramLM;
 use libakaza::lm::system_unigram_lm::MarisaSystemUnigramLM;
 use libakaza::romkan::RomKanConverter;
 
-use crate::input_mode::{InputMode, INPUT_MODE_HALFWIDTH_KATAKANA, INPUT_MODE_KATAKANA};
+use crate::input_mode::{
+    InputMode, INPUT_MODE_HALFWIDTH_KATAKANA, INPUT_MODE_HIRAGANA, INPUT_MODE_KATAKANA,
+};
+
+/// 選択範囲がないときに、カーソルの前から再変換の対象とする最大文字数。
+const RECONVERT_MAX_CHARS: usize = 20;
+
+/// 予測候補を出すのに必要な、読みの最小文字数。
+/// 一文字だと候補が多すぎて、キー入力ごとの処理が重くなる。
+const SUGGESTION_MIN_YOMI_LEN: usize = 2;
+
+/// 再変換している確定済みの文字列。
+/// 再変換を始めるときに入力欄から消しておき、キャンセルしたときに元に戻す。
+#[derive(Debug, Clone)]
+pub(crate) struct ReconvertTarget {
+    pub(crate) surface: String,
+}
+
+/// 先頭から n 文字目で分ける。
+fn split_at_char(s: &str, n: usize) -> (&str, &str) {
+    let pos = s.char_indices().nth(n).map(|(i, _)| i).unwrap_or(s.len());
+    s.split_at(pos)
+}
+
+// 以下の関数は、読みとキャレットの位置から、編集後のキャレットの前と後ろの読みを返す。
+
+/// キャレットの位置に一文字追加する。
+fn insert_at_caret(raw_input: &str, caret: usize, ch: char) -> (String, String) {
+    let (before, after) = split_at_char(raw_input, caret);
+    (
+        before.to_string() + ch.to_string().as_str(),
+        after.to_string(),
+    )
+}
+
+/// キャレットの前の一文字をけずるが、子音が先行しているばあいは、子音もついでにとる。
+fn erase_before_caret(romkan: &RomKanConverter, raw_input: &str, caret: usize) -> (String, String) {
+    let (before, after) = split_at_char(raw_input, caret);
+    (romkan.remove_last_char(before), after.to_string())
+}
+
+/// キャレットの後ろの一文字をけずる。
+fn erase_after_caret(raw_input: &str, caret: usize) -> (String, String) {
+    let (before, after) = split_at_char(raw_input, caret);
+    let mut after = after.chars();
+    after.next();
+    (before.to_string(), after.as_str().to_string())
+}
+
+/// キャレットを動かす。to には、今のキャレットの位置と読みの文字数を渡す。
+///
+/// 先にキャレットより前に残っているローマ字をかなにしておく。
+/// 読みの途中で入力したローマ字が、後ろのかなとつながって変換されないようにするため。
+/// 大文字で始まる場合は、かなにしないで表示しているので、そのままにする。
+fn move_caret_to(
+    romkan: &RomKanConverter,
+    raw_input: &str,
+    caret: usize,
+    to: impl FnOnce(usize, usize) -> usize,
+) -> (String, String) {
+    let (before, after) = split_at_char(raw_input, caret);
+    let mut raw_input = before.to_string();
+    if !matches!(before.chars().next(), Some(ch) if ch.is_ascii_uppercase()) {
+        let (output, pending) = romkan.convert(before);
+        raw_input = output + pending.as_str();
+    }
+    let caret = raw_input.chars().count();
+    raw_input += after;
+
+    let len = raw_input.chars().count();
+    let (before, after) = split_at_char(&raw_input, to(caret, len).min(len));
+    (before.to_string(), after.to_string())
+}
+
+/// raw_input の一部から、preedit に表示する (yomi, surface) を作る。
+fn preedit_word(
+    romkan: &RomKanConverter,
+    input_mode: &InputMode,
+    raw_input: &str,
+) -> (String, String) {
+    let preedit = raw_input.to_string();
+    // 先頭文字が大文字な場合は、そのまま返す。
+    // "IME" などと入力された場合は、それをそのまま返すようにする。
+    if !preedit.is_empty() && preedit.chars().next().unwrap().is_ascii_uppercase() {
+        return (preedit.clone(), preedit);
+    }
+
+    // hogen と入力された場合、"ほげn" と表示する。
+    // hogena となったら "ほげな"
+    // hogenn となったら "ほげん" と表示する必要があるため。
+    // 「ん」と一旦表示された後に「な」に変化したりすると気持ち悪く感じる。
+    // 未確定の部分は、かな入力のテーブルではかなのこともあるので、まとめてカタカナにする。
+    let (output, pending) = romkan.convert(preedit.as_str());
+    let yomi = output + pending.as_str();
+    if *input_mode == INPUT_MODE_KATAKANA {
+        let surface = hira2kata(yomi.as_str(), ConvOption::default());
+        (yomi, surface)
+    } else if *input_mode == INPUT_MODE_HALFWIDTH_KATAKANA {
+        let surface = z2h(
+            hira2kata(yomi.as_str(), ConvOption::default()).as_str(),
+            ConvOption::default(),
+        );
+        (yomi, surface)
+    } else {
+        (yomi.clone(), yomi)
+    }
+}
+
+/// 変換していないときの preedit の中の、キャレットの位置。
+/// キャレットより前の読みを表示したときの文字数になる。
+fn preedit_caret(
+    romkan: &RomKanConverter,
+    input_mode: &InputMode,
+    raw_input: &str,
+    caret: usize,
+) -> usize {
+    let (before, _) = split_at_char(raw_input, caret);
+    preedit_word(romkan, input_mode, before).1.chars().count()
+}
 
 #[derive(Debug)]
 pub struct CurrentState {
     pub(crate) input_mode: InputMode,
     raw_input: String,
+    /// 読みの中のキャレットの位置。raw_input の先頭からの文字数。
+    /// キャレットより後ろは、かなにしてある。
+    caret: usize,
     preedit: String,
+    /// preedit の中のキャレットの位置。文字数。
+    preedit_caret: usize,
     auxiliary_text: String,
+    /// キーシーケンスの途中で押されたプレフィックスキー。補助テキストのかわりに表示する。
+    pending_keys: String,
     pub(crate) clauses: Vec<Vec<Candidate>>,
+    /// 入力途中の読みから予測した候補。Composition 状態のときに lookup table に表示する。
+    pub(crate) suggestions: Vec<Candidate>,
+    /// 直前に確定した単語列。次の変換の左側の文脈として使う。
+    last_committed: Vec<Candidate>,
+    /// surrounding text から得た左側の文脈。入力のたびに引きなおさないように、元の文字列と一緒に保持する。
+    surrounding_context: Option<(String, Vec<Candidate>)>,
+    /// 再変換しているときだけ設定される。
+    pub(crate) reconvert_target: Option<ReconvertTarget>,
     /// 現在選択されている文節
     pub(crate) current_clause: usize,
     // key は、clause 番号。value は、node の index。
@@ -51,7 +185,8 @@ pub struct CurrentState {
     pub romkan: RomKanConverter,
     pub(crate) engine:
         BigramWordViterbiEngine<MarisaSystemUnigramLM, MarisaSystemBigramLM, MarisaKanaKanjiDict>,
-    consonant_suffix_extractor: ConsonantSuffixExtractor,
+    /// ライブコンバージョンで、前回の変換の途中結果を再利用するためのセッション
+    session: IncrementalSession<MarisaSystemUnigramLM, MarisaSystemBigramLM>,
 }
 
 impl CurrentState {
@@ -68,18 +203,25 @@ impl CurrentState {
         CurrentState {
             input_mode,
             raw_input: String::new(),
+            caret: 0,
             preedit: String::new(),
+            preedit_caret: 0,
             auxiliary_text: String::new(),
+            pending_keys: String::new(),
             clauses: vec![],
+            suggestions: vec![],
+            last_committed: vec![],
+            surrounding_context: None,
+            reconvert_target: None,
             current_clause: 0,
             node_selected: HashMap::new(),
             force_selected_clause: Vec::new(),
             live_conversion,
             lookup_table_visible: false,
-            lookup_table: IBusLookupTable::new(10, 0, 1, 1),
+            lookup_table: IBusLookupTable::new(CANDIDATE_PAGE_SIZE as u32, 0, 1, 1),
             romkan,
             engine,
-            consonant_suffix_extractor: ConsonantSuffixExtractor::default(),
+            session: IncrementalSession::default(),
         }
     }
 
@@ -97,8 +239,10 @@ impl CurrentState {
     }
 
     pub fn clear_raw_input(&mut self, engine: *mut IBusEngine) {
+        self.reconvert_target = None;
         if !self.raw_input.is_empty() {
             self.raw_input.clear();
+            self.caret = 0;
             self.on_raw_input_change(engine);
         }
     }
@@ -121,28 +265,87 @@ impl CurrentState {
         }
     }
 
-    pub(crate) fn append_raw_input(&mut self, engine: *mut IBusEngine, ch: char) {
-        self.raw_input.push(ch);
-        self.on_raw_input_change(engine);
+    /// キャレットの位置に一文字追加する。
+    pub(crate) fn insert_raw_input(&mut self, engine: *mut IBusEngine, ch: char) {
+        let (before, after) = insert_at_caret(&self.raw_input, self.caret, ch);
+        self.set_raw_input_around_caret(engine, before, &after);
+    }
+
+    /// キャレットの前の一文字をけずる。
+    pub(crate) fn erase_raw_input_before_caret(&mut self, engine: *mut IBusEngine) {
+        let (before, after) = erase_before_caret(&self.romkan, &self.raw_input, self.caret);
+        self.set_raw_input_around_caret(engine, before, &after);
     }
 
-    /// バックスペースで一文字削除した場合などに呼ばれる。
+    /// キャレットの後ろの一文字をけずる。
+    pub(crate) fn erase_raw_input_after_caret(&mut self, engine: *mut IBusEngine) {
+        let (before, after) = erase_after_caret(&self.raw_input, self.caret);
+        self.set_raw_input_around_caret(engine, before, &after);
+    }
+
+    /// 再変換の場合などに呼ばれる。キャレットは末尾に移る。
     pub(crate) fn set_raw_input(&mut self, engine: *mut IBusEngine, raw_input: String) {
+        self.set_raw_input_around_caret(engine, raw_input, "");
+    }
+
+    /// バックスペースで一文字削除した場合などに呼ばれる。キャレットは before と after の間に移る。
+    pub(crate) fn set_raw_input_around_caret(
+        &mut self,
+        engine: *mut IBusEngine,
+        before: String,
+        after: &str,
+    ) {
+        let caret = before.chars().count();
+        let raw_input = before + after;
         if self.raw_input != raw_input {
-            info!("set_raw_input: {:?}", raw_input);
+            info!("set_raw_input: {:?}, caret={}", raw_input, caret);
             self.raw_input = raw_input;
+            self.caret = caret;
             self.on_raw_input_change(engine);
+        } else if self.caret != caret {
+            self.caret = caret;
+            if self.live_conversion {
+                // キャレットが末尾にあるかどうかで、変換するかが変わる。
+                self.on_raw_input_change(engine);
+            } else {
+                self.update_preedit(engine);
+            }
         }
     }
 
+    /// キャレットを動かす。to には、今のキャレットの位置と読みの文字数を渡す。
+    pub(crate) fn move_caret(
+        &mut self,
+        engine: *mut IBusEngine,
+        to: impl FnOnce(usize, usize) -> usize,
+    ) {
+        let (before, after) = move_caret_to(&self.romkan, &self.raw_input, self.caret, to);
+        self.set_raw_input_around_caret(engine, before, &after);
+    }
+
+    /// 読みの中でキャレットを動かせるか。
+    /// ライブコンバージョンでは、候補を表示して変換を始めるまでは動かせる。
+    pub(crate) fn can_move_caret(&self) -> bool {
+        self.clauses.is_empty()
+            || (self.live_conversion
+                && !self.lookup_table_visible
+                && self.force_selected_clause.is_empty())
+    }
+
+    fn is_caret_at_end(&self) -> bool {
+        self.caret >= self.raw_input.chars().count()
+    }
+
     pub(crate) fn henkan(&mut self, engine: *mut IBusEngine) -> anyhow::Result<()> {
         if self.get_raw_input().is_empty() {
+            // 確定して学習した後は、前回の計算結果は使えない。
+            self.session.clear();
             self.set_clauses(engine, vec![]);
         } else {
             let yomi = self.get_raw_input().to_string();
 
             // 先頭が大文字なケースと、URL っぽい文字列のときは変換処理を実施しない。
-            let clauses = if (!yomi.is_empty()
+            let mut clauses = if (!yomi.is_empty()
                 && yomi.chars().next().unwrap().is_ascii_uppercase()
                 && self.force_selected_clause.is_empty())
                 || yomi.starts_with("https://")
@@ -153,13 +356,30 @@ impl CurrentState {
                     yomi.as_str(),
                     0_f32,
                 )])]
+            } else if self.live_conversion && self.force_selected_clause.is_empty() {
+                // 一文字入力するたびに変換するので、変化していない部分の計算結果を再利用する。
+                let left_context = self.left_context(engine);
+                self.engine.convert_incremental(
+                    &mut self.session,
+                    &left_context,
+                    self.romkan.to_hiragana(&yomi).as_str(),
+                )?
             } else {
-                self.engine.convert(
+                let left_context = self.left_context(engine);
+                self.engine.convert_with_context(
+                    &left_context,
                     self.romkan.to_hiragana(&yomi).as_str(),
                     Some(&self.force_selected_clause),
                 )?
             };
 
+            // 再変換で一文節のときは、元の文字列を最初の候補にする。そのまま確定すれば何も変わらない。
+            if let (Some(target), [clause]) = (&self.reconvert_target, clauses.as_mut_slice()) {
+                let yomi = clause[0].yomi.clone();
+                clause.retain(|it| it.surface != target.surface);
+                clause.insert(0, Candidate::new(&yomi, &target.surface, 0_f32));
+            }
+
             self.set_clauses(engine, clauses);
 
             self.adjust_current_clause(engine);
@@ -167,6 +387,124 @@ impl CurrentState {
         Ok(())
     }
 
+    /// 確定した単語列を記録しておく。連続して確定した場合に備えて、直近の二単語を保持する。
+    pub(crate) fn push_last_committed(&mut self, candidates: Vec<Candidate>) {
+        self.last_committed.extend(candidates);
+        let len = self.last_committed.len();
+        self.last_committed.drain(0..len.saturating_sub(2));
+    }
+
+    pub(crate) fn clear_last_committed(&mut self) {
+        self.last_committed.clear();
+    }
+
+    /// 変換対象の左側の文脈を得る。
+    /// カーソルの直前が直前に確定した文字列で終わっていれば、確定した単語列をそのまま使う。
+    /// そうでなければカーソルが移動しているので、surrounding text を言語モデルで引いて文脈にする。
+    fn left_context(&mut self, engine: *mut IBusEngine) -> Vec<Candidate> {
+        let Some(text) = Self::get_text_before_cursor(engine) else {
+            return self.last_committed.clone();
+        };
+
+        let committed = self.last_committed_text();
+        if !committed.is_empty() && text.ends_with(committed.as_str()) {
+            return self.last_committed.clone();
+        }
+        if let Some((cached_text, left_context)) = &self.surrounding_context {
+            if *cached_text == text {
+                return left_context.clone();
+            }
+        }
+        let left_context = match self.engine.left_context_from_text(&text) {
+            Ok(left_context) => {
+                info!("Left context from surrounding text: {:?}", left_context);
+                left_context
+            }
+            Err(err) => {
+                warn!("Cannot get left context from {:?}: {}", text, err);
+                Vec::new()
+            }
+        };
+        self.surrounding_context = Some((text, left_context.clone()));
+        left_context
+    }
+
+    /// 再変換の対象とする文字列を surrounding text から探す。
+    /// 選択範囲があればそれを、なければ直前に確定した文字列を対象とする。
+    /// どちらもなければ、カーソルの前にある空白や句読点までの文字列を対象とする。
+    ///
+    /// @return (対象の文字列, カーソル位置からの対象の開始位置のオフセット)
+    pub(crate) fn get_reconvert_target(&self, engine: *mut IBusEngine) -> Option<(String, i32)> {
+        let (text, cursor_pos, anchor_pos) = Self::get_surrounding_text(engine)?;
+        let chars: Vec<char> = text.chars().collect();
+        let cursor_pos = (cursor_pos as usize).min(chars.len());
+        let anchor_pos = (anchor_pos as usize).min(chars.len());
+
+        let (start, end) = if cursor_pos != anchor_pos {
+            (cursor_pos.min(anchor_pos), cursor_pos.max(anchor_pos))
+        } else {
+            let before_cursor: String = chars[0..cursor_pos].iter().collect();
+            let committed = self.last_committed_text();
+            let len = if !committed.is_empty() && before_cursor.ends_with(committed.as_str()) {
+                committed.chars().count()
+            } else {
+                chars[0..cursor_pos]
+                    .iter()
+                    .rev()
+                    .take(RECONVERT_MAX_CHARS)
+                    .take_while(|c| {
+                        !c.is_whitespace()
+                            && !c.is_ascii_punctuation()
+                            && !"、。，．・「」『』（）！？".contains(**c)
+                    })
+                    .count()
+            };
+            (cursor_pos - len, cursor_pos)
+        };
+
+        if start == end {
+            return None;
+        }
+        Some((
+            chars[start..end].iter().collect(),
+            start as i32 - cursor_pos as i32,
+        ))
+    }
+
+    fn last_committed_text(&self) -> String {
+        self.last_committed
+            .iter()
+            .map(|candidate| candidate.surface_with_dynamic())
+            .collect()
+    }
+
+    /// surrounding text のうち、カーソルより前の部分を得る。
+    /// クライアントが surrounding text に対応していない場合は None を返す。
+    fn get_text_before_cursor(engine: *mut IBusEngine) -> Option<String> {
+        let (text, cursor_pos, _) = Self::get_surrounding_text(engine)?;
+        Some(text.chars().take(cursor_pos as usize).collect())
+    }
+
+    /// @return (text, cursor_pos, anchor_pos)。位置は文字数単位。
+    fn get_surrounding_text(engine: *mut IBusEngine) -> Option<(String, guint, guint)> {
+        unsafe {
+            let mut text: *mut IBusText = std::ptr::null_mut();
+            let mut cursor_pos: guint = 0;
+            let mut anchor_pos: guint = 0;
+            ibus_engine_get_surrounding_text(engine, &mut text, &mut cursor_pos, &mut anchor_pos);
+            if text.is_null() {
+                return None;
+            }
+            let text = CStr::from_ptr(ibus_text_get_text(text))
+                .to_string_lossy()
+                .to_string();
+            if text.is_empty() {
+                return None;
+            }
+            Some((text, cursor_pos, anchor_pos))
+        }
+    }
+
     pub fn set_auxiliary_text(&mut self, engine: *mut IBusEngine, auxiliary_text: &str) {
         if self.auxiliary_text != auxiliary_text {
             self.auxiliary_text = auxiliary_text.to_string();
@@ -174,6 +512,13 @@ impl CurrentState {
         }
     }
 
+    pub(crate) fn set_pending_keys(&mut self, engine: *mut IBusEngine, pending_keys: &str) {
+        if self.pending_keys != pending_keys {
+            self.pending_keys = pending_keys.to_string();
+            self.render_auxiliary_text(engine);
+        }
+    }
+
     pub fn set_clauses(&mut self, engine: *mut IBusEngine, clause: Vec<Vec<Candidate>>) {
         if self.clauses != clause {
             self.clauses = clause;
@@ -220,9 +565,51 @@ impl CurrentState {
                 let candidate = &node.surface_with_dynamic();
                 self.lookup_table.append_candidate(candidate.to_ibus_text());
             }
+        } else {
+            // 変換前であれば、予測候補を表示する。
+            for candidate in &self.suggestions {
+                self.lookup_table
+                    .append_candidate(candidate.surface_with_dynamic().to_ibus_text());
+            }
+        }
+    }
+
+    /// 入力途中の読みから予測候補を算出して、lookup table に反映させる。
+    fn update_suggestions(&mut self) {
+        let page_size = self.lookup_table.get_page_size() as usize;
+        let suggestions = self.predict(page_size).unwrap_or_else(|err| {
+            warn!("Cannot predict: {:?}", err);
+            Vec::new()
+        });
+        if self.suggestions != suggestions {
+            self.suggestions = suggestions;
+            self.render_lookup_table();
         }
     }
 
+    fn predict(&self, limit: usize) -> anyhow::Result<Vec<Candidate>> {
+        let raw_input = self.get_raw_input();
+        // ひらがな入力中のみ予測する。大文字で始まる場合や URL っぽいときも予測しない。
+        if self.input_mode != INPUT_MODE_HIRAGANA
+            || self.live_conversion
+            || !self.clauses.is_empty()
+            || raw_input.is_empty()
+            || raw_input.chars().next().unwrap().is_ascii_uppercase()
+            || raw_input.starts_with("https://")
+            || raw_input.starts_with("http://")
+        {
+            return Ok(Vec::new());
+        }
+
+        // "tanak" のように末尾に未確定の入力が残っている場合は、それを除いた "たな" で予測する。
+        let (yomi, _pending) = self.romkan.convert(raw_input);
+        if yomi.chars().count() < SUGGESTION_MIN_YOMI_LEN {
+            return Ok(Vec::new());
+        }
+
+        self.engine.predict(yomi.as_str(), limit)
+    }
+
     pub fn get_first_candidates(&self) -> Vec<Candidate> {
         let mut targets: Vec<Candidate> = Vec::new();
         for (i, candidates) in self.clauses.iter().enumerate() {
@@ -232,6 +619,18 @@ impl CurrentState {
         targets
     }
 
+    /// 現在の文節で選択されている候補。
+    pub(crate) fn get_selected_candidate(&self) -> Option<&Candidate> {
+        let candidates = self.clauses.get(self.current_clause)?;
+        let idx = self.node_selected.get(&self.current_clause).unwrap_or(&0);
+        candidates.get(*idx)
+    }
+
+    /// 学習データが変わって前回の計算結果が使えなくなったときに呼ぶ。
+    pub(crate) fn clear_session(&mut self) {
+        self.session.clear();
+    }
+
     /// 一個右の文節を選択する
     pub fn select_right_clause(&mut self, engine: *mut IBusEngine) {
         if self.current_clause == self.clauses.len() - 1 {
@@ -313,11 +712,20 @@ impl CurrentState {
         // なので、先にクリアする必要がある。
         self.clear_force_selected_clause(engine);
 
-        if self.live_conversion {
+        if self.live_conversion && self.is_caret_at_end() {
             self.henkan(engine).unwrap();
-        } else if !self.clauses.is_empty() {
-            self.clauses.clear();
-            self.on_clauses_change(engine);
+        } else if self.live_conversion {
+            // キャレットが読みの途中にある間は変換しないで、読みを表示する。
+            if !self.clauses.is_empty() {
+                self.clauses.clear();
+                self.on_clauses_change(engine);
+            }
+        } else {
+            if !self.clauses.is_empty() {
+                self.clauses.clear();
+                self.on_clauses_change(engine);
+            }
+            self.update_suggestions();
         }
 
         self.clear_current_clause(engine);
@@ -359,21 +767,19 @@ impl CurrentState {
     }
 
     pub fn update_preedit(&mut self, engine: *mut IBusEngine) {
-        if self.live_conversion {
-            if self.clauses.is_empty() {
-                unsafe { ibus_engine_hide_preedit_text(engine) }
-            } else {
-                self.preedit = self.build_string();
-                self.render_preedit(engine);
-            }
+        if self.live_conversion && self.raw_input.is_empty() {
+            unsafe { ibus_engine_hide_preedit_text(engine) }
         } else if self.clauses.is_empty() {
-            // live conversion じゃなくて、変換中じゃないとき。
+            // 変換中じゃないとき。ライブコンバージョンでも、キャレットが読みの途中にあればここにくる。
             let (_yomi, surface) = self.make_preedit_word_for_precomposition();
             self.preedit = surface;
+            self.preedit_caret =
+                preedit_caret(&self.romkan, &self.input_mode, &self.raw_input, self.caret);
             self.render_preedit(engine);
         } else {
-            // live conversion じゃなくて、変換中のとき。
+            // 変換中のとき。
             self.preedit = self.build_string();
+            self.preedit_caret = self.preedit.chars().count();
             self.render_preedit(engine);
         }
     }
@@ -411,7 +817,7 @@ impl CurrentState {
             ibus_engine_update_preedit_text(
                 engine,
                 preedit_text,
-                self.preedit.len() as guint,
+                self.preedit_caret as guint,
                 to_gboolean(!self.preedit.is_empty()),
             );
         }
@@ -433,7 +839,11 @@ impl CurrentState {
 
     fn render_auxiliary_text(&self, engine: *mut IBusEngine) {
         unsafe {
-            if self.lookup_table_visible {
+            if !self.pending_keys.is_empty() {
+                let auxiliary_text = format!("{} -", self.pending_keys).to_ibus_text();
+                ibus_text_set_attributes(auxiliary_text, ibus_attr_list_new());
+                ibus_engine_update_auxiliary_text(engine, auxiliary_text, to_gboolean(true));
+            } else if self.lookup_table_visible {
                 if self.auxiliary_text.is_empty() {
                     ibus_engine_hide_auxiliary_text(engine);
                 } else {
@@ -470,40 +880,116 @@ impl CurrentState {
 
     /// (yomi, surface)
     pub fn make_preedit_word_for_precomposition(&self) -> (String, String) {
-        let preedit = self.get_raw_input().to_string();
-        // 先頭文字が大文字な場合は、そのまま返す。
-        // "IME" などと入力された場合は、それをそのまま返すようにする。
-        if !preedit.is_empty() && preedit.chars().next().unwrap().is_ascii_uppercase() {
-            return (preedit.clone(), preedit);
-        }
+        self.make_preedit_word(self.get_raw_input())
+    }
 
-        // hogen と入力された場合、"ほげn" と表示する。
-        // hogena となったら "ほげな"
-        // hogenn となったら "ほげん" と表示する必要があるため。
-        // 「ん」と一旦表示された後に「な」に変化したりすると気持ち悪く感じる。
-        let (preedit, suffix) = if self.romkan.mapping_name == "default" {
-            self.consonant_suffix_extractor.extract(preedit.as_str())
-        } else {
-            (preedit, "".to_string())
-        };
+    /// raw_input の一部から、preedit に表示する (yomi, surface) を作る。
+    fn make_preedit_word(&self, raw_input: &str) -> (String, String) {
+        preedit_word(&self.romkan, &self.input_mode, raw_input)
+    }
+}
 
-        let yomi = self.romkan.to_hiragana(preedit.as_str());
-        let surface = yomi.clone();
-        if self.input_mode == INPUT_MODE_KATAKANA {
-            (
-                yomi.to_string() + suffix.as_str(),
-                hira2kata(yomi.as_str(), ConvOption::default()) + suffix.as_str(),
-            )
-        } else if self.input_mode == INPUT_MODE_HALFWIDTH_KATAKANA {
-            (
-                yomi.to_string() + suffix.as_str(),
-                z2h(
-                    hira2kata(yomi.as_str(), ConvOption::default()).as_str(),
-                    ConvOption::default(),
-                ) + suffix.as_str(),
-            )
-        } else {
-            (yomi + suffix.as_str(), surface + suffix.as_str())
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn romkan() -> RomKanConverter {
+        RomKanConverter::new("../romkan/default.yml").unwrap()
+    }
+
+    fn pair(before: &str, after: &str) -> (String, String) {
+        (before.to_string(), after.to_string())
+    }
+
+    #[test]
+    fn test_split_at_char() {
+        assert_eq!(split_at_char("あいう", 0), ("", "あいう"));
+        assert_eq!(split_at_char("あいう", 1), ("あ", "いう"));
+        assert_eq!(split_at_char("あいう", 3), ("あいう", ""));
+        // 文字数より後ろは末尾とみなす。
+        assert_eq!(split_at_char("あいう", 5), ("あいう", ""));
+        assert_eq!(split_at_char("", 0), ("", ""));
+    }
+
+    #[test]
+    fn test_insert_at_caret() {
+        assert_eq!(insert_at_caret("あいう", 0, 'k'), pair("k", "あいう"));
+        assert_eq!(insert_at_caret("あいう", 1, 'k'), pair("あk", "いう"));
+        assert_eq!(insert_at_caret("あいう", 3, 'k'), pair("あいうk", ""));
+    }
+
+    #[test]
+    fn test_erase_at_caret() {
+        let romkan = romkan();
+        assert_eq!(erase_before_caret(&romkan, "あいう", 0), pair("", "あいう"));
+        assert_eq!(erase_before_caret(&romkan, "あいう", 2), pair("あ", "う"));
+        assert_eq!(erase_before_caret(&romkan, "あいう", 3), pair("あい", ""));
+        // キャレットの前にローマ字が残っていれば、ローマ字を一文字けずる。
+        assert_eq!(erase_before_caret(&romkan, "あkyう", 3), pair("あk", "う"));
+
+        assert_eq!(erase_after_caret("あいう", 0), pair("", "いう"));
+        assert_eq!(erase_after_caret("あいう", 1), pair("あ", "う"));
+        assert_eq!(erase_after_caret("あいう", 3), pair("あいう", ""));
+    }
+
+    #[test]
+    fn test_move_caret_to() {
+        let romkan = romkan();
+        assert_eq!(
+            move_caret_to(&romkan, "あいう", 3, |_, _| 0),
+            pair("", "あいう")
+        );
+        assert_eq!(
+            move_caret_to(&romkan, "あいう", 1, |caret, _| caret + 1),
+            pair("あい", "う")
+        );
+        // 末尾より後ろには動かない。
+        assert_eq!(
+            move_caret_to(&romkan, "あいう", 3, |caret, _| caret + 1),
+            pair("あいう", "")
+        );
+        assert_eq!(
+            move_caret_to(&romkan, "あいう", 0, |caret, _| caret.saturating_sub(1)),
+            pair("", "あいう")
+        );
+
+        // キャレットの前のローマ字は、かなにしてから動かす。
+        assert_eq!(
+            move_caret_to(&romkan, "kaki", 4, |_, _| 0),
+            pair("", "かき")
+        );
+        assert_eq!(
+            move_caret_to(&romkan, "あkaう", 3, |caret, _| caret.saturating_sub(1)),
+            pair("あ", "かう")
+        );
+        // かなにならないローマ字は、そのまま残す。
+        assert_eq!(
+            move_caret_to(&romkan, "あkう", 2, |_, len| len),
+            pair("あkう", "")
+        );
+        // 大文字で始まる場合は、かなにしない。
+        assert_eq!(move_caret_to(&romkan, "Ime", 3, |_, _| 1), pair("I", "me"));
+    }
+
+    #[test]
+    fn test_preedit_caret() {
+        let romkan = romkan();
+        let hiragana = INPUT_MODE_HIRAGANA;
+        assert_eq!(preedit_caret(&romkan, &hiragana, "あいう", 0), 0);
+        assert_eq!(preedit_caret(&romkan, &hiragana, "あいう", 2), 2);
+        assert_eq!(preedit_caret(&romkan, &hiragana, "あいう", 3), 3);
+        // キャレットの前のローマ字は、かなにした文字数で数える。
+        assert_eq!(preedit_caret(&romkan, &hiragana, "kaki", 4), 2);
+        assert_eq!(preedit_caret(&romkan, &hiragana, "あkaう", 3), 2);
+        assert_eq!(preedit_caret(&romkan, &hiragana, "あkyう", 3), 3);
+        // 半角カタカナでは、濁点が別の文字になる。
+        assert_eq!(
+            preedit_caret(&romkan, &INPUT_MODE_HALFWIDTH_KATAKANA, "gaう", 2),
+            2
+        );
+        assert_eq!(
+            preedit_word(&romkan, &INPUT_MODE_HALFWIDTH_KATAKANA, "gaう").1,
+            "ｶﾞｳ"
+        );
     }
 }
@@ -65,3 +65,15 @@ pub fn get_input_mode_from_prop_name(prop_code: &str) -> anyhow::Result<InputMod
     }
     bail!("Unknown prop_code: {}", prop_code)
 }
+
+/// キーマップの set_input_mode の引数から、入力モードを探す。
+pub fn get_input_mode_from_name(name: &str) -> anyhow::Result<InputMode> {
+    Ok(match name {
+        "hiragana" => INPUT_MODE_HIRAGANA,
+        "katakana" => INPUT_MODE_KATAKANA,
+        "halfwidth_katakana" => INPUT_MODE_HALFWIDTH_KATAKANA,
+        "alnum" => INPUT_MODE_ALNUM,
+        "fullwidth_alnum" => INPUT_MODE_FULLWIDTH_ALNUM,
+        _ => bail!("Unknown input mode: {}", name),
+    })
+}
@@ -1,35 +1,73 @@
 use alloc::ffi::CString;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use log::{error, trace};
+use log::{error, info, trace, warn};
 
-use ibus_sys::core::{IBusModifierType_IBUS_CONTROL_MASK, IBusModifierType_IBUS_SHIFT_MASK};
+use ibus_sys::core::{
+    IBusModifierType_IBUS_CONTROL_MASK, IBusModifierType_IBUS_HYPER_MASK,
+    IBusModifierType_IBUS_META_MASK, IBusModifierType_IBUS_MOD1_MASK,
+    IBusModifierType_IBUS_MOD3_MASK, IBusModifierType_IBUS_MOD4_MASK,
+    IBusModifierType_IBUS_SHIFT_MASK, IBusModifierType_IBUS_SUPER_MASK,
+};
 use ibus_sys::glib::guint;
-use ibus_sys::ibus_key::IBUS_KEY_VoidSymbol;
+use ibus_sys::ibus_key::{
+    IBUS_KEY_Hyper_R, IBUS_KEY_ISO_Level5_Lock, IBUS_KEY_ISO_Lock, IBUS_KEY_Mode_switch,
+    IBUS_KEY_Shift_L, IBUS_KEY_VoidSymbol,
+};
 use ibus_sys::keys::ibus_keyval_from_name;
-use libakaza::keymap::{KeyPattern, KeyState};
+use libakaza::keymap::{KeyPattern, KeyState, KeyStroke};
 
-#[derive(Hash, PartialEq)]
-struct IBusKeyPattern {
-    key_state: KeyState,
+use crate::commands::{BoundCommand, IbusAkazaCommands};
+
+/// キーシーケンスの途中で、次のキーを待つ時間。
+/// 時間がたつと AkazaContext のタイマーで途中の状態を捨てる。
+/// タイマーより先に次のキーが処理された場合に備えて、次のキーが押されたときにも判定する。
+pub(crate) const KEY_SEQUENCE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Hash, PartialEq, Clone)]
+struct IBusKeyStroke {
     keyval: u32,
     modifier: u32,
 }
 
+impl Eq for IBusKeyStroke {}
+
+#[derive(Hash, PartialEq, Clone)]
+struct IBusKeyPattern {
+    key_state: KeyState,
+    keys: Vec<IBusKeyStroke>,
+}
+
 impl Eq for IBusKeyPattern {}
 
 impl IBusKeyPattern {
-    fn new(key_state: KeyState, keyval: u32, modifier: u32) -> Self {
-        IBusKeyPattern {
-            key_state,
-            keyval,
-            modifier,
-        }
+    fn new(key_state: KeyState, keys: Vec<IBusKeyStroke>) -> Self {
+        IBusKeyPattern { key_state, keys }
     }
 }
 
+/// キーマップを引いた結果。
+pub(crate) enum KeyMapResult {
+    /// コマンドが割り当てられている。after_prefix はキーシーケンスの最後のキーだったか。
+    Command {
+        command: BoundCommand,
+        after_prefix: bool,
+    },
+    /// キーシーケンスの途中。押されたプレフィックスキーを表示用の文字列で持つ。
+    Pending(String),
+    /// キーシーケンスの途中で、割り当てのないキーが押された。
+    Canceled,
+    /// 何も割り当てられていない。
+    NotFound,
+}
+
 pub struct IBusKeyMap {
-    keymap: HashMap<IBusKeyPattern, String>,
+    keymap: HashMap<IBusKeyPattern, BoundCommand>,
+    /// キーシーケンスの途中までのキーと、その表示用の文字列。
+    prefixes: HashMap<IBusKeyPattern, String>,
+    /// 押されたプレフィックスキーと、最後に押された時刻。
+    pending: Option<(IBusKeyPattern, Instant)>,
 }
 
 impl IBusKeyMap {
@@ -38,38 +76,330 @@ impl IBusKeyMap {
         unsafe { ibus_keyval_from_name(cs.as_ptr()) }
     }
 
+    fn to_ibus_key_stroke(key_stroke: &KeyStroke) -> Option<IBusKeyStroke> {
+        let mut modifier = 0_u32;
+        if key_stroke.ctrl {
+            modifier |= IBusModifierType_IBUS_CONTROL_MASK;
+        }
+        if key_stroke.shift {
+            modifier |= IBusModifierType_IBUS_SHIFT_MASK;
+        }
+        if key_stroke.alt {
+            modifier |= IBusModifierType_IBUS_MOD1_MASK;
+        }
+        if key_stroke.super_key {
+            modifier |= IBusModifierType_IBUS_SUPER_MASK;
+        }
+        if key_stroke.hyper {
+            modifier |= IBusModifierType_IBUS_HYPER_MASK;
+        }
+        let keyval = Self::to_ibus_key(key_stroke.key.as_str());
+        if keyval == IBUS_KEY_VoidSymbol {
+            return None;
+        }
+        Some(IBusKeyStroke { keyval, modifier })
+    }
+
     pub(crate) fn new(keymap: HashMap<KeyPattern, String>) -> anyhow::Result<Self> {
-        let mut mapping: HashMap<IBusKeyPattern, String> = HashMap::new();
+        let commands = IbusAkazaCommands::new();
+        let mut mapping: HashMap<IBusKeyPattern, BoundCommand> = HashMap::new();
+        let mut prefixes: HashMap<IBusKeyPattern, String> = HashMap::new();
 
         for (key_pattern, command) in keymap {
-            let key = &key_pattern.key;
-            let mut modifier = 0_u32;
-            if key_pattern.ctrl {
-                modifier |= IBusModifierType_IBUS_CONTROL_MASK;
-            }
-            if key_pattern.shift {
-                modifier |= IBusModifierType_IBUS_SHIFT_MASK;
-            }
-            let keyval = Self::to_ibus_key(key.as_str());
-            if keyval == IBUS_KEY_VoidSymbol {
-                error!("Unknown key symbol: {} {:?}", key, key_pattern);
+            let Some(keys) = key_pattern
+                .keys
+                .iter()
+                .map(Self::to_ibus_key_stroke)
+                .collect::<Option<Vec<_>>>()
+            else {
+                error!("Unknown key symbol: {:?}", key_pattern);
                 continue;
-            }
-            trace!("Insert: {} {} {} {:?}", modifier, keyval, key, key_pattern);
+            };
+            trace!("Insert: {:?} {}", key_pattern, command);
+            // 引数も含めて、キーマップを読み込むときに検証しておく。
+            let command = match commands.bind(&command) {
+                Ok(command) => command,
+                Err(err) => {
+                    error!("Invalid command in keymap: {}: {:?}", command, err);
+                    continue;
+                }
+            };
             for state in &key_pattern.states {
-                mapping.insert(
-                    IBusKeyPattern::new(*state, keyval, modifier),
-                    command.clone(),
+                for i in 1..keys.len() {
+                    let display = key_pattern.keys[..i]
+                        .iter()
+                        .map(|it| it.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    prefixes.insert(IBusKeyPattern::new(*state, keys[..i].to_vec()), display);
+                }
+                mapping.insert(IBusKeyPattern::new(*state, keys.clone()), command.clone());
+            }
+        }
+
+        // プレフィックスキーにもコマンドが割り当てられているときは、キーシーケンスを優先する。
+        for prefix in prefixes.keys() {
+            if mapping.remove(prefix).is_some() {
+                warn!(
+                    "'{}' is a prefix key. The command for it is not available.",
+                    prefixes[prefix]
                 );
             }
         }
 
-        Ok(IBusKeyMap { keymap: mapping })
+        Ok(IBusKeyMap {
+            keymap: mapping,
+            prefixes,
+            pending: None,
+        })
+    }
+
+    /// キーシーケンスの途中か。
+    pub(crate) fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// キーシーケンスの途中の状態を捨てる。
+    pub(crate) fn clear_pending(&mut self) {
+        self.pending = None;
+    }
+
+    /// 押されたキーでキーマップを引く。キーシーケンスの途中なら、押されたキーを続きとして扱う。
+    pub(crate) fn process(
+        &mut self,
+        key_state: &KeyState,
+        keyval: u32,
+        modifier: u32,
+    ) -> KeyMapResult {
+        let key_stroke = IBusKeyStroke {
+            keyval,
+            modifier: Self::normalize_modifier(modifier),
+        };
+        trace!("MODIFIER: {}", key_stroke.modifier);
+
+        let pattern = match self.pending.take() {
+            Some((pending, pressed_at)) if pressed_at.elapsed() < KEY_SEQUENCE_TIMEOUT => {
+                if Self::is_modifier_key(keyval) {
+                    // 次のキーと組み合わせる修飾キーが押されただけなので、そのまま待つ。
+                    let display = self.prefixes[&pending].clone();
+                    self.pending = Some((pending, Instant::now()));
+                    return KeyMapResult::Pending(display);
+                }
+                let mut keys = pending.keys;
+                keys.push(key_stroke);
+                IBusKeyPattern::new(pending.key_state, keys)
+            }
+            Some(_) => {
+                info!("Key sequence timed out");
+                IBusKeyPattern::new(*key_state, vec![key_stroke])
+            }
+            None => IBusKeyPattern::new(*key_state, vec![key_stroke]),
+        };
+
+        let after_prefix = pattern.keys.len() > 1;
+        if let Some(command) = self.keymap.get(&pattern) {
+            KeyMapResult::Command {
+                command: command.clone(),
+                after_prefix,
+            }
+        } else if let Some(display) = self.prefixes.get(&pattern) {
+            let display = display.clone();
+            self.pending = Some((pattern, Instant::now()));
+            KeyMapResult::Pending(display)
+        } else if after_prefix {
+            KeyMapResult::Canceled
+        } else {
+            KeyMapResult::NotFound
+        }
+    }
+
+    /// Shift_L や Control_L のような、修飾キーそのものか。
+    fn is_modifier_key(keyval: u32) -> bool {
+        (IBUS_KEY_Shift_L..=IBUS_KEY_Hyper_R).contains(&keyval)
+            || (IBUS_KEY_ISO_Lock..=IBUS_KEY_ISO_Level5_Lock).contains(&keyval)
+            || keyval == IBUS_KEY_Mode_switch
+    }
+
+    /// ibus から渡される修飾キーのビットを、キーマップで使うビットにそろえる。
+    ///
+    /// Alt は MOD1 と META のどちらで来ることもあるので MOD1 にまとめる。
+    /// Super と Hyper は、仮想修飾キーのビットが立っていなければ MOD4 と MOD3 を使う。
+    /// NumLock(MOD2) などは無視する。
+    fn normalize_modifier(modifier: u32) -> u32 {
+        let mut result =
+            modifier & (IBusModifierType_IBUS_CONTROL_MASK | IBusModifierType_IBUS_SHIFT_MASK);
+        if modifier & (IBusModifierType_IBUS_MOD1_MASK | IBusModifierType_IBUS_META_MASK) != 0 {
+            result |= IBusModifierType_IBUS_MOD1_MASK;
+        }
+        if modifier & (IBusModifierType_IBUS_SUPER_MASK | IBusModifierType_IBUS_HYPER_MASK) != 0 {
+            result |=
+                modifier & (IBusModifierType_IBUS_SUPER_MASK | IBusModifierType_IBUS_HYPER_MASK);
+        } else {
+            if modifier & IBusModifierType_IBUS_MOD4_MASK != 0 {
+                result |= IBusModifierType_IBUS_SUPER_MASK;
+            }
+            if modifier & IBusModifierType_IBUS_MOD3_MASK != 0 {
+                result |= IBusModifierType_IBUS_HYPER_MASK;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ibus_sys::ibus_key::{IBUS_KEY_Control_L, IBUS_KEY_a, IBUS_KEY_k, IBUS_KEY_x};
+
+    use super::*;
+
+    fn key_stroke(ctrl: bool, key: &str) -> KeyStroke {
+        KeyStroke {
+            ctrl,
+            shift: false,
+            alt: false,
+            super_key: false,
+            hyper: false,
+            key: key.to_string(),
+        }
+    }
+
+    /// C-x k がキーシーケンス、C-x はそのプレフィックスと重なる単独のキー、a は単独のキー。
+    fn new_keymap() -> IBusKeyMap {
+        let states = vec![KeyState::Composition];
+        IBusKeyMap::new(HashMap::from([
+            (
+                KeyPattern {
+                    states: states.clone(),
+                    keys: vec![key_stroke(true, "x"), key_stroke(false, "k")],
+                },
+                "commit_candidate".to_string(),
+            ),
+            (
+                KeyPattern {
+                    states: states.clone(),
+                    keys: vec![key_stroke(true, "x")],
+                },
+                "page_up".to_string(),
+            ),
+            (
+                KeyPattern {
+                    states,
+                    keys: vec![key_stroke(false, "a")],
+                },
+                "escape".to_string(),
+            ),
+        ]))
+        .unwrap()
+    }
+
+    fn press(keymap: &mut IBusKeyMap, keyval: u32, modifier: u32) -> String {
+        match keymap.process(&KeyState::Composition, keyval, modifier) {
+            KeyMapResult::Command {
+                command,
+                after_prefix,
+            } => format!("Command({}, {})", command.name(), after_prefix),
+            KeyMapResult::Pending(display) => format!("Pending({})", display),
+            KeyMapResult::Canceled => "Canceled".to_string(),
+            KeyMapResult::NotFound => "NotFound".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_key_sequence() {
+        let mut keymap = new_keymap();
+        assert_eq!(
+            press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK),
+            "Pending(C-x)"
+        );
+        assert!(keymap.is_pending());
+        assert_eq!(
+            press(&mut keymap, IBUS_KEY_k, 0),
+            "Command(commit_candidate, true)"
+        );
+        assert!(!keymap.is_pending());
+        // キーシーケンスが終わったら、単独のキーとして引く。
+        assert_eq!(press(&mut keymap, IBUS_KEY_k, 0), "NotFound");
+    }
+
+    #[test]
+    fn test_canceled() {
+        let mut keymap = new_keymap();
+        press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK);
+        // 単独では割り当てのある a も、キーシーケンスの続きとしては割り当てがない。
+        assert_eq!(press(&mut keymap, IBUS_KEY_a, 0), "Canceled");
+        assert!(!keymap.is_pending());
+        assert_eq!(press(&mut keymap, IBUS_KEY_a, 0), "Command(escape, false)");
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut keymap = new_keymap();
+        press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK);
+        let (pattern, _) = keymap.pending.take().unwrap();
+        keymap.pending = Some((
+            pattern,
+            Instant::now() - KEY_SEQUENCE_TIMEOUT - Duration::from_millis(1),
+        ));
+        // 時間切れなので、k はキーシーケンスの続きではなく単独のキーとして扱う。
+        assert_eq!(press(&mut keymap, IBUS_KEY_k, 0), "NotFound");
+        assert!(!keymap.is_pending());
+
+        press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK);
+        let (pattern, _) = keymap.pending.take().unwrap();
+        keymap.pending = Some((
+            pattern,
+            Instant::now() - KEY_SEQUENCE_TIMEOUT - Duration::from_millis(1),
+        ));
+        assert_eq!(press(&mut keymap, IBUS_KEY_a, 0), "Command(escape, false)");
+    }
+
+    #[test]
+    fn test_modifier_key_keeps_pending() {
+        let mut keymap = new_keymap();
+        press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK);
+        let (_, pressed_at) = keymap.pending.clone().unwrap();
+        assert_eq!(
+            press(
+                &mut keymap,
+                IBUS_KEY_Control_L,
+                IBusModifierType_IBUS_CONTROL_MASK
+            ),
+            "Pending(C-x)"
+        );
+        // 修飾キーを押すと、待つ時間を延ばす。
+        let (_, extended_at) = keymap.pending.clone().unwrap();
+        assert!(extended_at >= pressed_at);
+        assert_eq!(
+            press(&mut keymap, IBUS_KEY_k, 0),
+            "Command(commit_candidate, true)"
+        );
+    }
+
+    #[test]
+    fn test_prefix_shadows_single_key() {
+        let mut keymap = new_keymap();
+        // C-x の page_up は、C-x k のプレフィックスと重なるので使えない。
+        assert_eq!(
+            press(&mut keymap, IBUS_KEY_x, IBusModifierType_IBUS_CONTROL_MASK),
+            "Pending(C-x)"
+        );
+        assert!(!keymap
+            .keymap
+            .values()
+            .any(|command| command.name() == "page_up"));
     }
 
-    pub fn get(&self, key_state: &KeyState, keyval: u32, modifier: u32) -> Option<&String> {
-        trace!("MODIFIER: {}", modifier);
-        self.keymap
-            .get(&IBusKeyPattern::new(*key_state, keyval, modifier))
+    #[test]
+    fn test_other_state() {
+        let mut keymap = new_keymap();
+        assert!(matches!(
+            keymap.process(
+                &KeyState::PreComposition,
+                IBUS_KEY_x,
+                IBusModifierType_IBUS_CONTROL_MASK
+            ),
+            KeyMapResult::NotFound
+        ));
+        assert!(!keymap.is_pending());
     }
 }
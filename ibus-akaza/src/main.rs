@@ -14,7 +14,7 @@ use log::{error, info, warn};
 use ibus_sys::core::ibus_main;
 use ibus_sys::engine::IBusEngine;
 use ibus_sys::glib::{gchar, guint};
-use libakaza::config::Config;
+use libakaza::config::{Config, UserDataConfig};
 use libakaza::engine::bigram_word_viterbi_engine::BigramWordViterbiEngineBuilder;
 use libakaza::user_side_data::user_data::UserData;
 
@@ -22,10 +22,12 @@ use crate::context::AkazaContext;
 use crate::wrapper_bindings::{ibus_akaza_init, ibus_akaza_set_callback};
 
 mod commands;
+mod config_watcher;
 mod context;
 mod current_state;
 mod input_mode;
 mod keymap;
+mod thumb_shift;
 mod ui;
 mod wrapper_bindings;
 
@@ -56,6 +58,11 @@ unsafe extern "C" fn focus_in(context: *mut c_void, engine: *mut IBusEngine) {
     context_ref.do_focus_in(engine);
 }
 
+unsafe extern "C" fn focus_out(context: *mut c_void, engine: *mut IBusEngine) {
+    let context_ref = &mut *(context as *mut AkazaContext);
+    context_ref.do_focus_out(engine);
+}
+
 unsafe extern "C" fn property_activate(
     context: *mut c_void,
     engine: *mut IBusEngine,
@@ -72,8 +79,18 @@ unsafe extern "C" fn property_activate(
     );
 }
 
-fn load_user_data() -> Arc<Mutex<UserData>> {
-    match UserData::load_from_default_path() {
+unsafe extern "C" fn set_content_type(
+    context: *mut c_void,
+    engine: *mut IBusEngine,
+    purpose: guint,
+    _hints: guint,
+) {
+    let context_ref = &mut *(context as *mut AkazaContext);
+    context_ref.do_set_content_type(engine, purpose);
+}
+
+fn load_user_data(config: &UserDataConfig) -> Arc<Mutex<UserData>> {
+    match UserData::load_from_default_path(config) {
         Ok(user_data) => Arc::new(Mutex::new(user_data)),
         Err(err) => {
             error!("Cannot load user data: {}", err);
@@ -122,12 +139,12 @@ fn main() -> Result<()> {
 
     unsafe {
         let sys_time = SystemTime::now();
-        let user_data = load_user_data();
         let config = Config::load()?;
+        let user_data = load_user_data(&config.user_data);
         let akaza = BigramWordViterbiEngineBuilder::new(Config::load()?.engine)
             .user_data(user_data.clone())
             .build()?;
-        let mut ac = AkazaContext::new(akaza, config);
+        let mut ac = AkazaContext::new(akaza, config)?;
         let new_sys_time = SystemTime::now();
         let difference = new_sys_time.duration_since(sys_time)?;
         info!(
@@ -159,7 +176,9 @@ fn main() -> Result<()> {
             process_key_event,
             candidate_clicked,
             focus_in,
+            focus_out,
             property_activate,
+            set_content_type,
         );
 
         ibus_akaza_init(arg.ibus);
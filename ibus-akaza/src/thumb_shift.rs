@@ -0,0 +1,91 @@
+use std::ffi::{CStr, CString};
+use std::time::Instant;
+
+use anyhow::Result;
+
+use ibus_sys::core::{
+    IBusModifierType_IBUS_CONTROL_MASK, IBusModifierType_IBUS_MOD1_MASK,
+    IBusModifierType_IBUS_RELEASE_MASK, IBusModifierType_IBUS_SHIFT_MASK,
+};
+use ibus_sys::glib::guint;
+use ibus_sys::keys::{ibus_keyval_from_name, ibus_keyval_name};
+use libakaza::config::ThumbShiftConfig;
+use libakaza::thumb_shift::{Thumb, ThumbShiftInput, ThumbShiftOutput, ThumbShiftTable};
+
+/// ibus のキーイベントを、親指シフトの同時打鍵の判定に渡す。
+pub(crate) struct IBusThumbShift {
+    input: ThumbShiftInput,
+    /// キーイベントの時刻をミリ秒にするための基準。
+    origin: Instant,
+    /// 親指キーの keyval。単独で押されたときに、元のキーとして処理するのに使う。
+    thumb_keyvals: [guint; 2],
+    /// 最後に押された親指キーの keycode。アプリケーションにキーを渡すときに使う。
+    thumb_keycodes: [guint; 2],
+}
+
+impl IBusThumbShift {
+    pub(crate) fn new(config: &ThumbShiftConfig) -> Result<IBusThumbShift> {
+        let table = ThumbShiftTable::load(&config.table)?;
+        let keyval = |name: &str| {
+            let cs = CString::new(name).unwrap();
+            unsafe { ibus_keyval_from_name(cs.as_ptr()) }
+        };
+        let thumb_keyvals = [keyval(&table.left_thumb), keyval(&table.right_thumb)];
+        Ok(IBusThumbShift {
+            input: ThumbShiftInput::new(table, config.overlap_ms),
+            origin: Instant::now(),
+            thumb_keyvals,
+            thumb_keycodes: [0, 0],
+        })
+    }
+
+    /// 親指シフトで扱うキーなら、同時打鍵を判定した結果を返す。
+    /// 扱わないキーなら None を返すので、flush してから普通のキーとして処理する。
+    pub(crate) fn process(
+        &mut self,
+        keyval: guint,
+        keycode: guint,
+        modifiers: guint,
+    ) -> Option<Vec<ThumbShiftOutput>> {
+        // Shift や Ctrl と一緒に押されたキーは、キーマップや英数の入力に使う。
+        if modifiers
+            & (IBusModifierType_IBUS_SHIFT_MASK
+                | IBusModifierType_IBUS_CONTROL_MASK
+                | IBusModifierType_IBUS_MOD1_MASK)
+            != 0
+        {
+            return None;
+        }
+        let name = unsafe { ibus_keyval_name(keyval) };
+        if name.is_null() {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+        if !self.input.is_key(&name) {
+            return None;
+        }
+
+        if let Some(i) = self.thumb_keyvals.iter().position(|it| *it == keyval) {
+            self.thumb_keycodes[i] = keycode;
+        }
+        let time = self.origin.elapsed().as_millis() as u64;
+        Some(if modifiers & IBusModifierType_IBUS_RELEASE_MASK != 0 {
+            self.input.release(&name, time)
+        } else {
+            self.input.press(&name, time)
+        })
+    }
+
+    pub(crate) fn flush(&mut self) -> Vec<ThumbShiftOutput> {
+        self.input.flush()
+    }
+
+    /// 単独で押された親指キーの keyval と keycode。
+    pub(crate) fn thumb_key(&self, thumb: Thumb) -> (guint, guint) {
+        let i = match thumb {
+            Thumb::Left => 0,
+            Thumb::Right => 1,
+        };
+        (self.thumb_keyvals[i], self.thumb_keycodes[i])
+    }
+}
@@ -11,30 +11,48 @@ use ibus_sys::property::{
     ibus_property_new, ibus_property_set_label, ibus_property_set_state,
     ibus_property_set_sub_props, ibus_property_set_symbol, IBusPropState_PROP_STATE_CHECKED,
     IBusPropState_PROP_STATE_UNCHECKED, IBusPropType_PROP_TYPE_MENU, IBusPropType_PROP_TYPE_RADIO,
-    IBusProperty,
+    IBusPropType_PROP_TYPE_TOGGLE, IBusProperty,
 };
 use ibus_sys::text::{IBusText, StringExt};
 use libakaza::config::{Config, DictConfig};
 
 use crate::input_mode::{get_all_input_modes, InputMode};
 
+/// init_props で作るプロパティ。(入力モードの親, 全体, 入力モードごと, シークレットモード, ユーザー辞書の親)
+type InitProps = (
+    *mut IBusProperty,
+    *mut IBusPropList,
+    HashMap<String, *mut IBusProperty>,
+    *mut IBusProperty,
+    *mut IBusProperty,
+);
+
 pub struct PropController {
     prop_list: *mut IBusPropList,
     /// input mode のメニューの親プロパティ。
     input_mode_prop: *mut IBusProperty,
     /// メニューの input mode ごとのメニュープロパティたち。
     prop_dict: HashMap<String, *mut IBusProperty>,
+    /// シークレットモードのトグル。
+    incognito_prop: *mut IBusProperty,
+    /// ユーザー辞書のメニューの親プロパティ。
+    user_dict_prop: *mut IBusProperty,
 }
 
 impl PropController {
     pub fn new(initial_input_mode: InputMode, config: Config) -> Result<Self> {
-        let (input_mode_prop, prop_list, prop_dict) = Self::init_props(initial_input_mode, config)?;
+        let (input_mode_prop, prop_list, prop_dict, incognito_prop, user_dict_prop) =
+            Self::init_props(initial_input_mode);
 
-        Ok(PropController {
+        let prop_controller = PropController {
             prop_list,
             input_mode_prop,
             prop_dict,
-        })
+            incognito_prop,
+            user_dict_prop,
+        };
+        prop_controller.set_user_dicts(&Self::find_user_dicts(&config)?);
+        Ok(prop_controller)
     }
 
     /// ibus の do_focus_in のときに呼ばれる。
@@ -47,14 +65,7 @@ impl PropController {
     /// タスクメニューからポップアップして選べるメニューを構築する。
     ///
     /// * `initial_input_mode`: 初期状態の input_mode
-    fn init_props(
-        initial_input_mode: InputMode,
-        config: Config,
-    ) -> Result<(
-        *mut IBusProperty,
-        *mut IBusPropList,
-        HashMap<String, *mut IBusProperty>,
-    )> {
+    fn init_props(initial_input_mode: InputMode) -> InitProps {
         unsafe {
             let prop_list =
                 g_object_ref_sink(ibus_prop_list_new() as gpointer) as *mut IBusPropList;
@@ -95,17 +106,43 @@ impl PropController {
             }
             ibus_property_set_sub_props(input_mode_prop, props);
 
+            // シークレットモード
+            let incognito_prop = Self::build_incognito(prop_list);
+
             // ユーザー辞書
-            Self::build_user_dict(prop_list, config)?;
+            let user_dict_prop = Self::build_user_dict(prop_list);
 
             // 設定ファイルを開くというやつ
             Self::build_preference_menu(prop_list);
 
-            Ok((input_mode_prop, prop_list, prop_map))
+            (
+                input_mode_prop,
+                prop_list,
+                prop_map,
+                incognito_prop,
+                user_dict_prop,
+            )
         }
     }
 
-    unsafe fn build_user_dict(prop_list: *mut IBusPropList, config: Config) -> Result<()> {
+    unsafe fn build_incognito(prop_list: *mut IBusPropList) -> *mut IBusProperty {
+        let incognito_prop = g_object_ref_sink(ibus_property_new(
+            "Incognito\0".as_ptr() as *const gchar,
+            IBusPropType_PROP_TYPE_TOGGLE,
+            "シークレットモード(学習しない)".to_ibus_text(),
+            "\0".as_ptr() as *const gchar,
+            "Incognito".to_ibus_text(),
+            to_gboolean(true),
+            to_gboolean(true),
+            IBusPropState_PROP_STATE_UNCHECKED,
+            std::ptr::null_mut() as *mut IBusPropList,
+        ) as gpointer) as *mut IBusProperty;
+        ibus_prop_list_append(prop_list, incognito_prop);
+        incognito_prop
+    }
+
+    /// ユーザー辞書のメニューの親プロパティを作る。辞書の一覧は set_user_dicts で設定する。
+    unsafe fn build_user_dict(prop_list: *mut IBusPropList) -> *mut IBusProperty {
         let user_dict_prop = g_object_ref_sink(ibus_property_new(
             "UserDict\0".as_ptr() as *const gchar,
             IBusPropType_PROP_TYPE_MENU,
@@ -118,32 +155,39 @@ impl PropController {
             std::ptr::null_mut() as *mut IBusPropList,
         ) as gpointer) as *mut IBusProperty;
         ibus_prop_list_append(prop_list, user_dict_prop);
+        user_dict_prop
+    }
 
-        let props = g_object_ref_sink(ibus_prop_list_new() as gpointer) as *mut IBusPropList;
-        for dict in Self::find_user_dicts(config)? {
-            let prop = g_object_ref_sink(ibus_property_new(
-                ("UserDict.".to_string() + dict.path.as_str() + "\0").as_ptr() as *const gchar,
-                IBusPropType_PROP_TYPE_MENU,
-                Path::new(&dict.path)
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_ibus_text(),
-                "\0".as_ptr() as *const gchar,
-                std::ptr::null_mut() as *mut IBusText,
-                to_gboolean(true),
-                to_gboolean(true),
-                IBusPropState_PROP_STATE_UNCHECKED,
-                std::ptr::null_mut() as *mut IBusPropList,
-            ) as gpointer) as *mut IBusProperty;
-            // prop_map.insert(input_mode.prop_name.to_string(), prop);
-            ibus_prop_list_append(props, prop);
+    /// ユーザー辞書のメニューの項目を、dicts で置きかえる。
+    /// 古い項目は、親プロパティから外されたときに解放される。
+    pub fn set_user_dicts(&self, dicts: &[DictConfig]) {
+        unsafe {
+            // 親プロパティだけが参照を持つように、ここでは参照を増やさない。
+            let props = ibus_prop_list_new();
+            for dict in dicts {
+                let prop = ibus_property_new(
+                    ("UserDict.".to_string() + dict.path.as_str() + "\0").as_ptr() as *const gchar,
+                    IBusPropType_PROP_TYPE_MENU,
+                    Path::new(&dict.path)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_ibus_text(),
+                    "\0".as_ptr() as *const gchar,
+                    std::ptr::null_mut() as *mut IBusText,
+                    to_gboolean(true),
+                    to_gboolean(true),
+                    IBusPropState_PROP_STATE_UNCHECKED,
+                    std::ptr::null_mut() as *mut IBusPropList,
+                );
+                ibus_prop_list_append(props, prop);
+            }
+            ibus_property_set_sub_props(self.user_dict_prop, props);
         }
-        ibus_property_set_sub_props(user_dict_prop, props);
-        Ok(())
     }
 
-    fn find_user_dicts(config: Config) -> anyhow::Result<Vec<DictConfig>> {
+    /// 設定された辞書のうち、ユーザー辞書のディレクトリにあるもの。
+    pub fn find_user_dicts(config: &Config) -> anyhow::Result<Vec<DictConfig>> {
         let dir = xdg::BaseDirectories::with_prefix("akaza")?;
         let dir = dir.create_data_directory("userdict")?;
         let dicts = config
@@ -172,6 +216,21 @@ impl PropController {
         ibus_prop_list_append(prop_list, preference_prop);
     }
 
+    /// シークレットモードの切り替え時に実行される処理
+    pub fn set_incognito(&self, incognito: bool, engine: *mut IBusEngine) {
+        unsafe {
+            ibus_property_set_state(
+                self.incognito_prop,
+                if incognito {
+                    IBusPropState_PROP_STATE_CHECKED
+                } else {
+                    IBusPropState_PROP_STATE_UNCHECKED
+                },
+            );
+            ibus_engine_update_property(engine, self.incognito_prop);
+        }
+    }
+
     /// input_mode の切り替え時に実行される処理
     pub fn set_input_mode(&self, input_mode: &InputMode, engine: *mut IBusEngine) {
         // メニューの親項目のラベルを変更したい。
@@ -34,6 +34,16 @@ pub(crate) type ibus_akaza_callback_property_activate = unsafe extern "C" fn(
 pub(crate) type ibus_akaza_callback_focus_in =
     unsafe extern "C" fn(context: *mut c_void, engine: *mut IBusEngine);
 
+pub(crate) type ibus_akaza_callback_focus_out =
+    unsafe extern "C" fn(context: *mut c_void, engine: *mut IBusEngine);
+
+pub(crate) type ibus_akaza_callback_set_content_type = unsafe extern "C" fn(
+    context: *mut c_void,
+    engine: *mut IBusEngine,
+    purpose: guint,
+    hints: guint,
+);
+
 extern "C" {
     /// is_ibus: true if the project run with `--ibus` option.
     pub fn ibus_akaza_init(is_ibus: bool);
@@ -43,6 +53,8 @@ extern "C" {
         key_event_cb: ibus_akaza_callback_key_event,
         candidate_cb: ibus_akaza_callback_candidate_clicked,
         focus_in_cb: ibus_akaza_callback_focus_in,
+        focus_out_cb: ibus_akaza_callback_focus_out,
         property_activate: ibus_akaza_callback_property_activate,
+        set_content_type: ibus_akaza_callback_set_content_type,
     );
 }
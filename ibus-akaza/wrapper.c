@@ -10,7 +10,9 @@ static void* global_context = NULL;
 static ibus_akaza_callback_key_event global_key_event_cb = NULL;
 static ibus_akaza_callback_candidate_clicked global_candidate_clicked_cb = NULL;
 static ibus_akaza_callback_focus_in global_focus_in_cb = NULL;
+static ibus_akaza_callback_focus_out global_focus_out_cb = NULL;
 static ibus_akaza_callback_property_activate global_property_activate_cb = NULL;
+static ibus_akaza_callback_set_content_type global_set_content_type_cb = NULL;
 
 #define IBUS_TYPE_AKAZA_ENGINE        \
         (ibus_akaza_engine_get_type ())
@@ -32,11 +34,22 @@ static gboolean ibus_akaza_engine_process_key_event(IBusEngine *engine,
 static void ibus_akaza_engine_focus_in(
     IBusEngine *engine
 );
+static void ibus_akaza_engine_focus_out(
+    IBusEngine *engine
+);
+static void ibus_akaza_engine_enable(
+    IBusEngine *engine
+);
 static void ibus_akaza_engine_property_activate(
     IBusEngine *engine,
     const gchar *prop_name,
     guint prop_state
 );
+static void ibus_akaza_engine_set_content_type(
+    IBusEngine *engine,
+    guint purpose,
+    guint hints
+);
 
 G_DEFINE_TYPE(IBusAkazaEngine, ibus_akaza_engine, IBUS_TYPE_ENGINE)
 
@@ -63,6 +76,19 @@ static void ibus_akaza_engine_focus_in(
    global_focus_in_cb(global_context, engine);
 }
 
+static void ibus_akaza_engine_focus_out(
+    IBusEngine *engine
+) {
+   global_focus_out_cb(global_context, engine);
+}
+
+static void ibus_akaza_engine_enable(
+    IBusEngine *engine
+) {
+  // surrounding text を利用することを input context に伝える。
+  ibus_engine_get_surrounding_text(engine, NULL, NULL, NULL);
+}
+
 static void ibus_akaza_engine_property_activate(
     IBusEngine *engine,
     const gchar *prop_name,
@@ -71,6 +97,14 @@ static void ibus_akaza_engine_property_activate(
    global_property_activate_cb(global_context, engine, prop_name, prop_state);
 }
 
+static void ibus_akaza_engine_set_content_type(
+    IBusEngine *engine,
+    guint purpose,
+    guint hints
+) {
+   global_set_content_type_cb(global_context, engine, purpose, hints);
+}
+
 static gboolean ibus_akaza_engine_process_key_event(IBusEngine *engine,
                                                       guint keyval,
                                                       guint keycode,
@@ -92,7 +126,10 @@ static void ibus_akaza_engine_class_init(IBusAkazaEngineClass *klass) {
   engine_class->process_key_event = ibus_akaza_engine_process_key_event;
   engine_class->candidate_clicked = ibus_akaza_engine_candidate_clicked;
   engine_class->focus_in = ibus_akaza_engine_focus_in;
+  engine_class->focus_out = ibus_akaza_engine_focus_out;
+  engine_class->enable = ibus_akaza_engine_enable;
   engine_class->property_activate = ibus_akaza_engine_property_activate;
+  engine_class->set_content_type = ibus_akaza_engine_set_content_type;
 }
 
 
@@ -101,13 +138,17 @@ void ibus_akaza_set_callback(
     ibus_akaza_callback_key_event* key_event_cb,
     ibus_akaza_callback_candidate_clicked* candidate_cb,
     ibus_akaza_callback_focus_in* focus_in_cb,
-    ibus_akaza_callback_property_activate* property_activate_cb
+    ibus_akaza_callback_focus_out* focus_out_cb,
+    ibus_akaza_callback_property_activate* property_activate_cb,
+    ibus_akaza_callback_set_content_type* set_content_type_cb
 ) {
     global_context = context;
     global_key_event_cb = key_event_cb;
     global_candidate_clicked_cb = candidate_cb;
     global_focus_in_cb = focus_in_cb;
+    global_focus_out_cb = focus_out_cb;
     global_property_activate_cb = property_activate_cb;
+    global_set_content_type_cb = set_content_type_cb;
 }
 
 void ibus_akaza_init(bool ibus) {
@@ -1,4 +1,4 @@
-use crate::glib::{gboolean, guint};
+use crate::glib::{gboolean, gint, guint};
 use crate::lookup_table::IBusLookupTable;
 use crate::prop_list::IBusPropList;
 use crate::property::IBusProperty;
@@ -32,9 +32,37 @@ extern "C" {
         visible: gboolean,
     );
 
+    #[doc = " ibus_engine_get_surrounding_text:\n @engine: An IBusEngine.\n @text: (out) (transfer none) (allow-none): Location to store surrounding text.\n @cursor_pos: (out) (allow-none): Cursor position in characters in @text.\n @anchor_pos: (out) (allow-none): Anchor position of selection in @text.\n\n Get surrounding text.\n\n It is also used to tell the input-context that the engine will\n utilize surrounding-text.  In that case, it must be called in\n #IBusEngine::enable handler, with both @text and @cursor set to\n %NULL."]
+    pub fn ibus_engine_get_surrounding_text(
+        engine: *mut IBusEngine,
+        text: *mut *mut IBusText,
+        cursor_pos: *mut guint,
+        anchor_pos: *mut guint,
+    );
+
+    #[doc = " ibus_engine_delete_surrounding_text:\n @engine: An IBusEngine.\n @offset: The offset of the first char.\n @nchars: Number of chars to be deleted.\n\n Delete surrounding text."]
+    pub fn ibus_engine_delete_surrounding_text(
+        engine: *mut IBusEngine,
+        offset: gint,
+        nchars: guint,
+    );
+
+    #[doc = " ibus_engine_forward_key_event:\n @engine: An IBusEngine.\n @keyval: KeySym.\n @keycode: keyboard scancode.\n @state: Key modifier flags.\n\n Forward the key event."]
+    pub fn ibus_engine_forward_key_event(
+        engine: *mut IBusEngine,
+        keyval: guint,
+        keycode: guint,
+        state: guint,
+    );
+
     pub fn ibus_engine_register_properties(engine: *mut IBusEngine, prop_list: *mut IBusPropList);
 
     pub fn ibus_engine_update_property(engine: *mut IBusEngine, prop: *mut IBusProperty);
 }
 
 pub type IBusEngine = [u64; 11usize];
+
+pub const IBusInputPurpose_IBUS_INPUT_PURPOSE_PASSWORD: IBusInputPurpose = 8;
+pub const IBusInputPurpose_IBUS_INPUT_PURPOSE_PIN: IBusInputPurpose = 9;
+
+pub type IBusInputPurpose = ::std::os::raw::c_uint;
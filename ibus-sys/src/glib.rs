@@ -1,6 +1,11 @@
 extern "C" {
     // This method retain the object's reference count.n
     pub fn g_object_ref_sink(object: gpointer) -> gpointer;
+
+    /// interval ミリ秒ごとに function を呼ぶ。function が FALSE を返すと止まる。
+    pub fn g_timeout_add(interval: guint, function: GSourceFunc, data: gpointer) -> guint;
+
+    pub fn g_source_remove(tag: guint) -> gboolean;
 }
 
 pub type gchar = ::std::os::raw::c_char;
@@ -10,6 +15,7 @@ pub type gsize = ::std::os::raw::c_ulong;
 pub type gssize = ::std::os::raw::c_long;
 pub type gint = ::std::os::raw::c_int;
 pub type gpointer = *mut ::std::os::raw::c_void;
+pub type GSourceFunc = Option<unsafe extern "C" fn(user_data: gpointer) -> gboolean>;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
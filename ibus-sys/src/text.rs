@@ -7,6 +7,8 @@ extern "C" {
     pub fn ibus_text_new_from_string(str_: *const gchar) -> *mut IBusText;
     #[doc = " ibus_text_set_attributes:\n @text: An IBusText.\n @attrs: An IBusAttrList"]
     pub fn ibus_text_set_attributes(text: *mut IBusText, attrs: *mut IBusAttrList);
+    #[doc = " ibus_text_get_text:\n @text: An IBusText.\n\n Return the text in IBusText.  Should not be freed.\n\n Returns: the text in IBusText."]
+    pub fn ibus_text_get_text(text: *mut IBusText) -> *const gchar;
 }
 
 pub type IBusText = [u64; 9usize];
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use libakaza::graph::graph_builder::GraphBuilder;
+use libakaza::graph::graph_resolver::GraphResolver;
+use libakaza::graph::segmenter::Segmenter;
+use libakaza::kana_kanji::hashmap_vec::HashmapVecKanaKanjiDict;
+use libakaza::kana_trie::cedarwood_kana_trie::CedarwoodKanaTrie;
+use libakaza::lm::base::SystemUnigramLM;
+use libakaza::lm::system_bigram::{MarisaSystemBigramLM, MarisaSystemBigramLMBuilder};
+use libakaza::lm::system_unigram_lm::{MarisaSystemUnigramLM, MarisaSystemUnigramLMBuilder};
+use libakaza::user_side_data::user_data::UserData;
+
+const SYLLABLES: [&str; 20] = [
+    "か", "き", "く", "け", "こ", "さ", "し", "す", "せ", "そ", "た", "ち", "つ", "て", "と", "な",
+    "に", "ぬ", "ね", "の",
+];
+
+/// 実際の辞書を使わずに、ラティスが十分に大きくなるような人工的な辞書と言語モデルを作る。
+/// 2 音節の単語は全ての組み合わせ、3 音節の単語は一部の組み合わせを登録する。
+struct Fixture {
+    segmenter: Segmenter,
+    graph_builder:
+        GraphBuilder<MarisaSystemUnigramLM, MarisaSystemBigramLM, HashmapVecKanaKanjiDict>,
+    resolver: GraphResolver,
+}
+
+impl Fixture {
+    fn new() -> anyhow::Result<Fixture> {
+        let mut words: Vec<String> = Vec::new();
+        for a in SYLLABLES {
+            for b in SYLLABLES {
+                words.push(a.to_string() + b);
+            }
+        }
+        for (i, a) in SYLLABLES.iter().enumerate() {
+            for (j, b) in SYLLABLES.iter().enumerate() {
+                for (k, c) in SYLLABLES.iter().enumerate() {
+                    if (i * 400 + j * 20 + k) % 7 == 0 {
+                        words.push(a.to_string() + b + c);
+                    }
+                }
+            }
+        }
+
+        let mut dict: HashMap<String, Vec<String>> = HashMap::new();
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        for (i, yomi) in words.iter().enumerate() {
+            let surfaces = vec![format!("語{i}"), format!("詞{i}")];
+            for (j, surface) in surfaces.iter().enumerate() {
+                let cost = 1_f32 + ((i * 7 + j * 3) % 17) as f32 * 0.3;
+                unigram_builder.add(&format!("{surface}/{yomi}"), cost);
+            }
+            dict.insert(yomi.to_string(), surfaces);
+        }
+        let system_unigram_lm = unigram_builder
+            .set_unique_words(words.len() as u32 * 2)
+            .set_total_words(words.len() as u32 * 20)
+            .build();
+
+        let mut bigram_builder = MarisaSystemBigramLMBuilder::default();
+        let word_ids = words
+            .iter()
+            .enumerate()
+            .filter_map(|(i, yomi)| system_unigram_lm.find(&format!("語{i}/{yomi}")))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for pair in word_ids.windows(2).step_by(3) {
+            bigram_builder.add(pair[0], pair[1], 2_f32);
+        }
+        let system_bigram_lm = bigram_builder.set_default_edge_cost(20_f32).build()?;
+
+        let kana_trie = CedarwoodKanaTrie::build(words);
+        Ok(Fixture {
+            segmenter: Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]),
+            graph_builder: GraphBuilder::new(
+                HashmapVecKanaKanjiDict::new(dict),
+                HashmapVecKanaKanjiDict::new(HashMap::new()),
+                Arc::new(Mutex::new(UserData::default())),
+                Arc::new(system_unigram_lm),
+                Arc::new(system_bigram_lm),
+            ),
+            resolver: GraphResolver::default(),
+        })
+    }
+
+    /// 音節を疑似乱数で並べて、len 文字の読みを作る。
+    fn yomi(len: usize) -> String {
+        let mut seed: u32 = 1;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                SYLLABLES[(seed >> 16) as usize % SYLLABLES.len()]
+            })
+            .collect()
+    }
+}
+
+fn bench_convert(c: &mut Criterion) {
+    let fixture = Fixture::new().unwrap();
+
+    let mut group = c.benchmark_group("convert");
+    for len in [16, 64, 256] {
+        let yomi = Fixture::yomi(len);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &yomi, |b, yomi| {
+            b.iter(|| {
+                let segmentation_result = fixture.segmenter.build(yomi, None);
+                let lattice = fixture.graph_builder.construct(yomi, &segmentation_result);
+                black_box(fixture.resolver.resolve(&lattice).unwrap())
+            })
+        });
+    }
+    group.finish();
+
+    // 構築済みのラティスに対する経路探索だけの時間
+    let mut group = c.benchmark_group("resolve");
+    for len in [16, 64, 256] {
+        let yomi = Fixture::yomi(len);
+        let segmentation_result = fixture.segmenter.build(&yomi, None);
+        let lattice = fixture.graph_builder.construct(&yomi, &segmentation_result);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &lattice, |b, lattice| {
+            b.iter(|| black_box(fixture.resolver.resolve(lattice).unwrap()))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("resolve_k_best");
+    for len in [16, 64] {
+        let yomi = Fixture::yomi(len);
+        let segmentation_result = fixture.segmenter.build(&yomi, None);
+        let lattice = fixture.graph_builder.construct(&yomi, &segmentation_result);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &lattice, |b, lattice| {
+            b.iter(|| black_box(fixture.resolver.resolve_k_best(lattice, 5).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_convert);
+criterion_main!(benches);
@@ -7,6 +7,7 @@ dicts:
  */
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::fs;
 use std::fs::File;
 use std::io::{BufReader, Write};
 use std::path::PathBuf;
@@ -23,12 +24,12 @@ use crate::resource::detect_resource_path;
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
 pub struct Config {
     /// ローマ字かな変換テーブルの指定
-    /// "default", "kana", etc.
+    /// "default", "kana", etc. ファイルのパスでもよい。
     #[serde(default = "default_romkan")]
     pub romkan: String,
 
     /// キーマップテーブルの指定
-    /// "default", "atok", etc.
+    /// "default", "atok", etc. ファイルのパスでもよい。
     #[serde(default = "default_keymap")]
     pub keymap: String,
 
@@ -38,14 +39,27 @@ pub struct Config {
     /// ライブ変換
     #[serde(default = "default_live_conversion")]
     pub live_conversion: bool,
+
+    /// ユーザーの学習データの設定
+    #[serde(default)]
+    pub user_data: UserDataConfig,
+
+    /// 親指シフトの設定。設定すると、かなの入力にローマ字かな変換のかわりに親指シフトを使う。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_shift: Option<ThumbShiftConfig>,
+
+    /// かな入力の配列の指定
+    /// "jis", etc. 設定すると、かなの入力でキーの位置(キーコード)からかなを入力する。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kana_layout: Option<String>,
 }
 
 fn default_romkan() -> String {
-    detect_resource_path("romkan", "default.yml").unwrap()
+    "default".to_string()
 }
 
 fn default_keymap() -> String {
-    detect_resource_path("keymap", "default.yml").unwrap()
+    "default".to_string()
 }
 
 fn default_engine_config() -> EngineConfig {
@@ -96,8 +110,11 @@ impl Config {
         let file_name = Self::file_name()?;
         let yml = serde_yaml::to_string(self)?;
         info!("Write to file: {}", file_name.to_str().unwrap());
-        let mut fp = File::create(file_name)?;
+        // ibus-akaza が書きかけのファイルを読まないように、一時ファイルに書いてから rename する。
+        let tmp_file_name = file_name.with_extension("yml.tmp");
+        let mut fp = File::create(&tmp_file_name)?;
         fp.write_all(yml.as_bytes())?;
+        fs::rename(tmp_file_name, file_name)?;
         Ok(())
     }
 
@@ -147,6 +164,55 @@ fn default_model() -> String {
     detect_resource_path("model", "default").unwrap()
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct UserDataConfig {
+    /// unigram, bigram それぞれの統計に保持する最大のエントリー数。
+    /// 超えた場合は、最後に使ってから最も時間の経ったものから、最大のエントリー数の 9 割まで捨てる。
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+
+    /// 学習した重みが半分になるまでの日数。0 の場合は減衰させない。
+    #[serde(default = "default_half_life_days")]
+    pub half_life_days: u32,
+}
+
+impl Default for UserDataConfig {
+    fn default() -> Self {
+        UserDataConfig {
+            max_entries: default_max_entries(),
+            half_life_days: default_half_life_days(),
+        }
+    }
+}
+
+fn default_max_entries() -> usize {
+    100_000
+}
+
+fn default_half_life_days() -> u32 {
+    90
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ThumbShiftConfig {
+    /// 親指シフトのテーブルの指定
+    /// "nicola", etc. ファイルのパスでもよい。
+    #[serde(default = "default_thumb_shift_table")]
+    pub table: String,
+
+    /// 文字キーと親指キーを、同時に押したとみなす時間(ミリ秒)
+    #[serde(default = "default_thumb_shift_overlap_ms")]
+    pub overlap_ms: u64,
+}
+
+fn default_thumb_shift_table() -> String {
+    "nicola".to_string()
+}
+
+fn default_thumb_shift_overlap_ms() -> u64 {
+    100
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
 pub struct DictConfig {
     #[serde(default = "default_path")]
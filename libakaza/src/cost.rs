@@ -14,3 +14,111 @@ pub fn calc_cost(count: u32, total_words: u32, unique_words: u32) -> f32 {
             ((total_words as f32) + ALPHA + (unique_words as f32)),
     )
 }
+
+/// 最後に使ってからの経過時間に応じて、コストに加算する値を計算する。
+/// half_life ごとに確率が半分になるように減衰させる。
+///
+/// - `elapsed_secs`: 最後に使ってからの経過秒数
+/// - `half_life_secs`: 半減期の秒数。0 の場合は減衰させない。
+///
+/// 経過時間に上限はないので、使う側でシステムのコストを上限にすること。
+pub fn calc_decay_cost(elapsed_secs: u64, half_life_secs: u64) -> f32 {
+    if half_life_secs == 0 {
+        return 0_f32;
+    }
+    (elapsed_secs as f32) / (half_life_secs as f32) * f32::log10(2.0)
+}
+
+/// modified Kneser-Ney の割引係数 D1, D2, D3+ を、出現回数の度数から推定する。
+/// Y = n1 / (n1 + 2 * n2), Dk = k - (k + 1) * Y * n(k+1) / nk
+///
+/// - `n`: ちょうど 1, 2, 3, 4 回出現した n-gram の種類数
+pub fn modified_kneser_ney_discounts(n: [u32; 4]) -> [f32; 3] {
+    if n[0] == 0 {
+        // 度数が偏っていて推定できない場合は、よく使われる値にしておく。
+        return [0.75; 3];
+    }
+    let n = n.map(|it| it as f32);
+    let y = n[0] / (n[0] + 2.0 * n[1]);
+    if n.contains(&0.0) {
+        // D2, D3+ を推定できない場合は、絶対値割引の D = Y をすべての度数に使う。
+        return [y; 3];
+    }
+    [1.0, 2.0, 3.0].map(|k: f32| {
+        let i = k as usize - 1;
+        (k - (k + 1.0) * y * n[i + 1] / n[i]).clamp(0.0, k)
+    })
+}
+
+/// 出現回数 cnt の n-gram から差し引く割引係数を得る。
+pub fn kneser_ney_discount(discounts: &[f32; 3], cnt: u32) -> f32 {
+    match cnt {
+        0 => 0.0,
+        1 => discounts[0],
+        2 => discounts[1],
+        _ => discounts[2],
+    }
+}
+
+/// 文脈のバックオフコストを計算する。
+///
+/// 割引で余った確率質量を、上位のモデルにない後続単語へ、下位のモデルの確率に比例して配分する。
+/// bigram の下位のモデルには、Kneser-Ney と同じく、継続回数から求めた確率を使う。
+///
+/// bigram では、未知の bigram (w1, w2) のコストは `backoff(w1) + continuation(w2)` として計算される。
+/// 既知の bigram のコストは (w1, w2) の同時確率なので、それにあわせて
+/// P(w1, w2) ≒ P(w1) * α(w1) * P_cont(w2) となるように、context_cost に w1 の unigram コストを渡す。
+/// trigram では、未知の trigram のコストは `backoff(w1, w2) + bigram のエッジコスト` で、context_cost は 0。
+///
+/// - `context_cost`: バックオフコストに含める、文脈そのもののコスト
+/// - `left_mass`: 割引によって文脈の後続として余った確率質量
+/// - `followers_prob`: 文脈の後続として上位のモデルに登録された単語の、下位のモデルでの確率の和
+pub fn calc_backoff_cost(context_cost: f32, left_mass: f32, followers_prob: f32) -> f32 {
+    // 余った確率質量を、上位のモデルに登録されていない後続単語に、下位のモデルの確率に応じて配分する。
+    let alpha = left_mass.max(ALPHA) / (1.0 - followers_prob).max(ALPHA);
+    context_cost - f32::log10(alpha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modified_kneser_ney_discounts() {
+        // Y = 0.5, D1 = 1 - 2 * 0.5 * 5 / 10, D2 = 2 - 3 * 0.5 * 2 / 5, D3+ = 3 - 4 * 0.5 * 1 / 2
+        assert_eq!(
+            modified_kneser_ney_discounts([10, 5, 2, 1]),
+            [0.5, 1.4, 2.0]
+        );
+        assert_eq!(modified_kneser_ney_discounts([10, 5, 0, 0]), [0.5; 3]);
+        assert_eq!(modified_kneser_ney_discounts([0, 5, 2, 1]), [0.75; 3]);
+    }
+
+    #[test]
+    fn test_kneser_ney_discount() {
+        let discounts = [0.5, 1.4, 2.0];
+        assert_eq!(kneser_ney_discount(&discounts, 1), 0.5);
+        assert_eq!(kneser_ney_discount(&discounts, 2), 1.4);
+        assert_eq!(kneser_ney_discount(&discounts, 3), 2.0);
+        assert_eq!(kneser_ney_discount(&discounts, 100), 2.0);
+    }
+
+    #[test]
+    fn test_calc_decay_cost() {
+        assert_eq!(calc_decay_cost(0, 100), 0.0);
+        assert_eq!(calc_decay_cost(100, 0), 0.0);
+        // 半減期が経過すると、確率が半分になる。
+        assert_eq!(calc_decay_cost(100, 100), f32::log10(2.0));
+        assert!(calc_decay_cost(200, 100) > calc_decay_cost(100, 100));
+    }
+
+    #[test]
+    fn test_calc_backoff_cost() {
+        // 余った確率質量がそのまま配分される場合は、unigram コストに一致する。
+        assert_eq!(calc_backoff_cost(2.0, 1.0, 0.0), 2.0);
+        // 余った確率質量が少ないほど、コストは高くなる。
+        assert!(calc_backoff_cost(2.0, 0.1, 0.5) > calc_backoff_cost(2.0, 0.4, 0.5));
+        // 後続単語の unigram 確率の和が大きいほど、残りの単語への配分は大きくなる。
+        assert!(calc_backoff_cost(2.0, 0.1, 0.9) < calc_backoff_cost(2.0, 0.1, 0.5));
+    }
+}
@@ -9,11 +9,23 @@ use crate::dict::merge_dict::merge_dict;
 pub fn write_skk_dict(
     ofname: &str,
     dicts: Vec<HashMap<String, Vec<String>>>,
+) -> anyhow::Result<()> {
+    write_skk_dict_with_comments(ofname, &[], dicts)
+}
+
+/// 先頭に ";; " で始まるコメント行を書いてから、辞書を書く。
+pub fn write_skk_dict_with_comments(
+    ofname: &str,
+    comments: &[String],
+    dicts: Vec<HashMap<String, Vec<String>>>,
 ) -> anyhow::Result<()> {
     info!("Writing {}", ofname);
     let merged_dict = merge_dict(dicts);
     {
         let mut wfp = File::create(ofname)?;
+        for comment in comments {
+            wfp.write_fmt(format_args!(";; {comment}\n"))?;
+        }
         wfp.write_all(";; okuri-ari entries.\n".as_bytes())?;
         wfp.write_all(";; okuri-nasi entries.\n".as_bytes())?;
         let mut keys = merged_dict.keys().collect::<Vec<_>>();
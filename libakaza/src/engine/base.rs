@@ -1,13 +1,40 @@
 use std::ops::Range;
 
 use crate::graph::candidate::Candidate;
+use crate::graph::graph_resolver::KBestPath;
 
 pub trait HenkanEngine {
-    fn learn(&mut self, candidates: &[Candidate]);
+    fn learn(&self, candidates: &[Candidate]);
 
     fn convert(
         &self,
         yomi: &str,
         force_ranges: Option<&[Range<usize>]>,
     ) -> anyhow::Result<Vec<Vec<Candidate>>>;
+
+    /// 変換対象の左側にある単語列(直前に確定した単語など)を考慮して変換する。
+    /// 文頭の単語のコストは、left_context の末尾の単語からの bigram で計算される。
+    fn convert_with_context(
+        &self,
+        left_context: &[Candidate],
+        yomi: &str,
+        force_ranges: Option<&[Range<usize>]>,
+    ) -> anyhow::Result<Vec<Vec<Candidate>>>;
+
+    /// 文全体の変換結果を、コストの小さい順に最大 k 個返す。
+    fn convert_k_best(
+        &self,
+        yomi: &str,
+        force_ranges: Option<&[Range<usize>]>,
+        k: usize,
+    ) -> anyhow::Result<Vec<KBestPath>>;
+
+    /// カーソルの直前にある確定済みの文字列を、convert_with_context に渡す左側の文脈にする。
+    fn left_context_from_text(&self, text: &str) -> anyhow::Result<Vec<Candidate>>;
+
+    /// 確定済みの文字列から読みを推定する。再変換で使う。
+    fn reverse_lookup(&self, surface: &str) -> anyhow::Result<String>;
+
+    /// 入力途中の読みから、読みが前方一致する単語を予測する。コストの小さい順に、最大 limit 件返す。
+    fn predict(&self, yomi_prefix: &str, limit: usize) -> anyhow::Result<Vec<Candidate>>;
 }
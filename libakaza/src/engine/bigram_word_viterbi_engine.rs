@@ -1,6 +1,6 @@
 use std::fmt::{Debug, Formatter};
 use std::ops::Range;
-use std::rc::Rc;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -8,9 +8,10 @@ use anyhow::Result;
 use crate::config::{DictConfig, DictEncoding, DictType, DictUsage, EngineConfig};
 use crate::dict::loader::{load_dicts, load_dicts_with_cache};
 use crate::engine::base::HenkanEngine;
+use crate::engine::incremental_session::{IncrementalSession, SessionState};
 use crate::graph::candidate::Candidate;
 use crate::graph::graph_builder::GraphBuilder;
-use crate::graph::graph_resolver::GraphResolver;
+use crate::graph::graph_resolver::{GraphResolver, KBestPath};
 use crate::graph::lattice_graph::LatticeGraph;
 use crate::graph::segmenter::Segmenter;
 use crate::kana_kanji::base::KanaKanjiDict;
@@ -18,6 +19,7 @@ use crate::kana_kanji::marisa_kana_kanji_dict::MarisaKanaKanjiDict;
 use crate::kana_trie::cedarwood_kana_trie::CedarwoodKanaTrie;
 use crate::lm::base::{SystemBigramLM, SystemUnigramLM};
 use crate::lm::system_bigram::MarisaSystemBigramLM;
+use crate::lm::system_trigram::MarisaSystemTrigramLM;
 use crate::lm::system_unigram_lm::MarisaSystemUnigramLM;
 use crate::user_side_data::user_data::UserData;
 
@@ -41,7 +43,7 @@ impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> Debug
 impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> HenkanEngine
     for BigramWordViterbiEngine<U, B, KD>
 {
-    fn learn(&mut self, candidates: &[Candidate]) {
+    fn learn(&self, candidates: &[Candidate]) {
         self.user_data.lock().unwrap().record_entries(candidates);
     }
 
@@ -53,6 +55,38 @@ impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> HenkanEngine
         let lattice = self.to_lattice(yomi, force_ranges)?;
         self.resolve(&lattice)
     }
+
+    fn convert_with_context(
+        &self,
+        left_context: &[Candidate],
+        yomi: &str,
+        force_ranges: Option<&[Range<usize>]>,
+    ) -> Result<Vec<Vec<Candidate>>> {
+        let lattice = self.to_lattice_with_context(left_context, yomi, force_ranges)?;
+        self.resolve(&lattice)
+    }
+
+    fn convert_k_best(
+        &self,
+        yomi: &str,
+        force_ranges: Option<&[Range<usize>]>,
+        k: usize,
+    ) -> Result<Vec<KBestPath>> {
+        let lattice = self.to_lattice(yomi, force_ranges)?;
+        self.graph_resolver.resolve_k_best(&lattice, k)
+    }
+
+    fn left_context_from_text(&self, text: &str) -> Result<Vec<Candidate>> {
+        Ok(self.graph_builder.left_context_from_text(text))
+    }
+
+    fn reverse_lookup(&self, surface: &str) -> Result<String> {
+        Ok(self.graph_builder.reverse_lookup(surface))
+    }
+
+    fn predict(&self, yomi_prefix: &str, limit: usize) -> Result<Vec<Candidate>> {
+        Ok(self.graph_builder.predict(yomi_prefix, limit))
+    }
 }
 
 impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> BigramWordViterbiEngine<U, B, KD> {
@@ -64,11 +98,67 @@ impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> BigramWordViterbi
         &self,
         yomi: &str,
         force_ranges: Option<&[Range<usize>]>,
+    ) -> Result<LatticeGraph<U, B>> {
+        self.to_lattice_with_context(&[], yomi, force_ranges)
+    }
+
+    pub fn to_lattice_with_context(
+        &self,
+        left_context: &[Candidate],
+        yomi: &str,
+        force_ranges: Option<&[Range<usize>]>,
     ) -> Result<LatticeGraph<U, B>> {
         let segmentation_result = &self.segmenter.build(yomi, force_ranges);
-        let lattice = self.graph_builder.construct(yomi, segmentation_result);
+        let lattice =
+            self.graph_builder
+                .construct_with_context(left_context, yomi, segmentation_result);
         Ok(lattice)
     }
+
+    /// 前回の変換結果を session に保持しておき、読みの変化していない部分のラティスと
+    /// ビタビの計算結果を再利用して変換する。ライブコンバージョンで、一文字入力するたびに
+    /// 変換する場合に使う。文節の区切りを強制する場合は convert_with_context を使うこと。
+    pub fn convert_incremental(
+        &self,
+        session: &mut IncrementalSession<U, B>,
+        left_context: &[Candidate],
+        yomi: &str,
+    ) -> Result<Vec<Vec<Candidate>>> {
+        let segmentation_result = self.segmenter.build(yomi, None);
+        let prev = session
+            .state
+            .take()
+            .filter(|prev| prev.left_context == left_context);
+
+        let (lattice, viterbi_table) = if let Some(prev) = prev {
+            let (lattice, stable_until) = self.graph_builder.construct_incremental(
+                &prev.lattice,
+                &prev.segmentation_result,
+                yomi,
+                &segmentation_result,
+            );
+            let viterbi_table =
+                GraphResolver::forward_incremental(&lattice, &prev.viterbi_table, stable_until)?;
+            (lattice, viterbi_table)
+        } else {
+            let lattice =
+                self.graph_builder
+                    .construct_with_context(left_context, yomi, &segmentation_result);
+            let viterbi_table = GraphResolver::forward(&lattice)?;
+            (lattice, viterbi_table)
+        };
+
+        let clauses = self
+            .graph_resolver
+            .resolve_with_table(&lattice, &viterbi_table)?;
+        session.state = Some(SessionState {
+            left_context: left_context.to_vec(),
+            segmentation_result,
+            lattice,
+            viterbi_table,
+        });
+        Ok(clauses)
+    }
 }
 
 pub struct BigramWordViterbiEngineBuilder {
@@ -108,6 +198,40 @@ impl BigramWordViterbiEngineBuilder {
             Arc::new(Mutex::new(UserData::default()))
         };
 
+        let (dict, single_term) = self.load_dicts(&system_dict)?;
+        let segmenter = Self::build_segmenter(&dict, &single_term, &user_data);
+
+        let mut graph_builder: GraphBuilder<
+            MarisaSystemUnigramLM,
+            MarisaSystemBigramLM,
+            MarisaKanaKanjiDict,
+        > = GraphBuilder::new(
+            dict,
+            single_term,
+            user_data.clone(),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+
+        // trigram.model は任意。なければ bigram までで変換する。
+        let trigram_path = Self::try_load(&model_name, "trigram.model")?;
+        if Path::new(&trigram_path).exists() {
+            graph_builder.set_system_trigram_lm(Arc::new(MarisaSystemTrigramLM::load(
+                trigram_path.as_str(),
+            )?));
+        }
+
+        let graph_resolver = GraphResolver::default();
+
+        Ok(BigramWordViterbiEngine {
+            graph_builder,
+            segmenter,
+            graph_resolver,
+            user_data,
+        })
+    }
+
+    fn load_dicts(&self, system_dict: &str) -> Result<(MarisaKanaKanjiDict, MarisaKanaKanjiDict)> {
         let dict = {
             let mut dicts = self
                 .config
@@ -117,7 +241,7 @@ impl BigramWordViterbiEngineBuilder {
                 .cloned()
                 .collect::<Vec<_>>();
             dicts.push(DictConfig {
-                path: system_dict,
+                path: system_dict.to_string(),
                 dict_type: DictType::SKK,
                 encoding: DictEncoding::Utf8,
                 usage: DictUsage::Normal,
@@ -147,6 +271,14 @@ impl BigramWordViterbiEngineBuilder {
             }
         };
 
+        Ok((dict, single_term))
+    }
+
+    fn build_segmenter(
+        dict: &MarisaKanaKanjiDict,
+        single_term: &MarisaKanaKanjiDict,
+        user_data: &Arc<Mutex<UserData>>,
+    ) -> Segmenter {
         // 辞書を元に、トライを作成していく。
         let mut kana_trie = CedarwoodKanaTrie::default();
         for yomi in dict.yomis() {
@@ -158,34 +290,42 @@ impl BigramWordViterbiEngineBuilder {
             kana_trie.update(yomi.as_str());
         }
 
-        let segmenter = Segmenter::new(vec![
+        Segmenter::new(vec![
             Arc::new(Mutex::new(kana_trie)),
             user_data.lock().unwrap().kana_trie.clone(),
-        ]);
-
-        let graph_builder: GraphBuilder<
-            MarisaSystemUnigramLM,
-            MarisaSystemBigramLM,
-            MarisaKanaKanjiDict,
-        > = GraphBuilder::new(
-            dict,
-            single_term,
-            user_data.clone(),
-            Rc::new(system_unigram_lm),
-            Rc::new(system_bigram_lm),
-        );
-
-        let graph_resolver = GraphResolver::default();
-
-        Ok(BigramWordViterbiEngine {
-            graph_builder,
-            segmenter,
-            graph_resolver,
-            user_data,
-        })
+        ])
     }
 
     fn try_load(model_dir: &str, name: &str) -> Result<String> {
         Ok(model_dir.to_string() + "/" + name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lm::on_memory::on_memory_system_bigram_lm::OnMemorySystemBigramLM;
+    use crate::lm::on_memory::on_memory_system_unigram_lm::OnMemorySystemUnigramLM;
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    /// ワーカースレッドで変換したり、サーバーで複数の接続から共有したりできること。
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<
+            BigramWordViterbiEngine<
+                MarisaSystemUnigramLM,
+                MarisaSystemBigramLM,
+                MarisaKanaKanjiDict,
+            >,
+        >();
+        assert_send_sync::<
+            BigramWordViterbiEngine<
+                OnMemorySystemUnigramLM,
+                OnMemorySystemBigramLM,
+                MarisaKanaKanjiDict,
+            >,
+        >();
+    }
+}
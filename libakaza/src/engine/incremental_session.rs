@@ -0,0 +1,43 @@
+use std::fmt::{Debug, Formatter};
+
+use crate::graph::candidate::Candidate;
+use crate::graph::graph_resolver::ViterbiTable;
+use crate::graph::lattice_graph::LatticeGraph;
+use crate::graph::segmenter::SegmentationResult;
+use crate::lm::base::{SystemBigramLM, SystemUnigramLM};
+
+/// ライブコンバージョンのように、一文字入力するたびに変換する場合のための変換セッション。
+/// 前回の変換の途中結果を保持しておき、次の変換では読みの変化した部分だけを計算しなおす。
+pub struct IncrementalSession<U: SystemUnigramLM, B: SystemBigramLM> {
+    pub(crate) state: Option<SessionState<U, B>>,
+}
+
+pub(crate) struct SessionState<U: SystemUnigramLM, B: SystemBigramLM> {
+    pub(crate) left_context: Vec<Candidate>,
+    pub(crate) segmentation_result: SegmentationResult,
+    pub(crate) lattice: LatticeGraph<U, B>,
+    pub(crate) viterbi_table: ViterbiTable,
+}
+
+impl<U: SystemUnigramLM, B: SystemBigramLM> Default for IncrementalSession<U, B> {
+    fn default() -> Self {
+        IncrementalSession { state: None }
+    }
+}
+
+impl<U: SystemUnigramLM, B: SystemBigramLM> Debug for IncrementalSession<U, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.state {
+            Some(state) => write!(f, "IncrementalSession(yomi={})", state.lattice.yomi),
+            None => write!(f, "IncrementalSession(empty)"),
+        }
+    }
+}
+
+impl<U: SystemUnigramLM, B: SystemBigramLM> IncrementalSession<U, B> {
+    /// 保持している途中結果を捨てる。
+    /// 学習してユーザーデータが変わった場合など、前回の計算結果が使えなくなったときに呼ぶ。
+    pub fn clear(&mut self) {
+        self.state = None;
+    }
+}
@@ -1,2 +1,3 @@
 pub mod base;
 pub mod bigram_word_viterbi_engine;
+pub mod incremental_session;
@@ -1,26 +1,31 @@
 use std::collections::btree_map::BTreeMap;
-use std::collections::HashSet;
-use std::rc::Rc;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
 use kelp::{hira2kata, ConvOption};
 use log::trace;
 use regex::Regex;
 
+use crate::graph::candidate::Candidate;
 use crate::graph::lattice_graph::LatticeGraph;
 use crate::graph::segmenter::SegmentationResult;
 use crate::graph::word_node::WordNode;
 use crate::kana_kanji::base::KanaKanjiDict;
-use crate::lm::base::{SystemBigramLM, SystemUnigramLM};
+use crate::lm::base::{SystemBigramLM, SystemTrigramLM, SystemUnigramLM};
+use crate::reverse_dict::{reverse_lookup, ReverseDict};
 use crate::user_side_data::user_data::UserData;
 
 pub struct GraphBuilder<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> {
     system_kana_kanji_dict: KD,
     system_single_term_dict: KD,
     user_data: Arc<Mutex<UserData>>,
-    system_unigram_lm: Rc<U>,
-    system_bigram_lm: Rc<B>,
+    system_unigram_lm: Arc<U>,
+    system_bigram_lm: Arc<B>,
+    system_trigram_lm: Option<Arc<dyn SystemTrigramLM + Send + Sync>>,
     number_pattern: Regex,
+    /// システム辞書と言語モデルから作る逆引き辞書。
+    /// 構築に時間がかかるので、キー入力の処理中ではなく、エンジンを作るときに作っておく。
+    system_reverse_dict: ReverseDict,
 }
 
 impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> GraphBuilder<U, B, KD> {
@@ -28,21 +33,158 @@ impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> GraphBuilder<U, B
         system_kana_kanji_dict: KD,
         system_single_term_dict: KD,
         user_data: Arc<Mutex<UserData>>,
-        system_unigram_lm: Rc<U>,
-        system_bigram_lm: Rc<B>,
+        system_unigram_lm: Arc<U>,
+        system_bigram_lm: Arc<B>,
     ) -> GraphBuilder<U, B, KD> {
         let number_pattern = Regex::new(r#"^[0-9]+"#).unwrap();
+        let system_reverse_dict =
+            Self::build_system_reverse_dict(&system_kana_kanji_dict, system_unigram_lm.as_ref());
         GraphBuilder {
             system_kana_kanji_dict,
             system_single_term_dict,
             user_data,
             system_unigram_lm,
             system_bigram_lm,
+            system_trigram_lm: None,
             number_pattern,
+            system_reverse_dict,
         }
     }
 
+    /// trigram 言語モデルを設定する。設定しない場合は bigram までで変換する。
+    pub fn set_system_trigram_lm(
+        &mut self,
+        system_trigram_lm: Arc<dyn SystemTrigramLM + Send + Sync>,
+    ) {
+        self.system_trigram_lm = Some(system_trigram_lm);
+    }
+
+    /// 入力途中の読みから、読みが前方一致する単語を予測して、コストの小さい順に最大 limit 件返す。
+    /// システム辞書、ユーザー辞書、ユーザーの unigram 統計から候補を探す。
+    /// 前方一致するエントリーはすべて調べて、コストの小さいものだけを残しながら走査する。
+    pub fn predict(&self, yomi_prefix: &str, limit: usize) -> Vec<Candidate> {
+        let user_data = self.user_data.lock().unwrap();
+
+        let mut top = TopCandidates::new(limit);
+        let mut add = |yomi: &str, surface: &str| {
+            // 読みが完全に一致しているものは、通常の変換で出てくるので除外する。
+            if yomi.len() <= yomi_prefix.len() || surface.is_empty() {
+                return;
+            }
+            let node = WordNode::new(
+                0,
+                surface,
+                yomi,
+                self.system_unigram_lm
+                    .find((surface.to_string() + "/" + yomi).as_str()),
+                false,
+            );
+            let system_cost = if let Some((_, system_cost)) = node.word_id_and_score {
+                system_cost
+            } else {
+                self.system_unigram_lm.get_cost(0)
+            };
+            // 減衰したユーザーのコストは、システムのコストで頭打ちにする。
+            let cost = user_data
+                .get_unigram_cost(&node)
+                .map_or(system_cost, |user_cost| user_cost.min(system_cost));
+            top.push(Candidate::new(yomi, surface, cost));
+        };
+
+        self.system_kana_kanji_dict
+            .predict(yomi_prefix, &mut |yomi, surfaces| {
+                for surface in surfaces {
+                    add(yomi, &surface);
+                }
+            });
+        for (yomi, surface) in user_data.predict(yomi_prefix) {
+            add(&yomi, &surface);
+        }
+        top.into_sorted_vec()
+    }
+
+    /// 確定済みの文字列から読みを推定する。再変換で使う。
+    /// ユーザー辞書とユーザーの unigram 統計にある読みを、システムのものより優先する。
+    pub fn reverse_lookup(&self, surface: &str) -> String {
+        let user_reverse_dict = self.user_data.lock().unwrap().reverse_dict();
+        reverse_lookup(&[&user_reverse_dict, &self.system_reverse_dict], surface)
+    }
+
+    /// カーソルの直前にある文字列の末尾から、言語モデルにある単語を最大二つ切り出して、
+    /// 変換の左側の文脈にする。末尾からなるべく長い単語を取り、言語モデルにない文字で打ち切る。
+    pub fn left_context_from_text(&self, text: &str) -> Vec<Candidate> {
+        let user_reverse_dict = self.user_data.lock().unwrap().reverse_dict();
+        let dicts = [user_reverse_dict.as_ref(), &self.system_reverse_dict];
+        let max_surface_len = dicts
+            .iter()
+            .map(|dict| dict.max_surface_len())
+            .max()
+            .unwrap_or(0);
+
+        let chars: Vec<char> = text.chars().collect();
+        let mut end = chars.len();
+        let mut result: Vec<Candidate> = Vec::new();
+        while result.len() < 2 {
+            let found = (end.saturating_sub(max_surface_len)..end).find_map(|start| {
+                let surface: String = chars[start..end].iter().collect();
+                dicts
+                    .iter()
+                    .filter_map(|dict| dict.get(&surface))
+                    .find(|yomi| {
+                        self.system_unigram_lm
+                            .find(&(surface.to_string() + "/" + yomi))
+                            .is_some()
+                    })
+                    .map(|yomi| (start, Candidate::new(yomi, &surface, 0_f32)))
+            });
+            let Some((start, candidate)) = found else {
+                break;
+            };
+            result.insert(0, candidate);
+            end = start;
+        }
+        result
+    }
+
+    fn build_system_reverse_dict(
+        system_kana_kanji_dict: &KD,
+        system_unigram_lm: &U,
+    ) -> ReverseDict {
+        let mut reverse_dict = ReverseDict::default();
+
+        // 同じ表層に複数の読みがある場合は、unigram コストの小さい読みを優先する。
+        let mut words = system_unigram_lm
+            .as_hash_map()
+            .into_iter()
+            .map(|(key, (_, cost))| (key, cost))
+            .collect::<Vec<_>>();
+        words.sort_by(|a, b| a.1.total_cmp(&b.1));
+        for (key, _) in words {
+            if let Some((surface, yomi)) = key.split_once('/') {
+                reverse_dict.add(surface, yomi);
+            }
+        }
+
+        system_kana_kanji_dict.predict("", &mut |yomi, surfaces| {
+            for surface in surfaces {
+                reverse_dict.add(&surface, yomi);
+            }
+        });
+        reverse_dict
+    }
+
     pub fn construct(&self, yomi: &str, words_ends_at: &SegmentationResult) -> LatticeGraph<U, B> {
+        self.construct_with_context(&[], yomi, words_ends_at)
+    }
+
+    /// left_context に、変換対象の左側にある単語列を渡すと、文頭の単語のコストを
+    /// それらの単語からの bigram/trigram で計算するラティスを構築する。
+    pub fn construct_with_context(
+        &self,
+        left_context: &[Candidate],
+        yomi: &str,
+        words_ends_at: &SegmentationResult,
+    ) -> LatticeGraph<U, B> {
         // このグラフのインデクスは単語の終了位置。
         let mut graph: BTreeMap<i32, Vec<WordNode>> = BTreeMap::new();
         graph.insert(0, vec![WordNode::create_bos()]);
@@ -52,108 +194,279 @@ impl<U: SystemUnigramLM, B: SystemBigramLM, KD: KanaKanjiDict> GraphBuilder<U, B
         );
 
         for (end_pos, segmented_yomis) in words_ends_at.iter() {
+            let vec = graph.entry(*end_pos as i32).or_default();
             for segmented_yomi in segmented_yomis {
-                let vec = graph.entry(*end_pos as i32).or_default();
-
-                let mut seen: HashSet<String> = HashSet::new();
-
-                // TODO このへんコピペすぎるので整理必要。
-                // システム辞書にある候補を元に候補をリストアップする
-                if let Some(kanjis) = self.system_kana_kanji_dict.get(segmented_yomi) {
-                    for kanji in kanjis {
-                        let node = WordNode::new(
-                            (end_pos - segmented_yomi.len()) as i32,
-                            &kanji,
-                            segmented_yomi,
-                            self.system_unigram_lm
-                                .find((kanji.to_string() + "/" + segmented_yomi).as_str()),
-                            false,
-                        );
-                        trace!("WordIDScore: {:?}", node.word_id_and_score);
-                        vec.push(node);
-                        seen.insert(kanji.to_string());
-                    }
-                }
-                if let Some(surfaces) = self.user_data.lock().unwrap().dict.get(segmented_yomi) {
-                    for surface in surfaces {
-                        if seen.contains(surface) {
-                            continue;
-                        }
-                        let node = WordNode::new(
-                            (end_pos - segmented_yomi.len()) as i32,
-                            surface,
-                            segmented_yomi,
-                            self.system_unigram_lm
-                                .find((surface.to_string() + "/" + segmented_yomi).as_str()),
-                            false,
-                        );
-                        trace!("WordIDScore: {:?}", node.word_id_and_score);
-                        vec.push(node);
-                        seen.insert(surface.to_string());
-                    }
+                self.push_nodes(vec, yomi, *end_pos, segmented_yomi);
+            }
+        }
+        LatticeGraph::new(
+            yomi,
+            graph,
+            self.user_data.clone(),
+            self.system_unigram_lm.clone(),
+            self.system_bigram_lm.clone(),
+            self.system_trigram_lm.clone(),
+            self.build_left_context(left_context),
+            None,
+        )
+    }
+
+    /// 前回のラティスのうち、読みの変化していない部分で終わるノードを再利用してラティスを構築する。
+    /// 読みの末尾に文字を追加したり削除したりした場合に、変化した部分のノードだけを作りなおす。
+    ///
+    /// @return (ラティス, 前回のラティスとノードが同一である最後の終了位置)
+    pub fn construct_incremental(
+        &self,
+        prev_lattice: &LatticeGraph<U, B>,
+        prev_words_ends_at: &SegmentationResult,
+        yomi: &str,
+        words_ends_at: &SegmentationResult,
+    ) -> (LatticeGraph<U, B>, i32) {
+        let prev_yomi = prev_lattice.yomi.as_str();
+        let common_prefix_len = prev_yomi
+            .char_indices()
+            .zip(yomi.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map(|((i, c), _)| i + c.len_utf8())
+            .unwrap_or(0);
+
+        let mut graph: BTreeMap<i32, Vec<WordNode>> = BTreeMap::new();
+        graph.insert(0, vec![WordNode::create_bos()]);
+        graph.insert(
+            (yomi.len() + 1) as i32,
+            vec![WordNode::create_eos(yomi.len() as i32)],
+        );
+
+        let mut stable_until = common_prefix_len as i32;
+        // 前回はあったが、今回はなくなった終了位置以降は作りなおしになる。
+        for (end_pos, _) in prev_words_ends_at.iter() {
+            if *end_pos <= common_prefix_len && words_ends_at.get(*end_pos).is_none() {
+                stable_until = stable_until.min(*end_pos as i32 - 1);
+            }
+        }
+
+        for (end_pos, segmented_yomis) in words_ends_at.iter() {
+            // 読み全体と一致する単語は single term 辞書を引くかどうかが変わるので、再利用しない。
+            let reusable = *end_pos <= common_prefix_len
+                && !segmented_yomis
+                    .iter()
+                    .any(|it| it == yomi || it == prev_yomi)
+                && prev_words_ends_at
+                    .get(*end_pos)
+                    .map(|prev| Self::same_yomis(prev, segmented_yomis))
+                    .unwrap_or(false);
+            if reusable {
+                if let Some(nodes) = prev_lattice.node_list(*end_pos as i32) {
+                    trace!("Reuse nodes: end_pos={}", end_pos);
+                    graph.insert(*end_pos as i32, nodes.into_iter().cloned().collect());
+                    continue;
                 }
-                // ひらがな候補をリストアップする
-                for surface in [
+            }
+
+            stable_until = stable_until.min(*end_pos as i32 - 1);
+            let vec = graph.entry(*end_pos as i32).or_default();
+            for segmented_yomi in segmented_yomis {
+                self.push_nodes(vec, yomi, *end_pos, segmented_yomi);
+            }
+        }
+
+        let lattice = LatticeGraph::new(
+            yomi,
+            graph,
+            self.user_data.clone(),
+            self.system_unigram_lm.clone(),
+            self.system_bigram_lm.clone(),
+            self.system_trigram_lm.clone(),
+            prev_lattice.left_context.clone(),
+            Some((prev_lattice, stable_until)),
+        );
+        (lattice, stable_until)
+    }
+
+    fn same_yomis(a: &[String], b: &[String]) -> bool {
+        a.len() == b.len() && a.iter().collect::<HashSet<_>>() == b.iter().collect::<HashSet<_>>()
+    }
+
+    /// segmented_yomi を読みとする単語のノードを vec に追加する。
+    fn push_nodes(
+        &self,
+        vec: &mut Vec<WordNode>,
+        yomi: &str,
+        end_pos: usize,
+        segmented_yomi: &str,
+    ) {
+        let mut seen: HashSet<String> = HashSet::new();
+
+        // TODO このへんコピペすぎるので整理必要。
+        // システム辞書にある候補を元に候補をリストアップする
+        if let Some(kanjis) = self.system_kana_kanji_dict.get(segmented_yomi) {
+            for kanji in kanjis {
+                let node = WordNode::new(
+                    (end_pos - segmented_yomi.len()) as i32,
+                    &kanji,
                     segmented_yomi,
-                    hira2kata(segmented_yomi, ConvOption::default()).as_str(),
-                ] {
-                    if seen.contains(surface) {
-                        continue;
-                    }
-                    // ひらがなそのものと、カタカナ表現もエントリーとして登録しておく。
-                    let node = WordNode::new(
-                        (end_pos - segmented_yomi.len()) as i32,
-                        surface,
-                        segmented_yomi,
-                        None,
-                        true,
-                    );
-                    vec.push(node);
+                    self.system_unigram_lm
+                        .find((kanji.to_string() + "/" + segmented_yomi).as_str()),
+                    false,
+                );
+                trace!("WordIDScore: {:?}", node.word_id_and_score);
+                vec.push(node);
+                seen.insert(kanji.to_string());
+            }
+        }
+        if let Some(surfaces) = self.user_data.lock().unwrap().dict.get(segmented_yomi) {
+            for surface in surfaces {
+                if seen.contains(surface) {
+                    continue;
                 }
+                let node = WordNode::new(
+                    (end_pos - segmented_yomi.len()) as i32,
+                    surface,
+                    segmented_yomi,
+                    self.system_unigram_lm
+                        .find((surface.to_string() + "/" + segmented_yomi).as_str()),
+                    false,
+                );
+                trace!("WordIDScore: {:?}", node.word_id_and_score);
+                vec.push(node);
+                seen.insert(surface.to_string());
+            }
+        }
+        // ひらがな候補をリストアップする
+        for surface in [
+            segmented_yomi,
+            hira2kata(segmented_yomi, ConvOption::default()).as_str(),
+        ] {
+            if seen.contains(surface) {
+                continue;
+            }
+            // ひらがなそのものと、カタカナ表現もエントリーとして登録しておく。
+            let node = WordNode::new(
+                (end_pos - segmented_yomi.len()) as i32,
+                surface,
+                segmented_yomi,
+                None,
+                true,
+            );
+            vec.push(node);
+        }
 
-                // 数字の場合は数字用の動的変換を入れる
-                if self.number_pattern.is_match(segmented_yomi) {
+        // 数字の場合は数字用の動的変換を入れる
+        if self.number_pattern.is_match(segmented_yomi) {
+            let node = WordNode::new(
+                (end_pos - segmented_yomi.len()) as i32,
+                "(*(*(NUMBER-KANSUJI",
+                segmented_yomi,
+                None,
+                true,
+            );
+            vec.push(node);
+        }
+
+        // 変換範囲が全体になっていれば single term 辞書を利用する。
+        if segmented_yomi == yomi {
+            if let Some(surfaces) = self.system_single_term_dict.get(yomi) {
+                for surface in surfaces {
                     let node = WordNode::new(
                         (end_pos - segmented_yomi.len()) as i32,
-                        "(*(*(NUMBER-KANSUJI",
+                        &surface,
                         segmented_yomi,
-                        None,
-                        true,
+                        self.system_unigram_lm
+                            .find((surface.to_string() + "/" + segmented_yomi).as_str()),
+                        false,
                     );
                     vec.push(node);
                 }
+            }
+        }
+    }
+
+    /// 左側の文脈の単語を、エッジコストの計算に必要な直近の二単語分だけノードにする。
+    fn build_left_context(&self, left_context: &[Candidate]) -> Vec<WordNode> {
+        let left_context = left_context
+            .iter()
+            .filter(|candidate| !candidate.surface.is_empty())
+            .collect::<Vec<_>>();
+        left_context[left_context.len().saturating_sub(2)..]
+            .iter()
+            .map(|candidate| {
+                WordNode::new(
+                    0,
+                    &candidate.surface,
+                    &candidate.yomi,
+                    self.system_unigram_lm.find(&candidate.key()),
+                    false,
+                )
+            })
+            .collect()
+    }
+}
+
+/// 予測変換の候補のうち、コストの小さいものを limit 件まで保持する。
+/// 同じ表層の候補は、コストの小さいものだけを残す。
+struct TopCandidates {
+    limit: usize,
+    /// コストの大きい順に取り出せるヒープ。表層のコストを下げたときの古い候補も残っている。
+    heap: BinaryHeap<Candidate>,
+    /// 表層 -> 保持している候補のコスト
+    costs: HashMap<String, f32>,
+}
+
+impl TopCandidates {
+    fn new(limit: usize) -> Self {
+        TopCandidates {
+            limit,
+            heap: BinaryHeap::new(),
+            costs: HashMap::new(),
+        }
+    }
+
+    fn is_stale(&self, candidate: &Candidate) -> bool {
+        self.costs.get(&candidate.surface) != Some(&candidate.cost)
+    }
 
-                // 変換範囲が全体になっていれば single term 辞書を利用する。
-                if segmented_yomi == yomi {
-                    if let Some(surfaces) = self.system_single_term_dict.get(yomi) {
-                        for surface in surfaces {
-                            let node = WordNode::new(
-                                (end_pos - segmented_yomi.len()) as i32,
-                                &surface,
-                                segmented_yomi,
-                                self.system_unigram_lm
-                                    .find((surface.to_string() + "/" + segmented_yomi).as_str()),
-                                false,
-                            );
-                            vec.push(node);
-                        }
-                    }
+    fn push(&mut self, candidate: Candidate) {
+        if let Some(cost) = self.costs.get(&candidate.surface) {
+            if *cost <= candidate.cost {
+                return;
+            }
+        } else if self.costs.len() >= self.limit {
+            // 上限に達していれば、保持しているもののうち最もコストの大きいものと比べる。
+            while self.heap.peek().is_some_and(|it| self.is_stale(it)) {
+                self.heap.pop();
+            }
+            match self.heap.peek() {
+                Some(worst) if candidate.cost < worst.cost => {
+                    let worst = self.heap.pop().unwrap();
+                    self.costs.remove(&worst.surface);
                 }
+                _ => return,
             }
         }
-        LatticeGraph {
-            graph,
-            yomi: yomi.to_string(),
-            user_data: self.user_data.clone(),
-            system_unigram_lm: self.system_unigram_lm.clone(),
-            system_bigram_lm: self.system_bigram_lm.clone(),
-        }
+        self.costs
+            .insert(candidate.surface.to_string(), candidate.cost);
+        self.heap.push(candidate);
+    }
+
+    fn into_sorted_vec(self) -> Vec<Candidate> {
+        let costs = self.costs;
+        let mut seen = HashSet::new();
+        let mut candidates = self
+            .heap
+            .into_vec()
+            .into_iter()
+            .filter(|it| {
+                costs.get(&it.surface) == Some(&it.cost) && seen.insert(it.surface.clone())
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
 
     use crate::kana_kanji::hashmap_vec::HashmapVecKanaKanjiDict;
     use crate::lm::system_bigram::MarisaSystemBigramLMBuilder;
@@ -170,13 +483,13 @@ mod tests {
                 vec!["🍣".to_string()],
             )])),
             Arc::new(Mutex::new(UserData::default())),
-            Rc::new(
+            Arc::new(
                 MarisaSystemUnigramLMBuilder::default()
                     .set_unique_words(20)
                     .set_total_words(19)
                     .build(),
             ),
-            Rc::new(
+            Arc::new(
                 MarisaSystemBigramLMBuilder::default()
                     .set_default_edge_cost(20_f32)
                     .build()?,
@@ -203,13 +516,13 @@ mod tests {
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             Arc::new(Mutex::new(UserData::default())),
-            Rc::new(
+            Arc::new(
                 MarisaSystemUnigramLMBuilder::default()
                     .set_unique_words(20)
                     .set_total_words(19)
                     .build(),
             ),
-            Rc::new(
+            Arc::new(
                 MarisaSystemBigramLMBuilder::default()
                     .set_default_edge_cost(20_f32)
                     .build()?,
@@ -236,13 +549,13 @@ mod tests {
             )])),
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             Arc::new(Mutex::new(UserData::default())),
-            Rc::new(
+            Arc::new(
                 MarisaSystemUnigramLMBuilder::default()
                     .set_unique_words(20)
                     .set_total_words(19)
                     .build(),
             ),
-            Rc::new(
+            Arc::new(
                 MarisaSystemBigramLMBuilder::default()
                     .set_default_edge_cost(20_f32)
                     .build()?,
@@ -258,4 +571,237 @@ mod tests {
         assert_eq!(got_surfaces, vec!["す".to_string(), "ス".to_string()]);
         Ok(())
     }
+
+    #[test]
+    fn test_predict() -> anyhow::Result<()> {
+        let mut system_unigram_lm_builder = MarisaSystemUnigramLMBuilder::default();
+        system_unigram_lm_builder.add("七夕/たなばた", 1.5);
+        system_unigram_lm_builder.add("田中/たなか", 1.0);
+        let system_unigram_lm = system_unigram_lm_builder
+            .set_unique_words(20)
+            .set_total_words(19)
+            .build();
+
+        let user_data = Arc::new(Mutex::new(UserData::default()));
+        user_data
+            .lock()
+            .unwrap()
+            .dict
+            .insert("たなかさん".to_string(), vec!["田中さん".to_string()]);
+
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(HashMap::from([
+                ("たな".to_string(), vec!["棚".to_string()]),
+                ("たなか".to_string(), vec!["田中".to_string()]),
+                ("たなばた".to_string(), vec!["七夕".to_string()]),
+                ("なか".to_string(), vec!["中".to_string()]),
+            ])),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            user_data,
+            Arc::new(system_unigram_lm),
+            Arc::new(
+                MarisaSystemBigramLMBuilder::default()
+                    .set_default_edge_cost(20_f32)
+                    .build()?,
+            ),
+        );
+
+        let got = graph_builder
+            .predict("たな", usize::MAX)
+            .iter()
+            .map(|it| it.surface.to_string())
+            .collect::<Vec<_>>();
+        // 読みが完全一致する「棚」は含まない。ユーザー辞書の単語はシステム辞書のものより後に来る。
+        assert_eq!(got, vec!["田中", "七夕", "田中さん"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_limit() -> anyhow::Result<()> {
+        let mut system_unigram_lm_builder = MarisaSystemUnigramLMBuilder::default();
+        system_unigram_lm_builder.add("七夕/たなばた", 1.5);
+        system_unigram_lm_builder.add("田中/たなか", 1.0);
+        let system_unigram_lm = system_unigram_lm_builder
+            .set_unique_words(20)
+            .set_total_words(19)
+            .build();
+
+        // 言語モデルにない単語がたくさんあっても、コストの小さいものが選ばれる。
+        let mut dict = (0..300)
+            .map(|i| (format!("たな{i}"), vec![format!("棚{i}")]))
+            .collect::<HashMap<_, _>>();
+        dict.insert("たなか".to_string(), vec!["田中".to_string()]);
+        dict.insert("たなばた".to_string(), vec!["七夕".to_string()]);
+        // 同じ表層は、コストの小さい読みのものだけが残る。
+        dict.insert("たなかあ".to_string(), vec!["田中".to_string()]);
+
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(system_unigram_lm),
+            Arc::new(
+                MarisaSystemBigramLMBuilder::default()
+                    .set_default_edge_cost(20_f32)
+                    .build()?,
+            ),
+        );
+
+        let got = graph_builder.predict("たな", 3);
+        assert_eq!(
+            got.iter()
+                .map(|it| (it.yomi.as_str(), it.surface.as_str()))
+                .take(2)
+                .collect::<Vec<_>>(),
+            vec![("たなか", "田中"), ("たなばた", "七夕")]
+        );
+        assert_eq!(got.len(), 3);
+        assert!(graph_builder.predict("たな", 0).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_candidates() {
+        let mut top = TopCandidates::new(2);
+        top.push(Candidate::new("たなか", "田中", 3.0));
+        top.push(Candidate::new("たなばた", "七夕", 2.0));
+        // 同じ表層でコストが小さくなったものは置き換える。
+        top.push(Candidate::new("たなかあ", "田中", 1.0));
+        top.push(Candidate::new("たなかい", "田中", 5.0));
+        // 上限に達していれば、最もコストの大きいものと比べる。
+        top.push(Candidate::new("たなべ", "田辺", 4.0));
+        top.push(Candidate::new("たなか", "棚か", 1.5));
+        assert_eq!(
+            top.into_sorted_vec(),
+            vec![
+                Candidate::new("たなかあ", "田中", 1.0),
+                Candidate::new("たなか", "棚か", 1.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_predict_decayed_user_cost() -> anyhow::Result<()> {
+        let mut system_unigram_lm_builder = MarisaSystemUnigramLMBuilder::default();
+        system_unigram_lm_builder.add("七夕/たなばた", 1.5);
+        system_unigram_lm_builder.add("田中/たなか", 1.0);
+        let system_unigram_lm = system_unigram_lm_builder
+            .set_unique_words(20)
+            .set_total_words(19)
+            .build();
+
+        // 「七夕」は大昔に一度だけ使ったので、減衰したコストはとても大きい。
+        let tmpdir = tempfile::TempDir::new()?;
+        let path = |name: &str| tmpdir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("unigram"), "七夕/たなばた 1 0\n")?;
+        std::fs::write(path("bigram"), "")?;
+        std::fs::write(path("dict"), "たなかさん /田中さん/\n")?;
+        let user_data = UserData::load_strict(&path("unigram"), &path("bigram"), &path("dict"))?;
+
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(HashMap::from([
+                ("たなか".to_string(), vec!["田中".to_string()]),
+                ("たなばた".to_string(), vec!["七夕".to_string()]),
+            ])),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(user_data)),
+            Arc::new(system_unigram_lm),
+            Arc::new(
+                MarisaSystemBigramLMBuilder::default()
+                    .set_default_edge_cost(20_f32)
+                    .build()?,
+            ),
+        );
+
+        // ユーザーのコストはシステムのコストで頭打ちになるので、学習していない場合と同じ順番になる。
+        let got = graph_builder.predict("たな", usize::MAX);
+        assert_eq!(
+            got.iter().map(|it| it.surface.as_str()).collect::<Vec<_>>(),
+            vec!["田中", "七夕", "田中さん"]
+        );
+        assert_eq!(got[1].cost, 1.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_lookup() -> anyhow::Result<()> {
+        let user_data = Arc::new(Mutex::new(UserData::default()));
+        user_data
+            .lock()
+            .unwrap()
+            .dict
+            .insert("あずま".to_string(), vec!["東".to_string()]);
+
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(HashMap::from([
+                ("ひがし".to_string(), vec!["東".to_string()]),
+                (
+                    "きょう".to_string(),
+                    vec!["京".to_string(), "今日".to_string()],
+                ),
+                ("とうきょう".to_string(), vec!["東京".to_string()]),
+            ])),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            user_data,
+            Arc::new(
+                MarisaSystemUnigramLMBuilder::default()
+                    .set_unique_words(20)
+                    .set_total_words(19)
+                    .build(),
+            ),
+            Arc::new(
+                MarisaSystemBigramLMBuilder::default()
+                    .set_default_edge_cost(20_f32)
+                    .build()?,
+            ),
+        );
+
+        assert_eq!(
+            graph_builder.reverse_lookup("東京の今日"),
+            "とうきょうのきょう"
+        );
+        // ユーザー辞書の読みが優先される。
+        assert_eq!(graph_builder.reverse_lookup("東の京"), "あずまのきょう");
+        Ok(())
+    }
+
+    #[test]
+    fn test_left_context_from_text() -> anyhow::Result<()> {
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        unigram_builder.add("東京/とうきょう", 1_f32);
+        unigram_builder.add("東/ひがし", 1_f32);
+        unigram_builder.add("京/きょう", 1_f32);
+        unigram_builder.add("に/に", 1_f32);
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(
+                unigram_builder
+                    .set_unique_words(20)
+                    .set_total_words(19)
+                    .build(),
+            ),
+            Arc::new(
+                MarisaSystemBigramLMBuilder::default()
+                    .set_default_edge_cost(20_f32)
+                    .build()?,
+            ),
+        );
+
+        let keys = |text: &str| {
+            graph_builder
+                .left_context_from_text(text)
+                .iter()
+                .map(|it| it.key())
+                .collect::<Vec<_>>()
+        };
+        // 末尾から長い単語を優先して、直近の二単語を取る。
+        assert_eq!(keys("東東京に"), vec!["東京/とうきょう", "に/に"]);
+        // 言語モデルにない文字で打ち切る。
+        assert_eq!(keys("あ東京"), vec!["東京/とうきょう"]);
+        assert!(keys("東京あ").is_empty());
+        assert!(keys("").is_empty());
+        Ok(())
+    }
 }
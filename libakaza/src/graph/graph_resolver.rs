@@ -1,12 +1,11 @@
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BinaryHeap, HashSet};
 
-use anyhow::Context;
+use anyhow::bail;
 use log::{info, trace};
 
 use crate::graph::candidate::Candidate;
-use crate::graph::lattice_graph::LatticeGraph;
-use crate::graph::word_node::WordNode;
+use crate::graph::lattice_graph::{LatticeGraph, NodeId, BOS_ID};
 use crate::lm::base::{SystemBigramLM, SystemUnigramLM};
 
 /**
@@ -23,100 +22,320 @@ impl GraphResolver {
         &self,
         lattice: &LatticeGraph<U, B>,
     ) -> anyhow::Result<Vec<Vec<Candidate>>> {
-        let yomi = &lattice.yomi;
-        let mut prevmap: HashMap<&WordNode, &WordNode> = HashMap::new();
-        let mut costmap: HashMap<&WordNode, f32> = HashMap::new();
-
-        // 前向きに動的計画法でたどる
-        for i in 1..yomi.len() + 2 {
-            let Some(nodes) = &lattice.node_list(i as i32) else {
-                continue;
-            };
-            for node in *nodes {
-                let node_cost = lattice.get_node_cost(node);
-                trace!("kanji={}, Cost={}", node, node_cost);
-                let mut cost = f32::MAX;
-                let mut shortest_prev = None;
-                let prev_nodes = lattice.get_prev_nodes(node).with_context(|| {
-                    format!(
-                        "Cannot get prev nodes for '{}' start={} lattice={:?}",
-                        node.surface, node.start_pos, lattice
-                    )
-                })?;
-                for prev in prev_nodes {
-                    let edge_cost = lattice.get_edge_cost(prev, node);
-                    let prev_cost = costmap.get(prev).unwrap_or(&0_f32); // unwrap が必要なのは、 __BOS__ 用。
-                    let tmp_cost = prev_cost + edge_cost + node_cost;
-                    trace!(
-                        "Replace??? prev_cost={} tmp_cost={} < cost={}: {}",
-                        prev_cost,
-                        tmp_cost,
-                        cost,
-                        prev
-                    );
-                    // コストが最小な経路を選ぶようにする。
-                    // そういうふうにコストを付与しているので。
-                    if cost > tmp_cost {
-                        if shortest_prev.is_none() {
-                            trace!("Replace None by {}", prev);
-                        } else {
-                            trace!("Replace {} by {}", shortest_prev.unwrap(), prev);
-                        }
-                        cost = tmp_cost;
-                        shortest_prev = Some(prev);
-                    }
-                }
-                prevmap.insert(node, shortest_prev.unwrap());
-                costmap.insert(node, cost);
-            }
-        }
+        let viterbi_table = Self::forward(lattice)?;
+        self.resolve_with_table(lattice, &viterbi_table)
+    }
 
+    /**
+     * 前向きの計算が済んでいる ViterbiTable を使って、最適な経路を見つける。
+     */
+    pub fn resolve_with_table<U: SystemUnigramLM, B: SystemBigramLM>(
+        &self,
+        lattice: &LatticeGraph<U, B>,
+        viterbi_table: &ViterbiTable,
+    ) -> anyhow::Result<Vec<Vec<Candidate>>> {
         // 後ろ向きに候補を探していく
-        let eos = lattice
-            .get((yomi.len() + 1) as i32)
-            .unwrap()
-            .get(0)
-            .unwrap();
-        let bos = lattice.get(0).unwrap().get(0).unwrap();
-        let mut node = eos;
+        let eos = lattice.eos_id();
+        let mut id = eos;
         let mut result: Vec<Vec<Candidate>> = Vec::new();
-        while node != bos {
-            if node.surface != "__EOS__" {
+        while id != BOS_ID {
+            if id != eos {
                 // 同一の開始位置、終了位置を持つものを集める。
+                let node = lattice.node(id);
                 let end_pos = node.start_pos + (node.yomi.len() as i32);
                 let candidates: Vec<Candidate> =
-                    self.get_candidates(node, lattice, &costmap, end_pos);
+                    self.get_candidates(id, lattice, viterbi_table, end_pos);
                 result.push(candidates);
             }
-            node = prevmap
-                .get(node)
-                .unwrap_or_else(|| panic!("Cannot get previous node: {}", node.surface));
+            id = viterbi_table.prev[id].unwrap_or_else(|| {
+                panic!("Cannot get previous node: {}", lattice.node(id).surface)
+            });
         }
         result.reverse();
         Ok(result)
     }
 
+    /**
+     * 文全体の変換候補を、コストの小さい順に最大 k 個求める。
+     *
+     * 前向きのビタビで各ノードまでの最小コストを求めておき、それをヒューリスティックとして
+     * EOS から BOS に向かって A* 探索する。ヒューリスティックが正確な値なので、
+     * BOS に到達した順にそのまま N-best になる。
+     *
+     * 表層文字列が同一になる経路(「わたし」と「わた/し」など)は、コストの小さいものだけを返す。
+     *
+     * trigram の文脈は経路ごとに異なるので、A* 探索は bigram のコストで行う。
+     * 見つかった経路と、trigram を含めた前向きの計算で得た最良の経路を、
+     * それぞれ trigram のコストで計算しなおして並べ替える。
+     */
+    pub fn resolve_k_best<U: SystemUnigramLM, B: SystemBigramLM>(
+        &self,
+        lattice: &LatticeGraph<U, B>,
+        k: usize,
+    ) -> anyhow::Result<Vec<KBestPath>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let bigram_table = Self::forward_from(lattice, ViterbiTable::default(), false)?;
+        let mut paths = Self::search_k_best(lattice, &bigram_table, k);
+        if !lattice.has_trigram_lm() {
+            // bigram のコストだけなので、A* 探索の結果がそのまま正しい順番になっている。
+            return Ok(paths.into_iter().map(|(_, path)| path).collect());
+        }
+
+        // trigram を含めた最良の経路は、A* 探索で見つかっていないことがあるので足しておく。
+        let viterbi_table = Self::forward(lattice)?;
+        let mut best_nodes = Vec::new();
+        let mut id = viterbi_table.prev[lattice.eos_id()];
+        while let Some(node) = id.filter(|it| *it != BOS_ID) {
+            best_nodes.push(node);
+            id = viterbi_table.prev[node];
+        }
+        paths.push((
+            best_nodes.clone(),
+            KBestPath {
+                candidates: Self::path_candidates(lattice, &best_nodes),
+                cost: 0_f32,
+            },
+        ));
+
+        for (nodes, path) in paths.iter_mut() {
+            path.cost = Self::trigram_path_cost(lattice, nodes);
+        }
+        paths.sort_by(|a, b| a.1.cost.total_cmp(&b.1.cost));
+        let mut seen: HashSet<String> = HashSet::new();
+        Ok(paths
+            .into_iter()
+            .map(|(_, path)| path)
+            .filter(|path| seen.insert(path.surface()))
+            .take(k)
+            .collect())
+    }
+
+    /// viterbi_table のコストをヒューリスティックにして、表層の異なる経路を最大 k 個探す。
+    /// 経路は EOS 側から辿ったノードのリストと一緒に返す。
+    fn search_k_best<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+        viterbi_table: &ViterbiTable,
+        k: usize,
+    ) -> Vec<(Vec<NodeId>, KBestPath)> {
+        let eos = lattice.eos_id();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut result: Vec<(Vec<NodeId>, KBestPath)> = Vec::new();
+        let mut queue: BinaryHeap<PartialPath> = BinaryHeap::new();
+        queue.push(PartialPath {
+            node: eos,
+            tail_cost: 0_f32,
+            total_cost: viterbi_table.costs[eos],
+            nodes: Vec::new(),
+        });
+
+        while let Some(path) = queue.pop() {
+            if result.len() >= k {
+                break;
+            }
+
+            if path.node == BOS_ID {
+                // BOS まで辿りついたので、経路が一つ確定した。
+                let found = KBestPath {
+                    candidates: Self::path_candidates(lattice, &path.nodes),
+                    cost: path.total_cost,
+                };
+                if seen.insert(found.surface()) {
+                    trace!("Found k-best path: {:?}", found.candidates);
+                    result.push((path.nodes, found));
+                }
+                continue;
+            }
+
+            let node_cost = lattice.get_node_cost(path.node);
+            for edge in lattice.edges(path.node) {
+                // エッジコストは viterbi_table と同じ定義にしておかないと、ヒューリスティックが正確にならない。
+                let tail_cost = path.tail_cost + edge.cost + node_cost;
+                let head_cost = viterbi_table.costs[edge.prev];
+                let mut nodes = path.nodes.clone();
+                if path.node != eos {
+                    nodes.push(path.node);
+                }
+                queue.push(PartialPath {
+                    node: edge.prev,
+                    tail_cost,
+                    total_cost: head_cost + tail_cost,
+                    nodes,
+                });
+            }
+        }
+
+        result
+    }
+
+    /// EOS 側から辿ったノードのリストについて、trigram のコストも含めた経路全体のコストを計算する。
+    fn trigram_path_cost<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+        nodes: &[NodeId],
+    ) -> f32 {
+        let path = std::iter::once(BOS_ID)
+            .chain(nodes.iter().rev().copied())
+            .chain(std::iter::once(lattice.eos_id()))
+            .collect::<Vec<_>>();
+        let mut cost = 0_f32;
+        for (i, id) in path.iter().enumerate().skip(1) {
+            let prev_prev = i.checked_sub(2).map(|j| path[j]);
+            let edge_cost = lattice
+                .edges(*id)
+                .iter()
+                .find(|edge| edge.prev == path[i - 1])
+                .map(|edge| lattice.get_trigram_edge_cost(prev_prev, edge, *id))
+                .unwrap_or_else(|| lattice.get_edge_cost(path[i - 1], *id));
+            cost += edge_cost + lattice.get_node_cost(*id);
+        }
+        cost
+    }
+
+    /// EOS 側から辿ったノードのリストを、BOS 側からの変換結果にする。
+    fn path_candidates<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+        nodes: &[NodeId],
+    ) -> Vec<Candidate> {
+        nodes
+            .iter()
+            .rev()
+            .map(|id| {
+                let node = lattice.node(*id);
+                Candidate {
+                    surface: node.surface.clone(),
+                    yomi: node.yomi.clone(),
+                    cost: lattice.get_node_cost(*id),
+                    compound_word: false,
+                }
+            })
+            .collect()
+    }
+
+    /// 前向きに動的計画法でたどり、各ノードまでの最小コストと、その時の直前のノードを求める。
+    pub(crate) fn forward<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+    ) -> anyhow::Result<ViterbiTable> {
+        Self::forward_from(lattice, ViterbiTable::default(), true)
+    }
+
+    /// 前回の変換の ViterbiTable のうち、stable_until までに終わるノードの計算結果を再利用して、
+    /// それより後ろのノードだけを計算しなおす。
+    /// stable_until までのノードは、前回のラティスと同一である必要がある。
+    pub(crate) fn forward_incremental<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+        prev_table: &ViterbiTable,
+        stable_until: i32,
+    ) -> anyhow::Result<ViterbiTable> {
+        // ノードは終了位置の順に並んでいるので、stable_until までに終わるノードの ID は前回と同じになる。
+        let n = lattice.nodes_until(stable_until);
+        if prev_table.costs.len() < n {
+            // 前回の計算結果にないノードがあるので、最初から計算しなおす。
+            trace!(
+                "Missing viterbi entries: {} < {}",
+                prev_table.costs.len(),
+                n
+            );
+            return Self::forward(lattice);
+        }
+        let viterbi_table = ViterbiTable {
+            prev: prev_table.prev[..n].to_vec(),
+            costs: prev_table.costs[..n].to_vec(),
+        };
+        Self::forward_from(lattice, viterbi_table, true)
+    }
+
+    /// viterbi_table に計算済みのノードの続きから計算する。
+    /// trigram が false なら、trigram のコストを使わずに bigram のコストだけで計算する。
+    fn forward_from<U: SystemUnigramLM, B: SystemBigramLM>(
+        lattice: &LatticeGraph<U, B>,
+        viterbi_table: ViterbiTable,
+        trigram: bool,
+    ) -> anyhow::Result<ViterbiTable> {
+        let ViterbiTable {
+            mut prev,
+            mut costs,
+        } = viterbi_table;
+
+        // 前向きに動的計画法でたどる。ノードは終了位置の順に並んでいるので、ID の順に計算すればよい。
+        for id in costs.len()..lattice.nodes.len() {
+            if id == BOS_ID {
+                prev.push(None);
+                costs.push(0_f32);
+                continue;
+            }
+
+            let node_cost = lattice.get_node_cost(id);
+            trace!("kanji={}, Cost={}", lattice.node(id), node_cost);
+            let mut cost = f32::MAX;
+            let mut shortest_prev = None;
+            let edges = lattice.edges(id);
+            if edges.is_empty() {
+                let node = lattice.node(id);
+                bail!(
+                    "Cannot get prev nodes for '{}' start={} lattice={:?}",
+                    node.surface,
+                    node.start_pos,
+                    lattice
+                );
+            }
+            for edge in edges {
+                // trigram の文脈には、prev までの最適経路における直前の単語を使う。
+                // 状態に (prev, prev_prev) の組を持たないので、これは近似になる。
+                // prev までの最適経路ではない経路を通ったほうが、trigram を含めると安くなる場合は見逃す。
+                let edge_cost = if trigram {
+                    lattice.get_trigram_edge_cost(prev[edge.prev], edge, id)
+                } else {
+                    edge.cost
+                };
+                let prev_cost = costs[edge.prev];
+                let tmp_cost = prev_cost + edge_cost + node_cost;
+                trace!(
+                    "Replace??? prev_cost={} tmp_cost={} < cost={}: {}",
+                    prev_cost,
+                    tmp_cost,
+                    cost,
+                    lattice.node(edge.prev)
+                );
+                // コストが最小な経路を選ぶようにする。
+                // そういうふうにコストを付与しているので。
+                if cost > tmp_cost {
+                    cost = tmp_cost;
+                    shortest_prev = Some(edge.prev);
+                }
+            }
+            prev.push(shortest_prev);
+            costs.push(cost);
+        }
+
+        Ok(ViterbiTable { prev, costs })
+    }
+
     fn get_candidates<U: SystemUnigramLM, B: SystemBigramLM>(
         &self,
-        node: &WordNode,
+        id: NodeId,
         lattice: &LatticeGraph<U, B>,
-        costmap: &HashMap<&WordNode, f32>,
+        viterbi_table: &ViterbiTable,
         end_pos: i32,
     ) -> Vec<Candidate> {
+        let node = lattice.node(id);
         // end_pos で終わる単語を得る。
         let mut strict_results: Vec<Candidate> = lattice
-            .node_list(end_pos)
-            .unwrap()
+            .node_ids(end_pos)
             .iter()
-            .filter(|alt_node| {
+            .filter(|alt_id| {
+                let alt_node = lattice.node(**alt_id);
                 alt_node.start_pos == node.start_pos // 同じ位置かそれより前から始まっている
                     && alt_node.yomi.len() == node.yomi.len() // 同じ長さの単語を得る
             })
-            .map(|f| Candidate {
-                surface: f.surface.clone(),
-                yomi: f.yomi.clone(),
-                cost: *costmap.get(f).unwrap(),
-                compound_word: false,
+            .map(|alt_id| {
+                let f = lattice.node(*alt_id);
+                Candidate {
+                    surface: f.surface.clone(),
+                    yomi: f.yomi.clone(),
+                    cost: viterbi_table.costs[*alt_id],
+                    compound_word: false,
+                }
             })
             .collect();
         strict_results.sort();
@@ -138,7 +357,7 @@ impl GraphResolver {
                 lattice,
                 end_pos,
                 0,
-                &costmap,
+                viterbi_table,
                 0_f32,
                 None,
             );
@@ -163,9 +382,9 @@ impl GraphResolver {
         lattice: &LatticeGraph<U, B>,
         end_pos: i32,
         depth: i32,
-        cost_map: &&HashMap<&WordNode, f32>,
+        viterbi_table: &ViterbiTable,
         tail_cost: f32,
-        next_node: Option<&WordNode>,
+        next_node: Option<NodeId>,
     ) {
         if depth > 4 {
             // depth が深過ぎたら諦める。
@@ -187,27 +406,25 @@ impl GraphResolver {
             return;
         }
 
-        let Some(targets) = lattice
-            .node_list(end_pos) else {
-            // 直前のノードはない場合ある。
-            return;
-        };
+        // 直前のノードはない場合ある。
+        let targets = lattice.node_ids(end_pos);
         trace!("Targets: {:?}", targets);
         let mut targets = targets
             .iter()
-            .filter(|cur| {
+            .filter(|id| {
+                let cur = lattice.node(**id);
                 // 単語の開始位置が、node の表示範囲内に収まっているもののみをリストアップする
                 min_start_pos <= cur.start_pos
                     // 元々の候補と完全に一致しているものは除外。
                     && cur.yomi != node_yomi
             })
-            .map(|f| BreakDown {
-                node: f.clone(),
-                head_cost: (*cost_map.get(f).unwrap()), // 先頭から辿った場合のコスト
+            .map(|id| BreakDown {
+                id: *id,
+                head_cost: viterbi_table.costs[*id], // 先頭から辿った場合のコスト
                 tail_cost: tail_cost
-                    + lattice.get_node_cost(f)
+                    + lattice.get_node_cost(*id)
                     + next_node
-                        .map(|nn| lattice.get_edge_cost(f, nn))
+                        .map(|nn| lattice.get_edge_cost(*id, nn))
                         .unwrap_or_else(|| lattice.get_default_edge_cost()),
             })
             .collect::<Vec<_>>();
@@ -220,39 +437,104 @@ impl GraphResolver {
 
         trace!("Targets: {:?}, min_start_pos={}", targets, min_start_pos);
         for target in targets {
-            if target.node.yomi == "__BOS__" || target.node.yomi == "__EOS__" {
+            let target_node = lattice.node(target.id);
+            if target_node.yomi == "__BOS__" || target_node.yomi == "__EOS__" {
                 continue;
             }
 
             trace!(
                 "Recursive tracking : {}/{}",
-                target.node.surface,
-                target.node.yomi
+                target_node.surface,
+                target_node.yomi
             );
-            if required_len < target.node.yomi.len() {
-                panic!("??? underflow: {:?}, {:?}", required_len, target.node.yomi);
+            if required_len < target_node.yomi.len() {
+                panic!("??? underflow: {:?}, {:?}", required_len, target_node.yomi);
             }
             Self::collect_breakdown_results(
                 node_yomi,
-                required_len - target.node.yomi.len(),
+                required_len - target_node.yomi.len(),
                 min_start_pos,
                 strict_results,
-                target.node.surface.clone() + cur_surface.as_str(),
-                target.node.yomi.clone() + cur_yomi.as_str(),
+                target_node.surface.clone() + cur_surface.as_str(),
+                target_node.yomi.clone() + cur_yomi.as_str(),
                 lattice,
-                end_pos - (target.node.yomi.len() as i32),
+                end_pos - (target_node.yomi.len() as i32),
                 depth + 1,
-                cost_map,
+                viterbi_table,
                 tail_cost + target.tail_cost,
-                Some(&target.node),
+                Some(target.id),
             )
         }
     }
 }
 
+/// ビタビアルゴリズムの前向きの計算結果。
+/// インクリメンタルに変換するときに、前回の計算結果を再利用するために保持しておく。
+/// NodeId を添字にしている。
+#[derive(Debug, Default, Clone)]
+pub struct ViterbiTable {
+    /// NodeId -> BOS からの最小コストの経路における、直前のノード
+    prev: Vec<Option<NodeId>>,
+    /// NodeId -> BOS からの最小コスト
+    costs: Vec<f32>,
+}
+
+/// N-best 変換の結果の一つ。文全体をどう区切ってどう変換したかを表す。
+#[derive(Debug, Clone, PartialEq)]
+pub struct KBestPath {
+    /// 区切られた単語ごとの変換結果。
+    pub candidates: Vec<Candidate>,
+    /// BOS から EOS までの経路全体のコスト。trigram 言語モデルがあれば、trigram のコストを含む。
+    pub cost: f32,
+}
+
+impl KBestPath {
+    pub fn surface(&self) -> String {
+        self.candidates
+            .iter()
+            .map(|it| it.surface_with_dynamic())
+            .collect()
+    }
+}
+
+/// A* 探索中の、EOS から node までたどった途中の経路。
+struct PartialPath {
+    node: NodeId,
+    /// node から EOS までのコスト
+    tail_cost: f32,
+    /// BOS から node までの最小コスト + tail_cost
+    total_cost: f32,
+    /// EOS 側から辿ってきたノード。EOS 自体は含まない。
+    nodes: Vec<NodeId>,
+}
+
+impl PartialEq for PartialPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_cost == other.total_cost
+    }
+}
+
+impl Eq for PartialPath {}
+
+impl PartialOrd for PartialPath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PartialPath {
+    // BinaryHeap は最大値から取り出されるので、コストの小さいものが先に出てくるように逆順にする。
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .total_cost
+            .partial_cmp(&self.total_cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
 #[derive(PartialEq, Debug)]
 struct BreakDown {
-    node: WordNode,
+    id: NodeId,
     /// 先頭から辿った場合のコスト
     pub head_cost: f32,
     /// 末尾から辿った場合のコスト
@@ -278,9 +560,9 @@ impl Ord for BreakDown {
 #[cfg(test)]
 mod tests {
     use std::collections::btree_map::BTreeMap;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::Write;
-    use std::rc::Rc;
     use std::sync::{Arc, Mutex};
 
     use anyhow::Result;
@@ -290,7 +572,9 @@ mod tests {
     use crate::graph::segmenter::{SegmentationResult, Segmenter};
     use crate::kana_kanji::hashmap_vec::HashmapVecKanaKanjiDict;
     use crate::kana_trie::cedarwood_kana_trie::CedarwoodKanaTrie;
+    use crate::lm::base::UnigramBackoff;
     use crate::lm::system_bigram::MarisaSystemBigramLMBuilder;
+    use crate::lm::system_trigram::MarisaSystemTrigramLMBuilder;
     use crate::lm::system_unigram_lm::MarisaSystemUnigramLMBuilder;
     use crate::user_side_data::user_data::UserData;
 
@@ -330,8 +614,8 @@ mod tests {
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             HashmapVecKanaKanjiDict::new(Default::default()),
             Arc::new(Mutex::new(user_data)),
-            Rc::new(system_unigram_lm),
-            Rc::new(system_bigram_lm),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
         );
         let lattice = graph_builder.construct("abc", &graph);
         let resolver = GraphResolver::default();
@@ -384,8 +668,8 @@ mod tests {
             HashmapVecKanaKanjiDict::new(dict),
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             Arc::new(Mutex::new(user_data)),
-            Rc::new(system_unigram_lm),
-            Rc::new(system_bigram_lm),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
         );
         let lattice = graph_builder.construct(&yomi, &graph);
         // dot -Tpng -o /tmp/lattice.png /tmp/lattice.dot && open /tmp/lattice.png
@@ -468,8 +752,8 @@ mod tests {
             HashmapVecKanaKanjiDict::new(dict),
             HashmapVecKanaKanjiDict::new(HashMap::new()),
             Arc::new(Mutex::new(user_data)),
-            Rc::new(system_unigram_lm),
-            Rc::new(system_bigram_lm),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
         );
         let lattice = graph_builder.construct(&yomi, &graph);
         // dot -Tpng -o /tmp/lattice.png /tmp/lattice.dot && open /tmp/lattice.png
@@ -493,4 +777,360 @@ mod tests {
         // assert_eq!(result, "来たかな");
         Ok(())
     }
+
+    #[test]
+    fn test_resolve_k_best() -> Result<()> {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let kana_trie = CedarwoodKanaTrie::build(Vec::from([
+            "わたし".to_string(),
+            "わた".to_string(),
+            "し".to_string(),
+        ]));
+        let segmenter = Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]);
+        let graph = segmenter.build("わたし", None);
+
+        let dict = HashMap::from([
+            (
+                "わたし".to_string(),
+                vec!["私".to_string(), "渡し".to_string()],
+            ),
+            ("わた".to_string(), vec!["綿".to_string()]),
+            ("し".to_string(), vec!["詩".to_string()]),
+        ]);
+
+        let system_unigram_lm = MarisaSystemUnigramLMBuilder::default()
+            .set_unique_words(19)
+            .set_total_words(20)
+            .build();
+        let system_bigram_lm = MarisaSystemBigramLMBuilder::default()
+            .set_default_edge_cost(20_f32)
+            .build()?;
+        let mut user_data = UserData::default();
+        user_data.record_entries(&[Candidate::new("わたし", "私", 0_f32)]);
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(user_data)),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+        let lattice = graph_builder.construct("わたし", &graph);
+        let resolver = GraphResolver::default();
+
+        let got = resolver.resolve_k_best(&lattice, 5)?;
+        let surfaces = got.iter().map(|it| it.surface()).collect::<Vec<_>>();
+        info!("Got: {:?}", surfaces);
+
+        // 1-best は resolve と同じ結果になる。
+        assert_eq!(surfaces[0], "私");
+        assert!(surfaces.len() > 1, "{:?}", surfaces);
+        assert!(surfaces.len() <= 5);
+        // 同一の表層文字列は重複しない。
+        assert_eq!(
+            surfaces.iter().collect::<HashSet<_>>().len(),
+            surfaces.len()
+        );
+        // コストの小さい順に並ぶ。
+        for w in got.windows(2) {
+            assert!(w[0].cost <= w[1].cost, "{:?}", got);
+        }
+        // 各経路の読みを連結すると、入力の読みに戻る。
+        for path in &got {
+            let yomi = path
+                .candidates
+                .iter()
+                .map(|it| it.yomi.as_str())
+                .collect::<String>();
+            assert_eq!(yomi, "わたし");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trigram_backoff() -> Result<()> {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        // 「は/わ」のように、直前の二単語を見ないと決められないケースを模している。
+        let kana_trie = CedarwoodKanaTrie::build(Vec::from([
+            "き".to_string(),
+            "わ".to_string(),
+            "た".to_string(),
+        ]));
+        let segmenter = Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]);
+        let graph = segmenter.build("きわた", None);
+
+        let dict = HashMap::from([
+            ("き".to_string(), vec!["木".to_string()]),
+            ("わ".to_string(), vec!["は".to_string(), "輪".to_string()]),
+            ("た".to_string(), vec!["田".to_string()]),
+        ]);
+
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        unigram_builder.add("木/き", 1_f32);
+        unigram_builder.add("は/わ", 1_f32);
+        unigram_builder.add("輪/わ", 2_f32);
+        unigram_builder.add("田/た", 1_f32);
+        let system_unigram_lm = unigram_builder
+            .set_unique_words(19)
+            .set_total_words(20)
+            .build();
+        let (ki, _) = system_unigram_lm.find("木/き").unwrap();
+        let (ha, _) = system_unigram_lm.find("は/わ").unwrap();
+        let (wa, _) = system_unigram_lm.find("輪/わ").unwrap();
+        let (ta, _) = system_unigram_lm.find("田/た").unwrap();
+
+        let system_bigram_lm = MarisaSystemBigramLMBuilder::default()
+            .set_default_edge_cost(5_f32)
+            .build()?;
+        let mut graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+        let resolver = GraphResolver::default();
+        // resolve の各文節の先頭候補は、その文節までのコストで並ぶので、経路全体は k-best で確認する。
+        let best = |lattice: &LatticeGraph<_, _>| -> Result<String> {
+            Ok(resolver.resolve_k_best(lattice, 1)?[0].surface())
+        };
+
+        // trigram がなければ、unigram のコストが安い「は」が選ばれる。
+        let lattice = graph_builder.construct("きわた", &graph);
+        assert_eq!(best(&lattice)?, "木は田");
+
+        // 木/輪/田 の trigram があれば、そちらが選ばれる。
+        let mut trigram_builder = MarisaSystemTrigramLMBuilder::default();
+        trigram_builder.add(ki, wa, ta, 0_f32);
+        graph_builder.set_system_trigram_lm(Arc::new(trigram_builder.build()?));
+        let lattice = graph_builder.construct("きわた", &graph);
+        assert_eq!(best(&lattice)?, "木輪田");
+
+        // k-best の経路は trigram のコストで並べなおされ、重複しない。
+        let got = resolver.resolve_k_best(&lattice, 3)?;
+        let surfaces = got.iter().map(|it| it.surface()).collect::<Vec<_>>();
+        assert_eq!(surfaces[0], "木輪田");
+        assert_eq!(
+            surfaces.iter().collect::<HashSet<_>>().len(),
+            surfaces.len()
+        );
+        assert_eq!(surfaces[1], "木は田");
+        for w in got.windows(2) {
+            assert!(w[0].cost <= w[1].cost, "{:?}", got);
+        }
+        // 最良の経路のコストは、trigram を含めた前向きの計算のコストと一致する。
+        let viterbi_table = GraphResolver::forward(&lattice)?;
+        assert_eq!(got[0].cost, viterbi_table.costs[lattice.eos_id()]);
+
+        // trigram がなくても、文脈 (木, は) のバックオフコストが大きければ「輪」が選ばれる。
+        let mut trigram_builder = MarisaSystemTrigramLMBuilder::default();
+        trigram_builder.add_backoff(ki, ha, 10_f32);
+        graph_builder.set_system_trigram_lm(Arc::new(trigram_builder.build()?));
+        let lattice = graph_builder.construct("きわた", &graph);
+        assert_eq!(best(&lattice)?, "木輪田");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bigram_backoff() -> Result<()> {
+        let kana_trie = CedarwoodKanaTrie::build(Vec::from(["き".to_string(), "た".to_string()]));
+        let segmenter = Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]);
+        let graph = segmenter.build("きた", None);
+
+        let dict = HashMap::from([
+            ("き".to_string(), vec!["木".to_string(), "気".to_string()]),
+            ("た".to_string(), vec!["田".to_string()]),
+        ]);
+
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        unigram_builder.add_with_backoff(
+            "木/き",
+            1_f32,
+            UnigramBackoff {
+                backoff: 0.5,
+                continuation: 3.0,
+            },
+        );
+        unigram_builder.add("気/き", 1_f32);
+        unigram_builder.add_with_backoff(
+            "田/た",
+            2_f32,
+            UnigramBackoff {
+                backoff: 4.0,
+                continuation: 1.0,
+            },
+        );
+        let system_unigram_lm = unigram_builder
+            .set_unique_words(19)
+            .set_total_words(20)
+            .build();
+        let system_bigram_lm = MarisaSystemBigramLMBuilder::default()
+            .set_default_edge_cost(20_f32)
+            .build()?;
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+        let lattice = graph_builder.construct("きた", &graph);
+        let find = |surface: &str| {
+            lattice
+                .nodes
+                .iter()
+                .position(|it| it.surface == surface)
+                .unwrap()
+        };
+
+        // バックオフコストがある単語からのエッジは、バックオフコスト + 継続コストになる。
+        assert_eq!(lattice.get_edge_cost(find("木"), find("田")), 1.5_f32);
+        // バックオフコストがなければ、デフォルトのエッジコストになる。
+        assert_eq!(lattice.get_edge_cost(find("気"), find("田")), 20_f32);
+
+        let resolver = GraphResolver::default();
+        assert_eq!(resolver.resolve_k_best(&lattice, 1)?[0].surface(), "木田");
+        Ok(())
+    }
+
+    #[test]
+    fn test_left_context() -> Result<()> {
+        let kana_trie = CedarwoodKanaTrie::build(Vec::from(["き".to_string()]));
+        let segmenter = Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]);
+        let graph = segmenter.build("き", None);
+
+        let dict = HashMap::from([("き".to_string(), vec!["木".to_string(), "気".to_string()])]);
+
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        unigram_builder.add("元/げん", 1_f32);
+        unigram_builder.add("木/き", 1_f32);
+        unigram_builder.add("気/き", 2_f32);
+        let system_unigram_lm = unigram_builder
+            .set_unique_words(19)
+            .set_total_words(20)
+            .build();
+        let (gen, _) = system_unigram_lm.find("元/げん").unwrap();
+        let (ki, _) = system_unigram_lm.find("気/き").unwrap();
+
+        let mut system_bigram_lm_builder = MarisaSystemBigramLMBuilder::default();
+        system_bigram_lm_builder.add(gen, ki, 0_f32);
+        let system_bigram_lm = system_bigram_lm_builder
+            .set_default_edge_cost(5_f32)
+            .build()?;
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+        let resolver = GraphResolver::default();
+
+        // 文脈がなければ、unigram のコストが安い「木」になる。
+        let lattice = graph_builder.construct("き", &graph);
+        assert_eq!(resolver.resolve_k_best(&lattice, 1)?[0].surface(), "木");
+
+        // 直前に「元」を確定していれば、「元気」の bigram が効く。
+        let lattice = graph_builder.construct_with_context(
+            &[Candidate::new("げん", "元", 0_f32)],
+            "き",
+            &graph,
+        );
+        assert_eq!(resolver.resolve_k_best(&lattice, 1)?[0].surface(), "気");
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_incremental() -> Result<()> {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let kana_trie = CedarwoodKanaTrie::build(Vec::from([
+            "わたし".to_string(),
+            "わた".to_string(),
+            "し".to_string(),
+            "の".to_string(),
+            "なまえ".to_string(),
+            "なま".to_string(),
+            "え".to_string(),
+        ]));
+        let segmenter = Segmenter::new(vec![Arc::new(Mutex::new(kana_trie))]);
+
+        let dict = HashMap::from([
+            ("わたし".to_string(), vec!["私".to_string()]),
+            ("わた".to_string(), vec!["綿".to_string()]),
+            ("なまえ".to_string(), vec!["名前".to_string()]),
+            ("なま".to_string(), vec!["生".to_string()]),
+            ("え".to_string(), vec!["絵".to_string()]),
+        ]);
+        let mut unigram_builder = MarisaSystemUnigramLMBuilder::default();
+        unigram_builder.add("私/わたし", 1_f32);
+        unigram_builder.add("綿/わた", 3_f32);
+        unigram_builder.add("の/の", 0.5_f32);
+        unigram_builder.add("名前/なまえ", 1.5_f32);
+        unigram_builder.add("生/なま", 2.5_f32);
+        unigram_builder.add("絵/え", 3.5_f32);
+        let system_unigram_lm = unigram_builder
+            .set_unique_words(19)
+            .set_total_words(20)
+            .build();
+        let system_bigram_lm = MarisaSystemBigramLMBuilder::default()
+            .set_default_edge_cost(5_f32)
+            .build()?;
+        let graph_builder = GraphBuilder::new(
+            HashmapVecKanaKanjiDict::new(dict),
+            HashmapVecKanaKanjiDict::new(HashMap::new()),
+            Arc::new(Mutex::new(UserData::default())),
+            Arc::new(system_unigram_lm),
+            Arc::new(system_bigram_lm),
+        );
+        let resolver = GraphResolver::default();
+        let surfaces = |clauses: Vec<Vec<Candidate>>| -> Vec<String> {
+            clauses.iter().map(|it| it[0].surface.clone()).collect()
+        };
+
+        // 一文字ずつ入力してから、バックスペースで消した場合に、毎回作りなおした場合と同じ結果になること。
+        let inputs = [
+            "わ",
+            "わた",
+            "わたし",
+            "わたしの",
+            "わたしのな",
+            "わたしのなま",
+            "わたしのなまえ",
+            "わたしのなま",
+            "わたしの",
+        ];
+        let mut prev = segmenter.build(inputs[0], None);
+        let mut prev_lattice = graph_builder.construct(inputs[0], &prev);
+        let mut prev_table = GraphResolver::forward(&prev_lattice)?;
+        for yomi in &inputs[1..] {
+            let words_ends_at = segmenter.build(yomi, None);
+            let (lattice, stable_until) =
+                graph_builder.construct_incremental(&prev_lattice, &prev, yomi, &words_ends_at);
+            let table = GraphResolver::forward_incremental(&lattice, &prev_table, stable_until)?;
+            let expected = resolver.resolve(&graph_builder.construct(yomi, &words_ends_at))?;
+            assert_eq!(
+                surfaces(resolver.resolve_with_table(&lattice, &table)?),
+                surfaces(expected),
+                "yomi={yomi}"
+            );
+
+            prev = words_ends_at;
+            prev_lattice = lattice;
+            prev_table = table;
+        }
+        assert_eq!(
+            surfaces(resolver.resolve_with_table(&prev_lattice, &prev_table)?),
+            vec!["私", "の"]
+        );
+
+        // 「わたしの」の後ろに文字を足した場合、「の」までのノードは再利用される。
+        let words_ends_at = segmenter.build("わたしのな", None);
+        let (_, stable_until) =
+            graph_builder.construct_incremental(&prev_lattice, &prev, "わたしのな", &words_ends_at);
+        assert_eq!(stable_until, "わたしの".len() as i32);
+        Ok(())
+    }
 }
@@ -1,51 +1,167 @@
 use std::collections::btree_map::BTreeMap;
 use std::fmt::{Debug, Formatter};
-use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
-use log::{error, info, trace};
+use log::{info, trace};
 
 use crate::graph::word_node::WordNode;
-use crate::lm::base::{SystemBigramLM, SystemUnigramLM};
+use crate::lm::base::{SystemBigramLM, SystemTrigramLM, SystemUnigramLM};
 use crate::user_side_data::user_data::UserData;
 
+/// ラティス中のノードの ID。LatticeGraph の nodes の添字。
+pub type NodeId = usize;
+
+/// BOS は常に先頭のノードになる。
+pub const BOS_ID: NodeId = 0;
+
+/// 直前のノードからのエッジ。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Edge {
+    pub(crate) prev: NodeId,
+    /// bigram までで計算したエッジコスト
+    pub(crate) cost: f32,
+    /// ユーザーの bigram 統計によるコストか。その場合は trigram よりも優先する。
+    pub(crate) user: bool,
+}
+
 // 考えられる単語の列全てを含むようなグラフ構造
+// ノードは一つの Vec に終了位置の順に並べて、NodeId で参照する。
+// ノードのコストと、直前のノードからのエッジのコストは、構築時に計算しておく。
 pub struct LatticeGraph<U: SystemUnigramLM, B: SystemBigramLM> {
     pub(crate) yomi: String,
-    pub(crate) graph: BTreeMap<i32, Vec<WordNode>>,
+    pub(crate) nodes: Vec<WordNode>,
+    /// 終了位置 -> その位置で終わるノード
+    ends_at: Vec<Vec<NodeId>>,
+    /// NodeId -> ノードのコスト
+    node_costs: Vec<f32>,
+    /// NodeId -> 直前のノードからのエッジ
+    edges: Vec<Vec<Edge>>,
     pub(crate) user_data: Arc<Mutex<UserData>>,
-    pub(crate) system_unigram_lm: Rc<U>,
-    pub(crate) system_bigram_lm: Rc<B>,
+    pub(crate) system_unigram_lm: Arc<U>,
+    pub(crate) system_bigram_lm: Arc<B>,
+    pub(crate) system_trigram_lm: Option<Arc<dyn SystemTrigramLM + Send + Sync>>,
+    /// 変換対象の左側にある単語列。直前に確定した単語など。
+    /// BOS からのエッジは、これらの単語からのエッジとして扱う。
+    pub(crate) left_context: Vec<WordNode>,
 }
 
 impl<U: SystemUnigramLM, B: SystemBigramLM> Debug for LatticeGraph<U, B> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "LatticeGraph(yomi={}, graph={:?})",
-            self.yomi, self.graph
+            "LatticeGraph(yomi={}, nodes={:?})",
+            self.yomi, self.nodes
         )
     }
 }
 
 impl<U: SystemUnigramLM, B: SystemBigramLM> LatticeGraph<U, B> {
+    /// graph は、終了位置 -> その位置で終わるノードのリスト。
+    /// reuse に前回のラティスと、前回とノードが同一である最後の終了位置を渡すと、
+    /// そこまでのノードのコストは前回の計算結果を使う。
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        yomi: &str,
+        graph: BTreeMap<i32, Vec<WordNode>>,
+        user_data: Arc<Mutex<UserData>>,
+        system_unigram_lm: Arc<U>,
+        system_bigram_lm: Arc<B>,
+        system_trigram_lm: Option<Arc<dyn SystemTrigramLM + Send + Sync>>,
+        left_context: Vec<WordNode>,
+        reuse: Option<(&LatticeGraph<U, B>, i32)>,
+    ) -> LatticeGraph<U, B> {
+        let mut nodes: Vec<WordNode> = Vec::new();
+        let mut ends_at: Vec<Vec<NodeId>> = vec![Vec::new(); yomi.len() + 2];
+        for (end_pos, word_nodes) in graph {
+            for node in word_nodes {
+                ends_at[end_pos as usize].push(nodes.len());
+                nodes.push(node);
+            }
+        }
+
+        let mut lattice = LatticeGraph {
+            yomi: yomi.to_string(),
+            nodes,
+            ends_at,
+            node_costs: Vec::new(),
+            edges: Vec::new(),
+            user_data,
+            system_unigram_lm,
+            system_bigram_lm,
+            system_trigram_lm,
+            left_context,
+        };
+
+        let (mut node_costs, mut edges) = match reuse {
+            Some((prev, stable_until)) => {
+                let n = lattice.nodes_until(stable_until).min(prev.nodes.len());
+                (prev.node_costs[..n].to_vec(), prev.edges[..n].to_vec())
+            }
+            None => (Vec::new(), Vec::new()),
+        };
+        {
+            let user_data = lattice.user_data.lock().unwrap();
+            for id in node_costs.len()..lattice.nodes.len() {
+                node_costs.push(lattice.calc_node_cost(&user_data, &lattice.nodes[id]));
+            }
+            for id in edges.len()..lattice.nodes.len() {
+                edges.push(lattice.calc_edges(&user_data, id));
+            }
+        }
+        lattice.node_costs = node_costs;
+        lattice.edges = edges;
+        lattice
+    }
+
     /// i文字目で終わるノードを探す
-    pub fn node_list(&self, end_pos: i32) -> Option<&Vec<WordNode>> {
-        self.graph.get(&end_pos)
+    pub fn node_list(&self, end_pos: i32) -> Option<Vec<&WordNode>> {
+        let ids = self.node_ids(end_pos);
+        if ids.is_empty() {
+            None
+        } else {
+            Some(ids.iter().map(|id| &self.nodes[*id]).collect())
+        }
+    }
+
+    /// end_pos で終わるノードの ID
+    pub fn node_ids(&self, end_pos: i32) -> &[NodeId] {
+        if end_pos < 0 {
+            return &[];
+        }
+        self.ends_at
+            .get(end_pos as usize)
+            .map(|it| it.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn node(&self, id: NodeId) -> &WordNode {
+        &self.nodes[id]
+    }
+
+    pub fn eos_id(&self) -> NodeId {
+        self.nodes.len() - 1
+    }
+
+    /// end_pos までに終わるノードの数。ノードは終了位置の順に並んでいるので、
+    /// ID がこの値より小さいノードが end_pos までに終わるノードになる。
+    pub(crate) fn nodes_until(&self, end_pos: i32) -> usize {
+        if end_pos < 0 {
+            return 0;
+        }
+        self.ends_at
+            .iter()
+            .take(end_pos as usize + 1)
+            .map(|it| it.len())
+            .sum()
     }
 
     // -1  0  1 2
     // BOS わ た し
     //     [  ][ ]
     //     [     ]
-    pub(crate) fn get_prev_nodes(&self, node: &WordNode) -> Option<&Vec<WordNode>> {
-        // ここの処理を簡単にするために BOS が入っている、のだとおもう。
-        trace!("get_prev_nodes: {}", node.start_pos - 1);
-        self.graph.get(&(node.start_pos))
-    }
-
-    pub(crate) fn get(&self, n: i32) -> Option<&Vec<WordNode>> {
-        return self.graph.get(&n);
+    /// node の直前のノードからのエッジ
+    pub(crate) fn edges(&self, id: NodeId) -> &[Edge] {
+        &self.edges[id]
     }
 
     // for debugging purpose
@@ -54,8 +170,9 @@ impl<U: SystemUnigramLM, B: SystemBigramLM> LatticeGraph<U, B> {
         let mut buf = String::new();
         buf += "digraph Lattice {\n";
         // start 及び end は、byte 数単位
-        for (end_pos, nodes) in self.graph.iter() {
-            for node in nodes {
+        for (end_pos, ids) in self.ends_at.iter().enumerate() {
+            for id in ids {
+                let node = &self.nodes[*id];
                 buf += &*format!(
                     r#"    {} -> "{}/{}"{}"#,
                     node.start_pos, node.surface, node.yomi, "\n"
@@ -86,32 +203,27 @@ impl<U: SystemUnigramLM, B: SystemBigramLM> LatticeGraph<U, B> {
         buf += "digraph Lattice {\n";
 
         // start 及び end は、byte 数単位
-        for (end_pos, nodes) in self.graph.iter() {
-            for node in nodes {
-                if Self::is_match(node.surface.as_str(), expected) {
-                    buf += &*format!(
-                        r#"    "{}/{}" [xlabel="{}"]{}"#,
-                        node.surface,
-                        node.yomi,
-                        self.get_node_cost(node),
-                        "\n"
-                    );
-                    if let Some(prev_nodes) = self.get_prev_nodes(node) {
-                        for prev_node in prev_nodes {
-                            if Self::is_match(prev_node.surface.as_str(), expected) {
-                                buf += &*format!(
-                                    r#"    "{}/{}" -> "{}/{}" [label="{}"]{}"#,
-                                    prev_node.surface,
-                                    prev_node.yomi,
-                                    node.surface,
-                                    node.yomi,
-                                    self.get_edge_cost(prev_node, node),
-                                    "\n"
-                                );
-                            }
-                        }
-                    } else {
-                        error!("Missing previous nodes for {}", node);
+        for (id, node) in self.nodes.iter().enumerate() {
+            if Self::is_match(node.surface.as_str(), expected) {
+                buf += &*format!(
+                    r#"    "{}/{}" [xlabel="{}"]{}"#,
+                    node.surface,
+                    node.yomi,
+                    self.get_node_cost(id),
+                    "\n"
+                );
+                for edge in self.edges(id) {
+                    let prev_node = &self.nodes[edge.prev];
+                    if Self::is_match(prev_node.surface.as_str(), expected) {
+                        buf += &*format!(
+                            r#"    "{}/{}" -> "{}/{}" [label="{}"]{}"#,
+                            prev_node.surface,
+                            prev_node.yomi,
+                            node.surface,
+                            node.yomi,
+                            edge.cost,
+                            "\n"
+                        );
                     }
                 }
             }
@@ -120,13 +232,22 @@ impl<U: SystemUnigramLM, B: SystemBigramLM> LatticeGraph<U, B> {
         buf
     }
 
-    pub(crate) fn get_node_cost(&self, node: &WordNode) -> f32 {
-        if let Some(user_cost) = self.user_data.lock().unwrap().get_unigram_cost(node) {
+    pub(crate) fn get_node_cost(&self, id: NodeId) -> f32 {
+        self.node_costs[id]
+    }
+
+    fn calc_node_cost(&self, user_data: &UserData, node: &WordNode) -> f32 {
+        let system_cost = self.calc_system_node_cost(node);
+        if let Some(user_cost) = user_data.get_unigram_cost(node) {
             info!("Use user's node score: {:?}", node);
             // use user's score. if it's exists.
-            return user_cost;
+            // 長く使っていなくて減衰したコストが、学習していない場合より高くならないようにする。
+            return user_cost.min(system_cost);
         }
+        system_cost
+    }
 
+    fn calc_system_node_cost(&self, node: &WordNode) -> f32 {
         return if let Some((_, system_unigram_cost)) = node.word_id_and_score {
             trace!("HIT!: {}, {}", node.key(), system_unigram_cost);
             system_unigram_cost
@@ -140,24 +261,132 @@ impl<U: SystemUnigramLM, B: SystemBigramLM> LatticeGraph<U, B> {
         };
     }
 
-    pub(crate) fn get_edge_cost(&self, prev: &WordNode, node: &WordNode) -> f32 {
+    fn calc_edges(&self, user_data: &UserData, id: NodeId) -> Vec<Edge> {
+        let node = &self.nodes[id];
+        if id == BOS_ID {
+            return Vec::new();
+        }
+        self.node_ids(node.start_pos)
+            .iter()
+            .map(|prev_id| {
+                let mut prev = &self.nodes[*prev_id];
+                if prev.is_bos() {
+                    // 文頭からのエッジは、左側の文脈の最後の単語からのエッジとみなす。
+                    if let Some(last) = self.left_context.last() {
+                        prev = last;
+                    }
+                }
+                let system_cost = self.get_system_edge_cost(prev, node);
+                match user_data.get_bigram_cost(prev, node) {
+                    Some(cost) => Edge {
+                        prev: *prev_id,
+                        cost: cost.min(system_cost),
+                        user: true,
+                    },
+                    None => Edge {
+                        prev: *prev_id,
+                        cost: system_cost,
+                        user: false,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// prev から node へのエッジコストを得る。
+    pub(crate) fn get_edge_cost(&self, prev: NodeId, node: NodeId) -> f32 {
+        if let Some(edge) = self.edges(node).iter().find(|edge| edge.prev == prev) {
+            return edge.cost;
+        }
+
+        // 隣接していないノードの場合は、その場で計算する。
+        let (prev, node) = (&self.nodes[prev], &self.nodes[node]);
+        let system_cost = self.get_system_edge_cost(prev, node);
         if let Some(cost) = self.user_data.lock().unwrap().get_bigram_cost(prev, node) {
-            return cost;
+            return cost.min(system_cost);
         }
+        system_cost
+    }
 
+    fn get_system_edge_cost(&self, prev: &WordNode, node: &WordNode) -> f32 {
         let Some((prev_id, _)) = prev.word_id_and_score else {
             return self.system_bigram_lm.get_default_edge_cost();
         };
-        let Some((node_id, _)) = node.word_id_and_score else {
+        let Some((node_id, node_cost)) = node.word_id_and_score else {
             return self.system_bigram_lm.get_default_edge_cost();
         };
         if let Some(cost) = self.system_bigram_lm.get_edge_cost(prev_id, node_id) {
             cost
+        } else if let Some(prev_backoff) = self.system_unigram_lm.find_backoff(&prev.key()) {
+            // 未知の bigram は、直前の単語のバックオフコストと、単語の継続コストから見積もる。
+            // 継続コストがない単語は、unigram コストで代用する。
+            prev_backoff.backoff
+                + self
+                    .system_unigram_lm
+                    .find_backoff(&node.key())
+                    .map_or(node_cost, |it| it.continuation)
         } else {
             self.system_bigram_lm.get_default_edge_cost()
         }
     }
 
+    pub(crate) fn has_trigram_lm(&self) -> bool {
+        self.system_trigram_lm.is_some()
+    }
+
+    /// 直前の二単語を考慮したエッジコストを得る。
+    /// prev_prev は、edge.prev までの経路における直前のノード。
+    /// trigram 言語モデルにエントリーがない場合は、文脈のバックオフコストを足して bigram のコストにバックオフする。
+    pub(crate) fn get_trigram_edge_cost(
+        &self,
+        prev_prev: Option<NodeId>,
+        edge: &Edge,
+        node: NodeId,
+    ) -> f32 {
+        // ユーザーの bigram スコアがある場合はそちらを優先する。
+        if edge.user || self.system_trigram_lm.is_none() {
+            return edge.cost;
+        }
+
+        let prev = &self.nodes[edge.prev];
+        let (prev_prev, prev) = if prev.is_bos() {
+            // 文頭からのエッジは、左側の文脈の最後の単語からのエッジとみなす。
+            let Some((last, rest)) = self.left_context.split_last() else {
+                return edge.cost;
+            };
+            (rest.last(), last)
+        } else {
+            let prev_prev = match prev_prev.map(|id| &self.nodes[id]) {
+                Some(prev_prev) if prev_prev.is_bos() => self.left_context.last(),
+                prev_prev => prev_prev,
+            };
+            (prev_prev, prev)
+        };
+
+        let (Some(trigram_lm), Some((prev_prev_id, _)), Some((prev_id, _))) = (
+            self.system_trigram_lm.as_ref(),
+            prev_prev.and_then(|it| it.word_id_and_score),
+            prev.word_id_and_score,
+        ) else {
+            return edge.cost;
+        };
+        if let Some((node_id, _)) = self.nodes[node].word_id_and_score {
+            if let Some(cost) = trigram_lm.get_edge_cost(prev_prev_id, prev_id, node_id) {
+                trace!(
+                    "Trigram HIT!: {:?} {} {}",
+                    prev_prev,
+                    prev,
+                    self.nodes[node]
+                );
+                return cost;
+            }
+        }
+        match trigram_lm.get_backoff_cost(prev_prev_id, prev_id) {
+            Some(backoff) => backoff + edge.cost,
+            None => edge.cost,
+        }
+    }
+
     pub fn get_default_edge_cost(&self) -> f32 {
         self.system_bigram_lm.get_default_edge_cost()
     }
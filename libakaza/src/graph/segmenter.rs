@@ -22,6 +22,11 @@ impl SegmentationResult {
         self.base.iter()
     }
 
+    /// end_pos で終わる読みのリスト
+    pub(crate) fn get(&self, end_pos: usize) -> Option<&Vec<String>> {
+        self.base.get(&end_pos)
+    }
+
     pub fn dump_dot(&self) -> String {
         let mut buf = String::new();
         buf += "digraph Lattice {\n";
@@ -38,12 +43,12 @@ impl SegmentationResult {
 }
 
 pub struct Segmenter {
-    tries: Vec<Arc<Mutex<dyn KanaTrie>>>,
+    tries: Vec<Arc<Mutex<dyn KanaTrie + Send>>>,
     number_pattern: Regex,
 }
 
 impl Segmenter {
-    pub fn new(tries: Vec<Arc<Mutex<dyn KanaTrie>>>) -> Segmenter {
+    pub fn new(tries: Vec<Arc<Mutex<dyn KanaTrie + Send>>>) -> Segmenter {
         info!("Registering tries for Segmenter: {}", tries.len());
         let number_pattern = Regex::new(r#"^(?:0|[1-9][0-9]*)(\.[0-9]*)?"#).unwrap();
         Segmenter {
@@ -52,6 +52,9 @@ impl WordNode {
             auto_generated: true,
         }
     }
+    pub(crate) fn is_bos(&self) -> bool {
+        self.start_pos == 0 && self.yomi == "__BOS__"
+    }
     pub(crate) fn create_eos(start_pos: i32) -> WordNode {
         WordNode {
             start_pos,
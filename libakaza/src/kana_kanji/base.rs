@@ -1,3 +1,6 @@
 pub trait KanaKanjiDict {
     fn get(&self, kana: &str) -> Option<Vec<String>>;
+
+    /// 読みが kana_prefix で始まるエントリーを、(読み, 表層のリスト) の形で一件ずつ callback に渡す。
+    fn predict(&self, kana_prefix: &str, callback: &mut dyn FnMut(&str, Vec<String>));
 }
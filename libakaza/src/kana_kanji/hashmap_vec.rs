@@ -17,4 +17,11 @@ impl KanaKanjiDict for HashmapVecKanaKanjiDict {
     fn get(&self, kana: &str) -> Option<Vec<String>> {
         self.map.get(kana).cloned()
     }
+
+    fn predict(&self, kana_prefix: &str, callback: &mut dyn FnMut(&str, Vec<String>)) {
+        self.map
+            .iter()
+            .filter(|(kana, _)| kana.starts_with(kana_prefix))
+            .for_each(|(kana, surfaces)| callback(kana, surfaces.clone()));
+    }
 }
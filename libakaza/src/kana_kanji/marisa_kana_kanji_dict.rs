@@ -103,6 +103,22 @@ impl KanaKanjiDict for MarisaKanaKanjiDict {
         trace!("Got result: {:?}, {:?}", kana, surfaces);
         Some(surfaces)
     }
+
+    fn predict(&self, kana_prefix: &str, callback: &mut dyn FnMut(&str, Vec<String>)) {
+        self.marisa
+            .predictive_search(kana_prefix.as_bytes(), |word, _| {
+                if !word.starts_with("__CACHE_SERIALIZED__\t".as_bytes()) {
+                    let idx = word.iter().position(|f| *f == b'\t').unwrap();
+                    let kana = String::from_utf8_lossy(&word[0..idx]);
+                    let surfaces = String::from_utf8_lossy(&word[idx + 1..word.len()])
+                        .split('/')
+                        .map(|it| it.to_string())
+                        .collect::<Vec<_>>();
+                    callback(&kana, surfaces);
+                }
+                true
+            });
+    }
 }
 
 #[cfg(test)]
@@ -126,4 +142,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_predict() -> anyhow::Result<()> {
+        let dict = MarisaKanaKanjiDict::build(HashMap::from([
+            ("たなか".to_string(), vec!["田中".to_string()]),
+            (
+                "たなばた".to_string(),
+                vec!["七夕".to_string(), "棚機".to_string()],
+            ),
+            ("なか".to_string(), vec!["中".to_string()]),
+        ]))?;
+
+        let mut got = Vec::new();
+        dict.predict("たな", &mut |kana, surfaces| {
+            got.push((kana.to_string(), surfaces))
+        });
+        got.sort();
+        assert_eq!(
+            got,
+            vec![
+                ("たなか".to_string(), vec!["田中".to_string()]),
+                (
+                    "たなばた".to_string(),
+                    vec!["七夕".to_string(), "棚機".to_string()]
+                ),
+            ]
+        );
+
+        Ok(())
+    }
 }
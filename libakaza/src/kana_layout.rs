@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::resource::find_table_path;
+
+/// evdev のキーコードの名前と値。かな配列で使う、JP106 キーボードの文字キーだけ。
+const EVDEV_KEYCODES: &[(&str, u32)] = &[
+    ("KEY_1", 2),
+    ("KEY_2", 3),
+    ("KEY_3", 4),
+    ("KEY_4", 5),
+    ("KEY_5", 6),
+    ("KEY_6", 7),
+    ("KEY_7", 8),
+    ("KEY_8", 9),
+    ("KEY_9", 10),
+    ("KEY_0", 11),
+    ("KEY_MINUS", 12),
+    ("KEY_EQUAL", 13),
+    ("KEY_Q", 16),
+    ("KEY_W", 17),
+    ("KEY_E", 18),
+    ("KEY_R", 19),
+    ("KEY_T", 20),
+    ("KEY_Y", 21),
+    ("KEY_U", 22),
+    ("KEY_I", 23),
+    ("KEY_O", 24),
+    ("KEY_P", 25),
+    ("KEY_LEFTBRACE", 26),
+    ("KEY_RIGHTBRACE", 27),
+    ("KEY_A", 30),
+    ("KEY_S", 31),
+    ("KEY_D", 32),
+    ("KEY_F", 33),
+    ("KEY_G", 34),
+    ("KEY_H", 35),
+    ("KEY_J", 36),
+    ("KEY_K", 37),
+    ("KEY_L", 38),
+    ("KEY_SEMICOLON", 39),
+    ("KEY_APOSTROPHE", 40),
+    ("KEY_GRAVE", 41),
+    ("KEY_BACKSLASH", 43),
+    ("KEY_Z", 44),
+    ("KEY_X", 45),
+    ("KEY_C", 46),
+    ("KEY_V", 47),
+    ("KEY_B", 48),
+    ("KEY_N", 49),
+    ("KEY_M", 50),
+    ("KEY_COMMA", 51),
+    ("KEY_DOT", 52),
+    ("KEY_SLASH", 53),
+    ("KEY_SPACE", 57),
+    ("KEY_RO", 89),
+    ("KEY_YEN", 124),
+];
+
+/// シフトなし・シフトありで打ったときの文字。
+pub type KanaLayoutChars = (Option<String>, Option<String>);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct KanaLayoutConfig {
+    keys: HashMap<String, KanaLayoutChars>,
+}
+
+/// キーボードの物理的なキーの位置(キーコード)で、かなを入力するための配列。
+///
+/// keyval では、JP106 キーボードの二つの "\" キーのように、同じ keyval になるキーを区別できないので、
+/// キーコードとシフトキーの状態から文字を決める。
+#[derive(Debug)]
+pub struct KanaLayout {
+    keys: HashMap<u32, KanaLayoutChars>,
+}
+
+impl KanaLayout {
+    /// layout は "jis" のような配列の名前か、ファイルのパス。
+    pub fn load(layout: &str) -> anyhow::Result<KanaLayout> {
+        let path = find_table_path("kana_layout", layout)?;
+        info!("Loading kana layout: {}", path);
+        let got: KanaLayoutConfig = serde_yaml::from_reader(BufReader::new(
+            File::open(&path).with_context(|| path.clone())?,
+        ))
+        .with_context(|| format!("Cannot parse {path}"))?;
+
+        let mut keys = HashMap::new();
+        for (name, chars) in got.keys {
+            let Some((_, keycode)) = EVDEV_KEYCODES.iter().find(|(it, _)| *it == name) else {
+                bail!("Unknown key name '{}' in {}", name, path);
+            };
+            keys.insert(*keycode, chars);
+        }
+        Ok(KanaLayout { keys })
+    }
+
+    /// keycode は evdev のキーコード。割り当てがなければ None を返す。
+    pub fn get(&self, keycode: u32, shift: bool) -> Option<&str> {
+        let (normal, shifted) = self.keys.get(&keycode)?;
+        if shift {
+            shifted.as_deref()
+        } else {
+            normal.as_deref()
+        }
+    }
+
+    /// キーマップより先に引く。割り当てのある文字キーなら、入力する文字を返す。
+    ///
+    /// 変換中の数字キーのように、キーマップにも割り当てのある文字キーでも、かなを入力する。
+    /// Control や Alt つきのキーと、キーシーケンスの途中のキーは、キーマップに任せるので None を返す。
+    pub fn get_before_keymap(
+        &self,
+        keycode: u32,
+        shift: bool,
+        control_or_alt: bool,
+        in_key_sequence: bool,
+    ) -> Option<&str> {
+        if control_or_alt || in_key_sequence {
+            return None;
+        }
+        self.get(keycode, shift)
+    }
+}
+
+const VOICED: &[(char, char)] = &[
+    ('う', 'ゔ'),
+    ('か', 'が'),
+    ('き', 'ぎ'),
+    ('く', 'ぐ'),
+    ('け', 'げ'),
+    ('こ', 'ご'),
+    ('さ', 'ざ'),
+    ('し', 'じ'),
+    ('す', 'ず'),
+    ('せ', 'ぜ'),
+    ('そ', 'ぞ'),
+    ('た', 'だ'),
+    ('ち', 'ぢ'),
+    ('つ', 'づ'),
+    ('て', 'で'),
+    ('と', 'ど'),
+    ('は', 'ば'),
+    ('ひ', 'び'),
+    ('ふ', 'ぶ'),
+    ('へ', 'べ'),
+    ('ほ', 'ぼ'),
+];
+
+const SEMI_VOICED: &[(char, char)] = &[
+    ('は', 'ぱ'),
+    ('ひ', 'ぴ'),
+    ('ふ', 'ぷ'),
+    ('へ', 'ぺ'),
+    ('ほ', 'ぽ'),
+];
+
+/// 直前の文字 prev に、濁点か半濁点の mark をつけた文字を返す。つけられなければ None。
+pub fn compose_voiced_mark(prev: char, mark: char) -> Option<char> {
+    let table = match mark {
+        '゛' => VOICED,
+        '゜' => SEMI_VOICED,
+        _ => return None,
+    };
+    table
+        .iter()
+        .find(|(base, _)| *base == prev)
+        .map(|(_, voiced)| *voiced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jis() -> anyhow::Result<()> {
+        let layout = KanaLayout::load("jis")?;
+        // どちらも keyval は backslash になるキー
+        assert_eq!(layout.get(124, false), Some("ー"));
+        assert_eq!(layout.get(89, false), Some("ろ"));
+        // シフトつき
+        assert_eq!(layout.get(8, false), Some("や"));
+        assert_eq!(layout.get(8, true), Some("ゃ"));
+        assert_eq!(layout.get(11, true), Some("を"));
+        assert_eq!(layout.get(16, true), None);
+        assert_eq!(layout.get(1, false), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_before_keymap() -> anyhow::Result<()> {
+        let layout = KanaLayout::load("jis")?;
+        // 変換中は "1" に候補の選択が割り当てられているが、かなの入力を優先する。
+        assert_eq!(layout.get_before_keymap(2, false, false, false), Some("ぬ"));
+        assert_eq!(layout.get_before_keymap(8, true, false, false), Some("ゃ"));
+        // 修飾キーつきのキーや、キーシーケンスの続きはキーマップで処理する。
+        assert_eq!(layout.get_before_keymap(2, false, true, false), None);
+        assert_eq!(layout.get_before_keymap(2, false, false, true), None);
+        // 割り当てのないキー (Esc) もキーマップで処理する。
+        assert_eq!(layout.get_before_keymap(1, false, false, false), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_voiced_mark() {
+        assert_eq!(compose_voiced_mark('か', '゛'), Some('が'));
+        assert_eq!(compose_voiced_mark('う', '゛'), Some('ゔ'));
+        assert_eq!(compose_voiced_mark('ほ', '゜'), Some('ぽ'));
+        assert_eq!(compose_voiced_mark('か', '゜'), None);
+        assert_eq!(compose_voiced_mark('あ', '゛'), None);
+    }
+}
@@ -38,6 +38,10 @@ impl CedarwoodKanaTrie {
         self.cedar.update(key, self.words.len() as i32);
         self.words.push(key.to_string());
     }
+
+    pub fn remove(&mut self, key: &str) {
+        self.cedar.erase(key);
+    }
 }
 
 impl KanaTrie for CedarwoodKanaTrie {
@@ -68,4 +72,12 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_remove() {
+        let mut trie = CedarwoodKanaTrie::build(vec!["わたし".to_string(), "わた".to_string()]);
+        trie.remove("わた");
+        assert!(!trie.contains("わた"));
+        assert_eq!(trie.common_prefix_search("わたしのきもち"), vec!("わたし"));
+    }
 }
@@ -1,8 +1,9 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::BufReader;
 
-use crate::resource::detect_resource_path;
+use crate::resource::{check_circular_extends, find_table_path, resolve_extends};
 use anyhow::{bail, Context, Result};
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -14,40 +15,78 @@ pub struct Keymap {
 }
 
 impl Keymap {
+    /// 状態ごとに分けたキーパターンと、コマンドの対応を作る。
+    /// 同じファイルで同じキーが二回定義されていたら、後のものを使う。
     fn to_map(&self) -> Result<HashMap<KeyPattern, Option<String>>> {
         let mut retval = HashMap::new();
 
         for kc in &self.keys {
             for key in &kc.key {
-                let (ctrl, shift, key) = Self::parse_key(key.as_str())?;
-
-                retval.insert(
-                    KeyPattern {
-                        states: kc.states.clone(),
-                        ctrl,
-                        shift,
-                        key,
-                    },
-                    kc.command.clone(),
-                );
+                let keys = Self::parse_key_sequence(key.as_str())?;
+                for state in &kc.states {
+                    retval.insert(
+                        KeyPattern {
+                            states: vec![*state],
+                            keys: keys.clone(),
+                        },
+                        kc.command.clone(),
+                    );
+                }
             }
         }
 
         Ok(retval)
     }
 
-    fn parse_key(key: &str) -> Result<(bool, bool, String)> {
+    /// extends で指定された、継承元のキーマップのパス。keymap_path はこのキーマップのパス。
+    pub(crate) fn parent_path(&self, keymap_path: &str) -> Result<Option<String>> {
+        let Some(parent) = &self.extends else {
+            return Ok(None);
+        };
+        Ok(Some(resolve_extends("keymap", parent, keymap_path)?))
+    }
+
+    /// "C-x C-k" のような、空白で区切ったキーの並びをパースする。
+    pub(crate) fn parse_key_sequence(key: &str) -> Result<Vec<KeyStroke>> {
+        let keys = key
+            .split_whitespace()
+            .map(Self::parse_key)
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            bail!("Empty key in keymap");
+        }
+        Ok(keys)
+    }
+
+    /// "C-S-h" のような文字列をパースする。修飾キーは Emacs と同じ書き方で、
+    /// C(Control), S(Shift), A または M(Alt), s(Super), H(Hyper) が使える。
+    fn parse_key(key: &str) -> Result<KeyStroke> {
+        let mut pattern = KeyStroke {
+            ctrl: false,
+            shift: false,
+            alt: false,
+            super_key: false,
+            hyper: false,
+            key: key.to_string(),
+        };
         if key.contains('-') {
-            let mut ctrl = false;
-            let mut shift = false;
             let keys = key.split('-').collect::<Vec<_>>();
             for m in &keys[0..keys.len() - 1] {
                 match *m {
                     "C" => {
-                        ctrl = true;
+                        pattern.ctrl = true;
                     }
                     "S" => {
-                        shift = true;
+                        pattern.shift = true;
+                    }
+                    "A" | "M" => {
+                        pattern.alt = true;
+                    }
+                    "s" => {
+                        pattern.super_key = true;
+                    }
+                    "H" => {
+                        pattern.hyper = true;
                     }
                     _ => {
                         bail!("Unknown modifier in keymap: {}", key);
@@ -55,10 +94,184 @@ impl Keymap {
                 }
             }
 
-            Ok((ctrl, shift, keys[keys.len() - 1].to_string()))
-        } else {
-            Ok((false, false, key.to_string()))
+            pattern.key = keys[keys.len() - 1].to_string();
         }
+        Ok(pattern)
+    }
+}
+
+/// 変換候補の一ページに表示する候補の数。select_candidate_in_page の引数の上限。
+pub const CANDIDATE_PAGE_SIZE: usize = 10;
+
+/// set_input_mode の引数に書ける入力モードの名前。
+pub const INPUT_MODE_NAMES: &[&str] = &[
+    "hiragana",
+    "katakana",
+    "halfwidth_katakana",
+    "alnum",
+    "fullwidth_alnum",
+];
+
+/// コマンドがとる引数の種類。
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CommandArg {
+    None,
+    /// INPUT_MODE_NAMES のどれか
+    InputMode,
+    /// 1 から CANDIDATE_PAGE_SIZE まで
+    IndexInPage,
+}
+
+/// 検証済みのコマンドの引数。
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CommandArgValue {
+    None,
+    /// INPUT_MODE_NAMES のどれか
+    InputMode(&'static str),
+    /// 現在のページの中の位置。0 から数える。
+    IndexInPage(usize),
+}
+
+/// キーマップに書けるコマンド。ibus-akaza の commands.rs で登録しているものと合わせること。
+/// 合っているかは、commands.rs のテストで確認している。
+const KEYMAP_COMMANDS: &[(&str, CommandArg)] = &[
+    ("commit_candidate", CommandArg::None),
+    ("commit_suggestion", CommandArg::None),
+    ("select_prev_suggestion", CommandArg::None),
+    ("select_next_suggestion", CommandArg::None),
+    ("commit_preedit", CommandArg::None),
+    ("reconvert", CommandArg::None),
+    ("escape", CommandArg::None),
+    ("page_up", CommandArg::None),
+    ("page_down", CommandArg::None),
+    ("update_candidates", CommandArg::None),
+    ("delete_candidate_from_history", CommandArg::None),
+    ("toggle_incognito", CommandArg::None),
+    ("erase_character_before_cursor", CommandArg::None),
+    ("erase_character_after_cursor", CommandArg::None),
+    ("cursor_up", CommandArg::None),
+    ("cursor_down", CommandArg::None),
+    ("cursor_right", CommandArg::None),
+    ("cursor_left", CommandArg::None),
+    ("cursor_home", CommandArg::None),
+    ("cursor_end", CommandArg::None),
+    ("extend_clause_right", CommandArg::None),
+    ("extend_clause_left", CommandArg::None),
+    ("convert_to_full_hiragana", CommandArg::None),
+    ("convert_to_full_katakana", CommandArg::None),
+    ("convert_to_half_katakana", CommandArg::None),
+    ("convert_to_full_romaji", CommandArg::None),
+    ("convert_to_half_romaji", CommandArg::None),
+    ("set_input_mode", CommandArg::InputMode),
+    ("select_candidate_in_page", CommandArg::IndexInPage),
+];
+
+/// 以前の名前のコマンドと、そのかわりのコマンド。古いキーマップもそのまま使えるように読み替える。
+const DEPRECATED_COMMANDS: &[(&str, &str)] = &[
+    ("set_input_mode_hiragana", "set_input_mode(hiragana)"),
+    ("set_input_mode_alnum", "set_input_mode(alnum)"),
+    (
+        "set_input_mode_fullwidth_alnum",
+        "set_input_mode(fullwidth_alnum)",
+    ),
+    ("set_input_mode_katakana", "set_input_mode(katakana)"),
+    (
+        "set_input_mode_halfwidth_katakana",
+        "set_input_mode(halfwidth_katakana)",
+    ),
+    ("press_number_1", "select_candidate_in_page(1)"),
+    ("press_number_2", "select_candidate_in_page(2)"),
+    ("press_number_3", "select_candidate_in_page(3)"),
+    ("press_number_4", "select_candidate_in_page(4)"),
+    ("press_number_5", "select_candidate_in_page(5)"),
+    ("press_number_6", "select_candidate_in_page(6)"),
+    ("press_number_7", "select_candidate_in_page(7)"),
+    ("press_number_8", "select_candidate_in_page(8)"),
+    ("press_number_9", "select_candidate_in_page(9)"),
+    ("press_number_0", "select_candidate_in_page(10)"),
+];
+
+/// キーマップに書かれたコマンド。`select_candidate_in_page(3)` のように引数を一つとれる。
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeymapCommand {
+    pub name: String,
+    pub arg: Option<String>,
+}
+
+impl KeymapCommand {
+    /// 以前の名前のコマンドなら、かわりに使うコマンドを返す。
+    pub fn deprecated_alias(command: &str) -> Option<&'static str> {
+        let command = command.trim();
+        DEPRECATED_COMMANDS
+            .iter()
+            .find(|(name, _)| *name == command)
+            .map(|(_, replacement)| *replacement)
+    }
+
+    /// キーマップに書けるコマンドの名前の一覧。takes_arg が true なら引数をとるもの、false ならとらないもの。
+    pub fn known_names(takes_arg: bool) -> Vec<&'static str> {
+        KEYMAP_COMMANDS
+            .iter()
+            .filter(|(_, arg_type)| (*arg_type != CommandArg::None) == takes_arg)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// 以前の名前のコマンドは、かわりのコマンドとして解釈する。
+    pub fn parse(command: &str) -> Result<KeymapCommand> {
+        if let Some(replacement) = Self::deprecated_alias(command) {
+            return Self::parse(replacement);
+        }
+        let command = command.trim();
+        let (name, arg) = match command.split_once('(') {
+            Some((name, rest)) => {
+                let Some(arg) = rest.strip_suffix(')') else {
+                    bail!("Missing ')' in command: {}", command);
+                };
+                let arg = arg.trim();
+                if arg.is_empty() || arg.contains(['(', ')', ',']) {
+                    bail!("Invalid argument in command: {}", command);
+                }
+                (name.trim(), Some(arg.to_string()))
+            }
+            None => (command, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid command name: {}", command);
+        }
+        Ok(KeymapCommand {
+            name: name.to_string(),
+            arg,
+        })
+    }
+
+    /// 存在するコマンドで、引数が正しいかを確認して、引数を解釈したものを返す。
+    pub fn validate(&self) -> Result<CommandArgValue> {
+        let Some((_, arg_type)) = KEYMAP_COMMANDS.iter().find(|(name, _)| *name == self.name)
+        else {
+            bail!("Unknown command: {}", self.name);
+        };
+        Ok(match (arg_type, &self.arg) {
+            (CommandArg::None, None) => CommandArgValue::None,
+            (CommandArg::None, Some(_)) => bail!("{} does not take an argument", self.name),
+            (_, None) => bail!("{} requires an argument", self.name),
+            (CommandArg::InputMode, Some(arg)) => {
+                match INPUT_MODE_NAMES.iter().find(|name| **name == arg) {
+                    Some(name) => CommandArgValue::InputMode(name),
+                    None => bail!(
+                        "Unknown input mode: {} (expected one of {})",
+                        arg,
+                        INPUT_MODE_NAMES.join(", ")
+                    ),
+                }
+            }
+            (CommandArg::IndexInPage, Some(arg)) => match arg.parse::<usize>() {
+                Ok(n) if (1..=CANDIDATE_PAGE_SIZE).contains(&n) => {
+                    CommandArgValue::IndexInPage(n - 1)
+                }
+                _ => bail!("Must be between 1 and {}: {}", CANDIDATE_PAGE_SIZE, arg),
+            },
+        })
     }
 }
 
@@ -73,14 +286,41 @@ pub struct KeyConfig {
 #[derive(PartialEq, Debug, Hash, Clone)]
 pub struct KeyPattern {
     pub states: Vec<KeyState>,
+    /// 順に押すキー。二つ以上なら、プレフィックスキーから始まるキーシーケンス。
+    pub keys: Vec<KeyStroke>,
+}
+
+impl Eq for KeyPattern {}
+
+/// 修飾キーと組み合わせた、一回分のキー入力。
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+pub struct KeyStroke {
     pub ctrl: bool,
     pub shift: bool,
+    pub alt: bool,
+    pub super_key: bool,
+    pub hyper: bool,
     pub key: String,
 }
 
-impl Eq for KeyPattern {}
+impl Display for KeyStroke {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (enabled, modifier) in [
+            (self.ctrl, "C-"),
+            (self.shift, "S-"),
+            (self.alt, "A-"),
+            (self.super_key, "s-"),
+            (self.hyper, "H-"),
+        ] {
+            if enabled {
+                f.write_str(modifier)?;
+            }
+        }
+        f.write_str(&self.key)
+    }
+}
 
-#[derive(Debug, Hash, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum KeyState {
     // 何も入力されていない状態。
     PreComposition,
@@ -91,35 +331,59 @@ pub enum KeyState {
 }
 
 impl Keymap {
-    pub fn load(keymap_path: &str) -> Result<HashMap<KeyPattern, String>> {
-        info!("Load {}", keymap_path);
-        let got: Keymap = serde_yaml::from_reader(BufReader::new(
-            File::open(keymap_path).with_context(|| keymap_path.to_string())?,
-        ))?;
-
-        if let Some(parent) = &got.extends {
-            let path = detect_resource_path("keymap", &format!("{parent}.yml"))?;
-            let mut map = Keymap::load(&path)?;
-
-            for (kp, opts) in &got.to_map()? {
+    /// キーマップを読み込む。返すキーパターンの states は、一つの状態だけを持つ。
+    /// keymap は "default" のようなキーマップの名前か、ファイルのパス。
+    pub fn load(keymap: &str) -> Result<HashMap<KeyPattern, String>> {
+        let mut map = HashMap::new();
+        for (_, got) in Self::load_chain(keymap)? {
+            for (kp, opts) in got.to_map()? {
                 if let Some(cmd) = opts {
                     // 親の値を上書き
-                    map.insert(kp.clone(), cmd.clone());
+                    map.insert(kp, cmd);
                 } else {
                     // null で親の値を消去できる。
-                    map.remove(kp);
+                    map.remove(&kp);
                 }
             }
+        }
+        Ok(map)
+    }
+
+    /// キーマップのファイルと、extends でたどれる継承元のファイルのパスを返す。設定の変更を監視するのに使う。
+    pub fn source_paths(keymap: &str) -> Result<Vec<String>> {
+        Ok(Self::load_chain(keymap)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect())
+    }
 
-            Ok(map)
-        } else {
-            let got = got
-                .to_map()?
-                .iter()
-                .map(|(a, b)| (a.clone(), b.clone().unwrap()))
-                .collect::<HashMap<KeyPattern, String>>();
-            Ok(got)
+    /// extends をたどって、継承元から順にキーマップとそのパスを読む。
+    pub(crate) fn load_chain(keymap: &str) -> Result<Vec<(String, Keymap)>> {
+        let mut chain: Vec<(String, Keymap)> = Vec::new();
+        let mut path = find_table_path("keymap", keymap)?;
+        loop {
+            info!("Load {}", path);
+            check_circular_extends(
+                &chain.iter().map(|(it, _)| it.clone()).collect::<Vec<_>>(),
+                &path,
+            )?;
+            let keymap = Self::read(&path)?;
+            let parent = keymap.parent_path(&path)?;
+            chain.push((path, keymap));
+            match parent {
+                Some(parent) => path = parent,
+                None => break,
+            }
         }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    pub(crate) fn read(keymap_path: &str) -> Result<Keymap> {
+        serde_yaml::from_reader(BufReader::new(
+            File::open(keymap_path).with_context(|| keymap_path.to_string())?,
+        ))
+        .with_context(|| format!("Cannot parse {keymap_path}"))
     }
 }
 
@@ -142,28 +406,156 @@ mod tests {
 
     #[test]
     fn test_c_h() -> Result<()> {
-        let (ctrl, shift, key) = Keymap::parse_key("C-h")?;
-        assert!(ctrl);
-        assert!(!shift);
-        assert_eq!(key, "h");
+        let pattern = Keymap::parse_key("C-h")?;
+        assert!(pattern.ctrl);
+        assert!(!pattern.shift);
+        assert_eq!(pattern.key, "h");
         Ok(())
     }
 
     #[test]
     fn test_c_s_h() -> Result<()> {
-        let (ctrl, shift, key) = Keymap::parse_key("C-S-h")?;
-        assert!(ctrl);
-        assert!(shift);
-        assert_eq!(key, "h");
+        let pattern = Keymap::parse_key("C-S-h")?;
+        assert!(pattern.ctrl);
+        assert!(pattern.shift);
+        assert_eq!(pattern.key, "h");
         Ok(())
     }
 
     #[test]
     fn test_shift() -> Result<()> {
-        let (ctrl, shift, key) = Keymap::parse_key("h")?;
-        assert!(!ctrl);
-        assert!(!shift);
-        assert_eq!(key, "h");
+        let pattern = Keymap::parse_key("h")?;
+        assert!(!pattern.ctrl);
+        assert!(!pattern.shift);
+        assert_eq!(pattern.key, "h");
+        Ok(())
+    }
+
+    #[test]
+    fn test_alt_super_hyper() -> Result<()> {
+        let pattern = Keymap::parse_key("A-space")?;
+        assert!(pattern.alt);
+        assert!(!pattern.super_key);
+        assert_eq!(pattern.key, "space");
+
+        // M- も Alt として扱う。
+        assert_eq!(Keymap::parse_key("M-space")?, pattern);
+
+        let pattern = Keymap::parse_key("s-H-S-j")?;
+        assert!(pattern.super_key);
+        assert!(pattern.hyper);
+        assert!(pattern.shift);
+        assert!(!pattern.alt);
+        assert!(!pattern.ctrl);
+        assert_eq!(pattern.key, "j");
+
+        assert!(Keymap::parse_key("X-h").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_sequence() -> Result<()> {
+        let keys = Keymap::parse_key_sequence("C-x  C-S-k")?;
+        assert_eq!(
+            keys.iter().map(|it| it.to_string()).collect::<Vec<_>>(),
+            vec!["C-x", "C-S-k"]
+        );
+
+        let keys = Keymap::parse_key_sequence("Muhenkan k")?;
+        assert_eq!(keys.len(), 2);
+        assert!(!keys[0].ctrl);
+        assert_eq!(keys[0].key, "Muhenkan");
+
+        assert!(Keymap::parse_key_sequence(" ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_command() -> Result<()> {
+        assert_eq!(
+            KeymapCommand::parse("commit_candidate")?,
+            KeymapCommand {
+                name: "commit_candidate".to_string(),
+                arg: None
+            }
+        );
+        assert_eq!(
+            KeymapCommand::parse("select_candidate_in_page( 3 )")?,
+            KeymapCommand {
+                name: "select_candidate_in_page".to_string(),
+                arg: Some("3".to_string())
+            }
+        );
+        assert!(KeymapCommand::parse("set_input_mode(katakana").is_err());
+        assert!(KeymapCommand::parse("set_input_mode()").is_err());
+        assert!(KeymapCommand::parse("set_input_mode(a, b)").is_err());
+        assert!(KeymapCommand::parse("(katakana)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deprecated_command() -> Result<()> {
+        assert_eq!(
+            KeymapCommand::parse("set_input_mode_katakana")?,
+            KeymapCommand::parse("set_input_mode(katakana)")?
+        );
+        assert_eq!(
+            KeymapCommand::parse("press_number_0")?,
+            KeymapCommand::parse("select_candidate_in_page(10)")?
+        );
+        for (name, _) in DEPRECATED_COMMANDS {
+            KeymapCommand::parse(name)?.validate()?;
+        }
+        assert_eq!(KeymapCommand::deprecated_alias("commit_candidate"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_command() -> Result<()> {
+        assert_eq!(
+            KeymapCommand::parse("commit_candidate")?.validate()?,
+            CommandArgValue::None
+        );
+        assert_eq!(
+            KeymapCommand::parse("set_input_mode(katakana)")?.validate()?,
+            CommandArgValue::InputMode("katakana")
+        );
+        assert_eq!(
+            KeymapCommand::parse("select_candidate_in_page(10)")?.validate()?,
+            CommandArgValue::IndexInPage(9)
+        );
+        assert_eq!(
+            KeymapCommand::parse("press_number_1")?.validate()?,
+            CommandArgValue::IndexInPage(0)
+        );
+
+        assert!(KeymapCommand::parse("no_such_command")?.validate().is_err());
+        assert!(KeymapCommand::parse("commit_candidate(1)")?
+            .validate()
+            .is_err());
+        assert!(KeymapCommand::parse("set_input_mode")?.validate().is_err());
+        assert!(KeymapCommand::parse("set_input_mode(kanji)")?
+            .validate()
+            .is_err());
+        assert!(KeymapCommand::parse("select_candidate_in_page(0)")?
+            .validate()
+            .is_err());
+        assert!(KeymapCommand::parse("select_candidate_in_page(11)")?
+            .validate()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_paths() -> Result<()> {
+        let tmpdir = tempfile::TempDir::new()?;
+        let path = tmpdir.path().join("my.yml");
+        std::fs::write(&path, "extends: default\nkeys: []\n")?;
+
+        let source_paths = Keymap::source_paths(&path.to_string_lossy())?;
+        assert_eq!(source_paths.len(), 2);
+        assert!(source_paths[0].ends_with("default.yml"));
+        assert_eq!(source_paths[1], path.to_string_lossy());
         Ok(())
     }
 }
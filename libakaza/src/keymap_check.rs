@@ -0,0 +1,274 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use anyhow::Result;
+
+use crate::keymap::{KeyState, KeyStroke, Keymap, KeymapCommand};
+use crate::keysym::is_keysym_name;
+
+/// キーマップの問題の深刻さ。
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KeymapProblemLevel {
+    /// そのままでは使えない設定。
+    Error,
+    /// 使えるが、意図したとおりに動かなさそうな設定。
+    Warning,
+    /// 継承元のキーマップの上書きなど、おそらく意図した設定。
+    Info,
+}
+
+/// キーマップの検査で見つかった問題。
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeymapProblem {
+    pub level: KeymapProblemLevel,
+    /// 問題のあるキーマップファイルのパス。
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for KeymapProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let level = match self.level {
+            KeymapProblemLevel::Error => "error",
+            KeymapProblemLevel::Warning => "warning",
+            KeymapProblemLevel::Info => "info",
+        };
+        write!(f, "{}: {}: {}", level, self.path, self.message)
+    }
+}
+
+/// 検査中に覚えておく、状態とキーの組に割り当てられたコマンド。
+struct Binding {
+    path: String,
+    key: String,
+    command: Option<String>,
+}
+
+/// キーマップを、extends で継承しているものも含めて検査する。
+///
+/// 存在しないコマンドやキーの名前、同じファイルでの重複、継承元の上書き、
+/// プレフィックスキーに隠されて使えなくなったキーを報告する。
+/// ファイルが読めないときだけエラーを返す。
+pub fn check_keymap(keymap_path: &str) -> Result<Vec<KeymapProblem>> {
+    let mut problems = Vec::new();
+    let mut bindings: HashMap<(KeyState, Vec<KeyStroke>), Binding> = HashMap::new();
+
+    for (path, keymap) in Keymap::load_chain(keymap_path)? {
+        let problem = |level, message| KeymapProblem {
+            level,
+            path: path.clone(),
+            message,
+        };
+        let mut defined_here = HashSet::new();
+
+        for kc in &keymap.keys {
+            if let Some(command) = &kc.command {
+                if let Some(replacement) = KeymapCommand::deprecated_alias(command) {
+                    problems.push(problem(
+                        KeymapProblemLevel::Warning,
+                        format!("'{command}' is deprecated. Use '{replacement}' instead"),
+                    ));
+                }
+                if let Err(err) = KeymapCommand::parse(command).and_then(|it| it.validate()) {
+                    problems.push(problem(
+                        KeymapProblemLevel::Error,
+                        format!("Invalid command '{command}': {err}"),
+                    ));
+                }
+            }
+
+            for key in &kc.key {
+                let keys = match Keymap::parse_key_sequence(key) {
+                    Ok(keys) => keys,
+                    Err(err) => {
+                        problems.push(problem(
+                            KeymapProblemLevel::Error,
+                            format!("Cannot parse key '{key}': {err}"),
+                        ));
+                        continue;
+                    }
+                };
+                for stroke in &keys {
+                    if !is_keysym_name(&stroke.key) {
+                        problems.push(problem(
+                            KeymapProblemLevel::Error,
+                            format!("Unknown key name '{}' in '{}'", stroke.key, key),
+                        ));
+                    }
+                }
+
+                for state in &kc.states {
+                    let id = (*state, keys.clone());
+                    let describe = |command: &Option<String>| {
+                        command.clone().unwrap_or_else(|| "null".to_string())
+                    };
+                    match bindings.get(&id) {
+                        Some(prev) if defined_here.contains(&id) => {
+                            problems.push(problem(
+                                KeymapProblemLevel::Warning,
+                                format!(
+                                    "'{}' in {:?} is defined more than once. '{}' is used instead of '{}'",
+                                    key,
+                                    state,
+                                    describe(&kc.command),
+                                    describe(&prev.command)
+                                ),
+                            ));
+                        }
+                        Some(prev) if prev.command.is_some() => {
+                            if prev.command != kc.command {
+                                problems.push(problem(
+                                    KeymapProblemLevel::Info,
+                                    format!(
+                                        "'{}' in {:?} overrides '{}' in {}",
+                                        key,
+                                        state,
+                                        describe(&prev.command),
+                                        prev.path
+                                    ),
+                                ));
+                            }
+                        }
+                        _ => {
+                            if kc.command.is_none() {
+                                problems.push(problem(
+                                    KeymapProblemLevel::Warning,
+                                    format!(
+                                        "'{key}' in {state:?} is not defined in the parent keymaps. null does nothing"
+                                    ),
+                                ));
+                            }
+                        }
+                    }
+                    defined_here.insert(id.clone());
+                    bindings.insert(
+                        id,
+                        Binding {
+                            path: path.clone(),
+                            key: key.clone(),
+                            command: kc.command.clone(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    problems.extend(check_prefix_keys(&bindings));
+    Ok(problems)
+}
+
+/// プレフィックスキーとしても使われているキーは、単独で割り当てたコマンドが使えない。
+fn check_prefix_keys(
+    bindings: &HashMap<(KeyState, Vec<KeyStroke>), Binding>,
+) -> Vec<KeymapProblem> {
+    let mut problems = Vec::new();
+    for ((state, keys), binding) in bindings {
+        if binding.command.is_none() {
+            continue;
+        }
+        for i in 1..keys.len() {
+            let Some(shadowed) = bindings.get(&(*state, keys[..i].to_vec())) else {
+                continue;
+            };
+            if shadowed.command.is_some() {
+                problems.push(KeymapProblem {
+                    level: KeymapProblemLevel::Warning,
+                    path: shadowed.path.clone(),
+                    message: format!(
+                        "'{}' in {:?} is not available because it is a prefix of '{}' in {}",
+                        shadowed.key, state, binding.key, binding.path
+                    ),
+                });
+            }
+        }
+    }
+    problems.sort_by(|a, b| (&a.path, &a.message).cmp(&(&b.path, &b.message)));
+    problems.dedup();
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_default_keymap() -> Result<()> {
+        let problems = check_keymap("../keymap/default.yml")?;
+        assert_eq!(problems, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_keymap() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let path = tmpdir.path().join("my.yml");
+        fs::write(
+            &path,
+            r#"
+keys:
+  - states: [Conversion]
+    key: [C-x, X-y, NoSuchKey]
+    command: no_such_command
+  - states: [Conversion]
+    key: [C-x]
+    command: commit_candidate
+  - states: [Conversion]
+    key: [C-x C-k]
+    command: set_input_mode(katakana)
+  - states: [PreComposition]
+    key: [F1]
+    command: null
+  - states: [Conversion]
+    key: [F2]
+    command: press_number_1
+"#,
+        )?;
+        let path = path.to_string_lossy().to_string();
+
+        let messages = check_keymap(&path)?
+            .iter()
+            .map(|it| (it.level, it.message.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    KeymapProblemLevel::Error,
+                    "Invalid command 'no_such_command': Unknown command: no_such_command"
+                        .to_string()
+                ),
+                (
+                    KeymapProblemLevel::Error,
+                    "Cannot parse key 'X-y': Unknown modifier in keymap: X-y".to_string()
+                ),
+                (
+                    KeymapProblemLevel::Error,
+                    "Unknown key name 'NoSuchKey' in 'NoSuchKey'".to_string()
+                ),
+                (
+                    KeymapProblemLevel::Warning,
+                    "'C-x' in Conversion is defined more than once. 'commit_candidate' is used instead of 'no_such_command'".to_string()
+                ),
+                (
+                    KeymapProblemLevel::Warning,
+                    "'F1' in PreComposition is not defined in the parent keymaps. null does nothing".to_string()
+                ),
+                (
+                    KeymapProblemLevel::Warning,
+                    "'press_number_1' is deprecated. Use 'select_candidate_in_page(1)' instead"
+                        .to_string()
+                ),
+                (
+                    KeymapProblemLevel::Warning,
+                    format!("'C-x' in Conversion is not available because it is a prefix of 'C-x C-k' in {path}")
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
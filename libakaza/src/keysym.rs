@@ -0,0 +1,34 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// キーマップに書けるキーの名前かどうか。
+/// ibus_keyval_from_name に渡せる名前と同じで、ibus-sys/src/ibus_key.rs の定数名から作る。
+pub(crate) fn is_keysym_name(name: &str) -> bool {
+    static KEYSYM_NAMES: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    KEYSYM_NAMES
+        .get_or_init(|| {
+            include_str!("../../ibus-sys/src/ibus_key.rs")
+                .lines()
+                .filter_map(|line| line.strip_prefix("pub const IBUS_KEY_"))
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, _)| name)
+                .collect()
+        })
+        .contains(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_keysym_name() {
+        assert!(is_keysym_name("a"));
+        assert!(is_keysym_name("Return"));
+        assert!(is_keysym_name("Henkan"));
+        assert!(is_keysym_name("3270_Attn"));
+        assert!(!is_keysym_name("Enter"));
+        assert!(!is_keysym_name("IBUS_KEY_a"));
+        assert!(!is_keysym_name(""));
+    }
+}
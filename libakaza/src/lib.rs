@@ -3,7 +3,6 @@
 extern crate core;
 
 pub mod config;
-pub mod consonant;
 pub mod corpus;
 pub mod cost;
 pub mod dict;
@@ -11,11 +10,16 @@ pub mod engine;
 pub mod extend_clause;
 pub mod graph;
 pub mod kana_kanji;
+pub mod kana_layout;
 pub mod kana_trie;
 pub mod kansuji;
 pub mod keymap;
+pub mod keymap_check;
+mod keysym;
 pub mod lm;
-mod resource;
+pub mod resource;
+pub mod reverse_dict;
 pub mod romkan;
 pub mod search_result;
+pub mod thumb_shift;
 pub mod user_side_data;
@@ -6,9 +6,36 @@ pub trait SystemBigramLM {
     fn as_hash_map(&self) -> HashMap<(i32, i32), f32>;
 }
 
+/// 未知の bigram (w1, w2) のコストを見積もるための、単語ごとのコスト。
+/// 未知の bigram のエッジコストは `w1.backoff + w2.continuation` になる。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnigramBackoff {
+    /// この単語から始まる bigram が見つからないときのバックオフコスト。
+    pub backoff: f32,
+    /// 継続回数 (いくつの種類の単語の後ろに出現したか) から求めた、Kneser-Ney の下位モデルのコスト。
+    pub continuation: f32,
+}
+
 pub trait SystemUnigramLM {
     fn get_cost(&self, wordcnt: u32) -> f32;
 
     fn find(&self, word: &str) -> Option<(i32, f32)>;
+    /// 単語のバックオフコストと継続コストを得る。
+    fn find_backoff(&self, word: &str) -> Option<UnigramBackoff>;
     fn as_hash_map(&self) -> HashMap<String, (i32, f32)>;
 }
+
+/// trigram の trie で、文脈のバックオフコストを格納するエントリーの三単語目の ID。
+/// 単語IDは 3 バイトに収めるので、この値は実際の単語には使われない。
+pub const TRIGRAM_BACKOFF_WORD_ID: i32 = 0xFF_FFFF;
+
+/// 直前の二単語を文脈とする言語モデル。
+/// エントリーが存在しない場合は bigram にバックオフするので、デフォルトのコストは持たない。
+pub trait SystemTrigramLM {
+    fn get_edge_cost(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<f32>;
+    /// 文脈 (word_id1, word_id2) のバックオフコストを得る。
+    /// この文脈の trigram が見つからないときのエッジコストは `backoff + bigram のコスト` になる。
+    fn get_backoff_cost(&self, word_id1: i32, word_id2: i32) -> Option<f32>;
+    /// バックオフコストのエントリーは含まない。
+    fn as_hash_map(&self) -> HashMap<(i32, i32, i32), f32>;
+}
@@ -1,4 +1,5 @@
 pub mod base;
 pub mod on_memory;
 pub mod system_bigram;
+pub mod system_trigram;
 pub mod system_unigram_lm;
@@ -1,2 +1,3 @@
 pub mod on_memory_system_bigram_lm;
+pub mod on_memory_system_trigram_lm;
 pub mod on_memory_system_unigram_lm;
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::cost::calc_cost;
+use crate::lm::base::SystemTrigramLM;
+
+// (word_id, word_id, word_id) -> cost
+type TrigramCountMap = HashMap<(i32, i32, i32), u32>;
+
+pub struct OnMemorySystemTrigramLM {
+    map: Arc<RwLock<TrigramCountMap>>,
+    /// (word_id, word_id) -> 文脈のバックオフコスト
+    backoff_map: HashMap<(i32, i32), f32>,
+    pub total_words: u32,
+    pub unique_words: u32,
+}
+
+impl OnMemorySystemTrigramLM {
+    pub fn new(map: Arc<RwLock<TrigramCountMap>>, c: u32, v: u32) -> Self {
+        OnMemorySystemTrigramLM {
+            map,
+            backoff_map: HashMap::new(),
+            total_words: c,
+            unique_words: v,
+        }
+    }
+
+    pub fn set_backoff_map(&mut self, backoff_map: HashMap<(i32, i32), f32>) {
+        self.backoff_map = backoff_map;
+    }
+
+    pub fn backoff_map(&self) -> &HashMap<(i32, i32), f32> {
+        &self.backoff_map
+    }
+
+    pub fn update(&self, word_id1: i32, word_id2: i32, word_id3: i32, cnt: u32) {
+        self.map
+            .write()
+            .unwrap()
+            .insert((word_id1, word_id2, word_id3), cnt);
+    }
+
+    pub fn get_edge_cnt(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<u32> {
+        self.map
+            .read()
+            .unwrap()
+            .get(&(word_id1, word_id2, word_id3))
+            .copied()
+    }
+}
+
+impl SystemTrigramLM for OnMemorySystemTrigramLM {
+    fn get_edge_cost(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<f32> {
+        self.map
+            .read()
+            .unwrap()
+            .get(&(word_id1, word_id2, word_id3))
+            .map(|f| calc_cost(*f, self.total_words, self.unique_words))
+    }
+
+    fn get_backoff_cost(&self, word_id1: i32, word_id2: i32) -> Option<f32> {
+        self.backoff_map.get(&(word_id1, word_id2)).copied()
+    }
+
+    fn as_hash_map(&self) -> HashMap<(i32, i32, i32), f32> {
+        self.map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|((id1, id2, id3), cnt)| {
+                (
+                    (*id1, *id2, *id3),
+                    calc_cost(*cnt, self.total_words, self.unique_words),
+                )
+            })
+            .collect()
+    }
+}
@@ -1,30 +1,36 @@
 use crate::cost::calc_cost;
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
 
-use crate::lm::base::SystemUnigramLM;
+use crate::lm::base::{SystemUnigramLM, UnigramBackoff};
 
 pub struct OnMemorySystemUnigramLM {
     // word -> (word_id, cost)
-    map: Rc<RefCell<HashMap<String, (i32, u32)>>>,
+    map: Arc<RwLock<HashMap<String, (i32, u32)>>>,
+    // word -> backoff cost
+    backoff_map: HashMap<String, UnigramBackoff>,
     pub total_words: u32,
     pub unique_words: u32,
 }
 
 impl OnMemorySystemUnigramLM {
     pub fn new(
-        map: Rc<RefCell<HashMap<String, (i32, u32)>>>,
+        map: Arc<RwLock<HashMap<String, (i32, u32)>>>,
         total_words: u32,
         unique_words: u32,
     ) -> Self {
         OnMemorySystemUnigramLM {
             map,
+            backoff_map: HashMap::new(),
             total_words,
             unique_words,
         }
     }
 
+    pub fn set_backoff_map(&mut self, backoff_map: HashMap<String, UnigramBackoff>) {
+        self.backoff_map = backoff_map;
+    }
+
     pub fn update(&self, word: &str, cnt: u32) {
         let Some((word_id, _)) = self.find(word) else {
             // 登録されてない単語は無視。
@@ -32,13 +38,15 @@ impl OnMemorySystemUnigramLM {
         };
 
         self.map
-            .borrow_mut()
+            .write()
+            .unwrap()
             .insert(word.to_string(), (word_id, cnt));
     }
 
     pub fn reverse_lookup(&self, word_id: i32) -> Option<String> {
         self.map
-            .borrow()
+            .read()
+            .unwrap()
             .iter()
             .filter(|(_, (id, _))| *id == word_id)
             .map(|(key, (_, _))| key.clone())
@@ -46,7 +54,7 @@ impl OnMemorySystemUnigramLM {
     }
 
     pub fn find_cnt(&self, word: &str) -> Option<(i32, u32)> {
-        self.map.borrow().get(word).copied()
+        self.map.read().unwrap().get(word).copied()
     }
 }
 
@@ -57,14 +65,20 @@ impl SystemUnigramLM for OnMemorySystemUnigramLM {
 
     fn find(&self, word: &str) -> Option<(i32, f32)> {
         self.map
-            .borrow()
+            .read()
+            .unwrap()
             .get(word)
             .map(|(id, cnt)| (*id, calc_cost(*cnt, self.total_words, self.unique_words)))
     }
 
+    fn find_backoff(&self, word: &str) -> Option<UnigramBackoff> {
+        self.backoff_map.get(word).copied()
+    }
+
     fn as_hash_map(&self) -> HashMap<String, (i32, f32)> {
         self.map
-            .borrow()
+            .read()
+            .unwrap()
             .iter()
             .map(|(key, (id, cnt))| {
                 (
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use half::f16;
+use log::info;
+
+use marisa_sys::{Keyset, Marisa};
+
+use crate::lm::base::{SystemTrigramLM, TRIGRAM_BACKOFF_WORD_ID};
+use crate::search_result::SearchResult;
+
+/*
+   {word1 ID}    # 3 bytes
+   {word2 ID}    # 3 bytes
+   {word3 ID}    # 3 bytes
+   packed float  # score: 2 bytes
+*/
+
+/**
+ * trigram 言語モデル。
+ * bigram と同様に、unigram の生成のときに得られた単語IDを利用することで、圧縮している。
+ */
+#[derive(Default)]
+pub struct MarisaSystemTrigramLMBuilder {
+    keyset: Keyset,
+}
+
+impl MarisaSystemTrigramLMBuilder {
+    pub fn add(&mut self, word_id1: i32, word_id2: i32, word_id3: i32, score: f32) {
+        assert_ne!(word_id3, TRIGRAM_BACKOFF_WORD_ID);
+        self.push(word_id1, word_id2, word_id3, score);
+    }
+
+    /// 文脈 (word_id1, word_id2) のバックオフコストを登録する。
+    pub fn add_backoff(&mut self, word_id1: i32, word_id2: i32, backoff: f32) {
+        self.push(word_id1, word_id2, TRIGRAM_BACKOFF_WORD_ID, backoff);
+    }
+
+    fn push(&mut self, word_id1: i32, word_id2: i32, word_id3: i32, score: f32) {
+        // ID は 3 byte に収める。詳細は MarisaSystemBigramLMBuilder を参照。
+        let mut key: Vec<u8> = Vec::new();
+        for word_id in [word_id1, word_id2, word_id3] {
+            let bytes = word_id.to_le_bytes();
+            assert_eq!(bytes[3], 0);
+            key.extend(bytes[0..3].iter());
+        }
+        key.extend(f16::from_f32(score).to_le_bytes());
+        self.keyset.push_back(key.as_slice());
+    }
+
+    pub fn build(&self) -> Result<MarisaSystemTrigramLM> {
+        let mut marisa = Marisa::default();
+        marisa.build(&self.keyset);
+        Ok(MarisaSystemTrigramLM { marisa })
+    }
+
+    pub fn save(&self, ofname: &str) -> Result<()> {
+        let mut marisa = Marisa::default();
+        marisa.build(&self.keyset);
+        marisa.save(ofname)?;
+        Ok(())
+    }
+}
+
+pub struct MarisaSystemTrigramLM {
+    marisa: Marisa,
+}
+
+impl MarisaSystemTrigramLM {
+    pub fn load(filename: &str) -> Result<MarisaSystemTrigramLM> {
+        info!("Loading system-trigram: {}", filename);
+        let mut marisa = Marisa::default();
+        marisa.load(filename)?;
+        Ok(MarisaSystemTrigramLM { marisa })
+    }
+
+    pub fn num_keys(&self) -> usize {
+        self.marisa.num_keys()
+    }
+}
+
+impl SystemTrigramLM for MarisaSystemTrigramLM {
+    /**
+     * edge cost を得る。
+     * この ID は、unigram の trie でふられたもの。
+     */
+    fn get_edge_cost(&self, word_id1: i32, word_id2: i32, word_id3: i32) -> Option<f32> {
+        let mut key: Vec<u8> = Vec::new();
+        key.extend(word_id1.to_le_bytes()[0..3].iter());
+        key.extend(word_id2.to_le_bytes()[0..3].iter());
+        key.extend(word_id3.to_le_bytes()[0..3].iter());
+        let mut got: Vec<SearchResult> = Vec::new();
+        self.marisa.predictive_search(key.as_slice(), |key, id| {
+            got.push(SearchResult {
+                keyword: key.to_vec(),
+                id,
+            });
+            true
+        });
+        let result = got.first()?;
+        let last2: [u8; 2] = result.keyword[result.keyword.len() - 2..result.keyword.len()]
+            .try_into()
+            .unwrap();
+        let score: f16 = f16::from_le_bytes(last2);
+        Some(score.to_f32())
+    }
+
+    fn get_backoff_cost(&self, word_id1: i32, word_id2: i32) -> Option<f32> {
+        self.get_edge_cost(word_id1, word_id2, TRIGRAM_BACKOFF_WORD_ID)
+    }
+
+    fn as_hash_map(&self) -> HashMap<(i32, i32, i32), f32> {
+        let mut map: HashMap<(i32, i32, i32), f32> = HashMap::new();
+        self.marisa.predictive_search("".as_bytes(), |word, _id| {
+            if word.len() == 11 && word[6..9] != TRIGRAM_BACKOFF_WORD_ID.to_le_bytes()[0..3] {
+                let word_id1 = i32::from_le_bytes([word[0], word[1], word[2], 0]);
+                let word_id2 = i32::from_le_bytes([word[3], word[4], word[5], 0]);
+                let word_id3 = i32::from_le_bytes([word[6], word[7], word[8], 0]);
+                let cost = f16::from_le_bytes([word[9], word[10]]).to_f32();
+                map.insert((word_id1, word_id2, word_id3), cost);
+            }
+            true
+        });
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_and_load() -> anyhow::Result<()> {
+        let mut builder = MarisaSystemTrigramLMBuilder::default();
+        builder.add(4649, 5963, 810, 5.11_f32);
+        let system_trigram_lm = builder.build()?;
+        let got_score = system_trigram_lm.get_edge_cost(4649, 5963, 810).unwrap();
+        assert!(5.0 < got_score && got_score < 5.12);
+        assert_eq!(system_trigram_lm.get_edge_cost(4649, 5963, 811), None);
+        assert_eq!(system_trigram_lm.get_backoff_cost(4649, 5963), None);
+
+        let map = system_trigram_lm.as_hash_map();
+        assert!(map.contains_key(&(4649, 5963, 810)));
+        let g = *map.get(&(4649, 5963, 810)).unwrap();
+        assert!(5.10_f32 < g && g < 5.12_f32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() -> anyhow::Result<()> {
+        let mut builder = MarisaSystemTrigramLMBuilder::default();
+        builder.add(4649, 5963, 810, 5.0_f32);
+        builder.add_backoff(4649, 5963, 1.5_f32);
+        let system_trigram_lm = builder.build()?;
+        assert_eq!(system_trigram_lm.get_backoff_cost(4649, 5963), Some(1.5));
+        assert_eq!(system_trigram_lm.get_backoff_cost(4649, 810), None);
+        // バックオフコストは trigram のエントリーとしては見えない。
+        assert_eq!(
+            system_trigram_lm.as_hash_map().keys().collect::<Vec<_>>(),
+            vec![&(4649, 5963, 810)]
+        );
+        Ok(())
+    }
+}
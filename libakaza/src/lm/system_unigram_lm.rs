@@ -6,44 +6,80 @@ use log::info;
 use marisa_sys::{Keyset, Marisa};
 
 use crate::cost::calc_cost;
-use crate::lm::base::SystemUnigramLM;
+use crate::lm::base::{SystemUnigramLM, UnigramBackoff};
 
 /*
    {word} # in utf-8
    0xff   # marker
    packed ID     # 3 bytes(24bit). 最大語彙: 8,388,608(2**24/2)
    packed float  # score: 4 bytes
+   packed float  # backoff score: 4 bytes(optional)
+   packed float  # continuation score: 4 bytes(optional, backoff score とセット)
 */
 
 const UNIQUE_WORDS_KEY: &str = "__UNIQUE_WORDS__";
 const TOTAL_WORDS_KEY: &str = "__TOTAL_WORDS__";
 
+/// unigram trie のキーを作る。akaza-data の WordcntUnigram とも共通の形式。
+/// score は、システム言語モデルでは f32 のコスト、WordcntUnigram では u32 の出現回数を入れる。
+pub fn encode_unigram_key(word: &str, score: [u8; 4], backoff: Option<UnigramBackoff>) -> Vec<u8> {
+    // 区切り文字をいれなくても、末尾の4バイトを取り出せば十分な気がしないでもない。。
+    // 先頭一致にして、+4バイトになるものを探せばいいはず。
+    // 最適化の余地だけど、現実的には空間効率よりも速度のほうが重要かもしれない。
+    [
+        word.as_bytes(),
+        b"\xff",
+        score.as_slice(), // バイナリにしてデータ容量を節約する
+        backoff
+            .map(|it| [it.backoff.to_le_bytes(), it.continuation.to_le_bytes()].concat())
+            .unwrap_or_default()
+            .as_slice(),
+    ]
+    .concat()
+}
+
+/// encode_unigram_key で作ったキーを、(単語, score, バックオフコスト) に分解する。
+/// UTF-8 の文字列には 0xff が現れないので、最初の 0xff が区切り文字になる。
+pub fn decode_unigram_key(key: &[u8]) -> (&[u8], [u8; 4], Option<UnigramBackoff>) {
+    let idx = key.iter().position(|f| *f == b'\xff').unwrap();
+    let score: [u8; 4] = key[idx + 1..idx + 1 + 4].try_into().unwrap();
+    let read_f32 = |offset: usize| {
+        let bytes: [u8; 4] = key[idx + 1 + offset..idx + 1 + offset + 4]
+            .try_into()
+            .unwrap();
+        f32::from_le_bytes(bytes)
+    };
+    let backoff = (key.len() == idx + 1 + 12).then(|| UnigramBackoff {
+        backoff: read_f32(4),
+        continuation: read_f32(8),
+    });
+    (&key[0..idx], score, backoff)
+}
+
 /**
  * unigram 言語モデル。
  * 「漢字/かな」に対して、発生確率スコアを保持している。
  */
 #[derive(Default)]
 pub struct MarisaSystemUnigramLMBuilder {
-    data: Vec<(String, f32)>,
+    data: Vec<(String, f32, Option<UnigramBackoff>)>,
 }
 
 impl MarisaSystemUnigramLMBuilder {
     pub fn add(&mut self, word: &str, score: f32) {
-        self.data.push((word.to_string(), score));
+        self.data.push((word.to_string(), score, None));
+    }
+
+    /// バックオフコストつきで単語を登録する。
+    /// バックオフコストは、この単語が前後にくる未知の bigram のコストを計算するのに使われる。
+    pub fn add_with_backoff(&mut self, word: &str, score: f32, backoff: UnigramBackoff) {
+        self.data.push((word.to_string(), score, Some(backoff)));
     }
 
     pub fn keyset(&self) -> Keyset {
         let mut keyset = Keyset::default();
-        for (kanji, score) in &self.data {
-            // 区切り文字をいれなくても、末尾の4バイトを取り出せば十分な気がしないでもない。。
-            // 先頭一致にして、+4バイトになるものを探せばいいはず。
-            // 最適化の余地だけど、現実的には空間効率よりも速度のほうが重要かもしれない。
-            let key = [
-                kanji.as_bytes(),
-                b"\xff",
-                score.to_le_bytes().as_slice(), // バイナリにしてデータ容量を節約する
-            ]
-            .concat();
+        for (kanji, score, backoff) in &self.data {
+            let key = encode_unigram_key(kanji, score.to_le_bytes(), *backoff);
             keyset.push_back(key.as_slice());
         }
         keyset
@@ -109,6 +145,16 @@ impl MarisaSystemUnigramLM {
         })
     }
 
+    fn find_backoff_from_trie(marisa: &Marisa, word: &str) -> Option<UnigramBackoff> {
+        let key = [word.as_bytes(), b"\xff"].concat();
+        let mut backoff = None;
+        marisa.predictive_search(key.as_slice(), |word, _| {
+            (_, _, backoff) = decode_unigram_key(word);
+            false
+        });
+        backoff
+    }
+
     fn find_from_trie(marisa: &Marisa, word: &str) -> Option<(i32, f32)> {
         assert_ne!(word.len(), 0);
 
@@ -118,8 +164,7 @@ impl MarisaSystemUnigramLM {
         marisa.predictive_search(key.as_slice(), |word, id| {
             kanji_id = id;
 
-            let idx = word.iter().position(|f| *f == b'\xff').unwrap();
-            let bytes: [u8; 4] = word[idx + 1..idx + 1 + 4].try_into().unwrap();
+            let (_, bytes, _) = decode_unigram_key(word);
             score = f32::from_le_bytes(bytes);
             false
         });
@@ -141,12 +186,15 @@ impl SystemUnigramLM for MarisaSystemUnigramLM {
         Self::find_from_trie(&self.marisa, word)
     }
 
+    fn find_backoff(&self, word: &str) -> Option<UnigramBackoff> {
+        Self::find_backoff_from_trie(&self.marisa, word)
+    }
+
     fn as_hash_map(&self) -> HashMap<String, (i32, f32)> {
         let mut map = HashMap::new();
         self.marisa.predictive_search("".as_bytes(), |word, id| {
-            let idx = word.iter().position(|f| *f == b'\xff').unwrap();
-            let bytes: [u8; 4] = word[idx + 1..idx + 1 + 4].try_into().unwrap();
-            let word = String::from_utf8_lossy(&word[0..idx]);
+            let (word, bytes, _) = decode_unigram_key(word);
+            let word = String::from_utf8_lossy(word);
             let cost = f32::from_le_bytes(bytes);
             map.insert(word.to_string(), (id as i32, cost));
             true
@@ -186,4 +234,23 @@ mod tests {
             assert_eq!(p, None);
         }
     }
+
+    #[test]
+    fn test_backoff() -> Result<()> {
+        let backoff = UnigramBackoff {
+            backoff: 1.5,
+            continuation: 2.5,
+        };
+        let mut builder = MarisaSystemUnigramLMBuilder::default();
+        builder.add_with_backoff("hello", 0.4, backoff);
+        builder.add("world", 0.2);
+        let lm = builder.set_total_words(2).set_unique_words(2).build();
+
+        assert_eq!(lm.find("hello").unwrap().1, 0.4_f32);
+        assert_eq!(lm.find_backoff("hello"), Some(backoff));
+        assert_eq!(lm.find("world").unwrap().1, 0.2_f32);
+        assert_eq!(lm.find_backoff("world"), None);
+        assert_eq!(lm.find_backoff("unknown"), None);
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 use std::env;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context};
 
@@ -23,3 +24,160 @@ pub fn detect_resource_path(base: &str, name: &str) -> anyhow::Result<String> {
     };
     Ok(pathstr)
 }
+
+/// keymap や romkan のテーブルを探すディレクトリを、優先する順に返す。
+///
+/// ユーザーの設定ディレクトリ(~/.config/akaza/{base}/)、XDG_DATA_HOME、XDG_DATA_DIRS の順に探す。
+pub fn table_dirs(base: &str) -> anyhow::Result<Vec<PathBuf>> {
+    if cfg!(test) {
+        return Ok(vec![PathBuf::from(format!(
+            "{}/../{}",
+            env!("CARGO_MANIFEST_DIR"),
+            base
+        ))]);
+    }
+    let basedirs = xdg::BaseDirectories::with_prefix("akaza")
+        .with_context(|| "Opening xdg directory with 'akaza' prefix")?;
+    let mut dirs = vec![
+        basedirs.get_config_home().join(base),
+        basedirs.get_data_home().join(base),
+    ];
+    dirs.extend(basedirs.get_data_dirs().iter().map(|it| it.join(base)));
+    Ok(dirs)
+}
+
+/// "default" のような名前ではなく、ファイルのパスで指定されているか。
+fn is_table_path(name: &str) -> bool {
+    name.contains('/') || name.ends_with(".yml")
+}
+
+/// テーブルの名前かパスから、読み込むファイルのパスを返す。
+/// 名前のときは、table_dirs の順に "{name}.yml" を探す。
+pub fn find_table_path(base: &str, name: &str) -> anyhow::Result<String> {
+    if is_table_path(name) {
+        return Ok(name.to_string());
+    }
+    let dirs = table_dirs(base)?;
+    find_in_dirs(&dirs, name, None)
+}
+
+/// extends で指定された、継承元のテーブルのパスを返す。
+///
+/// "../team/keymap.yml" のようなパスは、継承する側のファイルからの相対パスとして扱う。
+/// 名前のときは find_table_path と同じ順に探すが、継承する側のファイル自身と、それより優先される
+/// ディレクトリは飛ばす。~/.config/akaza/keymap/default.yml で "extends: default" と書けば、
+/// 同梱の default.yml を継承できる。
+pub fn resolve_extends(base: &str, extends: &str, including_path: &str) -> anyhow::Result<String> {
+    let dirs = table_dirs(base)?;
+    resolve_extends_in_dirs(&dirs, extends, including_path)
+}
+
+fn resolve_extends_in_dirs(
+    dirs: &[PathBuf],
+    extends: &str,
+    including_path: &str,
+) -> anyhow::Result<String> {
+    if is_table_path(extends) {
+        let parent = Path::new(including_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        return Ok(parent.join(extends).to_string_lossy().to_string());
+    }
+    find_in_dirs(dirs, extends, Some(Path::new(including_path)))
+}
+
+fn find_in_dirs(dirs: &[PathBuf], name: &str, including: Option<&Path>) -> anyhow::Result<String> {
+    let file_name = format!("{name}.yml");
+    let candidates = dirs
+        .iter()
+        .map(|dir| dir.join(&file_name))
+        .filter(|path| path.exists())
+        .collect::<Vec<_>>();
+
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let skip = including
+        .and_then(|including| {
+            let including = canonical(including);
+            candidates.iter().position(|it| canonical(it) == including)
+        })
+        .map_or(0, |i| i + 1);
+
+    let Some(path) = candidates.get(skip) else {
+        bail!("Cannot find {:?} in {:?}", file_name, dirs);
+    };
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// extends をたどるときに、同じファイルを二度読まないようにする。
+pub(crate) fn check_circular_extends(chain: &[String], path: &str) -> anyhow::Result<()> {
+    let canonical = |path: &str| {
+        Path::new(path)
+            .canonicalize()
+            .unwrap_or_else(|_| PathBuf::from(path))
+    };
+    if chain.iter().any(|it| canonical(it) == canonical(path)) {
+        bail!("Circular extends: {} -> {}", chain.join(" -> "), path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_extends() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let user_dir = tmpdir.path().join("config/keymap");
+        let system_dir = tmpdir.path().join("share/keymap");
+        let team_dir = tmpdir.path().join("team");
+        for dir in [&user_dir, &system_dir, &team_dir] {
+            fs::create_dir_all(dir)?;
+        }
+        let path = |dir: &Path, name: &str| {
+            let path = dir.join(name);
+            fs::write(&path, "").unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let user_default = path(&user_dir, "default.yml");
+        let user_atok = path(&user_dir, "atok.yml");
+        let system_default = path(&system_dir, "default.yml");
+        let team = path(&team_dir, "team.yml");
+        let dirs = vec![user_dir.clone(), system_dir.clone()];
+
+        // ユーザーのファイルが優先される。
+        assert_eq!(find_in_dirs(&dirs, "default", None)?, user_default);
+        // 同じ名前を継承すると、次のディレクトリのものを使う。
+        assert_eq!(
+            resolve_extends_in_dirs(&dirs, "default", &user_default)?,
+            system_default
+        );
+        assert_eq!(
+            resolve_extends_in_dirs(&dirs, "default", &user_atok)?,
+            user_default
+        );
+        assert!(resolve_extends_in_dirs(&dirs, "default", &system_default).is_err());
+        // パスは継承する側のファイルからの相対パス。
+        assert_eq!(
+            Path::new(&resolve_extends_in_dirs(
+                &dirs,
+                "../../team/team.yml",
+                &user_atok
+            )?)
+            .canonicalize()?,
+            Path::new(&team).canonicalize()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_circular_extends() {
+        let chain = vec!["a.yml".to_string(), "b.yml".to_string()];
+        assert!(check_circular_extends(&chain, "c.yml").is_ok());
+        assert!(check_circular_extends(&chain, "a.yml").is_err());
+    }
+}
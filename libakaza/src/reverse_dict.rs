@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use kelp::{kata2hira, ConvOption};
+
+/// 表層から読みを引くための逆引き辞書。
+/// 確定済みの文字列を再変換するときに、読みを復元するのに使う。
+#[derive(Default, Debug)]
+pub struct ReverseDict {
+    /// 表層 -> 読みのリスト。先に登録されたものを優先する。
+    map: HashMap<String, Vec<String>>,
+    /// 登録されている表層の最大文字数
+    max_surface_len: usize,
+}
+
+impl ReverseDict {
+    pub fn add(&mut self, surface: &str, yomi: &str) {
+        if surface.is_empty() || yomi.is_empty() {
+            return;
+        }
+
+        let yomis = self.map.entry(surface.to_string()).or_default();
+        if !yomis.iter().any(|it| it == yomi) {
+            yomis.push(yomi.to_string());
+        }
+        self.max_surface_len = self.max_surface_len.max(surface.chars().count());
+    }
+
+    pub fn get(&self, surface: &str) -> Option<&String> {
+        self.map.get(surface).and_then(|yomis| yomis.first())
+    }
+
+    /// 登録されている表層の最大文字数
+    pub fn max_surface_len(&self) -> usize {
+        self.max_surface_len
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// 表層文字列を単語に区切り、読みを推定する。
+/// 区切りの数が最小になるように分割する。同じ表層が複数の辞書にある場合は、前にある辞書の読みを使う。
+/// 辞書にない文字は、カタカナであれば平仮名にして、それ以外はそのまま読みとする。
+pub fn reverse_lookup(dicts: &[&ReverseDict], surface: &str) -> String {
+    let chars: Vec<char> = surface.chars().collect();
+    let max_surface_len = dicts
+        .iter()
+        .map(|dict| dict.max_surface_len)
+        .max()
+        .unwrap_or(0);
+
+    // best[i] = i 文字目までを読みにしたときの (区切りの数, 読み)
+    let mut best: Vec<Option<(usize, String)>> = vec![None; chars.len() + 1];
+    best[0] = Some((0, String::new()));
+    for i in 0..chars.len() {
+        let Some((segments, yomi)) = best[i].clone() else {
+            continue;
+        };
+
+        let mut update = |j: usize, word_yomi: &str| {
+            if best[j]
+                .as_ref()
+                .map(|(s, _)| segments + 1 < *s)
+                .unwrap_or(true)
+            {
+                best[j] = Some((segments + 1, yomi.to_string() + word_yomi));
+            }
+        };
+
+        for j in (i + 1)..=chars.len().min(i + max_surface_len) {
+            let word: String = chars[i..j].iter().collect();
+            if let Some(word_yomi) = dicts.iter().find_map(|dict| dict.get(&word)) {
+                update(j, word_yomi);
+            }
+        }
+
+        // 辞書にない文字は、一文字ずつ読みにする。
+        let ch = chars[i].to_string();
+        update(i + 1, kata2hira(&ch, ConvOption::default()).as_str());
+    }
+
+    best[chars.len()]
+        .as_ref()
+        .map(|(_, yomi)| yomi.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_lookup() {
+        let mut system_dict = ReverseDict::default();
+        system_dict.add("東京", "とうきょう");
+        system_dict.add("東", "ひがし");
+        system_dict.add("京", "きょう");
+        system_dict.add("行く", "いく");
+        let mut user_dict = ReverseDict::default();
+        user_dict.add("東", "あずま");
+
+        // 区切りが少なくなるように、長い単語を優先する。
+        assert_eq!(
+            reverse_lookup(&[&system_dict], "東京に行く"),
+            "とうきょうにいく"
+        );
+        // 前にある辞書の読みを優先する。
+        assert_eq!(
+            reverse_lookup(&[&user_dict, &system_dict], "東の京"),
+            "あずまのきょう"
+        );
+        // カタカナは平仮名にする。
+        assert_eq!(reverse_lookup(&[&system_dict], "アキバ"), "あきば");
+    }
+}
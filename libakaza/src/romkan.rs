@@ -1,34 +1,111 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::BufReader;
 
-use crate::resource::detect_resource_path;
-use anyhow::Context;
-use log::info;
-use regex::{Captures, Regex};
+use crate::kana_layout::compose_voiced_mark;
+use crate::resource::{check_circular_extends, find_table_path, resolve_extends};
+use anyhow::{bail, Context};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// ローマ字テーブルの一行の変換結果。
+///
+/// 出力だけのときは `"ka": "か"` のように書く。
+/// 出力した後に一部の文字を未確定の入力として残すときは、`"tt": ["っ", "t"]` のように
+/// 出力と残す文字の組で書く。残した文字は、続けて入力した文字と合わせて再び変換する。
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(untagged)]
+pub enum RomKanValue {
+    Output(String),
+    WithPending(String, String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
 pub struct RomKanConfig {
-    mapping: HashMap<String, Option<String>>,
+    mapping: BTreeMap<String, Option<RomKanValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     extends: Option<String>,
 }
 
-fn load_romkan_map(file_path: &str) -> anyhow::Result<HashMap<String, String>> {
+impl RomKanConfig {
+    /// Mozc のローマ字テーブルを読む。
+    ///
+    /// 一行が一つの規則で、タブ区切りで「入力 出力 残す文字」の形式。
+    /// 四列目の属性は akaza では使わないので読み捨てる。
+    pub fn from_mozc_tsv(src: &str) -> anyhow::Result<RomKanConfig> {
+        let mut mapping = BTreeMap::new();
+        for (i, line) in src.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns = line.split('\t').collect::<Vec<_>>();
+            if columns.len() < 2 || columns[0].is_empty() {
+                bail!(
+                    "Invalid line in Mozc romaji table at line {}: {:?}",
+                    i + 1,
+                    line
+                );
+            }
+            let output = columns[1].to_string();
+            let value = match columns.get(2) {
+                Some(pending) if !pending.is_empty() => {
+                    RomKanValue::WithPending(output, pending.to_string())
+                }
+                _ => RomKanValue::Output(output),
+            };
+            mapping.insert(columns[0].to_string(), Some(value));
+        }
+        Ok(RomKanConfig {
+            mapping,
+            extends: None,
+        })
+    }
+
+    pub fn to_yaml(&self) -> anyhow::Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+}
+
+/// 変換規則。入力に一致したら output を出力して、pending を未確定の入力として残す。
+#[derive(Debug, Clone, PartialEq)]
+struct RomKanRule {
+    output: String,
+    pending: String,
+}
+
+impl From<RomKanValue> for RomKanRule {
+    fn from(value: RomKanValue) -> Self {
+        match value {
+            RomKanValue::Output(output) => RomKanRule {
+                output,
+                pending: String::new(),
+            },
+            RomKanValue::WithPending(output, pending) => RomKanRule { output, pending },
+        }
+    }
+}
+
+fn load_romkan_map(
+    file_path: &str,
+    chain: &mut Vec<String>,
+) -> anyhow::Result<HashMap<String, RomKanRule>> {
     info!("Loading romkan map: {}", file_path);
+    check_circular_extends(chain, file_path)?;
+    chain.push(file_path.to_string());
     let got: RomKanConfig = serde_yaml::from_reader(BufReader::new(
         File::open(file_path).with_context(|| file_path.to_string())?,
-    ))?;
+    ))
+    .with_context(|| file_path.to_string())?;
 
     if let Some(parent) = got.extends {
         // 継承しているので親を読み込む。
         // 再帰的な処理になる。
-        let path = detect_resource_path("romkan", &format!("{parent}.yml"))?;
-        let mut parent = load_romkan_map(&path)?;
+        let path = resolve_extends("romkan", &parent, file_path)?;
+        let mut parent = load_romkan_map(&path, chain)?;
 
         for (k, v) in got.mapping {
             if let Some(v) = v {
-                parent.insert(k, v);
+                parent.insert(k, v.into());
             } else {
                 parent.remove(&k);
             }
@@ -39,74 +116,248 @@ fn load_romkan_map(file_path: &str) -> anyhow::Result<HashMap<String, String>> {
         // 継承していないのでそのまま。
         Ok(got
             .mapping
-            .iter()
-            .filter(|(_, v)| v.is_some())
-            .map(|(k, v)| (k.clone(), v.clone().unwrap()))
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v.into())))
             .collect())
     }
 }
 
+/// 変換規則の入力を一文字ずつたどるトライ木。
+#[derive(Debug, Default)]
+struct RomKanTrie {
+    rule: Option<RomKanRule>,
+    children: HashMap<char, RomKanTrie>,
+}
+
+impl RomKanTrie {
+    fn insert(&mut self, input: &str, rule: RomKanRule) {
+        let mut node = self;
+        for c in input.chars() {
+            node = node.children.entry(c).or_default();
+        }
+        node.rule = Some(rule);
+    }
+
+    fn find(&self, input: &str) -> Option<&RomKanTrie> {
+        let mut node = self;
+        for c in input.chars() {
+            node = node.children.get(&c)?;
+        }
+        Some(node)
+    }
+
+    /// input の先頭に一致する、いちばん長い規則と、その入力のバイト数を返す。
+    fn longest_prefix(&self, input: &str) -> Option<(usize, &RomKanRule)> {
+        let mut node = self;
+        let mut longest = None;
+        for (i, c) in input.char_indices() {
+            let Some(child) = node.children.get(&c) else {
+                break;
+            };
+            node = child;
+            if let Some(rule) = &node.rule {
+                longest = Some((i + c.len_utf8(), rule));
+            }
+        }
+        longest
+    }
+}
+
+/// 一回の入力で規則を適用する回数の上限。
+/// 残す文字が循環するような規則があっても、変換が止まるようにする。
+const MAX_REWRITES: usize = 64;
+
+/// キーを一つずつ受け取って、かなに変換していく。
+///
+/// もっと長い規則に一致するかもしれない間は、入力を未確定のまま持っておく。
+/// "hogen" まで入力した時点では "ほげ" が確定していて、"n" が未確定になる。
+pub struct RomKanComposer<'a> {
+    trie: &'a RomKanTrie,
+    output: String,
+    pending: String,
+}
+
+impl<'a> RomKanComposer<'a> {
+    pub fn push(&mut self, c: char) {
+        let mut input = std::mem::take(&mut self.pending);
+        input.push(c);
+        for _ in 0..MAX_REWRITES {
+            if input.is_empty() {
+                return;
+            }
+            if matches!(self.trie.find(&input), Some(node) if !node.children.is_empty()) {
+                // 続けて入力すると、もっと長い規則に一致するかもしれないので待つ。
+                self.pending = input;
+                return;
+            }
+            input = self.apply_longest_prefix(&input);
+        }
+        self.give_up(input);
+    }
+
+    /// 確定した出力。
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// まだ変換していない入力。
+    pub fn pending(&self) -> &str {
+        &self.pending
+    }
+
+    /// 入力の終わりとして、未確定の入力も一致する規則で変換して、出力を返す。
+    pub fn finish(mut self) -> String {
+        let mut input = std::mem::take(&mut self.pending);
+        for _ in 0..MAX_REWRITES {
+            if input.is_empty() {
+                return self.output;
+            }
+            input = self.apply_longest_prefix(&input);
+        }
+        self.give_up(input);
+        self.output
+    }
+
+    /// 規則の適用が終わらないときは、残りの入力をそのまま出力する。
+    fn give_up(&mut self, input: String) {
+        if !input.is_empty() {
+            warn!("Too many rewrites in romkan map. Output as is: {:?}", input);
+            self.output += &input;
+        }
+    }
+
+    /// input の先頭に一致するいちばん長い規則で変換して、残りの入力を返す。
+    fn apply_longest_prefix(&mut self, input: &str) -> String {
+        if let Some((len, rule)) = self.trie.longest_prefix(input) {
+            self.push_output(&rule.output);
+            return rule.pending.clone() + &input[len..];
+        }
+        // どの規則にも一致しないので、そのまま出力する。
+        let c = input.chars().next().unwrap();
+        self.push_output(&input[..c.len_utf8()]);
+        input[c.len_utf8()..].to_string()
+    }
+
+    /// 出力を追加する。「゛」や「゜」だけの出力は、直前の文字につけられるときはつける。
+    /// かな入力で「か」のあとに「゛」を入力すると「が」になる。
+    fn push_output(&mut self, output: &str) {
+        let mut chars = output.chars();
+        if let (Some(mark), None) = (chars.next(), chars.next()) {
+            let voiced = self
+                .output
+                .chars()
+                .last()
+                .and_then(|prev| compose_voiced_mark(prev, mark));
+            if let Some(voiced) = voiced {
+                self.output.pop();
+                self.output.push(voiced);
+                return;
+            }
+        }
+        self.output += output;
+    }
+}
+
 #[derive(Debug)]
 pub struct RomKanConverter {
     pub mapping_name: String,
-    romkan_pattern: Regex,
-    romkan_map: HashMap<String, String>,
-    last_char_pattern: Regex,
+    romkan_trie: RomKanTrie,
 }
 
 impl RomKanConverter {
+    /// mapping_name は "default" のようなテーブルの名前か、ファイルのパス。
     pub fn new(mapping_name: &str) -> anyhow::Result<RomKanConverter> {
-        let romkan_map = load_romkan_map(mapping_name)?;
+        let path = find_table_path("romkan", mapping_name)?;
+        let romkan_map = load_romkan_map(&path, &mut Vec::new())?;
 
-        let mut romas = Vec::from_iter(romkan_map.keys());
-        // 長いキーから一致させるようにする。
-        romas.sort_by_key(|a| std::cmp::Reverse(a.len()));
-        let mut pattern = String::from("(");
-        for x in romas {
-            pattern += &regex::escape(x);
-            pattern += "|";
+        let mut romkan_trie = RomKanTrie::default();
+        for (input, rule) in &romkan_map {
+            if input.is_empty() {
+                bail!("Empty input in romkan map: {}", mapping_name);
+            }
+            romkan_trie.insert(input, rule.clone());
+        }
+        // 残した文字の先頭がまた規則に一致して、入力が短くならないと、変換が終わらなくなる。
+        // かな入力のように、一致する規則のない文字を残すのはよい。
+        // ここで見つけられない循環は、RomKanComposer で適用する回数を制限して止める。
+        for (input, rule) in &romkan_map {
+            if rule.pending.chars().count() >= input.chars().count()
+                && romkan_trie.longest_prefix(&rule.pending).is_some()
+            {
+                bail!(
+                    "Pending '{}' of '{}' must be shorter than the input in romkan map: {}",
+                    rule.pending,
+                    input,
+                    mapping_name
+                );
+            }
         }
-        pattern += ".)";
-
-        let romkan_pattern = Regex::new(&pattern).unwrap();
-        let last_char_pattern = Regex::new(&(pattern + "$")).unwrap();
 
         Ok(RomKanConverter {
             mapping_name: mapping_name.to_string(),
-            romkan_pattern,
-            romkan_map,
-            last_char_pattern,
+            romkan_trie,
         })
     }
 
+    /// テーブルのファイルと、extends でたどれる継承元のファイルのパスを返す。設定の変更を監視するのに使う。
+    pub fn source_paths(mapping_name: &str) -> anyhow::Result<Vec<String>> {
+        let path = find_table_path("romkan", mapping_name)?;
+        let mut chain = Vec::new();
+        load_romkan_map(&path, &mut chain)?;
+        Ok(chain)
+    }
+
     pub fn default_mapping() -> anyhow::Result<RomKanConverter> {
-        Self::new(&detect_resource_path("romkan", "default.yml")?)
+        Self::new("default")
     }
 }
 
 impl RomKanConverter {
+    pub fn composer(&self) -> RomKanComposer<'_> {
+        RomKanComposer {
+            trie: &self.romkan_trie,
+            output: String::new(),
+            pending: String::new(),
+        }
+    }
+
     pub fn to_hiragana(&self, src: &str) -> String {
-        let src = src.to_ascii_lowercase();
-        let src = src.replace("nn", "n'"); // replace nn as n'.
-        let retval = self.romkan_pattern.replace_all(&src, |caps: &Captures| {
-            let rom = caps.get(1).unwrap().as_str();
-            if let Some(e) = self.romkan_map.get(rom) {
-                e.to_string()
-            } else {
-                rom.to_string()
-            }
-        });
-        retval.into_owned()
+        let mut composer = self.composer();
+        src.to_ascii_lowercase()
+            .chars()
+            .for_each(|c| composer.push(c));
+        composer.finish()
+    }
+
+    /// 入力途中の表示用に、確定した部分と未確定の部分に分けて変換する。
+    /// "meny" は ("め", "ny") になる。
+    pub fn convert(&self, src: &str) -> (String, String) {
+        let mut composer = self.composer();
+        src.to_ascii_lowercase()
+            .chars()
+            .for_each(|c| composer.push(c));
+        (composer.output, composer.pending)
     }
 
+    /// 末尾の一文字を消す。末尾が規則の入力に一致するときは、一致したぶんをまとめて消す。
     pub fn remove_last_char(&self, src: &str) -> String {
-        self.last_char_pattern.replace(src, "").to_string()
+        for (i, _) in src.char_indices() {
+            if matches!(self.romkan_trie.find(&src[i..]), Some(node) if node.rule.is_some()) {
+                return src[..i].to_string();
+            }
+        }
+        let mut src = src.to_string();
+        src.pop();
+        src
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use log::LevelFilter;
+    use tempfile::TempDir;
 
     use super::*;
 
@@ -184,6 +435,151 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_convert() -> anyhow::Result<()> {
+        let converter = RomKanConverter::default_mapping()?;
+        let cases = [
+            ("hogen", ("ほげ", "n")),
+            ("hogena", ("ほげな", "")),
+            ("hogenn", ("ほげん", "")),
+            ("meny", ("め", "ny")),
+            ("u-nnwwww", ("うーんwww", "w")),
+            ("sozh", ("そ←", "")),
+        ];
+        for (src, (output, pending)) in cases {
+            assert_eq!(
+                converter.convert(src),
+                (output.to_string(), pending.to_string())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let path = tmpdir.path().join("pending.yml");
+        fs::write(
+            &path,
+            r#"
+mapping:
+  ta: た
+  te: て
+  tt: [っ, t]
+  "n": ん
+  nn: ん
+  na: な
+"#,
+        )?;
+        let converter = RomKanConverter::new(&path.to_string_lossy())?;
+
+        let mut composer = converter.composer();
+        for (c, output, pending) in [
+            ('t', "", "t"),
+            ('t', "っ", "t"),
+            ('a', "った", ""),
+            ('n', "った", "n"),
+            ('t', "ったん", "t"),
+        ] {
+            composer.push(c);
+            assert_eq!((composer.output(), composer.pending()), (output, pending));
+        }
+        assert_eq!(composer.finish(), "ったんt");
+
+        assert_eq!(converter.to_hiragana("tttte"), "っっって");
+        assert_eq!(converter.to_hiragana("nnna"), "んな");
+        Ok(())
+    }
+
+    #[test]
+    fn test_circular_pending() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let path = tmpdir.path().join("circular.yml");
+        fs::write(&path, "mapping:\n  a: [\"\", b]\n  b: [\"\", a]\n")?;
+        assert!(RomKanConverter::new(&path.to_string_lossy()).is_err());
+
+        // 残した文字そのものは規則でなくても、先頭が規則に一致すれば循環する。
+        fs::write(&path, "mapping:\n  xz: [\"\", xyq]\n  xy: [\"\", xzz]\n")?;
+        assert!(RomKanConverter::new(&path.to_string_lossy()).is_err());
+
+        // 読み込み時に見つけられない循環でも、変換は止まる。
+        fs::write(&path, "mapping:\n  bc: [\"\", bbc]\n")?;
+        let converter = RomKanConverter::new(&path.to_string_lossy())?;
+        assert!(converter.to_hiragana("bc").starts_with('b'));
+        let mut composer = converter.composer();
+        composer.push('b');
+        composer.push('c');
+        assert!(composer.pending().is_empty());
+        assert!(composer.finish().ends_with("bc"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extends_path() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        fs::write(
+            tmpdir.path().join("team.yml"),
+            "extends: default\nmapping:\n  tso: つぉ\n",
+        )?;
+        let path = tmpdir.path().join("my.yml");
+        fs::write(&path, "extends: ./team.yml\nmapping:\n  zya: null\n")?;
+        let converter = RomKanConverter::new(&path.to_string_lossy())?;
+        assert_eq!(converter.to_hiragana("tsozya"), "つぉzや");
+        let source_paths = RomKanConverter::source_paths(&path.to_string_lossy())?;
+        assert_eq!(source_paths.len(), 3);
+        assert_eq!(source_paths[0], path.to_string_lossy());
+        assert!(source_paths[1].ends_with("team.yml"));
+        assert!(source_paths[2].ends_with("default.yml"));
+
+        // 循環している継承はエラーにする。
+        fs::write(
+            tmpdir.path().join("team.yml"),
+            "extends: ./my.yml\nmapping: {}\n",
+        )?;
+        assert!(RomKanConverter::new(&path.to_string_lossy()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kana() -> anyhow::Result<()> {
+        let converter = RomKanConverter::new("../romkan/kana.yml")?;
+        assert_eq!(converter.convert("t"), ("か".to_string(), "".to_string()));
+        assert_eq!(converter.to_hiragana("t@"), "が");
+        assert_eq!(converter.to_hiragana("4@"), "ゔ");
+        assert_eq!(converter.to_hiragana("tq"), "かた");
+        assert_eq!(converter.to_hiragana("f["), "ぱ");
+        // 濁音をつけられない文字のあとでは、そのまま出力する。
+        assert_eq!(converter.to_hiragana("3@"), "あ゛");
+        assert_eq!(converter.to_hiragana("t["), "か゜");
+        assert_eq!(converter.remove_last_char("t@"), "t");
+        Ok(())
+    }
+
+    #[test]
+    fn test_voiced_mark() -> anyhow::Result<()> {
+        // かな配列で入力したかなは、どのテーブルでもそのまま出力して、濁点を合わせる。
+        let converter = RomKanConverter::default_mapping()?;
+        assert_eq!(converter.to_hiragana("か゛"), "が");
+        assert_eq!(converter.to_hiragana("ほ゜"), "ぽ");
+        assert_eq!(converter.to_hiragana("゛"), "゛");
+        // 「゛」を含む規則の出力は、そのまま使う。
+        assert_eq!(converter.to_hiragana("vu"), "う゛");
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_mozc_tsv() -> anyhow::Result<()> {
+        let config =
+            RomKanConfig::from_mozc_tsv("ka\tか\ntt\tっ\tt\nnn\tん\t\tNoTransliteration\n")?;
+        assert_eq!(
+            config.to_yaml()?,
+            "mapping:\n  ka: か\n  nn: ん\n  tt:\n  - っ\n  - t\n"
+        );
+
+        assert!(RomKanConfig::from_mozc_tsv("ka\n").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_atok() -> anyhow::Result<()> {
         let _ = env_logger::builder()
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::keysym::is_keysym_name;
+use crate::resource::find_table_path;
+
+/// 単独・左親指・右親指それぞれで打ったときの文字。
+pub type ThumbShiftChars = (Option<String>, Option<String>, Option<String>);
+
+/// 親指シフトのテーブル。キーの名前は keymap と同じ keysym の名前で書く。
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ThumbShiftTable {
+    /// 左親指キー。"Muhenkan" など。
+    pub left_thumb: String,
+    /// 右親指キー。"Henkan" など。
+    pub right_thumb: String,
+    /// 文字キーと、そのキーで打てる文字。
+    pub keys: HashMap<String, ThumbShiftChars>,
+}
+
+impl ThumbShiftTable {
+    /// table は "nicola" のようなテーブルの名前か、ファイルのパス。
+    pub fn load(table: &str) -> anyhow::Result<ThumbShiftTable> {
+        let path = find_table_path("thumb_shift", table)?;
+        info!("Loading thumb shift table: {}", path);
+        let got: ThumbShiftTable = serde_yaml::from_reader(BufReader::new(
+            File::open(&path).with_context(|| path.clone())?,
+        ))
+        .with_context(|| format!("Cannot parse {path}"))?;
+
+        for key in got.keys.keys().chain([&got.left_thumb, &got.right_thumb]) {
+            if !is_keysym_name(key) {
+                bail!("Unknown key name '{}' in {}", key, path);
+            }
+        }
+        if got.keys.contains_key(&got.left_thumb) || got.keys.contains_key(&got.right_thumb) {
+            bail!("Thumb keys must not be character keys in {}", path);
+        }
+        Ok(got)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Thumb {
+    Left,
+    Right,
+}
+
+/// 同時打鍵を判定した結果。
+#[derive(Debug, PartialEq, Clone)]
+pub enum ThumbShiftOutput {
+    /// 文字を入力する。
+    Text(String),
+    /// 親指キーが単独で押された。親指キーそのものとして扱う。
+    Thumb(Thumb),
+}
+
+/// 文字キーと親指キーの押下・解放から、同時打鍵を判定する。
+///
+/// 時刻はミリ秒で、呼び出し側が渡す。タイマーは使わず、次のキーイベントで時間切れを判定するので、
+/// 押したまま次のキーを待っている間は何も出力しない。キーを離せば確定する。
+///
+/// 文字キー、親指キー、文字キーと続けて押されたときは、親指キーと時刻の近いほうの文字キーを組にする。
+pub struct ThumbShiftInput {
+    table: ThumbShiftTable,
+    /// 同時に押したとみなす、キーを押した時刻の差の上限。
+    overlap_ms: u64,
+    /// 親指キーと組になるかどうか、まだ決まっていない文字キーと押した時刻。
+    pending_char: Option<(String, u64)>,
+    /// 文字キーと組になるかどうか、まだ決まっていない親指キーと押した時刻。
+    pending_thumb: Option<(Thumb, u64)>,
+}
+
+impl ThumbShiftInput {
+    pub fn new(table: ThumbShiftTable, overlap_ms: u64) -> ThumbShiftInput {
+        ThumbShiftInput {
+            table,
+            overlap_ms,
+            pending_char: None,
+            pending_thumb: None,
+        }
+    }
+
+    fn thumb(&self, key: &str) -> Option<Thumb> {
+        if key == self.table.left_thumb {
+            Some(Thumb::Left)
+        } else if key == self.table.right_thumb {
+            Some(Thumb::Right)
+        } else {
+            None
+        }
+    }
+
+    /// テーブルにある文字キーか親指キーか。それ以外のキーは、flush してから普通に処理する。
+    pub fn is_key(&self, key: &str) -> bool {
+        self.table.keys.contains_key(key) || self.thumb(key).is_some()
+    }
+
+    /// まだ確定していないキーがあるか。
+    pub fn is_pending(&self) -> bool {
+        self.pending_char.is_some() || self.pending_thumb.is_some()
+    }
+
+    pub fn press(&mut self, key: &str, time: u64) -> Vec<ThumbShiftOutput> {
+        let mut output = self.expire(time);
+        if let Some(thumb) = self.thumb(key) {
+            // 組が決まっている親指キーや、単独で押された親指キーは確定する。
+            // 文字キーだけが待っているときは、三つ目のキーが来るかもしれないので、まだ確定しない。
+            if self.pending_thumb.is_some() {
+                output.extend(self.flush());
+            }
+            self.pending_thumb = Some((thumb, time));
+            return output;
+        }
+
+        match (self.pending_char.take(), self.pending_thumb.take()) {
+            (Some((prev, prev_time)), Some((thumb, thumb_time))) => {
+                // 文字キー、親指キー、文字キーの順に押された。親指キーと近いほうを組にする。
+                if time.saturating_sub(thumb_time) < thumb_time.saturating_sub(prev_time) {
+                    output.extend(self.output(&prev, None));
+                    output.extend(self.output(key, Some(thumb)));
+                } else {
+                    output.extend(self.output(&prev, Some(thumb)));
+                    self.pending_char = Some((key.to_string(), time));
+                }
+            }
+            (None, Some((thumb, _))) => output.extend(self.output(key, Some(thumb))),
+            (Some((prev, _)), None) => {
+                output.extend(self.output(&prev, None));
+                self.pending_char = Some((key.to_string(), time));
+            }
+            (None, None) => self.pending_char = Some((key.to_string(), time)),
+        }
+        output
+    }
+
+    pub fn release(&mut self, key: &str, time: u64) -> Vec<ThumbShiftOutput> {
+        let mut output = self.expire(time);
+        let is_pending_char = matches!(&self.pending_char, Some((it, _)) if it == key);
+        let is_pending_thumb =
+            matches!((self.thumb(key), self.pending_thumb), (Some(a), Some((b, _))) if a == b);
+        if is_pending_char || is_pending_thumb {
+            output.extend(self.flush());
+        }
+        output
+    }
+
+    /// 待っているキーを、組になっているものはそのまま、そうでないものは単独で打ったものとして確定する。
+    pub fn flush(&mut self) -> Vec<ThumbShiftOutput> {
+        match (self.pending_char.take(), self.pending_thumb.take()) {
+            (Some((key, _)), thumb) => self.output(&key, thumb.map(|(it, _)| it)),
+            (None, Some((thumb, _))) => vec![ThumbShiftOutput::Thumb(thumb)],
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// time の時点で、同時に押したとみなす時間を過ぎたキーを確定する。
+    fn expire(&mut self, time: u64) -> Vec<ThumbShiftOutput> {
+        let last = match (&self.pending_char, &self.pending_thumb) {
+            (Some((_, a)), Some((_, b))) => *a.max(b),
+            (Some((_, a)), None) | (None, Some((_, a))) => *a,
+            (None, None) => return Vec::new(),
+        };
+        if time.saturating_sub(last) > self.overlap_ms {
+            self.flush()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn output(&self, key: &str, thumb: Option<Thumb>) -> Vec<ThumbShiftOutput> {
+        let Some((none, left, right)) = self.table.keys.get(key) else {
+            return Vec::new();
+        };
+        let text = match thumb {
+            None => none,
+            Some(Thumb::Left) => left,
+            Some(Thumb::Right) => right,
+        };
+        // 親指キーとの組み合わせに文字がないときは、単独で打った文字にする。
+        text.as_ref()
+            .or(none.as_ref())
+            .map(|it| vec![ThumbShiftOutput::Text(it.clone())])
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (時刻, キー, 押したか) の並びを入力して、出力をつなげた文字列を返す。
+    /// 親指キーの単独打鍵は "<L>" "<R>" にする。
+    fn run(input: &mut ThumbShiftInput, events: &[(u64, &str, bool)]) -> String {
+        let mut result = String::new();
+        for (time, key, pressed) in events {
+            let output = if *pressed {
+                input.press(key, *time)
+            } else {
+                input.release(key, *time)
+            };
+            for it in output {
+                match it {
+                    ThumbShiftOutput::Text(text) => result += &text,
+                    ThumbShiftOutput::Thumb(Thumb::Left) => result += "<L>",
+                    ThumbShiftOutput::Thumb(Thumb::Right) => result += "<R>",
+                }
+            }
+        }
+        result
+    }
+
+    fn nicola() -> ThumbShiftInput {
+        ThumbShiftInput::new(ThumbShiftTable::load("nicola").unwrap(), 100)
+    }
+
+    #[test]
+    fn test_single() {
+        let mut input = nicola();
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (0, "w", true),
+                    (50, "w", false),
+                    (80, "e", true),
+                    (300, "e", false)
+                ]
+            ),
+            "かた"
+        );
+        assert!(!input.is_pending());
+    }
+
+    #[test]
+    fn test_simultaneous() {
+        let mut input = nicola();
+        // 文字キーが先
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (0, "w", true),
+                    (30, "Henkan", true),
+                    (80, "w", false),
+                    (90, "Henkan", false)
+                ]
+            ),
+            "が"
+        );
+        // 親指キーが先
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (1000, "Muhenkan", true),
+                    (1030, "s", true),
+                    (1080, "Muhenkan", false),
+                    (1090, "s", false)
+                ]
+            ),
+            "あ"
+        );
+        // 同時とみなす時間を過ぎている
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (2000, "w", true),
+                    (2150, "Henkan", true),
+                    (2200, "Henkan", false),
+                    (2210, "w", false)
+                ]
+            ),
+            "か<R>"
+        );
+        // 親指キーとの組み合わせに文字がないときは、単独で打った文字になる。
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (3000, "q", true),
+                    (3010, "Henkan", true),
+                    (3020, "q", false)
+                ]
+            ),
+            "。"
+        );
+    }
+
+    #[test]
+    fn test_three_keys() {
+        let mut input = nicola();
+        // 親指キーは後の文字キーのほうが近い。
+        assert_eq!(
+            run(
+                &mut input,
+                &[(0, "w", true), (60, "Henkan", true), (80, "e", true)]
+            ),
+            "かだ"
+        );
+        assert!(!input.is_pending());
+        // 親指キーは前の文字キーのほうが近い。
+        assert_eq!(
+            run(
+                &mut input,
+                &[
+                    (1000, "w", true),
+                    (1020, "Henkan", true),
+                    (1090, "e", true),
+                    (1100, "e", false)
+                ]
+            ),
+            "がた"
+        );
+    }
+
+    #[test]
+    fn test_timeout_and_flush() {
+        let mut input = nicola();
+        // 離す前に次のキーが押されても、時間を過ぎていれば単独で打ったことになる。
+        assert_eq!(
+            run(&mut input, &[(0, "w", true), (200, "Muhenkan", true)]),
+            "か"
+        );
+        assert!(input.is_pending());
+        assert_eq!(input.flush(), vec![ThumbShiftOutput::Thumb(Thumb::Left)]);
+        assert!(!input.is_pending());
+        assert!(input.is_key("semicolon"));
+        assert!(!input.is_key("Return"));
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
 
-use crate::cost::calc_cost;
+use crate::config::UserDataConfig;
+use crate::cost::{calc_cost, calc_decay_cost};
 use crate::graph::candidate::Candidate;
+use crate::user_side_data::user_stats_utils::{
+    evict_least_recently_used, merge_word_count, UserStatsEntry,
+};
 
 #[derive(Default)]
 pub(crate) struct BiGramUserStats {
@@ -11,36 +15,76 @@ pub(crate) struct BiGramUserStats {
     /// 総単語出現数
     total_words: u32,
     // V
-    /// その単語の出現頻度。「漢字/漢字」がキー。
-    pub(crate) word_count: HashMap<String, u32>,
+    /// その単語の出現頻度と最終使用日時。「漢字/漢字」がキー。
+    pub(crate) word_count: HashMap<String, UserStatsEntry>,
+
+    config: UserDataConfig,
 }
 
 impl BiGramUserStats {
     pub(crate) fn new(
-        unique_words: u32,
-        total_words: u32,
-        word_count: HashMap<String, u32>,
+        word_count: HashMap<String, UserStatsEntry>,
+        config: UserDataConfig,
     ) -> BiGramUserStats {
-        BiGramUserStats {
-            unique_words,
-            total_words,
+        Self::new_with_evicted(word_count, config).0
+    }
+
+    /// 上限を超えて捨てたエントリーの数も返す。
+    pub(crate) fn new_with_evicted(
+        mut word_count: HashMap<String, UserStatsEntry>,
+        config: UserDataConfig,
+    ) -> (BiGramUserStats, usize) {
+        let evicted = evict_least_recently_used(&mut word_count, config.max_entries).len();
+        let stats = BiGramUserStats {
+            unique_words: word_count.len() as u32,
+            total_words: word_count.values().map(|it| it.count).sum(),
             word_count,
-        }
+            config,
+        };
+        (stats, evicted)
+    }
+
+    /// 別の統計を足し合わせる。上限を超えた分は古いものから捨てる。
+    ///
+    /// @return 捨てたエントリーの数
+    pub(crate) fn merge(&mut self, other: &BiGramUserStats) -> usize {
+        let mut word_count = std::mem::take(&mut self.word_count);
+        merge_word_count(&mut word_count, &other.word_count);
+        let (stats, evicted) = BiGramUserStats::new_with_evicted(word_count, self.config.clone());
+        *self = stats;
+        evicted
+    }
+
+    /// 別の統計で置き換える。上限を超えた分は古いものから捨てる。
+    ///
+    /// @return 捨てたエントリーの数
+    pub(crate) fn replace(&mut self, other: BiGramUserStats) -> usize {
+        let (stats, evicted) =
+            BiGramUserStats::new_with_evicted(other.word_count, self.config.clone());
+        *self = stats;
+        evicted
     }
 
     /**
      * エッジコストを計算する。
      * システム言語モデルのコストよりも安くなるように調整してある。
+     * 最後に使ってから時間が経っているほど、コストが高くなる。
      */
-    pub(crate) fn get_cost(&self, key1: &str, key2: &str) -> Option<f32> {
+    pub(crate) fn get_cost(&self, key1: &str, key2: &str, now: u64) -> Option<f32> {
         let key = key1.to_owned() + "\t" + key2;
-        let Some(count) = self.word_count.get(key.as_str()) else {
+        let Some(entry) = self.word_count.get(key.as_str()) else {
             return None;
         };
-        Some(calc_cost(*count, self.unique_words, self.total_words))
+        Some(
+            calc_cost(entry.count, self.unique_words, self.total_words)
+                + calc_decay_cost(
+                    now.saturating_sub(entry.last_used),
+                    self.config.half_life_days as u64 * 24 * 60 * 60,
+                ),
+        )
     }
 
-    pub(crate) fn record_entries(&mut self, candidates: &[Candidate]) {
+    pub(crate) fn record_entries(&mut self, candidates: &[Candidate], now: u64) {
         if candidates.len() < 2 {
             return;
         }
@@ -55,13 +99,47 @@ impl BiGramUserStats {
             };
 
             let key = candidate1.key() + "\t" + candidate2.key().as_str();
-            if let Some(cnt) = self.word_count.get(&key) {
-                self.word_count.insert(key, cnt + 1);
+            if let Some(entry) = self.word_count.get_mut(&key) {
+                entry.count += 1;
+                entry.last_used = now;
             } else {
-                self.word_count.insert(key, 1);
+                self.word_count.insert(
+                    key,
+                    UserStatsEntry {
+                        count: 1,
+                        last_used: now,
+                    },
+                );
                 self.unique_words += 1;
             }
             self.total_words += 1;
         }
+
+        for (_, entry) in evict_least_recently_used(&mut self.word_count, self.config.max_entries) {
+            self.unique_words = self.unique_words.saturating_sub(1);
+            self.total_words = self.total_words.saturating_sub(entry.count);
+        }
+    }
+
+    /// key の単語を含む bigram の統計を全て削除する。削除した場合は true を返す。
+    pub(crate) fn delete_entries_with(&mut self, key: &str) -> bool {
+        let targets = self
+            .word_count
+            .keys()
+            .filter(|words| {
+                words
+                    .split_once('\t')
+                    .map(|(key1, key2)| key1 == key || key2 == key)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for words in &targets {
+            if let Some(entry) = self.word_count.remove(words) {
+                self.unique_words = self.unique_words.saturating_sub(1);
+                self.total_words = self.total_words.saturating_sub(entry.count);
+            }
+        }
+        !targets.is_empty()
     }
 }
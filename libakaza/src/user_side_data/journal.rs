@@ -0,0 +1,216 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use crate::graph::candidate::Candidate;
+
+/// ジャーナルに記録する、ユーザーデータへの一回分の変更。
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum JournalEntry {
+    /// record_entries の呼び出し。now は記録した日時。
+    Record {
+        now: u64,
+        candidates: Vec<Candidate>,
+    },
+    /// delete_candidate の呼び出し。
+    Delete { candidate: Candidate },
+}
+
+/// 統計ファイルに保存するまでの間の変更を、追記専用のファイルに書いておく。
+/// 保存前にプロセスが落ちても、次回の起動時に再適用できる。
+///
+/// 一行が一回分の変更で、タブ区切りで以下の形式。
+///
+/// - `R 日時 (表層 読み 複合語か)...`
+/// - `D 表層 読み 複合語か`
+///
+/// 先頭の行は `G 世代` で、どの世代の統計ファイルに対する変更かを表す。
+pub(crate) struct Journal {
+    path: String,
+    file: Option<File>,
+}
+
+impl Journal {
+    pub(crate) fn new(path: &str) -> Journal {
+        Journal {
+            path: path.to_string(),
+            file: None,
+        }
+    }
+
+    /// ジャーナルの内容を読む。書き込み途中で落ちたなどで壊れている行は読み飛ばす。
+    ///
+    /// @return (世代, 変更のリスト)。世代の行がなければ 0。
+    pub(crate) fn read(&self) -> Result<(u64, Vec<JournalEntry>)> {
+        if !Path::new(&self.path).exists() {
+            return Ok((0, Vec::new()));
+        }
+
+        let file = File::open(&self.path)?;
+        let mut generation = 0;
+        let mut result = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("Cannot read user data journal")?;
+            if i == 0 {
+                if let Some(Ok(g)) = line.strip_prefix("G\t").map(|it| it.parse::<u64>()) {
+                    generation = g;
+                    continue;
+                }
+            }
+            match parse_line(&line) {
+                Some(entry) => result.push(entry),
+                None => warn!("Broken line in user data journal: {:?}", line),
+            }
+        }
+        Ok((generation, result))
+    }
+
+    pub(crate) fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let file = self.open()?;
+        // 一行を一回の write で書いて、途中までしか書かれない可能性を減らす。
+        file.write_all((format_entry(entry) + "\n").as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// 統計ファイルを generation の世代で保存した後に、ジャーナルを空にする。
+    pub(crate) fn reset(&mut self, generation: u64) -> Result<()> {
+        let file = self.open()?;
+        file.set_len(0)?;
+        file.write_all(format!("G\t{generation}\n").as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<&mut File> {
+        if self.file.is_none() {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(&self.path)
+                .with_context(|| format!("Cannot open {}", self.path))?;
+            // 前回のプロセスが行の途中で落ちていたら、その行に続けて書かないように改行する。
+            if file.metadata()?.len() > 0 {
+                let mut last = [0_u8; 1];
+                file.seek(SeekFrom::End(-1))?;
+                file.read_exact(&mut last)?;
+                if last[0] != b'\n' {
+                    file.write_all(b"\n")?;
+                }
+            }
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+fn format_candidate(candidate: &Candidate) -> String {
+    format!(
+        "{}\t{}\t{}",
+        candidate.surface,
+        candidate.yomi,
+        if candidate.compound_word { 1 } else { 0 }
+    )
+}
+
+fn format_entry(entry: &JournalEntry) -> String {
+    match entry {
+        JournalEntry::Record { now, candidates } => {
+            let mut line = format!("R\t{now}");
+            for candidate in candidates {
+                line += "\t";
+                line += &format_candidate(candidate);
+            }
+            line
+        }
+        JournalEntry::Delete { candidate } => "D\t".to_string() + &format_candidate(candidate),
+    }
+}
+
+fn parse_candidates(fields: &[&str]) -> Option<Vec<Candidate>> {
+    let chunks = fields.chunks_exact(3);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    chunks
+        .map(|chunk| {
+            let mut candidate = Candidate::new(chunk[1], chunk[0], 0_f32);
+            candidate.compound_word = match chunk[2] {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            };
+            Some(candidate)
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<JournalEntry> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    match fields.first() {
+        Some(&"R") if fields.len() >= 2 => Some(JournalEntry::Record {
+            now: fields[1].parse::<u64>().ok()?,
+            candidates: parse_candidates(&fields[2..])?,
+        }),
+        Some(&"D") if fields.len() == 4 => Some(JournalEntry::Delete {
+            candidate: parse_candidates(&fields[1..])?.pop()?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let path = tmpdir.path().join("journal.txt");
+        let mut journal = Journal::new(path.to_str().unwrap());
+
+        let mut compound = Candidate::new("きたかな", "北香那", 0_f32);
+        compound.compound_word = true;
+        let entries = vec![
+            JournalEntry::Record {
+                now: 1672531200,
+                candidates: vec![Candidate::new("わたし", "私", 0_f32), compound.clone()],
+            },
+            JournalEntry::Delete {
+                candidate: compound,
+            },
+        ];
+        for entry in &entries {
+            journal.append(entry)?;
+        }
+        assert_eq!(journal.read()?, (0, entries.clone()));
+
+        // 書き込み途中で落ちた行は読み飛ばす。
+        fs::write(
+            &path,
+            fs::read_to_string(&path)? + "R\t1672531300\tあなた\tあな",
+        )?;
+        assert_eq!(journal.read()?, (0, entries.clone()));
+
+        // 壊れた行の後にも追記できる。
+        let mut journal = Journal::new(path.to_str().unwrap());
+        journal.append(&entries[1])?;
+        assert_eq!(journal.read()?.1.len(), 3);
+
+        journal.reset(3)?;
+        assert_eq!(journal.read()?, (3, vec![]));
+        journal.append(&entries[1])?;
+        assert_eq!(journal.read()?, (3, vec![entries[1].clone()]));
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 mod bigram_user_stats;
+mod journal;
 mod unigram_user_stats;
 // 調整めんどくさいのでいったんオフ。
 pub mod user_data;
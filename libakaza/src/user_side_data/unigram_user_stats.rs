@@ -1,7 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::cost::calc_cost;
+use crate::config::UserDataConfig;
+use crate::cost::{calc_cost, calc_decay_cost};
 use crate::graph::candidate::Candidate;
+use crate::user_side_data::user_stats_utils::{
+    evict_least_recently_used, merge_word_count, UserStatsEntry,
+};
 
 #[derive(Default)]
 pub(crate) struct UniGramUserStats {
@@ -11,44 +15,223 @@ pub(crate) struct UniGramUserStats {
     /// 総単語出現数
     total_words: u32,
     // V
-    /// その単語の出現頻度。「漢字/かな」がキー。
-    pub(crate) word_count: HashMap<String, u32>,
+    /// その単語の出現頻度と最終使用日時。「漢字/かな」がキー。
+    pub(crate) word_count: HashMap<String, UserStatsEntry>,
+    /// 読み -> 表層。予測変換で、読みの前方一致で探すのに使う。
+    surfaces_by_yomi: BTreeMap<String, BTreeSet<String>>,
+
+    config: UserDataConfig,
 }
 
 impl UniGramUserStats {
     pub(crate) fn new(
-        unique_words: u32,
-        total_words: u32,
-        word_count: HashMap<String, u32>,
+        word_count: HashMap<String, UserStatsEntry>,
+        config: UserDataConfig,
     ) -> UniGramUserStats {
-        UniGramUserStats {
-            unique_words,
-            total_words,
-            word_count,
+        Self::new_with_evicted(word_count, config).0
+    }
+
+    /// 上限を超えて捨てたエントリーの数も返す。
+    pub(crate) fn new_with_evicted(
+        mut word_count: HashMap<String, UserStatsEntry>,
+        config: UserDataConfig,
+    ) -> (UniGramUserStats, usize) {
+        let evicted = evict_least_recently_used(&mut word_count, config.max_entries).len();
+        let mut stats = UniGramUserStats {
+            unique_words: word_count.len() as u32,
+            total_words: word_count.values().map(|it| it.count).sum(),
+            word_count: HashMap::new(),
+            surfaces_by_yomi: BTreeMap::new(),
+            config,
+        };
+        for key in word_count.keys() {
+            stats.add_to_index(key);
         }
+        stats.word_count = word_count;
+        (stats, evicted)
+    }
+
+    fn add_to_index(&mut self, key: &str) {
+        if let Some((surface, yomi)) = key.split_once('/') {
+            self.surfaces_by_yomi
+                .entry(yomi.to_string())
+                .or_default()
+                .insert(surface.to_string());
+        }
+    }
+
+    fn remove_from_index(&mut self, key: &str) {
+        let Some((surface, yomi)) = key.split_once('/') else {
+            return;
+        };
+        if let Some(surfaces) = self.surfaces_by_yomi.get_mut(yomi) {
+            surfaces.remove(surface);
+            if surfaces.is_empty() {
+                self.surfaces_by_yomi.remove(yomi);
+            }
+        }
+    }
+
+    /// この読みの単語の統計があるか。
+    pub(crate) fn has_yomi(&self, yomi: &str) -> bool {
+        self.surfaces_by_yomi.contains_key(yomi)
+    }
+
+    /// 読みが yomi_prefix で始まる単語を (読み, 表層) の形で返す。
+    pub(crate) fn predict<'a>(
+        &'a self,
+        yomi_prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.surfaces_by_yomi
+            .range(yomi_prefix.to_string()..)
+            .take_while(move |(yomi, _)| yomi.starts_with(yomi_prefix))
+            .flat_map(|(yomi, surfaces)| {
+                surfaces
+                    .iter()
+                    .map(move |surface| (yomi.as_str(), surface.as_str()))
+            })
+    }
+
+    /// 別の統計を足し合わせる。上限を超えた分は古いものから捨てる。
+    ///
+    /// @return 捨てたエントリーの数
+    pub(crate) fn merge(&mut self, other: &UniGramUserStats) -> usize {
+        let mut word_count = std::mem::take(&mut self.word_count);
+        merge_word_count(&mut word_count, &other.word_count);
+        let (stats, evicted) = UniGramUserStats::new_with_evicted(word_count, self.config.clone());
+        *self = stats;
+        evicted
+    }
+
+    /// 別の統計で置き換える。上限を超えた分は古いものから捨てる。
+    ///
+    /// @return 捨てたエントリーの数
+    pub(crate) fn replace(&mut self, other: UniGramUserStats) -> usize {
+        let (stats, evicted) =
+            UniGramUserStats::new_with_evicted(other.word_count, self.config.clone());
+        *self = stats;
+        evicted
     }
 
     /**
      * ノードコストを計算する。
+     * 最後に使ってから時間が経っているほど、コストが高くなる。
      */
-    pub(crate) fn get_cost(&self, key: String) -> Option<f32> {
-        let Some(count) = self.word_count.get(key.as_str()) else {
+    pub(crate) fn get_cost(&self, key: String, now: u64) -> Option<f32> {
+        let Some(entry) = self.word_count.get(key.as_str()) else {
             return None;
         };
 
-        Some(calc_cost(*count, self.unique_words, self.total_words))
+        Some(
+            calc_cost(entry.count, self.unique_words, self.total_words)
+                + calc_decay_cost(
+                    now.saturating_sub(entry.last_used),
+                    self.config.half_life_days as u64 * 24 * 60 * 60,
+                ),
+        )
     }
 
-    pub(crate) fn record_entries(&mut self, candidates: &[Candidate]) {
+    pub(crate) fn record_entries(&mut self, candidates: &[Candidate], now: u64) {
         for candidate in candidates {
             let key = candidate.key();
-            if let Some(i) = self.word_count.get(&key) {
-                self.word_count.insert(key, i + 1);
+            if let Some(entry) = self.word_count.get_mut(&key) {
+                entry.count += 1;
+                entry.last_used = now;
             } else {
-                self.word_count.insert(key, 1);
+                self.add_to_index(&key);
+                self.word_count.insert(
+                    key,
+                    UserStatsEntry {
+                        count: 1,
+                        last_used: now,
+                    },
+                );
                 self.unique_words += 1;
             }
             self.total_words += 1;
         }
+
+        for (key, entry) in evict_least_recently_used(&mut self.word_count, self.config.max_entries)
+        {
+            self.remove_from_index(&key);
+            self.unique_words = self.unique_words.saturating_sub(1);
+            self.total_words = self.total_words.saturating_sub(entry.count);
+        }
+    }
+
+    /// 単語の統計を削除する。削除した場合は true を返す。
+    pub(crate) fn delete_entry(&mut self, key: &str) -> bool {
+        let Some(entry) = self.word_count.remove(key) else {
+            return false;
+        };
+        self.remove_from_index(key);
+        self.unique_words = self.unique_words.saturating_sub(1);
+        self.total_words = self.total_words.saturating_sub(entry.count);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decay_and_eviction() {
+        let mut stats = UniGramUserStats::new(
+            HashMap::new(),
+            UserDataConfig {
+                max_entries: 2,
+                half_life_days: 1,
+            },
+        );
+        stats.record_entries(&[Candidate::new("わたし", "私", 0_f32)], 0);
+        stats.record_entries(&[Candidate::new("わたし", "渡し", 0_f32)], 100);
+
+        // 同じ回数使われていても、古いほうがコストが高い。
+        let now = 24 * 60 * 60;
+        assert!(
+            stats.get_cost("私/わたし".to_string(), now).unwrap()
+                > stats.get_cost("渡し/わたし".to_string(), now).unwrap()
+        );
+
+        // 上限を超えると、最後に使ってから最も時間が経ったものから捨てる。
+        stats.record_entries(&[Candidate::new("わたし", "綿", 0_f32)], 200);
+        assert_eq!(stats.get_cost("私/わたし".to_string(), now), None);
+        assert!(stats.get_cost("渡し/わたし".to_string(), now).is_some());
+        assert_eq!(stats.unique_words, 2);
+        assert_eq!(stats.total_words, 2);
+    }
+
+    #[test]
+    fn test_predict() {
+        let mut stats = UniGramUserStats::new(
+            HashMap::from([(
+                "田中/たなか".to_string(),
+                UserStatsEntry {
+                    count: 1,
+                    last_used: 0,
+                },
+            )]),
+            UserDataConfig::default(),
+        );
+        stats.record_entries(
+            &[
+                Candidate::new("たなばた", "七夕", 0_f32),
+                Candidate::new("なか", "中", 0_f32),
+            ],
+            0,
+        );
+        assert_eq!(
+            stats.predict("たな").collect::<Vec<_>>(),
+            vec![("たなか", "田中"), ("たなばた", "七夕")]
+        );
+
+        // 削除した単語は出てこない。
+        stats.delete_entry("田中/たなか");
+        assert_eq!(
+            stats.predict("たな").collect::<Vec<_>>(),
+            vec![("たなばた", "七夕")]
+        );
+        assert!(!stats.has_yomi("たなか"));
     }
 }
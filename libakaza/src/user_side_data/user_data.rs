@@ -1,20 +1,50 @@
 use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::SystemTime;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use encoding_rs::UTF_8;
 use log::{info, warn};
 
-use crate::dict::skk::read::read_skkdict;
-use crate::dict::skk::write::write_skk_dict;
+use crate::config::UserDataConfig;
+use crate::dict::skk::read::{parse_skkdict, read_skkdict};
+use crate::dict::skk::write::write_skk_dict_with_comments;
 use crate::graph::candidate::Candidate;
 use crate::graph::word_node::WordNode;
 use crate::kana_trie::cedarwood_kana_trie::CedarwoodKanaTrie;
+use crate::reverse_dict::ReverseDict;
 use crate::user_side_data::bigram_user_stats::BiGramUserStats;
+use crate::user_side_data::journal::{Journal, JournalEntry};
 use crate::user_side_data::unigram_user_stats::UniGramUserStats;
-use crate::user_side_data::user_stats_utils::{read_user_stats_file, write_user_stats_file};
+use crate::user_side_data::user_stats_utils::{
+    migrate_user_stats_file, read_generation, read_user_stats_v2_file,
+    read_user_stats_v2_file_strict, unix_time, write_user_stats_file, STATS_GENERATION_PREFIX,
+};
+
+/// ユーザー辞書の先頭に書く、保存した世代のコメント。
+const DICT_GENERATION_COMMENT: &str = "generation ";
+
+/// ジャーナルを再適用する対象。
+/// 保存の途中で落ちると、一部のファイルだけが新しい世代になる。
+/// 新しい世代のファイルにはジャーナルの内容が反映済みなので、二重に適用しないようにする。
+#[derive(Clone, Copy)]
+struct ReplayTargets {
+    unigram: bool,
+    bigram: bool,
+    dict: bool,
+}
+
+impl ReplayTargets {
+    const ALL: ReplayTargets = ReplayTargets {
+        unigram: true,
+        bigram: true,
+        dict: true,
+    };
+}
 
 /**
  * ユーザー固有データ
@@ -22,9 +52,7 @@ use crate::user_side_data::user_stats_utils::{read_user_stats_file, write_user_s
 #[derive(Default)]
 pub struct UserData {
     /// 読み仮名のトライ。入力変換時に共通接頭辞検索するために使用。
-    // ここで MARISA ではなく Cedarwood を採用しているのは
-    // - FFI していると std::marker::Send を実装できなくてスレッドをまたいだ処理が困難になるから
-    // - 更新可能なトライ構造だから
+    // ここで MARISA ではなく Cedarwood を採用しているのは、更新可能なトライ構造だから。
     pub(crate) kana_trie: Arc<Mutex<CedarwoodKanaTrie>>,
 
     unigram_user_stats: UniGramUserStats,
@@ -36,73 +64,227 @@ pub struct UserData {
 
     pub dict: HashMap<String, Vec<String>>,
 
+    /// ユーザー辞書と unigram 統計から作る逆引き辞書。学習データが変わったら作りなおす。
+    reverse_dict: OnceLock<Arc<ReverseDict>>,
+
+    /// 保存するまでの間の変更を記録するジャーナル。
+    journal: Option<Journal>,
+
+    /// ibus-akaza、akaza-server、akaza-data が同じファイルを読み書きするので、
+    /// このファイルで advisory lock を取ってから読み書きする。
+    lock_path: Option<String>,
+
+    /// ファイルから読み込みなおすときに使う設定。
+    config: UserDataConfig,
+
+    /// メモリ上の学習データに反映されている、保存されたファイルの世代。
+    /// これより新しい世代があれば、他のプロセスが保存している。
+    generation: u64,
+
     pub(crate) need_save: bool,
 }
 
 impl UserData {
-    pub fn load_from_default_path() -> Result<Self> {
-        let basedir = xdg::BaseDirectories::with_prefix("akaza")?;
-        let unigram_path = basedir
-            .place_data_file(Path::new("unigram.v1.txt"))?
-            .to_str()
-            .unwrap()
-            .to_string();
-        let bigram_path = basedir
-            .place_data_file(Path::new("bigram.v1.txt"))?
-            .to_str()
-            .unwrap()
-            .to_string();
-        let dict_path = basedir
-            .place_data_file(Path::new("SKK-JISYO.user"))?
+    /// ユーザーデータを保存するデフォルトのパス。
+    ///
+    /// @return (unigram の統計, bigram の統計, ユーザー辞書) のパス
+    fn default_paths() -> Result<(String, String, String)> {
+        Ok((
+            Self::data_file("unigram.v2.txt")?,
+            Self::data_file("bigram.v2.txt")?,
+            Self::data_file("SKK-JISYO.user")?,
+        ))
+    }
+
+    fn data_file(name: &str) -> Result<String> {
+        Ok(xdg::BaseDirectories::with_prefix("akaza")?
+            .place_data_file(Path::new(name))?
             .to_str()
             .unwrap()
-            .to_string();
+            .to_string())
+    }
+
+    pub fn load_from_default_path(config: &UserDataConfig) -> Result<Self> {
+        let (unigram_path, bigram_path, dict_path) = Self::default_paths()?;
+        let lock_path = Self::data_file("user_data.lock")?;
+        let _lock = lock_file(&lock_path)?;
+        // 最終使用日時を持たない v1 のファイルしかなければ、v2 に変換する。
+        for (v1_path, v2_path) in [
+            (Self::data_file("unigram.v1.txt")?, &unigram_path),
+            (Self::data_file("bigram.v1.txt")?, &bigram_path),
+        ] {
+            if let Err(err) = migrate_user_stats_file(&v1_path, v2_path) {
+                warn!("Cannot migrate user stats file {}: {:?}", v1_path, err);
+            }
+        }
         info!(
             "Load user data from default path: unigram={}, bigram={}",
             unigram_path, bigram_path
         );
-        Ok(UserData::load(&unigram_path, &bigram_path, &dict_path))
+        let mut user_data = UserData::load(&unigram_path, &bigram_path, &dict_path, config);
+        user_data.lock_path = Some(lock_path);
+        user_data.open_journal(&Self::data_file("journal.txt")?)?;
+        Ok(user_data)
+    }
+
+    /// デフォルトのパスの学習データを、ロックを取ったまま読み込んで f で書き換え、保存する。
+    /// 学習データの取り込みのように、ジャーナルに記録しない変更をするときに使う。
+    /// f の中で record_entries や delete_candidate を呼ばないこと。
+    pub fn update_default_files<R>(
+        config: &UserDataConfig,
+        f: impl FnOnce(&mut UserData) -> R,
+    ) -> Result<R> {
+        let mut user_data = Self::load_from_default_path(config)?;
+        let _lock = user_data.lock()?;
+        // ロックを取るまでの間に、他のプロセスが保存したかもしれない。
+        user_data.reload()?;
+        let result = f(&mut user_data);
+        user_data.write_files()?;
+        Ok(result)
     }
 
-    pub fn load(unigram_path: &String, bigram_path: &String, dict_path: &String) -> Self {
+    /// ジャーナルに残っている、前回保存してから後の変更を再適用する。
+    /// 以後の変更はジャーナルに記録する。ロックを取ってから呼ぶこと。
+    fn open_journal(&mut self, journal_path: &str) -> Result<()> {
+        let mut journal = Journal::new(journal_path);
+        let replayed = self.replay_journal(&journal)?;
+        if replayed == 0 {
+            // 以後の変更がどの世代に対するものか分かるように、世代を書いておく。
+            self.generation = self.latest_generation(&journal)?;
+            journal.reset(self.generation)?;
+            self.journal = Some(journal);
+            return Ok(());
+        }
+
+        self.journal = Some(journal);
+        // 再適用した内容を保存して、ジャーナルと統計ファイルの世代をそろえる。
+        self.write_files()
+    }
+
+    /// ジャーナルに記録されている変更を、まだ反映していないファイルの分だけ適用する。
+    ///
+    /// @return 適用した変更の数
+    fn replay_journal(&mut self, journal: &Journal) -> Result<usize> {
+        let (generation, entries) = journal.read()?;
+        let [unigram_generation, bigram_generation, dict_generation] = self.saved_generations()?;
+        let targets = ReplayTargets {
+            unigram: unigram_generation <= generation,
+            bigram: bigram_generation <= generation,
+            dict: dict_generation <= generation,
+        };
+        if !entries.is_empty() {
+            info!("Replaying {} entries from the journal", entries.len());
+        }
+        for entry in &entries {
+            match entry {
+                JournalEntry::Record { now, candidates } => {
+                    self.apply_record(candidates, *now, targets);
+                }
+                JournalEntry::Delete { candidate } => {
+                    self.apply_delete(candidate, targets);
+                }
+            }
+        }
+        Ok(entries.len())
+    }
+
+    /// 保存されているファイルとジャーナルから、学習データを読み込みなおす。ロックを取ってから呼ぶこと。
+    /// 自分の変更もジャーナルに記録してあるので、他のプロセスの変更とあわせて取り込まれる。
+    fn reload(&mut self) -> Result<()> {
+        let (Some(unigram_path), Some(bigram_path), Some(dict_path), Some(journal)) = (
+            &self.unigram_path,
+            &self.bigram_path,
+            &self.dict_path,
+            &self.journal,
+        ) else {
+            return Ok(());
+        };
+        let mut reloaded = UserData::load(unigram_path, bigram_path, dict_path, &self.config);
+        reloaded.replay_journal(journal)?;
+        self.generation = self.latest_generation(journal)?;
+
+        self.unigram_user_stats = reloaded.unigram_user_stats;
+        self.bigram_user_stats = reloaded.bigram_user_stats;
+        self.dict = reloaded.dict;
+        self.reverse_dict = OnceLock::new();
+        // かなトライは Segmenter と共有しているので、中身だけを入れ替える。
+        *self.kana_trie.lock().unwrap() =
+            Self::build_kana_trie(&self.unigram_user_stats, &self.dict);
+        Ok(())
+    }
+
+    /// 保存されている unigram、bigram、ユーザー辞書の世代。
+    fn saved_generations(&self) -> Result<[u64; 3]> {
+        let generation = |path: &Option<String>, prefix: &str| match path {
+            Some(path) => read_generation(path, prefix),
+            None => Ok(0),
+        };
+        Ok([
+            generation(&self.unigram_path, STATS_GENERATION_PREFIX)?,
+            generation(&self.bigram_path, STATS_GENERATION_PREFIX)?,
+            generation(
+                &self.dict_path,
+                &(";; ".to_string() + DICT_GENERATION_COMMENT),
+            )?,
+        ])
+    }
+
+    /// ファイルに保存されている中で、最も新しい世代。
+    /// 他のプロセスが保存していることもあるので、ファイルから読む。
+    fn latest_generation(&self, journal: &Journal) -> Result<u64> {
+        let saved = self.saved_generations()?;
+        Ok(saved.into_iter().max().unwrap_or(0).max(journal.read()?.0))
+    }
+
+    /// 他のプロセスと同時に書き込まないように、ロックを取る。返り値を drop すると解放される。
+    fn lock(&self) -> Result<Option<File>> {
+        self.lock_path.as_deref().map(lock_file).transpose()
+    }
+
+    fn append_journal(&mut self, entry: JournalEntry) {
+        // 他のプロセスが保存して、ジャーナルを空にしている途中に追記しないようにする。
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => {
+                warn!("Cannot lock the user data: {:?}", err);
+                return;
+            }
+        };
+        if let Some(journal) = &mut self.journal {
+            if let Err(err) = journal.append(&entry) {
+                warn!("Cannot write the user data journal: {:?}", err);
+            }
+        }
+    }
+
+    pub fn load(
+        unigram_path: &String,
+        bigram_path: &String,
+        dict_path: &String,
+        config: &UserDataConfig,
+    ) -> Self {
         // ユーザーデータが読み込めないことは fatal エラーではない。
         // 初回起動時にはデータがないので。
         // データがなければ初期所状態から始める
-        let unigram_user_stats = match read_user_stats_file(unigram_path) {
-            Ok(dat) => {
-                let unique_count = dat.len() as u32;
-                let total_count: u32 = dat.iter().map(|f| f.1).sum();
-                let mut word_count: HashMap<String, u32> = HashMap::new();
-                for (word, count) in dat {
-                    word_count.insert(word, count);
-                }
-                UniGramUserStats::new(unique_count, total_count, word_count)
-            }
+        let unigram_user_stats = match read_user_stats_v2_file(unigram_path) {
+            Ok(word_count) => UniGramUserStats::new(word_count, config.clone()),
             Err(err) => {
                 warn!(
                     "Cannot load user unigram data from {}: {}",
                     unigram_path, err
                 );
 
-                UniGramUserStats::new(0, 0, HashMap::new())
+                UniGramUserStats::new(HashMap::new(), config.clone())
             }
         };
 
         // build bigram
-        let bigram_user_stats = match read_user_stats_file(bigram_path) {
-            Ok(dat) => {
-                let unique_count = dat.len() as u32;
-                let total_count: u32 = dat.iter().map(|f| f.1).sum();
-                let mut words_count: HashMap<String, u32> = HashMap::new();
-                for (words, count) in dat {
-                    words_count.insert(words, count);
-                }
-                BiGramUserStats::new(unique_count, total_count, words_count)
-            }
+        let bigram_user_stats = match read_user_stats_v2_file(bigram_path) {
+            Ok(words_count) => BiGramUserStats::new(words_count, config.clone()),
             Err(err) => {
                 warn!("Cannot load user bigram data from {}: {}", bigram_path, err);
                 // ユーザーデータは初回起動時などにはないので、データがないものとして処理を続行する
-                BiGramUserStats::new(0, 0, HashMap::new())
+                BiGramUserStats::new(HashMap::new(), config.clone())
             }
         };
 
@@ -124,6 +306,67 @@ impl UserData {
 
         // cedarwood トライを構築する。
         // キャッシュせずに動的に構築する方向性。
+        let kana_trie = Self::build_kana_trie(&unigram_user_stats, &dict);
+
+        UserData {
+            unigram_user_stats,
+            bigram_user_stats,
+            dict,
+            kana_trie: Arc::new(Mutex::new(kana_trie)),
+            unigram_path: Some(unigram_path.clone()),
+            bigram_path: Some(bigram_path.clone()),
+            dict_path: Some(dict_path.clone()),
+            reverse_dict: OnceLock::new(),
+            journal: None,
+            lock_path: None,
+            config: config.clone(),
+            generation: 0,
+            need_save: false,
+        }
+    }
+
+    /// アーカイブから取り込むときに使う。load と違って、ファイルがない場合や、
+    /// 壊れた行がある場合はエラーにする。上限を超えたエントリーも、ここでは捨てずに読む。
+    pub fn load_strict(unigram_path: &str, bigram_path: &str, dict_path: &str) -> Result<Self> {
+        let config = UserDataConfig {
+            max_entries: usize::MAX,
+            ..Default::default()
+        };
+        let unigram_user_stats = UniGramUserStats::new(
+            read_user_stats_v2_file_strict(unigram_path, '/')?,
+            config.clone(),
+        );
+        let bigram_user_stats =
+            BiGramUserStats::new(read_user_stats_v2_file_strict(bigram_path, '\t')?, config);
+
+        let src =
+            fs::read_to_string(dict_path).with_context(|| format!("Cannot read {dict_path}"))?;
+        for (i, line) in src.lines().enumerate() {
+            if line.is_empty() || line.starts_with(";;") {
+                continue;
+            }
+            let valid = matches!(line.split_once(' '),
+                Some((yomi, surfaces)) if !yomi.is_empty() && surfaces.len() > 1 && surfaces.starts_with('/') && surfaces.ends_with('/'));
+            if !valid {
+                bail!("Invalid line {} in {}: {:?}", i + 1, dict_path, line);
+            }
+        }
+        let dict = parse_skkdict(&src)?;
+
+        let kana_trie = Self::build_kana_trie(&unigram_user_stats, &dict);
+        Ok(UserData {
+            unigram_user_stats,
+            bigram_user_stats,
+            dict,
+            kana_trie: Arc::new(Mutex::new(kana_trie)),
+            ..Default::default()
+        })
+    }
+
+    fn build_kana_trie(
+        unigram_user_stats: &UniGramUserStats,
+        dict: &HashMap<String, Vec<String>>,
+    ) -> CedarwoodKanaTrie {
         let t1 = SystemTime::now();
         let mut yomis = unigram_user_stats
             .word_count
@@ -141,35 +384,40 @@ impl UserData {
             t2.duration_since(t1).unwrap().as_millis(),
             yomi_len
         );
-
-        UserData {
-            unigram_user_stats,
-            bigram_user_stats,
-            dict,
-            kana_trie: Arc::new(Mutex::new(kana_trie)),
-            unigram_path: Some(unigram_path.clone()),
-            bigram_path: Some(bigram_path.clone()),
-            dict_path: Some(dict_path.clone()),
-            need_save: false,
-        }
+        kana_trie
     }
 
     /// 入力確定した漢字のリストをユーザー統計データとして記録する。
     /// "Surface/Kana" のフォーマットで渡すこと。
     pub fn record_entries(&mut self, candidates: &[Candidate]) {
-        self.unigram_user_stats.record_entries(candidates);
-        self.bigram_user_stats.record_entries(candidates);
+        let now = unix_time();
+        self.apply_record(candidates, now, ReplayTargets::ALL);
+        self.append_journal(JournalEntry::Record {
+            now,
+            candidates: candidates.to_vec(),
+        });
+    }
+
+    fn apply_record(&mut self, candidates: &[Candidate], now: u64, targets: ReplayTargets) {
+        if targets.unigram {
+            self.unigram_user_stats.record_entries(candidates, now);
+        }
+        if targets.bigram {
+            self.bigram_user_stats.record_entries(candidates, now);
+        }
 
         // 複合語として覚えておくべきものがあれば、学習する。
-        candidates
-            .iter()
-            .filter(|candidate| candidate.compound_word)
-            .for_each(|candidate| {
-                self.dict
-                    .entry(candidate.yomi.to_string())
-                    .or_default()
-                    .push(candidate.surface.to_string())
-            });
+        if targets.dict {
+            candidates
+                .iter()
+                .filter(|candidate| candidate.compound_word)
+                .for_each(|candidate| {
+                    self.dict
+                        .entry(candidate.yomi.to_string())
+                        .or_default()
+                        .push(candidate.surface.to_string())
+                });
+        }
 
         // かなトライを更新する
         let mut kana_trie = self.kana_trie.lock().unwrap();
@@ -182,44 +430,262 @@ impl UserData {
                 }
             });
 
+        self.reverse_dict = OnceLock::new();
         self.need_save = true;
     }
 
-    pub fn write_user_files(&mut self) -> Result<()> {
-        if self.need_save {
-            info!(
-                "Saving user stats file: unigram={:?},{}, bigram={:?},{}",
-                self.unigram_path,
-                self.unigram_user_stats.word_count.len(),
-                self.bigram_path,
-                self.bigram_user_stats.word_count.len(),
-            );
-            if let Some(unigram_path) = &self.unigram_path {
-                write_user_stats_file(unigram_path, &self.unigram_user_stats.word_count)?;
+    /// 誤って学習した候補を忘れる。
+    /// 候補の unigram と、候補を含む bigram の統計、ユーザー辞書に登録された複合語を削除する。
+    /// 何か削除した場合は true を返す。
+    pub fn delete_candidate(&mut self, candidate: &Candidate) -> bool {
+        let deleted = self.apply_delete(candidate, ReplayTargets::ALL);
+        if deleted {
+            self.append_journal(JournalEntry::Delete {
+                candidate: candidate.clone(),
+            });
+        }
+        deleted
+    }
+
+    fn apply_delete(&mut self, candidate: &Candidate, targets: ReplayTargets) -> bool {
+        let key = candidate.key();
+        let mut deleted = false;
+        if targets.unigram {
+            deleted |= self.unigram_user_stats.delete_entry(&key);
+        }
+        if targets.bigram {
+            deleted |= self.bigram_user_stats.delete_entries_with(&key);
+        }
+
+        if let Some(surfaces) = self.dict.get_mut(&candidate.yomi).filter(|_| targets.dict) {
+            let len = surfaces.len();
+            surfaces.retain(|surface| *surface != candidate.surface);
+            deleted |= surfaces.len() != len;
+            if surfaces.is_empty() {
+                self.dict.remove(&candidate.yomi);
             }
-            if let Some(bigram_path) = &self.bigram_path {
-                write_user_stats_file(bigram_path, &self.bigram_user_stats.word_count)?;
+        }
+
+        if deleted {
+            info!("Deleted the candidate from user data: {}", key);
+            // 他に同じ読みの単語が残っていなければ、かなトライからも消す。
+            if !self.has_yomi(&candidate.yomi) {
+                self.kana_trie.lock().unwrap().remove(&candidate.yomi);
             }
-            if let Some(dict_path) = &self.dict_path {
-                write_skk_dict(dict_path, vec![self.dict.clone()])?;
+            self.reverse_dict = OnceLock::new();
+            self.need_save = true;
+        }
+        deleted
+    }
+
+    /// ユーザー辞書か unigram 統計に、この読みの単語があるか。
+    fn has_yomi(&self, yomi: &str) -> bool {
+        self.dict.contains_key(yomi) || self.unigram_user_stats.has_yomi(yomi)
+    }
+
+    /// 別の環境の学習データを取り込む。
+    /// 統計の出現回数は足し合わせ、ユーザー辞書の単語は和集合をとる。
+    ///
+    /// @return 上限を超えたので捨てた、統計のエントリーの数
+    pub fn merge(&mut self, other: &UserData) -> usize {
+        let evicted = self.unigram_user_stats.merge(&other.unigram_user_stats)
+            + self.bigram_user_stats.merge(&other.bigram_user_stats);
+        for (yomi, surfaces) in &other.dict {
+            let dst = self.dict.entry(yomi.to_string()).or_default();
+            for surface in surfaces {
+                if !dst.contains(surface) {
+                    dst.push(surface.to_string());
+                }
             }
+        }
+
+        // かなトライを更新する
+        let mut kana_trie = self.kana_trie.lock().unwrap();
+        other
+            .unigram_user_stats
+            .word_count
+            .keys()
+            .filter_map(|it| it.split_once('/'))
+            .map(|(_, yomi)| yomi)
+            .chain(other.dict.keys().map(|it| it.as_str()))
+            .for_each(|yomi| {
+                if !kana_trie.contains(yomi) {
+                    kana_trie.update(yomi)
+                }
+            });
+        drop(kana_trie);
+
+        self.reverse_dict = OnceLock::new();
+        self.need_save = true;
+        evicted
+    }
 
-            self.need_save = false;
+    /// 学習データを、別の環境の学習データで置き換える。
+    ///
+    /// @return 上限を超えたので捨てた、統計のエントリーの数
+    pub fn replace(&mut self, other: UserData) -> usize {
+        let evicted = self.unigram_user_stats.replace(other.unigram_user_stats)
+            + self.bigram_user_stats.replace(other.bigram_user_stats);
+        self.dict = other.dict;
+        *self.kana_trie.lock().unwrap() =
+            Self::build_kana_trie(&self.unigram_user_stats, &self.dict);
+        self.reverse_dict = OnceLock::new();
+        self.need_save = true;
+        evicted
+    }
+
+    /// 学習データを指定したパスに書き出す。一時ファイルに書いてから置き換える。
+    pub fn write_files_to(
+        &self,
+        unigram_path: &str,
+        bigram_path: &str,
+        dict_path: &str,
+    ) -> Result<()> {
+        write_user_stats_file(unigram_path, &self.unigram_user_stats.word_count, 0)?;
+        write_user_stats_file(bigram_path, &self.bigram_user_stats.word_count, 0)?;
+        self.write_dict(dict_path, 0)
+    }
+
+    fn write_dict(&self, dict_path: &str, generation: u64) -> Result<()> {
+        write_skk_dict_with_comments(
+            &(dict_path.to_string() + ".tmp"),
+            &[format!("{DICT_GENERATION_COMMENT}{generation}")],
+            vec![self.dict.clone()],
+        )?;
+        fs::rename(dict_path.to_string() + ".tmp", dict_path)?;
+        Ok(())
+    }
+
+    /// 変更があれば保存する。
+    /// 変更がなくても、akaza-data での取り込みなどで他のプロセスが保存していれば、読み込みなおす。
+    pub fn write_user_files(&mut self) -> Result<()> {
+        if self.need_save {
+            let _lock = self.lock()?;
+            self.save()?;
+        } else if self.is_saved_by_others()? {
+            let _lock = self.lock()?;
+            info!("User data was saved by another process. Reloading.");
+            self.reload()?;
         }
 
         Ok(())
     }
 
+    /// 読み込んだ後に、他のプロセスが新しい世代のファイルを保存したか。
+    fn is_saved_by_others(&self) -> Result<bool> {
+        if self.journal.is_none() {
+            return Ok(false);
+        }
+        Ok(self.saved_generations()?.into_iter().max().unwrap_or(0) > self.generation)
+    }
+
+    /// 他のプロセスの変更を取り込んでから保存する。ロックを取ってから呼ぶこと。
+    ///
+    /// メモリ上の学習データをそのまま書くと、同じファイルを使う他のプロセスの学習結果を上書きしてしまう。
+    /// そこで、保存されているファイルに、すべてのプロセスが追記しているジャーナルを適用しなおしてから書く。
+    fn save(&mut self) -> Result<()> {
+        self.reload()?;
+        self.write_files()
+    }
+
+    /// すべてのファイルを新しい世代で書き出して、ジャーナルを空にする。ロックを取ってから呼ぶこと。
+    ///
+    /// 書き出しの途中で落ちても、ジャーナルより新しい世代のファイルには再適用しないので、
+    /// 次回の起動時に二重に数えることはない。
+    fn write_files(&mut self) -> Result<()> {
+        info!(
+            "Saving user stats file: unigram={:?},{}, bigram={:?},{}",
+            self.unigram_path,
+            self.unigram_user_stats.word_count.len(),
+            self.bigram_path,
+            self.bigram_user_stats.word_count.len(),
+        );
+        let generation = match &self.journal {
+            Some(journal) => self.latest_generation(journal)?,
+            None => self.saved_generations()?.into_iter().max().unwrap_or(0),
+        } + 1;
+        if let Some(unigram_path) = &self.unigram_path {
+            write_user_stats_file(
+                unigram_path,
+                &self.unigram_user_stats.word_count,
+                generation,
+            )?;
+        }
+        if let Some(bigram_path) = &self.bigram_path {
+            write_user_stats_file(bigram_path, &self.bigram_user_stats.word_count, generation)?;
+        }
+        if let Some(dict_path) = &self.dict_path {
+            self.write_dict(dict_path, generation)?;
+        }
+        // ジャーナルの内容は統計ファイルに書き出したので、もういらない。
+        if let Some(journal) = &mut self.journal {
+            journal.reset(generation)?;
+        }
+
+        self.generation = generation;
+        self.need_save = false;
+        Ok(())
+    }
+
+    /// 読みが yomi_prefix で始まる単語を、ユーザー辞書とユーザーの unigram 統計から探す。
+    ///
+    /// @return (読み, 表層) のリスト。
+    pub fn predict(&self, yomi_prefix: &str) -> Vec<(String, String)> {
+        let mut result: Vec<(String, String)> = self
+            .dict
+            .iter()
+            .filter(|(yomi, _)| yomi.starts_with(yomi_prefix))
+            .flat_map(|(yomi, surfaces)| {
+                surfaces
+                    .iter()
+                    .map(|surface| (yomi.to_string(), surface.to_string()))
+            })
+            .collect();
+        result.extend(
+            self.unigram_user_stats
+                .predict(yomi_prefix)
+                .map(|(yomi, surface)| (yomi.to_string(), surface.to_string())),
+        );
+        result
+    }
+
+    /// ユーザー辞書と unigram 統計から作った逆引き辞書を得る。
+    /// 学習データが変わるまでは、同じものを使いまわす。
+    pub fn reverse_dict(&self) -> Arc<ReverseDict> {
+        self.reverse_dict
+            .get_or_init(|| {
+                let mut reverse_dict = ReverseDict::default();
+                for (yomi, surface) in self.predict("") {
+                    reverse_dict.add(&surface, &yomi);
+                }
+                Arc::new(reverse_dict)
+            })
+            .clone()
+    }
+
     pub fn get_unigram_cost(&self, node: &WordNode) -> Option<f32> {
-        self.unigram_user_stats.get_cost(node.key())
+        self.unigram_user_stats.get_cost(node.key(), unix_time())
     }
 
     pub fn get_bigram_cost(&self, node1: &WordNode, node2: &WordNode) -> Option<f32> {
         self.bigram_user_stats
-            .get_cost(node1.key().as_str(), node2.key().as_str())
+            .get_cost(node1.key().as_str(), node2.key().as_str(), unix_time())
     }
 }
 
+/// ロックファイルを開いて、排他ロックを取る。他のプロセスがロックを持っていれば待つ。
+fn lock_file(path: &str) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Cannot open {path}"))?;
+    file.lock().with_context(|| format!("Cannot lock {path}"))?;
+    Ok(file)
+}
+
 #[cfg(test)]
 mod tests {
     use log::LevelFilter;
@@ -273,4 +739,231 @@ mod tests {
         info!("{}, {}", cost2, cost3);
         assert!(cost2 > cost3);
     }
+
+    #[test]
+    fn test_reverse_dict() {
+        let mut user_data = UserData::default();
+        user_data.record_entries(&[Candidate::new("あずま", "東", 0_f32)]);
+        let reverse_dict = user_data.reverse_dict();
+        assert_eq!(reverse_dict.get("東"), Some(&"あずま".to_string()));
+        // 学習データが変わらなければ、同じものを使いまわす。
+        assert!(Arc::ptr_eq(&reverse_dict, &user_data.reverse_dict()));
+
+        // 学習したり削除したりすると、作りなおす。
+        user_data.record_entries(&[Candidate::new("ひがし", "東", 0_f32)]);
+        assert!(!Arc::ptr_eq(&reverse_dict, &user_data.reverse_dict()));
+        user_data.delete_candidate(&Candidate::new("あずま", "東", 0_f32));
+        assert_eq!(
+            user_data.reverse_dict().get("東"),
+            Some(&"ひがし".to_string())
+        );
+    }
+
+    #[test]
+    fn test_delete_candidate() {
+        let mut user_data = UserData::default();
+        let mut compound = Candidate::new("きたかな", "北香那", 0_f32);
+        compound.compound_word = true;
+        let candidates = [Candidate::new("わたし", "渡し", 0_f32), compound.clone()];
+        user_data.record_entries(&candidates);
+        user_data.record_entries(&[Candidate::new("わたし", "私", 0_f32)]);
+
+        let node = |surface: &str, yomi: &str| WordNode::new(0, surface, yomi, None, false);
+        assert!(user_data
+            .get_bigram_cost(&node("渡し", "わたし"), &node("北香那", "きたかな"))
+            .is_some());
+        assert_eq!(
+            user_data.dict.get("きたかな"),
+            Some(&vec!["北香那".to_string()])
+        );
+
+        assert!(user_data.delete_candidate(&compound));
+        assert_eq!(
+            user_data.get_unigram_cost(&node("北香那", "きたかな")),
+            None
+        );
+        assert_eq!(
+            user_data.get_bigram_cost(&node("渡し", "わたし"), &node("北香那", "きたかな")),
+            None
+        );
+        assert_eq!(user_data.dict.get("きたかな"), None);
+        assert!(user_data.need_save);
+
+        // 他に使う単語のない読みは、かなトライからも消える。
+        assert!(!user_data.kana_trie.lock().unwrap().contains("きたかな"));
+
+        // 他の候補の統計は残る。
+        assert!(user_data
+            .get_unigram_cost(&node("渡し", "わたし"))
+            .is_some());
+        assert!(user_data.get_unigram_cost(&node("私", "わたし")).is_some());
+
+        // 同じ読みの単語が残っている間は、かなトライに読みを残す。
+        assert!(user_data.delete_candidate(&Candidate::new("わたし", "渡し", 0_f32)));
+        assert!(user_data.kana_trie.lock().unwrap().contains("わたし"));
+        assert!(user_data.delete_candidate(&Candidate::new("わたし", "私", 0_f32)));
+        assert!(!user_data.kana_trie.lock().unwrap().contains("わたし"));
+
+        // 二回目は何も削除しない。
+        assert!(!user_data.delete_candidate(&compound));
+    }
+
+    #[test]
+    fn test_replace_evicted() {
+        let config = UserDataConfig {
+            max_entries: 1,
+            ..Default::default()
+        };
+        let missing = "/nonexistent/akaza".to_string();
+        let mut user_data = UserData::load(&missing, &missing, &missing, &config);
+
+        let mut src = UserData::default();
+        src.record_entries(&[
+            Candidate::new("わたし", "私", 0_f32),
+            Candidate::new("が", "が", 0_f32),
+        ]);
+        // unigram は二つのうち一つを捨てる。bigram は一つなので捨てない。
+        assert_eq!(user_data.replace(src), 1);
+    }
+
+    #[test]
+    fn test_replay_journal() -> Result<()> {
+        let tmpdir = tempfile::TempDir::new()?;
+        let journal_path = tmpdir.path().join("journal.txt");
+        let journal_path = journal_path.to_str().unwrap();
+        let watashi = WordNode::new(0, "私", "わたし", None, false);
+        let kitakana = Candidate::new("きたかな", "北香那", 0_f32);
+
+        let mut user_data = UserData::default();
+        user_data.open_journal(journal_path)?;
+        user_data.record_entries(&[Candidate::new("わたし", "私", 0_f32), kitakana.clone()]);
+        user_data.delete_candidate(&kitakana);
+
+        // 保存前に落ちても、ジャーナルから復元できる。
+        let mut restored = UserData::default();
+        restored.open_journal(journal_path)?;
+        assert_eq!(
+            restored.get_unigram_cost(&watashi),
+            user_data.get_unigram_cost(&watashi)
+        );
+        assert_eq!(
+            restored.get_unigram_cost(&WordNode::new(0, "北香那", "きたかな", None, false)),
+            None
+        );
+        assert!(restored.kana_trie.lock().unwrap().contains("わたし"));
+
+        // 保存するとジャーナルは空になる。
+        user_data.write_user_files()?;
+        let mut restored = UserData::default();
+        restored.open_journal(journal_path)?;
+        assert_eq!(restored.get_unigram_cost(&watashi), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_journal_after_partial_save() -> Result<()> {
+        let tmpdir = tempfile::TempDir::new()?;
+        let path = |name: &str| tmpdir.path().join(name).to_string_lossy().to_string();
+        let (unigram_path, bigram_path, dict_path) =
+            (path("unigram.txt"), path("bigram.txt"), path("dict.txt"));
+        let config = UserDataConfig::default();
+        let load = || -> Result<UserData> {
+            let mut user_data = UserData::load(&unigram_path, &bigram_path, &dict_path, &config);
+            user_data.lock_path = Some(path("user_data.lock"));
+            user_data.open_journal(&path("journal.txt"))?;
+            Ok(user_data)
+        };
+        let count = |user_data: &UserData, key: &str| {
+            user_data
+                .unigram_user_stats
+                .word_count
+                .get(key)
+                .map(|it| it.count)
+        };
+
+        let mut user_data = load()?;
+        user_data.record_entries(&[
+            Candidate::new("わたし", "私", 0_f32),
+            Candidate::new("が", "が", 0_f32),
+        ]);
+        // unigram のファイルを書いたところで落ちたことにする。ジャーナルは残っている。
+        write_user_stats_file(&unigram_path, &user_data.unigram_user_stats.word_count, 1)?;
+
+        // unigram には再適用しないので二重に数えない。bigram には再適用する。
+        let restored = load()?;
+        assert_eq!(count(&restored, "私/わたし"), Some(1));
+        assert_eq!(restored.bigram_user_stats.word_count.len(), 1);
+
+        // 再適用した内容は保存されていて、ジャーナルは空になっている。
+        let restored = load()?;
+        assert_eq!(count(&restored, "私/わたし"), Some(1));
+        assert_eq!(restored.bigram_user_stats.word_count.len(), 1);
+        let (generation, entries) = Journal::new(&path("journal.txt")).read()?;
+        assert!(entries.is_empty());
+        assert_eq!(
+            generation,
+            read_generation(&unigram_path, STATS_GENERATION_PREFIX)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_from_two_processes() -> Result<()> {
+        let tmpdir = tempfile::TempDir::new()?;
+        let path = |name: &str| tmpdir.path().join(name).to_string_lossy().to_string();
+        let (unigram_path, bigram_path, dict_path) =
+            (path("unigram.txt"), path("bigram.txt"), path("dict.txt"));
+        let config = UserDataConfig::default();
+        let load = || -> Result<UserData> {
+            let mut user_data = UserData::load(&unigram_path, &bigram_path, &dict_path, &config);
+            user_data.lock_path = Some(path("user_data.lock"));
+            user_data.open_journal(&path("journal.txt"))?;
+            Ok(user_data)
+        };
+        let watashi = WordNode::new(0, "私", "わたし", None, false);
+        let kitakana = WordNode::new(0, "北香那", "きたかな", None, false);
+
+        // ibus-akaza と akaza-server が同時に動いていて、それぞれ学習する。
+        let mut ibus = load()?;
+        let mut server = load()?;
+        ibus.record_entries(&[Candidate::new("わたし", "私", 0_f32)]);
+        let mut compound = Candidate::new("きたかな", "北香那", 0_f32);
+        compound.compound_word = true;
+        server.record_entries(&[compound]);
+
+        // 片方が保存しても、もう片方のまだ保存していない学習結果はジャーナルから取り込まれる。
+        ibus.write_user_files()?;
+        assert!(ibus.get_unigram_cost(&kitakana).is_some());
+        assert_eq!(ibus.dict.get("きたかな"), Some(&vec!["北香那".to_string()]));
+
+        // もう片方が保存しても、先に保存した学習結果は上書きされない。
+        server.record_entries(&[Candidate::new("わたし", "私", 0_f32)]);
+        server.write_user_files()?;
+        let restored = load()?;
+        assert_eq!(
+            restored
+                .unigram_user_stats
+                .word_count
+                .get("私/わたし")
+                .map(|it| it.count),
+            Some(2)
+        );
+        assert!(restored.get_unigram_cost(&kitakana).is_some());
+        assert!(restored.kana_trie.lock().unwrap().contains("わたし"));
+        assert!(server.get_unigram_cost(&watashi).is_some());
+
+        // 変更のないプロセスも、他のプロセスが保存した内容を読み込みなおす。
+        let mut idle = load()?;
+        let mut importer = load()?;
+        importer.replace(UserData::default());
+        {
+            let _lock = importer.lock()?;
+            importer.write_files()?;
+        }
+        assert!(idle.get_unigram_cost(&watashi).is_some());
+        idle.write_user_files()?;
+        assert_eq!(idle.get_unigram_cost(&watashi), None);
+        assert!(!idle.kana_trie.lock().unwrap().contains("わたし"));
+        Ok(())
+    }
 }
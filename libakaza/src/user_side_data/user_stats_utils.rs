@@ -1,10 +1,35 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::collections::HashMap;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
 use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use log::info;
+
+/// ユーザー統計の一エントリー。
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct UserStatsEntry {
+    /// 出現回数
+    pub(crate) count: u32,
+    /// 最後に使われた日時。UNIX 時間の秒数。
+    pub(crate) last_used: u64,
+}
+
+/// 統計ファイルの先頭に書く、保存した世代の行の接頭辞。
+pub(crate) const STATS_GENERATION_PREFIX: &str = "#generation ";
+
+/// 現在の UNIX 時間の秒数。
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+/// v1 形式のファイルを読む。一行が "キー 出現回数" の形式。
 pub(crate) fn read_user_stats_file(path: &String) -> Result<Vec<(String, u32)>> {
     let file = File::open(path)?;
 
@@ -27,7 +52,88 @@ pub(crate) fn read_user_stats_file(path: &String) -> Result<Vec<(String, u32)>>
     Ok(result)
 }
 
-pub(crate) fn write_user_stats_file(path: &str, word_count: &HashMap<String, u32>) -> Result<()> {
+/// v2 形式のファイルを読む。一行が "キー 出現回数 最終使用日時" の形式。
+pub(crate) fn read_user_stats_v2_file(path: &str) -> Result<HashMap<String, UserStatsEntry>> {
+    let file = File::open(path)?;
+
+    let mut result: HashMap<String, UserStatsEntry> = HashMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.context("Cannot read user language model file")?;
+        if line.starts_with(STATS_GENERATION_PREFIX) {
+            continue;
+        }
+        let mut iter = line.trim().rsplitn(3, ' ');
+        let (Some(last_used), Some(count), Some(key)) = (iter.next(), iter.next(), iter.next())
+        else {
+            continue;
+        };
+
+        let count = count
+            .parse::<u32>()
+            .with_context(|| format!("Invalid count in user language model: {line}"))?;
+        let last_used = last_used
+            .parse::<u64>()
+            .with_context(|| format!("Invalid timestamp in user language model: {line}"))?;
+
+        result.insert(key.to_string(), UserStatsEntry { count, last_used });
+    }
+
+    Ok(result)
+}
+
+/// v2 形式のファイルを、壊れた行を許さずに読む。
+/// アーカイブから取り込むときなど、読めなかったデータで既存のデータを上書きしないようにするために使う。
+/// key_separator は、キーに含まれているはずの区切り文字。
+pub(crate) fn read_user_stats_v2_file_strict(
+    path: &str,
+    key_separator: char,
+) -> Result<HashMap<String, UserStatsEntry>> {
+    let src = fs::read_to_string(path).with_context(|| format!("Cannot read {path}"))?;
+    // 書き出すときは必ず改行で終わるので、そうでなければ途中で切れている。
+    if !src.is_empty() && !src.ends_with('\n') {
+        bail!("{} is truncated", path);
+    }
+
+    let mut result: HashMap<String, UserStatsEntry> = HashMap::new();
+
+    for (i, line) in src.lines().enumerate() {
+        if i == 0 {
+            if let Some(generation) = line.strip_prefix(STATS_GENERATION_PREFIX) {
+                if generation.parse::<u64>().is_err() {
+                    bail!("Invalid generation in {}: {:?}", path, line);
+                }
+                continue;
+            }
+        }
+        let mut iter = line.rsplitn(3, ' ');
+        let (Some(last_used), Some(count), Some(key)) = (iter.next(), iter.next(), iter.next())
+        else {
+            bail!("Invalid line {} in {}: {:?}", i + 1, path, line);
+        };
+        if !key.contains(key_separator) {
+            bail!("Invalid key at line {} in {}: {:?}", i + 1, path, line);
+        }
+        let (Ok(count), Ok(last_used)) = (count.parse::<u32>(), last_used.parse::<u64>()) else {
+            bail!("Invalid number at line {} in {}: {:?}", i + 1, path, line);
+        };
+        if result
+            .insert(key.to_string(), UserStatsEntry { count, last_used })
+            .is_some()
+        {
+            bail!("Duplicated key at line {} in {}: {:?}", i + 1, path, key);
+        }
+    }
+
+    Ok(result)
+}
+
+/// 統計ファイルを書く。先頭の行には、保存した世代を書く。
+pub(crate) fn write_user_stats_file(
+    path: &str,
+    word_count: &HashMap<String, UserStatsEntry>,
+    generation: u64,
+) -> Result<()> {
     let mut tmpfile = OpenOptions::new()
         .write(true)
         .create(true)
@@ -35,10 +141,13 @@ pub(crate) fn write_user_stats_file(path: &str, word_count: &HashMap<String, u32
         .mode(0o600)
         .open(path.to_string() + ".tmp")?;
 
-    for (key, cnt) in word_count {
+    tmpfile.write_all(format!("{STATS_GENERATION_PREFIX}{generation}\n").as_bytes())?;
+    for (key, entry) in word_count {
         tmpfile.write_all(key.as_bytes())?;
         tmpfile.write_all(" ".as_bytes())?;
-        tmpfile.write_all(cnt.to_string().as_bytes())?;
+        tmpfile.write_all(entry.count.to_string().as_bytes())?;
+        tmpfile.write_all(" ".as_bytes())?;
+        tmpfile.write_all(entry.last_used.to_string().as_bytes())?;
         tmpfile.write_all("\n".as_bytes())?;
     }
     fs::rename(path.to_owned() + ".tmp", path)?;
@@ -46,19 +155,257 @@ pub(crate) fn write_user_stats_file(path: &str, word_count: &HashMap<String, u32
     Ok(())
 }
 
+/// ファイルの先頭の行から、保存した世代を読む。
+/// ファイルがない場合や、世代を書いていない古いファイルの場合は 0。
+pub(crate) fn read_generation(path: &str, prefix: &str) -> Result<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).with_context(|| format!("Cannot open {path}")),
+    };
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    Ok(line
+        .trim_end()
+        .strip_prefix(prefix)
+        .and_then(|it| it.parse::<u64>().ok())
+        .unwrap_or(0))
+}
+
+/// v2 のファイルがまだなければ、v1 のファイルを変換して作る。
+/// v1 には最終使用日時がないので、ファイルの更新日時を使う。v1 のファイルは消さずに残す。
+pub(crate) fn migrate_user_stats_file(v1_path: &String, v2_path: &str) -> Result<()> {
+    if Path::new(v2_path).exists() || !Path::new(v1_path).exists() {
+        return Ok(());
+    }
+
+    let last_used = fs::metadata(v1_path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0);
+    let word_count = read_user_stats_file(v1_path)?
+        .into_iter()
+        .map(|(key, count)| (key, UserStatsEntry { count, last_used }))
+        .collect::<HashMap<_, _>>();
+    write_user_stats_file(v2_path, &word_count, 0)?;
+    info!(
+        "Migrated user stats file: {} -> {} ({} entries)",
+        v1_path,
+        v2_path,
+        word_count.len()
+    );
+    Ok(())
+}
+
+/// src の統計を dst に足し合わせる。出現回数は合計し、最終使用日時は新しいほうを使う。
+pub(crate) fn merge_word_count(
+    dst: &mut HashMap<String, UserStatsEntry>,
+    src: &HashMap<String, UserStatsEntry>,
+) {
+    for (key, entry) in src {
+        dst.entry(key.to_string())
+            .and_modify(|it| {
+                it.count = it.count.saturating_add(entry.count);
+                it.last_used = it.last_used.max(entry.last_used);
+            })
+            .or_insert(*entry);
+    }
+}
+
+/// エントリー数が max_entries を超えていたら、最後に使われてから最も時間が経ったものから捨てる。
+/// 最終使用日時が同じものは、出現回数の少ないものから捨てる。v1 から変換したファイルは、
+/// 全部のエントリーが同じ日時になっているため。
+/// 学習するたびに捨てなくてすむよう、超えたときは max_entries の 9 割まで減らす。
+///
+/// @return 捨てたエントリー
+pub(crate) fn evict_least_recently_used(
+    word_count: &mut HashMap<String, UserStatsEntry>,
+    max_entries: usize,
+) -> Vec<(String, UserStatsEntry)> {
+    if word_count.len() <= max_entries {
+        return Vec::new();
+    }
+
+    let excess = word_count.len() - (max_entries - max_entries / 10);
+    let mut entries = word_count
+        .iter()
+        .map(|(key, entry)| (entry.last_used, entry.count, key.as_str()))
+        .collect::<Vec<_>>();
+    entries.select_nth_unstable(excess - 1);
+    let targets = entries[..excess]
+        .iter()
+        .map(|(_, _, key)| key.to_string())
+        .collect::<Vec<_>>();
+    targets
+        .iter()
+        .filter_map(|key| word_count.remove_entry(key))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeSet;
     use std::io::Read;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
 
     #[test]
     fn test_write() {
         let tmpfile = NamedTempFile::new().unwrap();
         let path = tmpfile.path().to_str().unwrap().to_string();
-        write_user_stats_file(&path, &HashMap::from([("渡し".to_string(), 3_u32)])).unwrap();
+        write_user_stats_file(
+            &path,
+            &HashMap::from([(
+                "渡し".to_string(),
+                UserStatsEntry {
+                    count: 3,
+                    last_used: 1672531200,
+                },
+            )]),
+            5,
+        )
+        .unwrap();
         let mut buf = String::new();
-        File::open(path).unwrap().read_to_string(&mut buf).unwrap();
-        assert_eq!(buf, "渡し 3\n");
+        File::open(&path).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "#generation 5\n渡し 3 1672531200\n");
+        assert_eq!(read_generation(&path, STATS_GENERATION_PREFIX).unwrap(), 5);
+
+        let got = read_user_stats_v2_file(&path).unwrap();
+        assert_eq!(
+            got.get("渡し"),
+            Some(&UserStatsEntry {
+                count: 3,
+                last_used: 1672531200
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_strict() -> anyhow::Result<()> {
+        let tmpfile = NamedTempFile::new()?;
+        let path = tmpfile.path().to_str().unwrap().to_string();
+
+        fs::write(&path, "私/わたし 3 1672531200\n渡し/わたし 1 1672531300\n")?;
+        assert_eq!(read_user_stats_v2_file_strict(&path, '/')?.len(), 2);
+        fs::write(&path, "#generation 2\n私/わたし 3 1672531200\n")?;
+        assert_eq!(read_user_stats_v2_file_strict(&path, '/')?.len(), 1);
+
+        // v1 形式の行や、途中で切れた行、区切り文字のないキーはエラーにする。
+        for broken in [
+            "私/わたし 3\n",
+            "私/わたし 3 16725x\n",
+            "私/わたし 3 16725",
+            "私 3 1672531200\n",
+            "私/わたし 3 1672531200\n私/わたし 1 1672531300\n",
+            "#generation x\n私/わたし 3 1672531200\n",
+        ] {
+            fs::write(&path, broken)?;
+            assert!(
+                read_user_stats_v2_file_strict(&path, '/').is_err(),
+                "{broken}"
+            );
+        }
+        // 寛容な読み込みでは、読めない行は読み飛ばす。
+        fs::write(&path, "私/わたし 3\n")?;
+        assert!(read_user_stats_v2_file(&path)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> anyhow::Result<()> {
+        let tmpdir = TempDir::new()?;
+        let v1_path = tmpdir
+            .path()
+            .join("unigram.v1.txt")
+            .to_string_lossy()
+            .to_string();
+        let v2_path = tmpdir
+            .path()
+            .join("unigram.v2.txt")
+            .to_string_lossy()
+            .to_string();
+        fs::write(&v1_path, "渡し/わたし 3\n私/わたし 5\n")?;
+
+        migrate_user_stats_file(&v1_path, &v2_path)?;
+
+        let got = read_user_stats_v2_file(&v2_path)?;
+        assert_eq!(got.len(), 2);
+        assert_eq!(got.get("私/わたし").unwrap().count, 5);
+        assert!(got.get("私/わたし").unwrap().last_used > 0);
+        assert!(Path::new(&v1_path).exists());
+        // 世代を書いていないファイルは 0 世代とみなす。
+        assert_eq!(read_generation(&v1_path, STATS_GENERATION_PREFIX)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut word_count = HashMap::from([
+            (
+                "a".to_string(),
+                UserStatsEntry {
+                    count: 10,
+                    last_used: 1,
+                },
+            ),
+            (
+                "b".to_string(),
+                UserStatsEntry {
+                    count: 1,
+                    last_used: 3,
+                },
+            ),
+            (
+                "c".to_string(),
+                UserStatsEntry {
+                    count: 1,
+                    last_used: 2,
+                },
+            ),
+        ]);
+        assert!(evict_least_recently_used(&mut word_count, 3).is_empty());
+
+        // 出現回数ではなく、最後に使われた日時で捨てる。
+        let evicted = evict_least_recently_used(&mut word_count, 1);
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(word_count.keys().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[test]
+    fn test_evict_by_count_in_batch() {
+        // v1 から変換したファイルのように、最終使用日時がすべて同じ。
+        let mut word_count = (1..=11)
+            .map(|count| {
+                (
+                    count.to_string(),
+                    UserStatsEntry {
+                        count,
+                        last_used: 1,
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        // 上限を一つ超えたら、上限の 9 割まで、出現回数の少ないものから捨てる。
+        let evicted = evict_least_recently_used(&mut word_count, 10);
+        assert_eq!(
+            evicted
+                .iter()
+                .map(|(_, entry)| entry.count)
+                .collect::<BTreeSet<_>>(),
+            BTreeSet::from([1, 2])
+        );
+        assert_eq!(word_count.len(), 9);
+
+        // 9 割まで減らしたので、次に一つ増えても捨てない。
+        word_count.insert(
+            "12".to_string(),
+            UserStatsEntry {
+                count: 12,
+                last_used: 1,
+            },
+        );
+        assert!(evict_least_recently_used(&mut word_count, 10).is_empty());
     }
 }
@@ -13,9 +13,6 @@ struct marisa_obj {
     trie: *mut c_void,
 }
 
-// ↓↓ It's unsafe operation. I'll remove this in the future.
-unsafe impl Send for marisa_obj {}
-
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 struct marisa_keyset {
@@ -70,6 +67,12 @@ pub struct Marisa {
     marisa: *mut marisa_obj,
 }
 
+// marisa::Trie は検索のたびに Agent をローカルに作っているので、読み出しだけであれば
+// 複数のスレッドから同時に呼んでも安全。load/build は &mut self を要求するので、
+// 読み出しと同時に書き換えられることはない。
+unsafe impl Send for Marisa {}
+unsafe impl Sync for Marisa {}
+
 impl Default for Marisa {
     fn default() -> Marisa {
         let marisa = unsafe { marisa_new() };